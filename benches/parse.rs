@@ -0,0 +1,99 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! How fast we split reads into lines and lines into messages, e.g. for busy
+//! channels and the NAMES burst on joining a big one. Run with `cargo bench`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use r8ball::irc::{
+    iter::{BufIterator, TruncStatus},
+    parse::Message,
+};
+
+/// A read full of channel chatter.
+fn chatter() -> Vec<u8> {
+    let mut buf = vec![];
+    for n in 0..200 {
+        buf.extend(
+            format!(
+                "@time=2021-06-01T12:00:00.000Z;account=user{0} :user{0}!~user@host-{0}.example.org PRIVMSG #channel :this is message number {0}, with some words in it\r\n",
+                n
+            )
+            .as_bytes(),
+        );
+    }
+    buf
+}
+
+/// A NAMES reply for a big channel, split across lines like servers do.
+fn names() -> Vec<u8> {
+    let mut buf = vec![];
+    for line in 0..50 {
+        let nicks = (0..40)
+            .map(|n| format!("@nick{}_{}!user@host", line, n))
+            .collect::<Vec<String>>()
+            .join(" ");
+        buf.extend(format!(":irc.example.org 353 bot = #big :{}\r\n", nicks).as_bytes());
+    }
+    buf
+}
+
+fn parse_all(buf: &[u8]) -> usize {
+    let mut params = 0;
+    for line in BufIterator::new(buf) {
+        if let TruncStatus::Full(line) = line {
+            let msg = Message::new(line);
+            params += msg.parameters().count();
+            params += msg.tag(b"account").map_or(0, <[u8]>::len);
+        }
+    }
+    params
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, buf) in [("chatter", chatter()), ("names", names())] {
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_function(name, |b| b.iter(|| parse_all(black_box(&buf))));
+    }
+    group.finish();
+}
+
+fn bench_names(c: &mut Criterion) {
+    let buf = names();
+    c.bench_function("names/split", |b| {
+        b.iter(|| {
+            let mut nicks = 0;
+            for line in BufIterator::new(black_box(&buf)) {
+                if let TruncStatus::Full(line) = line {
+                    let msg = Message::new(line);
+                    let names = msg.parameters().nth(3).unwrap_or_default();
+                    nicks += names.split(|&chr| chr == b' ').count();
+                }
+            }
+            nicks
+        })
+    });
+}
+
+criterion_group!(benches, bench_parse, bench_names);
+criterion_main!(benches);
@@ -0,0 +1,142 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const NUMERICS: &str = "src/irc/client/numerics.txt";
+
+/// RPL_WELCOME -> RplWelcome
+fn variant(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first
+                    .to_uppercase()
+                    .chain(chars.flat_map(char::to_lowercase))
+                    .collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// R8BALL_BUILD, what --version and CTCP VERSION say about the build: the git commit,
+/// target triple and enabled features.
+fn build_info() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|commit| commit.trim().to_owned())
+        .unwrap_or_else(|| "unknown commit".to_owned());
+    let mut info = vec![commit, env::var("TARGET").expect("cargo sets TARGET")];
+    // only our [features], not the ones cargo makes for optional dependencies.
+    let manifest = fs::read_to_string("Cargo.toml").expect("Cargo.toml is readable");
+    let features = manifest
+        .lines()
+        .skip_while(|line| line.trim() != "[features]")
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .filter_map(|line| line.split_once('=').map(|(name, _)| name.trim()))
+        .filter(|name| !name.starts_with('#') && *name != "default")
+        .filter(|name| {
+            let var = format!("CARGO_FEATURE_{}", name.to_uppercase().replace('-', "_"));
+            env::var_os(var).is_some()
+        })
+        .map(str::to_owned);
+    info.extend(features);
+    println!("cargo:rustc-env=R8BALL_BUILD={}", info.join(", "));
+}
+
+/// Generate the Numeric enum from the table of numerics.
+fn main() {
+    build_info();
+    println!("cargo:rerun-if-changed={}", NUMERICS);
+    let table = fs::read_to_string(NUMERICS).expect("the numerics table is readable");
+
+    let mut numerics = vec![];
+    for (lineno, line) in table.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (code, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(code), Some(name), None) if code.len() == 3 => (code, name),
+            _ => panic!("{}:{}: expected `code NAME`", NUMERICS, lineno + 1),
+        };
+        let code: u16 = code
+            .parse()
+            .unwrap_or_else(|_| panic!("{}:{}: bad code {}", NUMERICS, lineno + 1, code));
+        numerics.push((code, name, variant(name)));
+    }
+
+    let mut out = String::new();
+    out.push_str("/// A numeric reply from the server, generated from numerics.txt.\n");
+    out.push_str("#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]\n");
+    out.push_str("pub enum Numeric {\n");
+    for (code, name, variant) in &numerics {
+        writeln!(out, "    /// {:03} {}", code, name).unwrap();
+        writeln!(out, "    {} = {},", variant, code).unwrap();
+    }
+    out.push_str("}\n\n");
+
+    out.push_str("impl Numeric {\n");
+    out.push_str("    /// The numeric for a command, if it is three digits we know.\n");
+    out.push_str("    pub fn parse(command: &[u8]) -> Option<Numeric> {\n");
+    out.push_str("        let code = match command {\n");
+    out.push_str("            [a, b, c] if command.iter().all(u8::is_ascii_digit) => {\n");
+    out.push_str("                u16::from(a - b'0') * 100 + u16::from(b - b'0') * 10 + u16::from(c - b'0')\n");
+    out.push_str("            }\n");
+    out.push_str("            _ => return None,\n");
+    out.push_str("        };\n");
+    out.push_str("        match code {\n");
+    for (code, _, variant) in &numerics {
+        writeln!(out, "            {} => Some(Numeric::{}),", code, variant).unwrap();
+    }
+    out.push_str("            _ => None,\n");
+    out.push_str("        }\n");
+    out.push_str("    }\n\n");
+    out.push_str("    /// The three digit code, e.g. 1 for RPL_WELCOME.\n");
+    out.push_str("    pub fn code(self) -> u16 {\n");
+    out.push_str("        self as u16\n");
+    out.push_str("    }\n\n");
+    out.push_str("    /// The name from the specifications, e.g. RPL_WELCOME.\n");
+    out.push_str("    pub fn name(self) -> &'static str {\n");
+    out.push_str("        match self {\n");
+    for (_, name, variant) in &numerics {
+        writeln!(out, "            Numeric::{} => \"{}\",", variant, name).unwrap();
+    }
+    out.push_str("        }\n");
+    out.push_str("    }\n");
+    out.push_str("}\n");
+
+    let dest = Path::new(&env::var("OUT_DIR").expect("cargo sets OUT_DIR")).join("numerics.rs");
+    fs::write(dest, out).expect("the generated numerics are writable");
+}
@@ -0,0 +1,47 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Any read splits into full lines, then at most one partial line, without losing
+//! anything but the line endings. Run with `cargo fuzz run buf_iter`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use r8ball::irc::iter::{BufIterator, TruncStatus};
+
+fuzz_target!(|read: &[u8]| {
+    let mut kept = 0;
+    let mut partial = false;
+    for line in BufIterator::new(read) {
+        assert!(!partial, "a line after the partial one");
+        let line = match line {
+            TruncStatus::Full(line) => line,
+            TruncStatus::Part(line) => {
+                partial = true;
+                line
+            }
+        };
+        assert!(!line.is_empty());
+        assert!(!line.iter().any(|&chr| chr == b'\r' || chr == b'\n'));
+        kept += line.len();
+    }
+    let content = read.iter().filter(|&&chr| chr != b'\r' && chr != b'\n');
+    assert_eq!(kept, content.count());
+});
@@ -0,0 +1,69 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Server traffic, fed to a Client in chunks of the sizes the input starts with,
+//! must never panic it; it may only answer, or hang up. Run with
+//! `cargo fuzz run client -- -dict=irc.dict`.
+
+#![no_main]
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use libfuzzer_sys::fuzz_target;
+
+use r8ball::{
+    config::config_file::Config,
+    irc::client::{Client, ClientAction},
+    storage::Storage,
+};
+
+const CONF: &str = r##"
+[general]
+nick = "bot"
+server = "localhost"
+channels = ["#chan"]
+"##;
+
+fuzz_target!(|data: &[u8]| {
+    // the first 4 bytes pick where reads end, the rest is what the server sent.
+    if data.len() < 4 {
+        return;
+    }
+    let (splits, mut traffic) = data.split_at(4);
+    let conf = Config::from_str(CONF).unwrap();
+    let mut client = Client::new(&conf, Storage::in_memory().unwrap());
+    let mut now = Instant::now();
+    let mut split = splits.iter().cycle();
+    while !traffic.is_empty() {
+        let len = (*split.next().unwrap() as usize + 1).min(traffic.len());
+        let (read, rest) = traffic.split_at(len);
+        traffic = rest;
+        let actions = client.feed_bytes(read);
+        if matches!(actions.last(), Some(ClientAction::Close(_))) {
+            return;
+        }
+        // timers and pacing, as if time went by between reads.
+        now += Duration::from_secs(1);
+        client.tick(now);
+        if let Some(ClientAction::Close(_)) = client.take_output(now) {
+            return;
+        }
+    }
+});
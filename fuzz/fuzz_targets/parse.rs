@@ -0,0 +1,47 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Any line parses into a Message, whose parts are slices of the line.
+//! Run with `cargo fuzz run parse`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use r8ball::irc::parse::Message;
+
+fuzz_target!(|line: &[u8]| {
+    let msg = Message::new(line);
+    let parts = [
+        msg.tags,
+        msg.nick,
+        msg.user,
+        msg.host,
+        msg.command,
+        msg.params,
+    ];
+    let range = line.as_ptr_range();
+    for part in parts.iter().flatten() {
+        let part = part.as_ptr_range();
+        assert!(range.start <= part.start && part.end <= range.end);
+    }
+    let _ = msg.tag(b"batch");
+    for _ in msg.parameters() {}
+    let _ = msg.is_empty();
+});
@@ -0,0 +1,195 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! --check-config: everything wrong with a config that parses, without connecting.
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use super::config_file::Config;
+use crate::irc::tls;
+
+/// The channel prefixes nearly every network uses, before CHANTYPES tells us.
+const CHANTYPES: &[char] = &['#', '&', '+', '!'];
+
+/// Where a plugin's path leads: relative to the working directory with a /, else $PATH.
+fn find_program(path: &str) -> Option<PathBuf> {
+    if path.contains('/') {
+        return Some(PathBuf::from(path));
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.is_file())
+}
+
+pub(super) fn check_program(what: &str, path: &str) -> Option<String> {
+    let program = match find_program(path) {
+        Some(program) => program,
+        None => return Some(format!("{} {:?} is not in $PATH", what, path)),
+    };
+    match fs::metadata(&program) {
+        Err(e) => Some(format!("{} {}: {}", what, program.display(), e)),
+        Ok(meta) if !meta.is_file() => {
+            Some(format!("{} {} is not a file", what, program.display()))
+        }
+        Ok(meta) if meta.permissions().mode() & 0o111 == 0 => {
+            Some(format!("{} {} is not executable", what, program.display()))
+        }
+        Ok(_) => None,
+    }
+}
+
+fn check_channel(what: &str, channel: &str) -> Option<String> {
+    match channel.chars().next() {
+        Some(chr) if CHANTYPES.contains(&chr) => None,
+        _ => Some(format!(
+            "{} {:?} does not start with one of {}",
+            what,
+            channel,
+            CHANTYPES.iter().collect::<String>()
+        )),
+    }
+}
+
+/// One network's problems, see check.
+fn check_network(config: &Config) -> Vec<String> {
+    let general = &config.general;
+    let mut problems = Vec::new();
+    if config.connect_strings().is_empty() {
+        problems.push("No server or servers to connect to".to_owned());
+    }
+    for entry in &general.channels {
+        let channel = entry.split_whitespace().next().unwrap_or_default();
+        problems.extend(check_channel("Channel", channel));
+    }
+    for channel in config.channels.keys() {
+        problems.extend(check_channel("[channels] entry", channel));
+    }
+
+    let mut commands = config.commands.iter().collect::<Vec<_>>();
+    commands.sort_by_key(|(name, _)| name.as_str());
+    for (name, command) in commands {
+        problems.extend(check_program(&format!("Command {}", name), &command.path));
+    }
+    for matcher in &config.matchers {
+        problems.extend(check_program(
+            &format!("Matcher {}", matcher.regex),
+            &matcher.plugin,
+        ));
+    }
+    for schedule in config.schedule.iter().filter(|s| !s.plugin.is_empty()) {
+        problems.extend(check_program("Scheduled plugin", &schedule.plugin));
+    }
+    let hooks = &config.hooks;
+    for (event, path) in [
+        ("join", &hooks.join),
+        ("part", &hooks.part),
+        ("kick", &hooks.kick),
+        ("topic", &hooks.topic),
+        ("nick", &hooks.nick),
+        ("online", &hooks.online),
+        ("offline", &hooks.offline),
+    ] {
+        if !path.is_empty() {
+            problems.extend(check_program(&format!("Hook {}", event), path));
+        }
+    }
+
+    // loads the client certificate and key, and parses tls_fingerprint.
+    if general.tls || !general.tls_client_cert.is_empty() || !general.tls_fingerprint.is_empty() {
+        if let Err(e) = tls::client_config(general) {
+            problems.push(e.to_string());
+        }
+    }
+    problems
+}
+
+/// Everything wrong with config as "network: problem", empty when it looks good.
+pub fn check(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    // networks share commands and plugins, each problem is listed for the first one.
+    let mut seen = Vec::new();
+    for (idx, net) in config.networks().iter().enumerate() {
+        let name = match idx {
+            0 => "[general]".to_owned(),
+            idx => format!("[[network]] {}", idx),
+        };
+        for problem in check_network(net) {
+            if !seen.contains(&problem) {
+                problems.push(format!("{}: {}", name, problem));
+                seen.push(problem);
+            }
+        }
+    }
+    let log_dir = Path::new(&config.general.log_dir);
+    if config.general.log_channels && log_dir.exists() && !log_dir.is_dir() {
+        problems.push(format!("log_dir {} is not a directory", log_dir.display()));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::config_file::Config;
+
+    use super::check;
+
+    #[test]
+    fn config_problems() {
+        let good = r##"
+[general]
+nick = "bot"
+server = "irc.one"
+channels = ["#one", "&two key"]
+
+[commands]
+sh = "/bin/sh"
+"##
+        .parse::<Config>()
+        .unwrap();
+        assert_eq!(check(&good), Vec::<String>::new());
+
+        let bad = r##"
+[general]
+nick = "bot"
+channels = ["one"]
+tls_client_cert = "/nonexistent/r8ball.pem"
+
+[commands]
+missing = "./no/such/plugin"
+notexec = "./Cargo.toml"
+
+[[network]]
+nick = "bot"
+server = "irc.two"
+channels = ["#two"]
+"##
+        .parse::<Config>()
+        .unwrap();
+        let problems = check(&bad);
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[..5].iter().all(|p| p.starts_with("[general]: ")));
+        assert!(problems[0].contains("No server"));
+        assert!(problems[1].contains("\"one\""));
+        assert!(problems[2].contains("./no/such/plugin"));
+        assert!(problems[3].contains("Cargo.toml is not executable"));
+        assert!(problems[4].contains("/nonexistent/r8ball.pem"));
+    }
+}
@@ -20,36 +20,96 @@
 use core::fmt;
 use std::env;
 
-use ParseState::{Boolarg, Config, LogFile};
+use super::xdg;
 
-const HELP_MESSAGE: &str = r#"neo8ball [-c|--config=] [-o|--log-output=] [-t|--timestamp] [-h|--help]
+const HELP_MESSAGE: &str = r#"r8ball [run|check|dump-config|send|version] [options]
+r8ball --version
 
--c --config=str       The Config File to use.
+run           Connect and run the bot, the default without a subcommand.
+check         Report problems with the config and its plugins.
+dump-config   Print a commented config with every setting.
+send          Send a command to a running bot's control socket.
+version       Print the version.
+
+r8ball <subcommand> --help describes its options.
+"#;
+
+const RUN_HELP: &str = r#"r8ball [run] [-c|--config=] [-s|--set=] [-o|--log-output=] [-r|--raw-log=] [-p|--pidfile=] [-d|--daemon] [-t|--timestamp] [-h|--help]
+
+-c --config=str       The Config File to use. Defaults to the first of
+                      $XDG_CONFIG_HOME/r8ball/config.toml, /etc/r8ball/config.toml
+                      and ./r8ball.conf.
+-s --set=key=value    Override a config setting, e.g. general.nick=testbot or
+                      network.0.port=6697; the value is TOML or a plain string.
+                      May be given more than once.
 -o --log-output=str   Log Output to file instead of stdout.
+-r --raw-log=str      Append every IRC line sent and received to this file.
+-p --pidfile=str      Write our pid to this file, and lock it so only one copy runs.
+-d --daemon           Detach from the terminal. Output goes to --log-output, if given.
 -t --timestamp        Timestamp logs using RFC 3339. (YYYY-MM-DD HH:MM:SS[+/-TZ]).
+   --check-config     Same as r8ball check.
+   --dump-config      Same as r8ball dump-config.
+-h --help             This message.
+"#;
+
+const CHECK_HELP: &str = r#"r8ball check [-c|--config=] [-s|--set=] [-h|--help]
+
+Report problems with the config and its plugins, exiting non-zero if there are any.
+
+-c --config=str       The Config File to check, see r8ball run --help.
+-s --set=key=value    Override a config setting, as with r8ball run.
+-h --help             This message.
+"#;
+
+const DUMP_HELP: &str = r#"r8ball dump-config [-h|--help]
+
+Print a commented config with every setting, to start one from.
+"#;
+
+const SEND_HELP: &str = r#"r8ball send [-c|--config=] [-S|--socket=] [-h|--help] <command>...
+
+Send a command to a running bot's control socket and print the answer, exiting
+non-zero when it fails. e.g. r8ball send @1 JOIN #chan, or r8ball send STATUS.
+
+-c --config=str       Find the socket in this config's control_socket.
+-S --socket=str       The socket, instead of the config's.
 -h --help             This message.
 "#;
 
-#[derive(PartialEq)]
-enum ParseState {
-    Boolarg,
-    Config,
-    LogFile,
+const VERSION_HELP: &str = r#"r8ball version [-h|--help]
+
+Print the version, git commit, target and enabled features.
+"#;
+
+/// What to do, see HELP_MESSAGE.
+#[derive(Debug, PartialEq)]
+pub enum Subcommand {
+    Run,
+    Check,
+    DumpConfig,
+    // the command line, and the socket when given instead of the config's.
+    Send { line: String, socket: String },
+    Version,
 }
 
 #[derive(thiserror::Error, Debug)]
-pub struct ParsedArgsError(String);
-
-impl fmt::Display for ParsedArgsError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+pub enum ParsedArgsError {
+    // --help, which isn't an error for whoever asked.
+    #[error("{0}")]
+    Help(&'static str),
+    #[error("{0}")]
+    Usage(String),
 }
 
 #[derive(Debug)]
 pub struct ParsedArgs {
+    pub command: Subcommand,
     pub config: String,
+    pub overrides: Vec<String>,
     pub log_file: String,
+    pub raw_log: String,
+    pub pidfile: String,
+    pub daemon: bool,
     pub timestamp_logs: bool,
     pub mock: bool,
 }
@@ -57,63 +117,220 @@ pub struct ParsedArgs {
 impl Default for ParsedArgs {
     fn default() -> Self {
         ParsedArgs {
-            config: "./r8ball.conf".to_owned(),
+            command: Subcommand::Run,
+            config: "".to_owned(),
+            overrides: Vec::new(),
             log_file: "".to_owned(),
+            raw_log: "".to_owned(),
+            pidfile: "".to_owned(),
+            daemon: false,
             timestamp_logs: false,
             mock: false,
         }
     }
 }
 
+/// An option and the value given with it, if any: -c val, --config val or --config=val.
+struct Opt<'a, I: Iterator<Item = String>> {
+    flag: String,
+    inline: Option<String>,
+    rest: &'a mut I,
+}
+
+impl<I: Iterator<Item = String>> Opt<'_, I> {
+    fn value(self) -> Result<String, ParsedArgsError> {
+        match self.inline {
+            Some(val) => Ok(val),
+            None => self.rest.next().ok_or_else(|| {
+                ParsedArgsError::Usage(format!("{} needs a value, see --help", self.flag))
+            }),
+        }
+    }
+
+    fn unknown<T>(&self) -> Result<T, ParsedArgsError> {
+        Err(ParsedArgsError::Usage(format!(
+            "Unknown option passed ({}), see --help",
+            self.flag,
+        )))
+    }
+}
+
+impl fmt::Display for Subcommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Subcommand::Run => "run",
+            Subcommand::Check => "check",
+            Subcommand::DumpConfig => "dump-config",
+            Subcommand::Send { .. } => "send",
+            Subcommand::Version => "version",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl ParsedArgs {
     pub fn new() -> Result<ParsedArgs, ParsedArgsError> {
-        let mut ret = ParsedArgs::default();
-        let mut arg_state = ParseState::Boolarg;
         let mut itr = env::args();
         itr.next(); // throw away first arg
-        for arg in itr {
-            let (flag, val) = if arg_state != Boolarg {
-                (arg.as_str(), "")
-            } else if let Some(idx) = arg.as_str().find('=') {
-                arg.split_at(idx + 1usize)
-            } else {
-                (arg.as_str(), "")
-            };
+        ParsedArgs::parse(itr)
+    }
 
-            arg_state = match flag {
-                "-t" | "--timestamp" => {
-                    ret.timestamp_logs = true;
-                    Boolarg
-                }
-                "-c" | "--config" => Config,
-                "--config=" => {
-                    ret.config = val.to_string();
-                    Boolarg
+    fn parse(args: impl Iterator<Item = String>) -> Result<ParsedArgs, ParsedArgsError> {
+        let mut ret = ParsedArgs::default();
+        let mut args = args.peekable();
+        let (command, help) = match args.peek().map(String::as_str) {
+            Some("run") => (Subcommand::Run, RUN_HELP),
+            Some("check") => (Subcommand::Check, CHECK_HELP),
+            Some("dump-config") => (Subcommand::DumpConfig, DUMP_HELP),
+            Some("send") => (
+                Subcommand::Send {
+                    line: String::new(),
+                    socket: String::new(),
+                },
+                SEND_HELP,
+            ),
+            Some("version") => (Subcommand::Version, VERSION_HELP),
+            Some("-h") | Some("--help") => return Err(ParsedArgsError::Help(HELP_MESSAGE)),
+            Some("-V") | Some("--version") => (Subcommand::Version, VERSION_HELP),
+            Some(other) if !other.starts_with('-') => {
+                return Err(ParsedArgsError::Usage(format!(
+                    "Unknown subcommand {}, see --help",
+                    other
+                )))
+            }
+            // only options, as before there were subcommands.
+            _ => (Subcommand::Run, ""),
+        };
+        if !help.is_empty() {
+            args.next();
+        }
+        let help = if help.is_empty() { RUN_HELP } else { help };
+        ret.command = command;
+
+        let mut words = Vec::new();
+        while let Some(arg) = args.next() {
+            // a control command's words may start with -, once it has begun.
+            let sending = matches!(ret.command, Subcommand::Send { .. }) && !words.is_empty();
+            if arg == "--" {
+                words.extend(args.by_ref());
+                break;
+            }
+            if sending || !arg.starts_with('-') || arg == "-" {
+                words.push(arg);
+                continue;
+            }
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, val)) if flag.starts_with("--") => {
+                    (flag.to_owned(), Some(val.to_owned()))
                 }
-                "-o" | "--log-output" => LogFile,
-                "--log-output=" => {
-                    ret.log_file = val.to_string();
-                    Boolarg
+                _ => (arg, None),
+            };
+            let opt = Opt {
+                flag,
+                inline,
+                rest: &mut args,
+            };
+            let takes_config =
+                ret.command != Subcommand::DumpConfig && ret.command != Subcommand::Version;
+            match (opt.flag.as_str(), &mut ret.command) {
+                ("-h" | "--help", _) => return Err(ParsedArgsError::Help(help)),
+                ("-c" | "--config", _) if takes_config => ret.config = opt.value()?,
+                ("-s" | "--set", Subcommand::Run | Subcommand::Check) => {
+                    ret.overrides.push(opt.value()?)
                 }
-                "-h" | "--help" => return Err(ParsedArgsError(HELP_MESSAGE.to_string())),
-                _ => match arg_state {
-                    Boolarg => {
-                        return Err(ParsedArgsError(format!(
-                            "Unknown option passed ({}), see --help",
-                            flag,
-                        )))
-                    }
-                    Config => {
-                        ret.config = flag.to_string();
-                        Boolarg
-                    }
-                    LogFile => {
-                        ret.log_file = flag.to_string();
-                        Boolarg
-                    }
-                },
+                ("-S" | "--socket", Subcommand::Send { socket, .. }) => *socket = opt.value()?,
+                ("-o" | "--log-output", Subcommand::Run) => ret.log_file = opt.value()?,
+                ("-r" | "--raw-log", Subcommand::Run) => ret.raw_log = opt.value()?,
+                ("-p" | "--pidfile", Subcommand::Run) => ret.pidfile = opt.value()?,
+                ("-d" | "--daemon", Subcommand::Run) => ret.daemon = true,
+                ("-t" | "--timestamp", Subcommand::Run) => ret.timestamp_logs = true,
+                ("--check-config", Subcommand::Run) => ret.command = Subcommand::Check,
+                ("--dump-config", Subcommand::Run) => ret.command = Subcommand::DumpConfig,
+                _ => return opt.unknown(),
             }
         }
+
+        match &mut ret.command {
+            Subcommand::Send { line, .. } if !words.is_empty() => *line = words.join(" "),
+            Subcommand::Send { .. } => {
+                return Err(ParsedArgsError::Usage(
+                    "send needs a command, see r8ball send --help".to_owned(),
+                ))
+            }
+            command if !words.is_empty() => {
+                return Err(ParsedArgsError::Usage(format!(
+                    "Unexpected argument {} to {}, see --help",
+                    words[0], command
+                )))
+            }
+            _ => (),
+        }
+        if ret.config.is_empty() {
+            ret.config = xdg::find_config();
+        }
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ParsedArgs, ParsedArgsError, Subcommand};
+
+    fn parse(line: &str) -> Result<ParsedArgs, ParsedArgsError> {
+        ParsedArgs::parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn subcommands() {
+        let run = parse("-c bot.toml --set=general.nick=x -s general.port=6697 -d").unwrap();
+        assert_eq!(run.command, Subcommand::Run);
+        assert_eq!(run.config, "bot.toml");
+        assert_eq!(run.overrides, vec!["general.nick=x", "general.port=6697"]);
+        assert!(run.daemon);
+        let run = parse("run --config=bot.toml --pidfile /run/r8.pid").unwrap();
+        assert_eq!(run.config, "bot.toml");
+        assert_eq!(run.pidfile, "/run/r8.pid");
+
+        assert_eq!(parse("check -c x").unwrap().command, Subcommand::Check);
+        assert_eq!(parse("--check-config").unwrap().command, Subcommand::Check);
+        assert_eq!(
+            parse("dump-config").unwrap().command,
+            Subcommand::DumpConfig
+        );
+        assert_eq!(parse("version").unwrap().command, Subcommand::Version);
+        assert_eq!(parse("--version").unwrap().command, Subcommand::Version);
+        assert_eq!(
+            parse("send -S /run/r8.sock @1 JOIN #chan").unwrap().command,
+            Subcommand::Send {
+                line: "@1 JOIN #chan".to_owned(),
+                socket: "/run/r8.sock".to_owned()
+            }
+        );
+        assert_eq!(
+            parse("send -- -weird SAY #chan -hi").unwrap().command,
+            Subcommand::Send {
+                line: "-weird SAY #chan -hi".to_owned(),
+                socket: String::new()
+            }
+        );
+
+        assert!(matches!(parse("--help"), Err(ParsedArgsError::Help(_))));
+        assert!(
+            matches!(parse("send --help"), Err(ParsedArgsError::Help(h)) if h.starts_with("r8ball send"))
+        );
+        for bad in [
+            "frobnicate",
+            "check --daemon",
+            "version -c x",
+            "send",
+            "run extra",
+            "-c",
+        ] {
+            assert!(
+                matches!(parse(bad), Err(ParsedArgsError::Usage(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+}
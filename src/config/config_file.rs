@@ -17,40 +17,831 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{self, Read};
+use std::net::IpAddr;
 use std::path::Path;
+use std::str::FromStr;
 
+use indexmap::IndexMap;
+use regex::Regex;
 use serde::Deserialize;
 
-#[derive(Deserialize, Debug)]
+use super::check::check_program;
+use super::include::load_includes;
+use super::plugin_dir::load_plugin_dir;
+use super::xdg;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub general: General,
     // List of prefix and their associated plugins
-    pub commands: HashMap<String, String>,
+    #[serde(default)]
+    pub commands: HashMap<String, CommandConfig>,
+    #[serde(default)]
+    pub eightball: EightBall,
+    // relay bots (matrix/telegram bridges) whose messages are from "<nick> message".
+    #[serde(default)]
+    pub gateways: Vec<Gateway>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    // per-channel features, keyed by channel name.
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelConfig>,
+    #[serde(default)]
+    pub schedule: Vec<Schedule>,
+    // plugins run on channel messages matching a regex.
+    #[serde(default)]
+    pub matchers: Vec<Matcher>,
+    // plugins run when people join, leave, get kicked, change topic or nick.
+    #[serde(default)]
+    pub hooks: Hooks,
+    // limits for every external plugin, commands may have their own.
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    // words and patterns we never say, from plugins or otherwise.
+    #[serde(default)]
+    pub filter: Filter,
+    // keys for +k channels, as an alternative to "#chan key" in channels.
+    #[serde(default)]
+    pub channel_keys: HashMap<String, String>,
+    // hostmasks (with a !) or accounts to give a prefix mode, e.g. "o" or "v", when
+    // they join a channel where we are an op. The first one matching, in order, wins.
+    #[serde(default)]
+    pub friends: IndexMap<String, String>,
+    // a SOCKS5 proxy to connect through, e.g. Tor.
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    // more networks to connect to, [general] is the first.
+    #[serde(default)]
+    pub network: Vec<Network>,
+    // more config files merged into this one, e.g. "commands.d/*.toml".
+    #[serde(default)]
+    pub include: Vec<String>,
+    // --set key=value settings, applied again when the file is reloaded.
+    #[serde(skip)]
+    pub overrides: Vec<String>,
+}
+
+/// Another network to sit on, with the same settings as `[general]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "toml::value::Table")]
+pub struct Network {
+    pub general: General,
+    // the top level commands are used when a network has none of its own.
+    pub commands: Option<HashMap<String, CommandConfig>>,
+}
+
+// serde(flatten) would let typos in the General part through.
+impl TryFrom<toml::value::Table> for Network {
+    type Error = toml::de::Error;
+
+    fn try_from(mut table: toml::value::Table) -> Result<Self, Self::Error> {
+        let commands = table
+            .remove("commands")
+            .map(toml::Value::try_into)
+            .transpose()?;
+        Ok(Network {
+            general: toml::Value::Table(table).try_into()?,
+            commands,
+        })
+    }
+}
+
+/// A periodic announcement or plugin run.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    // five field cron expression in UTC, e.g. "0 9 * * 1-5".
+    #[serde(default)]
+    pub cron: String,
+    // or every this many seconds.
+    #[serde(default)]
+    pub interval: u64,
+    // channel (or nick) messages and plugin output go to.
+    pub target: String,
+    #[serde(default)]
+    pub message: String,
+    // plugin run as if it was a command named "schedule" sent by us.
+    #[serde(default)]
+    pub plugin: String,
+}
+
+/// A plugin run on any channel message matching a regex.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Matcher {
+    pub regex: String,
+    pub plugin: String,
+    // the command name the plugin gets, and that [channels] settings refer to.
+    #[serde(default = "default_matcher_name")]
+    pub name: String,
+    // may print :kick, :ban and :mode directives.
+    #[serde(default)]
+    pub moderator: bool,
+}
+
+fn default_matcher_name() -> String {
+    "match".to_owned()
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    #[serde(default)]
+    pub verbosity: Verbosity,
+    // announce the titles of links, when built with the url-title feature.
+    #[serde(default)]
+    pub url_titles: bool,
+    // only these commands are answered in the channel, all when left out.
+    #[serde(default)]
+    pub commands: Option<Vec<String>>,
+    #[serde(default)]
+    pub disabled_commands: Vec<String>,
+    // overrides [general] command_prefix.
+    #[serde(default)]
+    pub command_prefix: Option<String>,
+    // minimum seconds between runs of the same command in the channel, 0 to never throttle.
+    #[serde(default)]
+    pub throttle: u64,
+    // override [general] reply_notice and reply_prefix_nick.
+    #[serde(default)]
+    pub reply_notice: Option<bool>,
+    #[serde(default)]
+    pub reply_prefix_nick: Option<bool>,
+    // remove bold, colors and such from what we say in the channel, e.g. when it's +c.
+    #[serde(default)]
+    pub strip_formatting: bool,
+}
+
+/// What to do when more output waits to be sent than send_queue_max allows.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+/// How log records are written, see crate::logging.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// "LEVEL: message"
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// When channel logs are synced to disk, see General::log_fsync.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFsync {
+    /// Whenever the OS gets to it.
+    #[default]
+    Never,
+    /// When a day's file is closed.
+    Rotate,
+    /// After every line.
+    Always,
+}
+
+/// How to log in with SASL, see General::sasl_mechanism.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SaslMechanism {
+    External,
+}
+
+impl SaslMechanism {
+    pub fn name(self) -> &'static str {
+        match self {
+            SaslMechanism::External => "EXTERNAL",
+        }
+    }
+}
+
+/// How much output a channel wants, e.g. busy channels may want shorter replies.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    Compact,
+    #[default]
+    Normal,
+    Verbose,
+}
+
+impl Verbosity {
+    /// The longest output, in characters, this class allows; None is unlimited.
+    pub fn max_len(self) -> Option<usize> {
+        match self {
+            Verbosity::Compact => Some(80),
+            Verbosity::Normal => Some(200),
+            Verbosity::Verbose => None,
+        }
+    }
+
+    /// Shorten text to fit this class, marking the cut with an ellipsis.
+    pub fn truncate(self, text: &str) -> Cow<'_, str> {
+        let max = match self.max_len() {
+            Some(max) => max,
+            None => return Cow::Borrowed(text),
+        };
+        match text.char_indices().nth(max) {
+            Some(_) => {
+                let (cut, _) = text.char_indices().nth(max - 1).unwrap();
+                Cow::Owned(format!("{}…", &text[..cut]))
+            }
+            None => Cow::Borrowed(text),
+        }
+    }
+}
+
+/// A SOCKS5 proxy; it looks up the servers, not us.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Proxy {
+    pub host: String,
+    #[serde(default = "default_proxy_port")]
+    pub port: u16,
+    // leave out for no authentication.
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
 }
 
-#[derive(Deserialize, Debug)]
+impl Proxy {
+    pub fn connect_string(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn auth(&self) -> Option<(String, String)> {
+        if self.username.is_empty() && self.password.is_empty() {
+            None
+        } else {
+            Some((self.username.clone(), self.password.clone()))
+        }
+    }
+}
+
+/// Resource limits and environment for external plugins.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Sandbox {
+    // seconds of CPU time, 0 for no limit.
+    #[serde(default)]
+    pub cpu_secs: u64,
+    // address space in MiB, 0 for no limit.
+    #[serde(default)]
+    pub memory_mb: u64,
+    // open file descriptors, 0 for no limit.
+    #[serde(default)]
+    pub nofile: u64,
+    // directory plugins run in, ours when empty.
+    #[serde(default)]
+    pub workdir: String,
+    // variables plugins inherit from us; R8_* are always set.
+    #[serde(default = "default_sandbox_env")]
+    pub env: Vec<String>,
+    // pass on all of our environment instead, secrets included.
+    #[serde(default)]
+    pub inherit_env: bool,
+    // seconds before the plugin is killed, 0 to let it run.
+    #[serde(default)]
+    pub timeout: u64,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            cpu_secs: 0,
+            memory_mb: 0,
+            nofile: 0,
+            workdir: String::new(),
+            env: default_sandbox_env(),
+            inherit_env: false,
+            timeout: 0,
+        }
+    }
+}
+
+fn default_sandbox_env() -> Vec<String> {
+    vec!["PATH".to_owned(), "LANG".to_owned()]
+}
+
+/// Words and patterns kept out of our PRIVMSGs and NOTICEs.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    // matched as whole words, ignoring case.
+    #[serde(default)]
+    pub words: Vec<String>,
+    // regular expressions, matched anywhere in the text.
+    #[serde(default)]
+    pub regexes: Vec<String>,
+    #[serde(default)]
+    pub action: FilterAction,
+}
+
+/// What to do with a line the filter matches.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Send it with the matches starred out.
+    #[default]
+    Mask,
+    /// Don't send it at all.
+    Drop,
+}
+
+/// Plugin paths run on IRC events, empty for none.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default)]
+    pub join: String,
+    #[serde(default)]
+    pub part: String,
+    #[serde(default)]
+    pub kick: String,
+    #[serde(default)]
+    pub topic: String,
+    #[serde(default)]
+    pub nick: String,
+    // watched nicks, see [general] monitor.
+    #[serde(default)]
+    pub online: String,
+    #[serde(default)]
+    pub offline: String,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    // sqlite database shared with plugins, $XDG_STATE_HOME/r8ball/r8ball.db when
+    // empty, ":memory:" to keep everything in memory.
+    #[serde(default)]
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Gateway {
+    // nick!user@host mask of the relay bot, * and ? are wildcards.
+    pub mask: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct General {
     pub nick: String,
+    // ident and GECOS, the nick when empty.
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub realname: String,
+    // user modes to set once registered, e.g. "+iw"; empty to leave them alone.
+    #[serde(default = "default_usermode")]
+    pub usermode: String,
+    #[serde(default)]
     server: String,
     #[serde(default = "default_port")]
     port: u16,
+    // more servers to fall back on, host:port or host to use port above.
+    #[serde(default)]
+    servers: Vec<String>,
     #[serde(default = "default_tls")]
     pub tls: bool,
+    // check the server's certificate; tls_fingerprint pins it instead, e.g. self-signed ones.
+    #[serde(default = "default_tls_verify")]
+    pub tls_verify: bool,
+    #[serde(default)]
+    pub tls_fingerprint: String,
+    // PEM files of a certificate and its key to identify with, e.g. for CertFP;
+    // the key may be in the certificate's file.
+    #[serde(default)]
+    pub tls_client_cert: String,
+    #[serde(default)]
+    pub tls_client_key: String,
+    // seconds to look up the server, and then to connect to it.
+    #[serde(default = "default_dns_timeout")]
+    pub dns_timeout: u64,
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    // seconds of silence from the server before we PING it, and again before we give
+    // up on the connection, 0 to never.
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    // milliseconds before reconnecting to the next server; it doubles, up to five
+    // minutes, while connections fail before we are registered.
+    #[serde(default = "default_reconnect_delay")]
+    pub reconnect_delay_ms: u64,
+    // local address and (on Linux) interface to connect from, e.g. for a vhost.
+    #[serde(default)]
+    pub bind_addr: Option<IpAddr>,
+    #[serde(default)]
+    pub bind_device: String,
     #[serde(default = "default_prefix")]
     pub command_prefix: String,
+    // read, but not sent to the server yet.
     #[serde(default)]
+    #[allow(dead_code)]
     server_password: String,
     #[serde(default)]
+    #[allow(dead_code)]
     sasl_password: String,
+    // log in with SASL while registering, EXTERNAL uses tls_client_cert.
+    #[serde(default)]
+    pub sasl_mechanism: Option<SaslMechanism>,
     #[serde(default)]
     pub nickserv_password: String,
+    // network operator credentials, sent with OPER once registered.
+    #[serde(default)]
+    pub oper_name: String,
+    #[serde(default)]
+    pub oper_password: String,
+    // services accounts, or nick!user@host masks, allowed to run admin commands.
+    // Accounts can't be spoofed, masks only as far as the host is cloaked.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    // nicks, or nick!user@host masks, of other bots we never answer.
+    #[serde(default)]
+    pub bots: Vec<String>,
+    // messages from one nick we answer a minute, before ignoring them for the rest
+    // of it, 0 for no limit.
+    #[serde(default = "default_replies_per_nick")]
+    pub replies_per_nick: u32,
+    // ignore users for a while who repeat what we just said, likely bots echoing us.
+    #[serde(default = "default_loop_guard")]
+    pub loop_guard: bool,
+    // "#chan" or "#chan key".
     #[serde(default)]
     pub channels: Vec<String>,
+    // join channels again after being kicked from them, backing off while we keep
+    // being kicked.
+    #[serde(default)]
+    pub rejoin_on_kick: bool,
+    // seconds before trying a channel we couldn't join again, doubled for every
+    // retry that fails, 0 to give up on it.
+    #[serde(default)]
+    pub join_retry: u64,
+    // KNOCK on invite only channels we couldn't join.
+    #[serde(default)]
+    pub knock: bool,
     #[serde(default)]
     pub invite_file: String,
+    // how many messages per channel to remember, e.g. for s/// corrections.
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    // channels per JOIN when joining many at once, 0 to fit as many as a line allows.
+    #[serde(default)]
+    pub join_batch_size: usize,
+    // milliseconds to wait between JOIN batches.
+    #[serde(default = "default_join_delay")]
+    pub join_delay_ms: u64,
+    // milliseconds between lines after a burst of send_burst lines; PONG, NICK and
+    // QUIT skip the line.
+    #[serde(default = "default_send_delay")]
+    pub send_delay_ms: u64,
+    #[serde(default = "default_send_burst")]
+    pub send_burst: u32,
+    // bytes of output that may wait to be sent, 0 for no limit, and what to do past it.
+    #[serde(default = "default_send_queue_max")]
+    pub send_queue_max: usize,
+    #[serde(default)]
+    pub send_queue_overflow: Overflow,
+    // milliseconds each subsystem (plugins, connection, storage) gets to close on exit.
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace_ms: u64,
+    // threads parsing plugin output, 0 to parse it on the event loop.
+    #[serde(default)]
+    pub plugin_workers: usize,
+    // wait for services to confirm we are identified before joining, for +r channels.
+    #[serde(default)]
+    pub join_after_identify: bool,
+    // seconds to wait on services before joining anyway.
+    #[serde(default = "default_identify_timeout")]
+    pub identify_timeout: u64,
+    // seconds after which we quit cleanly so a supervisor can restart us, 0 to never.
+    #[serde(default)]
+    pub max_uptime: u64,
+    // five field cron expression (UTC); once max_uptime passes, wait for it to match.
+    #[serde(default)]
+    pub restart_window: String,
+    // external plugins running at once, 0 for no limit.
+    #[serde(default = "default_max_plugins")]
+    pub max_plugins: usize,
+    // invocations waiting for a free slot before we reply we're too busy.
+    #[serde(default = "default_plugin_queue")]
+    pub plugin_queue: usize,
+    // our answer to CTCP VERSION.
+    #[serde(default = "default_ctcp_version")]
+    pub ctcp_version: String,
+    // answer in channels with NOTICE rather than PRIVMSG.
+    #[serde(default)]
+    pub reply_notice: bool,
+    // start answers with the nick of who asked.
+    #[serde(default = "default_reply_prefix_nick")]
+    pub reply_prefix_nick: bool,
+    // every executable in here is a command, set up by an optional <name>.toml.
+    #[serde(default)]
+    pub plugin_dir: String,
+    // lines with a server-time older than this many seconds, e.g. bouncer playback,
+    // run no commands. 0 to act on everything.
+    #[serde(default = "default_playback_max_age")]
+    pub playback_max_age: u64,
+    // lines of history to ask the server for when we join, 0 to not ask.
+    #[serde(default)]
+    pub chathistory: usize,
+    // nicks to watch come online and go offline, with MONITOR or else ISON.
+    #[serde(default)]
+    pub monitor: Vec<String>,
+    // seconds between ISON polls, when the server has no MONITOR.
+    #[serde(default = "default_ison_interval")]
+    pub ison_interval: u64,
+    // a unix socket taking commands for the live bot, see irc::control. Only the
+    // top level [general] one is used.
+    #[serde(default)]
+    pub control_socket: String,
+    // keep plaintext logs of channels, a file per channel and day, under log_dir.
+    #[serde(default)]
+    pub log_channels: bool,
+    #[serde(default = "default_log_dir")]
+    pub log_dir: String,
+    #[serde(default)]
+    pub log_fsync: LogFsync,
+    // append every line to and from the servers here, see irc::rawlog. Only the
+    // top level [general] one is used, --raw-log overrides it.
+    #[serde(default)]
+    pub raw_log: String,
+    // text or json, only the top level [general] one is used.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    // also log what happens in channels, like log_channels, as records with a
+    // channel and nick.
+    #[serde(default)]
+    pub log_events: bool,
+    // started as root, become this user (and group, else the user's own) once the
+    // first connections, control socket and logs are open. Only the top level
+    // [general] ones are used.
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub group: String,
+}
+
+/// How a command is triggered.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// The message starts with the command prefix and the command, e.g. ".weather".
+    #[default]
+    Prefix,
+    /// The message is only the command word, without a prefix.
+    Bare,
+    /// The command word appears anywhere in the message.
+    Anywhere,
+}
+
+/// A command, either `name = "./plugin"` or
+/// `name = { path = "./plugin", trigger = "bare", throttle = 10 }`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "CommandDef")]
+pub struct CommandConfig {
+    pub path: String,
+    pub trigger: Trigger,
+    // minimum seconds between invocations, 0 to never throttle.
+    pub throttle: u64,
+    // copies running at once, 0 for no limit besides [general] max_plugins.
+    pub max_running: usize,
+    // replaces the top level [sandbox] for this command.
+    pub sandbox: Option<Sandbox>,
+    // one line description, for .help.
+    pub help: String,
+    // only [general] admins may run it.
+    pub admin: bool,
+    // only channel operators (and admins) may run it.
+    pub require_op: bool,
+    // may print :kick, :ban and :mode directives.
+    pub moderator: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum CommandDef {
+    Path(String),
+    Full {
+        path: String,
+        #[serde(default)]
+        trigger: Trigger,
+        #[serde(default)]
+        throttle: u64,
+        #[serde(default)]
+        max_running: usize,
+        #[serde(default)]
+        sandbox: Option<Sandbox>,
+        #[serde(default)]
+        help: String,
+        #[serde(default)]
+        admin: bool,
+        #[serde(default)]
+        require_op: bool,
+        #[serde(default)]
+        moderator: bool,
+    },
+}
+
+impl From<CommandDef> for CommandConfig {
+    fn from(def: CommandDef) -> Self {
+        match def {
+            CommandDef::Path(path) => CommandConfig {
+                path,
+                trigger: Trigger::default(),
+                throttle: 0,
+                max_running: 0,
+                sandbox: None,
+                help: String::new(),
+                admin: false,
+                require_op: false,
+                moderator: false,
+            },
+            CommandDef::Full {
+                path,
+                trigger,
+                throttle,
+                max_running,
+                sandbox,
+                help,
+                admin,
+                require_op,
+                moderator,
+            } => CommandConfig {
+                path,
+                trigger,
+                throttle,
+                max_running,
+                sandbox,
+                help,
+                admin,
+                require_op,
+                moderator,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EightBall {
+    #[serde(default = "default_answers")]
+    pub answers: Vec<Answer>,
+}
+
+impl Default for EightBall {
+    fn default() -> Self {
+        EightBall {
+            answers: default_answers(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Answer {
+    pub text: String,
+    // relative chance of this answer being picked.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_answers() -> Vec<Answer> {
+    [
+        "It is certain.",
+        "It is decidedly so.",
+        "Without a doubt.",
+        "Yes, definitely.",
+        "You may rely on it.",
+        "As I see it, yes.",
+        "Most likely.",
+        "Outlook good.",
+        "Yes.",
+        "Signs point to yes.",
+        "Reply hazy, try again.",
+        "Ask again later.",
+        "Better not tell you now.",
+        "Cannot predict now.",
+        "Concentrate and ask again.",
+        "Don't count on it.",
+        "My reply is no.",
+        "My sources say no.",
+        "Outlook not so good.",
+        "Very doubtful.",
+    ]
+    .iter()
+    .map(|&text| Answer {
+        text: text.to_owned(),
+        weight: default_weight(),
+    })
+    .collect()
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_history_size() -> usize {
+    32
+}
+
+fn default_join_delay() -> u64 {
+    1000
+}
+
+fn default_dns_timeout() -> u64 {
+    10
+}
+
+fn default_connect_timeout() -> u64 {
+    30
+}
+
+fn default_replies_per_nick() -> u32 {
+    20
+}
+
+fn default_loop_guard() -> bool {
+    true
+}
+
+fn default_ping_interval() -> u64 {
+    120
+}
+
+fn default_reconnect_delay() -> u64 {
+    5000
+}
+
+fn default_proxy_port() -> u16 {
+    1080
+}
+
+fn default_send_delay() -> u64 {
+    500
+}
+
+fn default_log_dir() -> String {
+    "logs".to_owned()
+}
+
+fn default_send_burst() -> u32 {
+    5
+}
+
+fn default_send_queue_max() -> usize {
+    64 * 1024
+}
+
+fn default_shutdown_grace() -> u64 {
+    2000
+}
+
+fn default_identify_timeout() -> u64 {
+    30
+}
+
+fn default_usermode() -> String {
+    "+i".to_owned()
+}
+
+fn default_ctcp_version() -> String {
+    crate::VERSION.to_owned()
+}
+
+fn default_reply_prefix_nick() -> bool {
+    true
+}
+
+fn default_playback_max_age() -> u64 {
+    60
+}
+
+fn default_ison_interval() -> u64 {
+    60
+}
+
+fn default_max_plugins() -> usize {
+    16
+}
+
+fn default_plugin_queue() -> usize {
+    32
 }
 
 fn default_port() -> u16 {
@@ -65,27 +856,513 @@ fn default_tls() -> bool {
     false
 }
 
+fn default_tls_verify() -> bool {
+    true
+}
+
+/// Every setting, commented, with its default; what --dump-config prints.
+pub const EXAMPLE: &str = include_str!("../../examples/r8ball_conf.toml");
+
 #[derive(thiserror::Error, Debug)]
 pub enum ConfigError {
     #[error("Could not open/read config file: {0}")]
     IO(#[from] io::Error),
     #[error("Could not parse config file: {0}")]
     Toml(#[from] toml::de::Error),
+    #[error("Could not parse plugin manifest {0}: {1}")]
+    Manifest(String, toml::de::Error),
+    #[error("Could not substitute {0}")]
+    Env(String),
+    #[error("Bad --set {0}")]
+    Override(String),
+    #[error("Could not include {0}: {1}")]
+    Include(String, String),
+    #[error("Could not parse config file: {0}")]
+    UnknownKey(String),
+    #[error("Bad [filter] regex {0:?}: {1}")]
+    Filter(String, regex::Error),
+}
+
+/// Replace every ${NAME} in s with the environment variable NAME; $${ is a literal ${.
+fn expand_env(s: &str) -> Result<Cow<'_, str>, ConfigError> {
+    if !s.contains("${") {
+        return Ok(Cow::Borrowed(s));
+    }
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        ret.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            ret.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| ConfigError::Env(format!("unterminated ${{ in {:?}", s)))?;
+            let name = &after[..end];
+            let value = std::env::var(name)
+                .map_err(|e| ConfigError::Env(format!("${{{}}}: {}", name, e)))?;
+            ret.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            ret.push('$');
+            rest = &rest[1..];
+        }
+    }
+    ret.push_str(rest);
+    Ok(Cow::Owned(ret))
+}
+
+/// How many single character edits turn a into b.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut prev = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != *cb);
+            cur.push(substitute.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// The first line of c setting key, 1-based.
+fn key_line(c: &str, key: &str) -> Option<usize> {
+    let quoted = format!("\"{}\"", key);
+    c.lines()
+        .position(|line| {
+            let line = line.trim_start();
+            line.strip_prefix(key)
+                .or_else(|| line.strip_prefix(quoted.as_str()))
+                .is_some_and(|rest| rest.trim_start().starts_with(['=', '.']))
+        })
+        .map(|idx| idx + 1)
+}
+
+/// Turn a serde error from a parsed config c into one that says where the problem
+/// is: an unknown key's line and the known key it's closest to, else the error
+/// the text gives, which has a line when the file alone causes it.
+fn diagnose(e: toml::de::Error, c: &str) -> ConfigError {
+    let msg = e.to_string();
+    let field = match msg.strip_prefix("unknown field `") {
+        Some(rest) => rest.split('`').next().unwrap_or_default(),
+        None => {
+            return match toml::from_str::<Config>(c) {
+                Err(raw) if raw.to_string().starts_with(&msg) => ConfigError::Toml(raw),
+                _ => ConfigError::Toml(e),
+            };
+        }
+    };
+    let (expected, table) = match msg.split_once(" for key `") {
+        Some((expected, table)) => (expected, table.trim_end_matches('`')),
+        None => (msg.as_str(), ""),
+    };
+    let suggestion = expected
+        .split('`')
+        .skip(3)
+        .step_by(2)
+        .map(|known| (edit_distance(field, known), known))
+        .filter(|(distance, _)| *distance <= (field.len() / 3).max(2))
+        .min();
+    let mut ret = format!("unknown key `{}`", field);
+    if !table.is_empty() {
+        ret.push_str(&format!(" in [{}]", table));
+    }
+    if let Some(line) = key_line(c, field) {
+        ret.push_str(&format!(" on line {}", line));
+    }
+    match suggestion {
+        Some((_, known)) => ret.push_str(&format!(", did you mean `{}`?", known)),
+        None => ret.push_str(&format!(
+            ", {}",
+            expected.split_once(", ").map_or("", |(_, rest)| rest)
+        )),
+    }
+    ConfigError::UnknownKey(ret)
+}
+
+/// Apply a --set dotted.key=value, e.g. general.nick=bot or network.0.port=6697. The
+/// value is TOML when it parses as such, else a string.
+fn apply_override(root: &mut toml::Value, setting: &str) -> Result<(), ConfigError> {
+    let bad = |why: &str| ConfigError::Override(format!("{}: {}", setting, why));
+    let (key, raw) = setting
+        .split_once('=')
+        .ok_or_else(|| bad("expected key=value"))?;
+    let raw = raw.trim();
+    let value = toml::from_str::<toml::value::Table>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_owned()));
+    let mut parts = key.trim().split('.').peekable();
+    let mut at = root;
+    while let Some(part) = parts.next() {
+        if part.is_empty() {
+            return Err(bad("empty key"));
+        }
+        let last = parts.peek().is_none();
+        at = match at {
+            toml::Value::Table(table) if last => {
+                table.insert(part.to_owned(), value);
+                return Ok(());
+            }
+            toml::Value::Table(table) => table
+                .entry(part.to_owned())
+                .or_insert_with(|| toml::Value::Table(Default::default())),
+            toml::Value::Array(values) => {
+                let idx = part
+                    .parse::<usize>()
+                    .map_err(|_| bad("expected an index"))?;
+                let slot = values
+                    .get_mut(idx)
+                    .ok_or_else(|| bad("no such array entry"))?;
+                if last {
+                    *slot = value;
+                    return Ok(());
+                }
+                slot
+            }
+            _ => return Err(bad("not a table")),
+        };
+    }
+    Ok(())
+}
+
+/// If the setting key in table holds a credential, the only strings ${NAME} is
+/// expanded in; a regex or command with a literal ${ is left as written.
+fn is_secret(table: &str, key: &str) -> bool {
+    key.ends_with("password") || (table == "proxy" && key == "username")
+}
+
+/// Expand the environment variables in every credential in the table named name.
+fn interpolate(name: &str, value: &mut toml::Value) -> Result<(), ConfigError> {
+    match value {
+        toml::Value::Array(values) => {
+            for value in values {
+                interpolate(name, value)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                match value {
+                    toml::Value::String(s) if is_secret(name, key) => {
+                        if let Cow::Owned(expanded) = expand_env(s)? {
+                            *s = expanded;
+                        }
+                    }
+                    _ => interpolate(key, value)?,
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(c: &str) -> Result<Config, ConfigError> {
+        Config::with_overrides(c, &[])
+    }
 }
 
 impl Config {
-    pub fn from_str(c: &str) -> Result<Config, ConfigError> {
-        toml::from_str::<Config>(&c).map_err(|e| e.into())
+    /// Parse c, then apply the --set overrides. Includes are relative to the working
+    /// directory; plugin_dir is only read by from_path().
+    pub fn with_overrides(c: &str, overrides: &[String]) -> Result<Config, ConfigError> {
+        Config::parse(c, Path::new("."), overrides)
     }
 
-    pub fn from_path(p: &Path) -> Result<Config, ConfigError> {
-        let mut f = File::open(&p)?;
+    fn parse(c: &str, dir: &Path, overrides: &[String]) -> Result<Config, ConfigError> {
+        let mut value = toml::from_str::<toml::Value>(c)?;
+        load_includes(&mut value, dir)?;
+        for setting in overrides {
+            apply_override(&mut value, setting)?;
+        }
+        interpolate("", &mut value)?;
+        let mut conf = value.try_into::<Config>().map_err(|e| diagnose(e, c))?;
+        conf.overrides = overrides.to_vec();
+        // a filter left out would let through what it should keep us from saying.
+        for source in &conf.filter.regexes {
+            Regex::new(source).map_err(|e| ConfigError::Filter(source.clone(), e))?;
+        }
+        Ok(conf)
+    }
+
+    pub fn from_path(p: &Path, overrides: &[String]) -> Result<Config, ConfigError> {
+        let mut f = File::open(p)?;
         let mut c = String::new();
         f.read_to_string(&mut c)?;
-        Config::from_str(c.as_ref())
+        let dir = p.parent().unwrap_or_else(|| Path::new("."));
+        let mut conf = Config::parse(c.as_ref(), dir, overrides)?;
+        load_plugin_dir(&mut conf, dir)?;
+        if conf.storage.path.is_empty() {
+            if let Some(path) = xdg::default_storage() {
+                conf.storage.path = path.display().to_string();
+            }
+        }
+        // a mistyped path would otherwise only show once someone runs the command.
+        let own = conf.network.iter().filter_map(|net| net.commands.as_ref());
+        for commands in std::iter::once(&conf.commands).chain(own) {
+            for (name, command) in commands {
+                if let Some(problem) = check_program(&format!("Command {}", name), &command.path) {
+                    warn!("{}", problem);
+                }
+            }
+        }
+        Ok(conf)
     }
 
-    pub fn connect_string(&self) -> String {
-        format!("{}:{}", self.general.server, self.general.port)
+    /// Every host:port to try, in order, server first.
+    pub fn connect_strings(&self) -> Vec<String> {
+        let general = &self.general;
+        let mut ret = Vec::new();
+        if !general.server.is_empty() {
+            ret.push(format!("{}:{}", general.server, general.port));
+        }
+        for server in &general.servers {
+            // a port after the last colon; an IPv6 address needs [brackets] around it.
+            let has_port = server.rsplit_once(':').is_some_and(|(host, port)| {
+                (!host.contains(':') || host.ends_with(']')) && port.parse::<u16>().is_ok()
+            });
+            if has_port {
+                ret.push(server.clone());
+            } else {
+                ret.push(format!("{}:{}", server, general.port));
+            }
+        }
+        ret
+    }
+
+    /// The config of every network to connect to, `[general]` first, each with
+    /// its own general settings, commands and storage.
+    pub fn networks(&self) -> Vec<Config> {
+        let mut first = self.clone();
+        first.network.clear();
+        let mut ret = vec![first];
+        for (idx, net) in self.network.iter().enumerate() {
+            let mut conf = self.clone();
+            conf.network.clear();
+            conf.storage.path = network_storage(&self.storage.path, idx + 1);
+            conf.general = net.general.clone();
+            if let Some(commands) = &net.commands {
+                conf.commands = commands.clone();
+            }
+            ret.push(conf);
+        }
+        ret
+    }
+}
+
+/// The database of the Nth network, path with .N before its extension, so karma,
+/// seen, tell and STS policies of one network don't mix with another's.
+/// Every network has a database of its own in memory anyway.
+fn network_storage(path: &str, idx: usize) -> String {
+    let path = Path::new(path);
+    let name = match (path.file_stem(), path.extension()) {
+        _ if path.as_os_str() == ":memory:" => return ":memory:".to_owned(),
+        (Some(stem), Some(ext)) => format!(
+            "{}.{}.{}",
+            stem.to_string_lossy(),
+            idx,
+            ext.to_string_lossy()
+        ),
+        (Some(stem), None) => format!("{}.{}", stem.to_string_lossy(), idx),
+        (None, _) => return String::new(),
+    };
+    path.with_file_name(name).display().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand_env, Config, ConfigError, FromStr, Verbosity, EXAMPLE};
+
+    #[test]
+    fn verbosity_truncate() {
+        let long = "é".repeat(100);
+        assert_eq!(Verbosity::Compact.truncate(&long).chars().count(), 80);
+        assert!(Verbosity::Compact.truncate(&long).ends_with('…'));
+        assert_eq!(Verbosity::Normal.truncate(&long), long);
+        assert_eq!(Verbosity::Verbose.truncate(&long), long);
+        assert_eq!(Verbosity::Compact.truncate("short"), "short");
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let err = |c: &str| Config::from_str(c).unwrap_err().to_string();
+        assert_eq!(
+            err("[general]\nnick = \"bot\"\ncomand_prefix = \"!\"\n"),
+            "Could not parse config file: unknown key `comand_prefix` in [general] on line 3, did you mean `command_prefix`?"
+        );
+        assert_eq!(
+            err("[general]\nnick = \"bot\"\n[[network]]\nnick = \"bot\"\n  sever = \"irc\"\n"),
+            "Could not parse config file: unknown key `sever` in [network] on line 5, did you mean `server`?"
+        );
+        assert_eq!(
+            err("[general]\nnick = \"bot\"\n[storage]\nxyzzy = 1\n"),
+            "Could not parse config file: unknown key `xyzzy` in [storage] on line 4, expected `path`"
+        );
+        assert_eq!(
+            err("comands = {}\n[general]\nnick = \"bot\"\n"),
+            "Could not parse config file: unknown key `comands` on line 1, did you mean `commands`?"
+        );
+        // other errors keep the position the text gives them.
+        assert!(err("[general]\nnick = \"bot\"\nport = \"high\"\n").contains("line 3"));
+    }
+
+    #[test]
+    fn example_config() {
+        let conf = Config::from_str(EXAMPLE).unwrap();
+        assert_eq!(conf.general.nick, "neo8ball");
+        assert!(conf.commands.is_empty());
+        assert_eq!(crate::config::check::check(&conf), Vec::<String>::new());
+    }
+
+    #[test]
+    fn env_interpolation() {
+        std::env::set_var("R8BALL_TEST_SASL", "hunter2");
+        std::env::remove_var("R8BALL_TEST_UNSET");
+        assert_eq!(expand_env("plain").unwrap(), "plain");
+        assert_eq!(expand_env("a${R8BALL_TEST_SASL}b").unwrap(), "ahunter2b");
+        assert_eq!(expand_env("$5 ${R8BALL_TEST_SASL}").unwrap(), "$5 hunter2");
+        assert_eq!(
+            expand_env("$${R8BALL_TEST_SASL}").unwrap(),
+            "${R8BALL_TEST_SASL}"
+        );
+        assert!(matches!(
+            expand_env("${R8BALL_TEST_UNSET}"),
+            Err(ConfigError::Env(_))
+        ));
+        assert!(matches!(expand_env("${oops"), Err(ConfigError::Env(_))));
+
+        let conf = Config::from_str(
+            r##"
+[general]
+nick = "bot"
+server = "irc.one"
+nickserv_password = "${R8BALL_TEST_SASL}"
+channels = ["#${R8BALL_TEST_SASL}"]
+
+[proxy]
+host = "localhost"
+username = "${R8BALL_TEST_SASL}"
+password = "$${R8BALL_TEST_SASL}"
+"##,
+        )
+        .unwrap();
+        assert_eq!(conf.general.nickserv_password, "hunter2");
+        // only credentials.
+        assert_eq!(conf.general.channels, vec!["#${R8BALL_TEST_SASL}"]);
+        let proxy = conf.proxy.unwrap();
+        assert_eq!(proxy.username, "hunter2");
+        assert_eq!(proxy.password, "${R8BALL_TEST_SASL}");
+    }
+
+    #[test]
+    fn overrides() {
+        let conf = |overrides: &[&str]| {
+            let overrides = overrides.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            Config::with_overrides(
+                r##"
+[general]
+nick = "bot"
+server = "irc.one"
+
+[[network]]
+nick = "bot2"
+server = "irc.two"
+"##,
+                &overrides,
+            )
+        };
+        let set = conf(&[
+            "general.nick=testbot",
+            "general.port=6697",
+            "general.channels=[\"#test\"]",
+            "network.0.server = irc.staging",
+            "storage.path=:memory:",
+        ])
+        .unwrap();
+        assert_eq!(set.general.nick, "testbot");
+        assert_eq!(set.connect_strings(), vec!["irc.one:6697"]);
+        assert_eq!(set.general.channels, vec!["#test"]);
+        assert_eq!(set.network[0].general.nick, "bot2");
+        assert_eq!(
+            set.networks()[1].connect_strings(),
+            vec!["irc.staging:6667"]
+        );
+        assert_eq!(set.storage.path, ":memory:");
+        assert_eq!(set.overrides.len(), 5);
+
+        for bad in [
+            "general.nick",
+            "network.1.nick=x",
+            "general.nick.first=x",
+            ".x=1",
+        ] {
+            assert!(
+                matches!(conf(&[bad]), Err(ConfigError::Override(_))),
+                "{}",
+                bad
+            );
+        }
+        assert!(matches!(
+            conf(&["general.port=\"high\""]),
+            Err(ConfigError::Toml(_))
+        ));
+    }
+
+    #[test]
+    fn networks() {
+        let conf = Config::from_str(
+            r##"
+[general]
+nick = "bot"
+server = "irc.one"
+
+[commands]
+test = "./test"
+
+[[network]]
+nick = "bot2"
+server = "irc.two"
+port = 6697
+servers = ["irc.four:6667", "irc.five", "[::1]"]
+channels = ["#two"]
+
+[[network]]
+nick = "bot3"
+server = "irc.three"
+[network.commands]
+other = "./other"
+
+[storage]
+path = "/var/lib/r8ball/bot.db"
+"##,
+        )
+        .unwrap();
+        let nets = conf.networks();
+        assert_eq!(nets.len(), 3);
+        assert_eq!(nets[0].connect_strings(), vec!["irc.one:6667"]);
+        assert_eq!(
+            nets[1].connect_strings(),
+            vec![
+                "irc.two:6697",
+                "irc.four:6667",
+                "irc.five:6697",
+                "[::1]:6697"
+            ]
+        );
+        assert_eq!(nets[1].general.nick, "bot2");
+        assert_eq!(nets[1].general.channels, vec!["#two"]);
+        assert!(nets[1].commands.contains_key("test"));
+        assert!(nets[2].commands.contains_key("other"));
+        assert!(!nets[2].commands.contains_key("test"));
+        assert!(nets.iter().all(|net| net.network.is_empty()));
+        assert_eq!(nets[0].storage.path, "/var/lib/r8ball/bot.db");
+        assert_eq!(nets[2].storage.path, "/var/lib/r8ball/bot.2.db");
     }
 }
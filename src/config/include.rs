@@ -0,0 +1,162 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::config_file::ConfigError;
+use crate::irc::client::helpers::mask_match;
+
+/// The files a pattern names, sorted; * and ? only work in the file name.
+fn resolve(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let path = dir.join(pattern);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![path]);
+    }
+    let parent = path.parent().unwrap_or(dir);
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?.path();
+        let matches = entry
+            .file_name()
+            .and_then(|file| file.to_str())
+            .is_some_and(|file| {
+                !file.starts_with('.') && mask_match(name.as_bytes(), file.as_bytes())
+            });
+        if matches && entry.is_file() {
+            ret.push(entry);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Add what from has to into: tables are merged, arrays appended, and any other
+/// value already in into is kept.
+fn merge(into: &mut toml::Value, from: toml::Value) {
+    match (into, from) {
+        (toml::Value::Table(into), toml::Value::Table(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(into), toml::Value::Array(from)) => into.extend(from),
+        _ => (),
+    }
+}
+
+/// Merge the files named by the top level include = [...] patterns into root, in
+/// order. Patterns are relative to dir, the config file's directory; included
+/// files may not include others.
+pub fn load_includes(root: &mut toml::Value, dir: &Path) -> Result<(), ConfigError> {
+    let patterns = match root.get("include").and_then(|include| include.as_array()) {
+        Some(patterns) => patterns.clone(),
+        None => return Ok(()),
+    };
+    for pattern in patterns {
+        let pattern = pattern
+            .as_str()
+            .ok_or_else(|| ConfigError::Include(pattern.to_string(), "not a string".to_owned()))?;
+        for path in resolve(dir, pattern)? {
+            let name = path.display().to_string();
+            let c = fs::read_to_string(&path)
+                .map_err(|e| ConfigError::Include(name.clone(), e.to_string()))?;
+            let value = toml::from_str::<toml::Value>(&c)
+                .map_err(|e| ConfigError::Include(name.clone(), e.to_string()))?;
+            if value.get("include").is_some() {
+                return Err(ConfigError::Include(
+                    name,
+                    "included files can't include others".to_owned(),
+                ));
+            }
+            merge(root, value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::config::config_file::{Config, ConfigError};
+
+    #[test]
+    fn includes() {
+        let dir = env::temp_dir().join(format!("r8ball-include-{}", process::id()));
+        fs::create_dir_all(dir.join("commands.d")).unwrap();
+        fs::write(
+            dir.join("commands.d/a.toml"),
+            "[commands]\nweather = \"./weather\"\n[friends]\n\"*!*@friend\" = \"v\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("commands.d/b.toml"),
+            "[general]\nnick = \"ignored\"\nmonitor = [\"two\"]\n[commands]\nfact = \"./fact\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("commands.d/notes.txt"), "not = toml = at all").unwrap();
+        fs::write(
+            dir.join("nested.toml"),
+            "include = [\"commands.d/*.toml\"]\n",
+        )
+        .unwrap();
+        let main = "include = [\"commands.d/*.toml\"]\n[general]\nnick = \"bot\"\nmonitor = [\"one\"]\n[commands]\nsh = \"/bin/sh\"\n";
+        fs::write(dir.join("config.toml"), main).unwrap();
+
+        let conf = Config::from_path(&dir.join("config.toml"), &[]).unwrap();
+        assert_eq!(conf.general.nick, "bot");
+        assert_eq!(conf.general.monitor, vec!["one", "two"]);
+        let mut commands = conf.commands.keys().collect::<Vec<_>>();
+        commands.sort();
+        assert_eq!(commands, vec!["fact", "sh", "weather"]);
+        assert_eq!(conf.friends["*!*@friend"], "v");
+
+        fs::write(
+            dir.join("config.toml"),
+            "include = [\"nested.toml\"]\n[general]\nnick = \"bot\"\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Config::from_path(&dir.join("config.toml"), &[]),
+            Err(ConfigError::Include(..))
+        ));
+        fs::write(
+            dir.join("config.toml"),
+            "include = [\"missing.toml\"]\n[general]\nnick = \"bot\"\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Config::from_path(&dir.join("config.toml"), &[]),
+            Err(ConfigError::Include(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,2 +1,6 @@
+pub mod check;
 pub mod cmdline;
 pub mod config_file;
+pub mod include;
+pub mod plugin_dir;
+pub mod xdg;
@@ -0,0 +1,160 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use serde::Deserialize;
+
+use super::config_file::{CommandConfig, Config, ConfigError, Matcher, Sandbox, Trigger};
+
+/// The optional `<name>.toml` next to a plugin in plugin_dir.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    // the command word, the file name without its extension when left out.
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub help: String,
+    // regexes which also run the plugin, like [[matchers]].
+    #[serde(default)]
+    pub matchers: Vec<String>,
+    // seconds before the plugin is killed, 0 to let it run.
+    #[serde(default)]
+    pub timeout: u64,
+}
+
+/// Register every executable in `[general]` plugin_dir, relative to dir, the config
+/// file's directory, as a command. Commands already in `[commands]` win over
+/// discovered ones.
+pub fn load_plugin_dir(conf: &mut Config, dir: &Path) -> Result<(), ConfigError> {
+    if conf.general.plugin_dir.is_empty() {
+        return Ok(());
+    }
+
+    let mut entries = fs::read_dir(dir.join(&conf.general.plugin_dir))?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    for path in entries {
+        if !is_executable(&path) {
+            continue;
+        }
+        let stem = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let manifest_path = path.with_file_name(format!("{}.toml", stem));
+        let manifest = if manifest_path.is_file() {
+            let manifest = fs::read_to_string(&manifest_path)?;
+            toml::from_str::<Manifest>(&manifest).map_err(|e| {
+                ConfigError::Manifest(manifest_path.to_string_lossy().to_string(), e)
+            })?
+        } else {
+            Manifest::default()
+        };
+
+        let name = manifest.command.unwrap_or(stem);
+        let plugin = path.to_string_lossy().to_string();
+        for regex in manifest.matchers {
+            conf.matchers.push(Matcher {
+                regex,
+                plugin: plugin.clone(),
+                name: name.clone(),
+                moderator: false,
+            });
+        }
+        let sandbox = match manifest.timeout {
+            0 => None,
+            timeout => Some(Sandbox {
+                timeout,
+                ..conf.sandbox.clone()
+            }),
+        };
+        conf.commands.entry(name).or_insert(CommandConfig {
+            path: plugin,
+            trigger: manifest.trigger,
+            throttle: 0,
+            max_running: 0,
+            sandbox,
+            help: manifest.help,
+            admin: false,
+            require_op: false,
+            moderator: false,
+        });
+    }
+    Ok(())
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, os::unix::fs::PermissionsExt, process};
+
+    use crate::config::config_file::{Config, Trigger};
+
+    #[test]
+    fn discover_plugins() {
+        let dir = env::temp_dir().join(format!("r8ball-plugins-{}", process::id()));
+        let plugins = dir.join("plugins");
+        fs::create_dir_all(&plugins).unwrap();
+        for name in &["weather.sh", "factoid", "notes.txt"] {
+            fs::write(plugins.join(name), "#!/bin/sh\n").unwrap();
+        }
+        for name in &["weather.sh", "factoid"] {
+            fs::set_permissions(plugins.join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(
+            plugins.join("factoid.toml"),
+            "command = \"fact\"\ntrigger = \"bare\"\nhelp = \"look up a fact\"\nmatchers = [\"^(\\\\w+)\\\\?$\"]\ntimeout = 5\n",
+        )
+        .unwrap();
+        // relative to the config file, wherever we run from.
+        fs::write(
+            dir.join("config.toml"),
+            "[general]\nnick = \"bot\"\nserver = \"localhost\"\nplugin_dir = \"plugins\"\n[commands]\nweather = \"./mine\"\n",
+        )
+        .unwrap();
+
+        let conf = Config::from_path(&dir.join("config.toml"), &[]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // [commands] wins
+        assert_eq!(conf.commands["weather"].path, "./mine");
+        let fact = &conf.commands["fact"];
+        assert_eq!(fact.path, plugins.join("factoid").display().to_string());
+        assert_eq!(fact.trigger, Trigger::Bare);
+        assert_eq!(fact.help, "look up a fact");
+        assert_eq!(fact.sandbox.as_ref().unwrap().timeout, 5);
+        assert!(!conf.commands.contains_key("notes"));
+        assert_eq!(conf.matchers.len(), 1);
+        assert_eq!(conf.matchers[0].name, "fact");
+    }
+}
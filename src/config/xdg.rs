@@ -0,0 +1,111 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! Where to look for the config, and where to keep what we generate, per the XDG
+//! base directory spec.
+use std::{env, ffi::OsString, path::PathBuf};
+
+/// Used when none of the other candidates exist.
+const FALLBACK_CONFIG: &str = "./r8ball.conf";
+
+/// The environment, or a stand-in for tests.
+type Getenv<'a> = &'a dyn Fn(&str) -> Option<OsString>;
+
+/// $name, or $HOME/home_dir when it's unset; relative values are ignored as the spec says.
+fn base_dir(getenv: Getenv, name: &str, home_dir: &str) -> Option<PathBuf> {
+    match getenv(name).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => Some(dir),
+        _ => getenv("HOME")
+            .map(PathBuf::from)
+            .filter(|home| home.is_absolute())
+            .map(|home| home.join(home_dir)),
+    }
+}
+
+/// The config files tried without --config, in order.
+fn config_candidates(getenv: Getenv) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    if let Some(dir) = base_dir(getenv, "XDG_CONFIG_HOME", ".config") {
+        ret.push(dir.join("r8ball/config.toml"));
+    }
+    ret.push(PathBuf::from("/etc/r8ball/config.toml"));
+    ret
+}
+
+/// The first config file which exists, else ./r8ball.conf.
+pub fn find_config() -> String {
+    config_candidates(&|name| env::var_os(name))
+        .into_iter()
+        .find(|path| path.is_file())
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| FALLBACK_CONFIG.to_owned())
+}
+
+fn state_dir(getenv: Getenv) -> Option<PathBuf> {
+    base_dir(getenv, "XDG_STATE_HOME", ".local/state").map(|dir| dir.join("r8ball"))
+}
+
+/// $XDG_STATE_HOME/r8ball/r8ball.db, the storage database when `[storage]` has no path.
+pub fn default_storage() -> Option<PathBuf> {
+    state_dir(&|name| env::var_os(name)).map(|dir| dir.join("r8ball.db"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsString, path::PathBuf};
+
+    use super::{config_candidates, state_dir};
+
+    #[test]
+    fn base_dirs() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                pairs
+                    .iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        };
+        let getenv = vars(&[
+            ("HOME", "/home/bot"),
+            ("XDG_CONFIG_HOME", "/xdg/config"),
+            ("XDG_STATE_HOME", "relative/is/ignored"),
+        ]);
+        assert_eq!(
+            config_candidates(&getenv),
+            vec![
+                PathBuf::from("/xdg/config/r8ball/config.toml"),
+                PathBuf::from("/etc/r8ball/config.toml")
+            ]
+        );
+        assert_eq!(
+            state_dir(&getenv),
+            Some(PathBuf::from("/home/bot/.local/state/r8ball"))
+        );
+
+        let getenv = vars(&[("HOME", "/home/bot"), ("XDG_STATE_HOME", "/xdg/state")]);
+        assert_eq!(
+            config_candidates(&getenv)[0],
+            PathBuf::from("/home/bot/.config/r8ball/config.toml")
+        );
+        assert_eq!(state_dir(&getenv), Some(PathBuf::from("/xdg/state/r8ball")));
+
+        assert_eq!(state_dir(&vars(&[])), None);
+        assert_eq!(config_candidates(&vars(&[])).len(), 1);
+    }
+}
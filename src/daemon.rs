@@ -0,0 +1,263 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! Running as a traditional daemon: detaching from the terminal, sending output to
+//! the log file, a locked PID file for init scripts, and dropping root privileges.
+
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// set once drop_privileges gave up root.
+static DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// A PID file we hold a lock on, removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Lock the file, failing if another process holds it. Forks keep the lock.
+    pub fn lock(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        // Safety: flock on a descriptor we own.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+            let running = fs::read_to_string(path).unwrap_or_default();
+            return Err(io::Error::other(format!(
+                "{} is locked, r8ball is already running as pid {}",
+                path.display(),
+                running.trim()
+            )));
+        }
+        Ok(PidFile {
+            path: path.to_owned(),
+            file,
+        })
+    }
+
+    /// Write our pid, once we are done forking.
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        (&self.file).write_all(format!("{}\n", std::process::id()).as_bytes())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn fork() -> io::Result<libc::pid_t> {
+    // Safety: called before we start any threads.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        pid => Ok(pid),
+    }
+}
+
+/// Detach from the terminal: fork, start a new session and fork again, so we are no
+/// session leader that could get one back. The parents exit right away. We stay in
+/// the working directory, as paths in the config are relative to it.
+/// This must be called before any threads are started.
+pub fn daemonize() -> io::Result<()> {
+    if fork()? != 0 {
+        // Safety: _exit skips destructors, which belong to the child now.
+        unsafe { libc::_exit(0) };
+    }
+    // Safety: we are not a process group leader after the fork.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if fork()? != 0 {
+        unsafe { libc::_exit(0) };
+    }
+    Ok(())
+}
+
+fn replace_fd(file: &File, fd: libc::c_int) -> io::Result<()> {
+    // Safety: dup2 onto one of the standard descriptors.
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send stdout and stderr to log_file, appending. Detached, stdin is /dev/null, as
+/// are stdout and stderr without a log_file.
+pub fn redirect_output(log_file: &str, detached: bool) -> io::Result<()> {
+    let null = || OpenOptions::new().read(true).write(true).open("/dev/null");
+    if detached {
+        replace_fd(&null()?, libc::STDIN_FILENO)?;
+    }
+    let out = if !log_file.is_empty() {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)?
+    } else if detached {
+        null()?
+    } else {
+        return Ok(());
+    };
+    replace_fd(&out, libc::STDOUT_FILENO)?;
+    replace_fd(&out, libc::STDERR_FILENO)
+}
+
+/// The uid and primary gid of a user.
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name).map_err(io::Error::other)?;
+    let mut buf = vec![0 as libc::c_char; 16384];
+    // Safety: getpwnam_r only writes to pwd, buf and found, which outlive the call.
+    unsafe {
+        let mut pwd: libc::passwd = std::mem::zeroed();
+        let mut found = std::ptr::null_mut();
+        let ret = libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        );
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        if found.is_null() {
+            return Err(io::Error::other(format!("No such user {}", name)));
+        }
+        Ok((pwd.pw_uid, pwd.pw_gid))
+    }
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = CString::new(name).map_err(io::Error::other)?;
+    let mut buf = vec![0 as libc::c_char; 16384];
+    // Safety: as in lookup_user.
+    unsafe {
+        let mut grp: libc::group = std::mem::zeroed();
+        let mut found = std::ptr::null_mut();
+        let ret = libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        );
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        if found.is_null() {
+            return Err(io::Error::other(format!("No such group {}", name)));
+        }
+        Ok(grp.gr_gid)
+    }
+}
+
+/// Become user and group, see General::user. The group defaults to the user's own,
+/// and the supplementary groups are dropped. Nothing to do unless we are root.
+pub fn drop_privileges(user: &str, group: &str) -> io::Result<()> {
+    if user.is_empty() && group.is_empty() {
+        return Ok(());
+    }
+    // Safety: geteuid can't fail.
+    if unsafe { libc::geteuid() } != 0 {
+        warn!("Not started as root, ignoring user and group.");
+        return Ok(());
+    }
+    let uid = match user {
+        "" => None,
+        user => Some(lookup_user(user)?),
+    };
+    let gid = match (group, uid) {
+        ("", Some((_, gid))) => gid,
+        ("", None) => unreachable!("checked above"),
+        (group, _) => lookup_group(group)?,
+    };
+    let check = |ret: libc::c_int| match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    // Safety: plain system calls, the group before the user while we still may.
+    unsafe {
+        check(libc::setgroups(1, &gid))?;
+        check(libc::setgid(gid))?;
+        if let Some((uid, _)) = uid {
+            check(libc::setuid(uid))?;
+            // there is no way back.
+            if libc::setuid(0) == 0 {
+                return Err(io::Error::other("Still able to become root after setuid"));
+            }
+        }
+    }
+    DROPPED.store(true, Ordering::Relaxed);
+    info!("Running as user {:?} and group {}.", user, gid);
+    Ok(())
+}
+
+/// If drop_privileges gave up root, so files only root may read are out of reach.
+pub fn privileges_dropped() -> bool {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{lookup_group, lookup_user, PidFile};
+
+    #[test]
+    fn user_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert!(lookup_user("r8ball-no-such-user").is_err());
+        assert!(lookup_group("r8ball-no-such-group").is_err());
+    }
+
+    #[test]
+    fn pidfile_lock() {
+        let path = std::env::temp_dir().join(format!("r8ball-{}.pid", std::process::id()));
+        let mut pidfile = PidFile::lock(&path).unwrap();
+        pidfile.write_pid().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        let err = PidFile::lock(&path).err().unwrap().to_string();
+        assert!(err.ends_with(&format!("already running as pid {}", std::process::id())));
+
+        drop(pidfile);
+        assert!(!path.exists());
+        drop(PidFile::lock(&path).unwrap());
+    }
+}
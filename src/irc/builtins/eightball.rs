@@ -0,0 +1,115 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use rand::Rng;
+
+use crate::{
+    config::config_file::{Answer, Config},
+    irc::client::native::{BotPlugin, Command, Context, PrivMsg},
+};
+
+/// Answers `".8 <question>"` with a weighted random answer.
+pub struct EightBall {
+    answers: Vec<Answer>,
+    // u64, so a few answers weighted near u32::MAX can't overflow it.
+    total_weight: u64,
+}
+
+impl EightBall {
+    pub fn new(answers: Vec<Answer>) -> Self {
+        let total_weight = answers.iter().map(|ans| u64::from(ans.weight)).sum();
+        EightBall {
+            answers,
+            total_weight,
+        }
+    }
+
+    fn pick(&self, roll: u64) -> Option<&str> {
+        let mut roll = roll;
+        for ans in &self.answers {
+            let weight = u64::from(ans.weight);
+            if roll < weight {
+                return Some(&ans.text);
+            }
+            roll -= weight;
+        }
+        None
+    }
+}
+
+impl BotPlugin for EightBall {
+    fn name(&self) -> &str {
+        "8ball"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["8", "8ball"]
+    }
+
+    fn help(&self, _cmd: &str) -> &str {
+        "ask a yes or no question."
+    }
+
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+        if cmd.args.is_empty() {
+            return vec![msg.answer("ask me a question.")];
+        }
+        if self.total_weight == 0 {
+            return vec![];
+        }
+
+        let roll = ctx.rng.gen_range(0..self.total_weight);
+        match self.pick(roll) {
+            Some(answer) => vec![msg.answer(answer)],
+            None => vec![],
+        }
+    }
+
+    fn reload(&mut self, config: &Config) {
+        *self = EightBall::new(config.eightball.answers.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::config_file::Answer;
+
+    use super::EightBall;
+
+    fn answer(text: &str, weight: u32) -> Answer {
+        Answer {
+            text: text.to_owned(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn weighted_pick() {
+        let ball = EightBall::new(vec![answer("yes", 2), answer("no", 0), answer("maybe", 1)]);
+        assert_eq!(ball.total_weight, 3);
+        assert_eq!(ball.pick(0), Some("yes"));
+        assert_eq!(ball.pick(1), Some("yes"));
+        assert_eq!(ball.pick(2), Some("maybe"));
+        assert_eq!(ball.pick(3), None);
+
+        let ball = EightBall::new(vec![answer("yes", u32::MAX), answer("no", u32::MAX)]);
+        assert_eq!(ball.total_weight, 2 * u64::from(u32::MAX));
+        assert_eq!(ball.pick(u64::from(u32::MAX)), Some("no"));
+    }
+}
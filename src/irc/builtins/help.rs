@@ -0,0 +1,142 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use crate::irc::client::native::{BotPlugin, Command, Context, PrivMsg};
+
+// longest list of commands in one NOTICE.
+const PAGE_LEN: usize = 400;
+
+/// Lists the commands we answer to and explains them, over NOTICE to the asker.
+/// Also answers the .bots and .source conventions.
+pub struct Help {
+    prefix: String,
+    // (command, help) sorted by command; bare and anywhere triggers have no prefix.
+    topics: Vec<(String, String)>,
+}
+
+impl Help {
+    pub fn new(prefix: String, mut topics: Vec<(String, String)>) -> Self {
+        topics.sort();
+        topics.dedup_by(|a, b| a.0 == b.0);
+        Help { prefix, topics }
+    }
+
+    fn list(&self, msg: &PrivMsg) -> Vec<String> {
+        let mut pages = vec![];
+        let mut page = String::from("Commands:");
+        for (cmd, _) in &self.topics {
+            if page.len() + cmd.len() + 2 > PAGE_LEN {
+                pages.push(std::mem::replace(&mut page, String::from("Commands:")));
+            }
+            page.push(' ');
+            page.push_str(cmd);
+        }
+        pages.push(page);
+        pages.push(format!("Use {}help <command> for more.", self.prefix));
+        pages
+            .into_iter()
+            .map(|page| format!("NOTICE {} :{}", msg.nick, page))
+            .collect()
+    }
+
+    fn explain(&self, msg: &PrivMsg, topic: &str) -> String {
+        let text = self
+            .topics
+            .iter()
+            .find(|(cmd, _)| cmd.trim_start_matches(self.prefix.as_str()) == topic)
+            .map(|(cmd, help)| match help.is_empty() {
+                true => format!("{}: no help available.", cmd),
+                false => format!("{}: {}", cmd, help),
+            })
+            .unwrap_or_else(|| format!("No such command: {}", topic));
+        format!("NOTICE {} :{}", msg.nick, text)
+    }
+}
+
+impl BotPlugin for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["help", "bots", "source"]
+    }
+
+    fn command(&mut self, _ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+        match cmd.name {
+            "help" => match cmd.args.split_whitespace().next() {
+                Some(topic) => vec![self.explain(msg, topic.trim_start_matches(&self.prefix))],
+                None => self.list(msg),
+            },
+            _ => vec![msg.reply(&format!(
+                "Reporting in! [Rust] r8ball, try {}help. Source: {}",
+                self.prefix,
+                env!("CARGO_PKG_REPOSITORY")
+            ))],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Help;
+    use crate::irc::client::{native::PrivMsg, output::ReplyPolicy};
+
+    fn msg() -> PrivMsg {
+        PrivMsg {
+            nick: "nick".to_owned(),
+            user: "user".to_owned(),
+            host: "host".to_owned(),
+            target: "#chan".to_owned(),
+            reply_to: "#chan".to_owned(),
+            private: false,
+            text: String::new(),
+            action: false,
+            policy: ReplyPolicy::default(),
+            account: None,
+            admin: false,
+            relay: None,
+        }
+    }
+
+    #[test]
+    fn help_pages() {
+        let help = Help::new(
+            ".".to_owned(),
+            (0..100)
+                .map(|i| (format!(".cmd{}", i), String::new()))
+                .chain(vec![(".8".to_owned(), "ask a question".to_owned())])
+                .collect(),
+        );
+        let pages = help.list(&msg());
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|page| page.len() <= 420));
+        assert!(pages[0].starts_with("NOTICE nick :Commands: .8 .cmd0 "));
+
+        assert_eq!(help.explain(&msg(), "8"), "NOTICE nick :.8: ask a question");
+        assert_eq!(
+            help.explain(&msg(), "cmd1"),
+            "NOTICE nick :.cmd1: no help available."
+        );
+        assert_eq!(
+            help.explain(&msg(), "nope"),
+            "NOTICE nick :No such command: nope"
+        );
+    }
+}
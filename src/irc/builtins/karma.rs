@@ -0,0 +1,149 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::irc::client::{
+    helpers::{case_cmp, irc_uppercase},
+    native::{BotPlugin, Command, Context, PrivMsg},
+    users::UserKey,
+};
+
+const KARMA_NS: &str = "karma";
+// how long a user has to wait between votes.
+const VOTE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Find the first `word++` or `word--` in a message.
+fn parse_vote(text: &str) -> Option<(&str, i64)> {
+    text.split_whitespace().find_map(|word| {
+        let word = word.trim_end_matches(|chr: char| ",.;:!?".contains(chr));
+        let (target, by) = if let Some(target) = word.strip_suffix("++") {
+            (target, 1)
+        } else {
+            (word.strip_suffix("--")?, -1)
+        };
+        if target.is_empty() || target.ends_with(['+', '-']) {
+            None
+        } else {
+            Some((target, by))
+        }
+    })
+}
+
+/// Counts `word++` and `word--` in channels; `.karma <word>` tells the score.
+#[derive(Default)]
+pub struct Karma {
+    last_vote: HashMap<UserKey, Instant>,
+}
+
+impl Karma {
+    pub fn new() -> Self {
+        Karma::default()
+    }
+
+    fn key(&self, ctx: &Context, word: &str) -> String {
+        String::from_utf8_lossy(&irc_uppercase(&ctx.state.casemapping, word.as_bytes())).to_string()
+    }
+}
+
+impl BotPlugin for Karma {
+    fn name(&self) -> &str {
+        "karma"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["karma"]
+    }
+
+    fn help(&self, _cmd: &str) -> &str {
+        "show the karma of something; vote with thing++ or thing--."
+    }
+
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+        let word = match cmd.args.split_whitespace().next() {
+            Some(word) => word,
+            None => return vec![msg.answer("usage: karma <word>")],
+        };
+
+        let score = match ctx.storage.get(KARMA_NS, &self.key(ctx, word)) {
+            Ok(score) => score.and_then(|s| s.parse::<i64>().ok()).unwrap_or(0),
+            Err(e) => {
+                warn!("Could not look up karma for {:?}: {}", word, e);
+                return vec![];
+            }
+        };
+        vec![msg.answer(&format!("{} has karma of {}.", word, score))]
+    }
+
+    fn privmsg(&mut self, ctx: &mut Context, msg: &PrivMsg) -> Vec<String> {
+        if msg.private {
+            return vec![];
+        }
+        let (word, by) = match parse_vote(&msg.text) {
+            Some(vote) => vote,
+            None => return vec![],
+        };
+
+        if case_cmp(&ctx.state.casemapping, word.as_bytes(), msg.nick.as_bytes()) {
+            return vec![msg.answer("you can't change your own karma.")];
+        }
+
+        let voter = ctx.state.users.key(
+            msg.account.as_deref().map(str::as_bytes),
+            msg.user.as_bytes(),
+            msg.host.as_bytes(),
+        );
+        let now = Instant::now();
+        if let Some(last) = self.last_vote.get(&voter) {
+            if now.duration_since(*last) < VOTE_INTERVAL {
+                return vec![];
+            }
+        }
+        // whoever could vote again anyway is forgotten, so the map stays small.
+        self.last_vote
+            .retain(|_, last| now.duration_since(*last) < VOTE_INTERVAL);
+        self.last_vote.insert(voter, now);
+
+        match ctx.storage.incr(KARMA_NS, &self.key(ctx, word), by) {
+            Ok(score) => vec![msg.reply(&format!("{} now has karma of {}.", word, score))],
+            Err(e) => {
+                warn!("Could not update karma for {:?}: {}", word, e);
+                vec![]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_vote;
+
+    #[test]
+    fn votes() {
+        assert_eq!(parse_vote("rust++"), Some(("rust", 1)));
+        assert_eq!(parse_vote("thanks bob++!"), Some(("bob", 1)));
+        assert_eq!(parse_vote("meetings-- and lunch++"), Some(("meetings", -1)));
+        assert_eq!(parse_vote("i++ is fine"), Some(("i", 1)));
+        assert_eq!(parse_vote("++ -- +++ nope"), None);
+        assert_eq!(parse_vote("no votes here"), None);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod eightball;
+pub mod help;
+pub mod karma;
+pub mod sed;
+pub mod seen;
+pub mod stats;
+pub mod tell;
+pub mod topic;
+pub mod uptime;
+#[cfg(feature = "url-title")]
+pub mod urltitle;
+
+use crate::{
+    config::config_file::{Config, Trigger},
+    irc::client::native::Registry,
+};
+
+/// Register the plugins compiled into the bot.
+pub fn register(registry: &mut Registry, config: &Config) {
+    registry.register(Box::new(eightball::EightBall::new(
+        config.eightball.answers.clone(),
+    )));
+    registry.register(Box::new(karma::Karma::new()));
+    registry.register(Box::new(sed::Sed));
+    registry.register(Box::new(seen::Seen));
+    registry.register(Box::new(stats::Stats));
+    registry.register(Box::new(tell::Tell));
+    registry.register(Box::new(topic::Topic));
+    registry.register(Box::new(uptime::Uptime));
+    #[cfg(feature = "url-title")]
+    registry.register(Box::new(urltitle::UrlTitle::new()));
+    register_help(registry, config);
+}
+
+/// Apply a reloaded config to the registered plugins, keeping what they learned,
+/// e.g. karma cooldowns, and any an embedder added.
+pub fn reload(registry: &mut Registry, config: &Config) {
+    registry.reload(config);
+    register_help(registry, config);
+}
+
+// .help lists everything else registered and the external commands.
+fn register_help(registry: &mut Registry, config: &Config) {
+    let prefix = config
+        .general
+        .command_prefix
+        .chars()
+        .next()
+        .map(String::from)
+        .unwrap_or_default();
+    let mut topics = registry
+        .help()
+        .into_iter()
+        .filter(|(cmd, _)| registry.owner(cmd) != Some("help"))
+        .map(|(cmd, help)| (format!("{}{}", prefix, cmd), help))
+        .collect::<Vec<(String, String)>>();
+    topics.extend(config.commands.iter().map(|(cmd, conf)| {
+        let word = match conf.trigger {
+            Trigger::Prefix => format!("{}{}", prefix, cmd),
+            Trigger::Bare | Trigger::Anywhere => cmd.clone(),
+        };
+        (word, conf.help.clone())
+    }));
+    // answered by the client itself, see Client::dispatch.
+    topics.push((
+        format!("{}optout", prefix),
+        "have the bot leave your messages alone.".to_owned(),
+    ));
+    topics.push((format!("{}optin", prefix), "undo optout.".to_owned()));
+    topics.push((
+        format!("{}help", prefix),
+        "list commands, or explain one.".to_owned(),
+    ));
+    registry.register(Box::new(help::Help::new(prefix, topics)));
+}
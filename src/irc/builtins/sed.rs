@@ -0,0 +1,195 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use regex::{Regex, RegexBuilder};
+
+use crate::irc::client::native::{BotPlugin, Command, Context, PrivMsg};
+
+// keep user supplied patterns from compiling into something huge.
+const REGEX_SIZE_LIMIT: usize = 1024 * 1024;
+
+struct Substitution {
+    re: Regex,
+    replacement: String,
+    global: bool,
+}
+
+/// Split a sed expression on unescaped slashes, unescaping any escaped ones.
+fn split_expr(expr: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = expr.chars();
+    while let Some(chr) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        match chr {
+            '\\' => match chars.next() {
+                Some('/') => part.push('/'),
+                Some(escaped) => {
+                    part.push('\\');
+                    part.push(escaped);
+                }
+                None => part.push('\\'),
+            },
+            '/' => parts.push(String::new()),
+            _ => part.push(chr),
+        }
+    }
+    parts
+}
+
+/// Convert a sed replacement (\1, &) into the regex crate's syntax (${1}, ${0}).
+fn convert_replacement(repl: &str) -> String {
+    let mut ret = String::with_capacity(repl.len());
+    let mut chars = repl.chars();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '\\' => match chars.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    ret.push_str("${");
+                    ret.push(digit);
+                    ret.push('}');
+                }
+                Some('&') => ret.push('&'),
+                Some('$') => ret.push_str("$$"),
+                Some(other) => ret.push(other),
+                None => ret.push('\\'),
+            },
+            '&' => ret.push_str("${0}"),
+            '$' => ret.push_str("$$"),
+            _ => ret.push(chr),
+        }
+    }
+    ret
+}
+
+/// Parse `s/pattern/replacement/[gi]`
+fn parse_sed(text: &str) -> Option<Substitution> {
+    let expr = text.strip_prefix("s/")?;
+    let parts = split_expr(expr);
+    let (pattern, replacement, flags) = match parts.as_slice() {
+        [pattern, replacement] => (pattern, replacement, ""),
+        [pattern, replacement, flags] => (pattern, replacement, flags.as_str()),
+        _ => return None,
+    };
+    if pattern.is_empty() {
+        return None;
+    }
+
+    let mut global = false;
+    let mut insensitive = false;
+    for flag in flags.chars() {
+        match flag {
+            'g' => global = true,
+            'i' => insensitive = true,
+            _ => return None,
+        }
+    }
+
+    let re = RegexBuilder::new(pattern)
+        .case_insensitive(insensitive)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .ok()?;
+    Some(Substitution {
+        re,
+        replacement: convert_replacement(replacement),
+        global,
+    })
+}
+
+impl Substitution {
+    fn apply(&self, text: &str) -> String {
+        if self.global {
+            self.re.replace_all(text, self.replacement.as_str())
+        } else {
+            self.re.replace(text, self.replacement.as_str())
+        }
+        .into_owned()
+    }
+}
+
+/// Lets users correct their last message with s/foo/bar/.
+pub struct Sed;
+
+impl BotPlugin for Sed {
+    fn name(&self) -> &str {
+        "sed"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &[]
+    }
+
+    fn command(&mut self, _ctx: &mut Context, _msg: &PrivMsg, _cmd: &Command) -> Vec<String> {
+        vec![]
+    }
+
+    fn privmsg(&mut self, ctx: &mut Context, msg: &PrivMsg) -> Vec<String> {
+        if msg.private {
+            return vec![];
+        }
+        let sub = match parse_sed(&msg.text) {
+            Some(sub) => sub,
+            None => return vec![],
+        };
+
+        ctx.state
+            .history
+            .recent(&ctx.state.chan_key(msg.target.as_bytes()))
+            .find(|line| {
+                line.nick == msg.nick && !line.text.starts_with("s/") && sub.re.is_match(&line.text)
+            })
+            .map(|line| {
+                let fixed = sub.apply(&line.text);
+                vec![msg.reply(&format!("{} meant to say: {}", msg.nick, fixed))]
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_sed, split_expr};
+
+    #[test]
+    fn split_escaped() {
+        assert_eq!(split_expr(r"a\/b/c/g"), vec!["a/b", "c", "g"]);
+        assert_eq!(split_expr(r"\d+/x"), vec![r"\d+", "x"]);
+    }
+
+    #[test]
+    fn substitutions() {
+        let sub = parse_sed("s/teh/the/").unwrap();
+        assert_eq!(sub.apply("teh cat teh dog"), "the cat teh dog");
+        let sub = parse_sed("s/teh/the/g").unwrap();
+        assert_eq!(sub.apply("teh cat teh dog"), "the cat the dog");
+        let sub = parse_sed("s/TEH/the/i").unwrap();
+        assert_eq!(sub.apply("teh cat"), "the cat");
+        let sub = parse_sed(r"s/(\w+) (\w+)/\2 \1 & $1").unwrap();
+        assert_eq!(sub.apply("hello world"), "world hello hello world $1");
+    }
+
+    #[test]
+    fn not_substitutions() {
+        assert!(parse_sed("hello").is_none());
+        assert!(parse_sed("s/").is_none());
+        assert!(parse_sed("s//x/").is_none());
+        assert!(parse_sed("s/a/b/q").is_none());
+        assert!(parse_sed("s/(/b/").is_none());
+    }
+}
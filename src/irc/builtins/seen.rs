@@ -0,0 +1,175 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::irc::client::{
+    helpers::irc_uppercase,
+    native::{BotPlugin, Command, Context, Event, PrivMsg},
+    CaseMapping,
+};
+
+const SEEN_NS: &str = "seen";
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0)
+}
+
+fn seen_key(casemap: &CaseMapping, nick: &str) -> String {
+    String::from_utf8_lossy(&irc_uppercase(casemap, nick.as_bytes())).to_string()
+}
+
+/// Format seconds as the two largest units, e.g. "2d 3h" or "5m 10s".
+pub fn format_duration(secs: u64) -> String {
+    let units = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+    let parts = units
+        .iter()
+        .scan(secs, |left, &(name, size)| {
+            let count = *left / size;
+            *left %= size;
+            Some((count, name))
+        })
+        .skip_while(|&(count, _)| count == 0)
+        .take(2)
+        .filter(|&(count, _)| count != 0)
+        .map(|(count, name)| format!("{}{}", count, name))
+        .collect::<Vec<String>>();
+    if parts.is_empty() {
+        "0s".to_owned()
+    } else {
+        parts.join(" ")
+    }
+}
+
+/// Remembers when every user was last seen and what they were doing.
+/// Records are stored as `"<unix time>\t<nick>\t<doing what>"`.
+pub struct Seen;
+
+impl Seen {
+    fn record(&self, ctx: &Context, nick: &str, doing: &str) {
+        let key = seen_key(&ctx.state.casemapping, nick);
+        let value = format!("{}\t{}\t{}", now(), nick, doing);
+        if let Err(e) = ctx.storage.set(SEEN_NS, &key, &value) {
+            warn!("Could not record seen for {:?}: {}", nick, e);
+        }
+    }
+
+    fn lookup(&self, ctx: &Context, nick: &str) -> Option<String> {
+        let key = seen_key(&ctx.state.casemapping, nick);
+        let value = match ctx.storage.get(SEEN_NS, &key) {
+            Ok(value) => value?,
+            Err(e) => {
+                warn!("Could not look up seen for {:?}: {}", nick, e);
+                return None;
+            }
+        };
+
+        let mut fields = value.splitn(3, '\t');
+        let (when, nick, doing) = (fields.next()?, fields.next()?, fields.next()?);
+        let ago = now().saturating_sub(when.parse().ok()?);
+        Some(format!(
+            "{} was last seen {} ago, {}",
+            nick,
+            format_duration(ago),
+            doing
+        ))
+    }
+}
+
+impl BotPlugin for Seen {
+    fn name(&self) -> &str {
+        "seen"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["seen"]
+    }
+
+    fn help(&self, _cmd: &str) -> &str {
+        "when a nick last spoke, and what they said."
+    }
+
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+        let nick = match cmd.args.split_whitespace().next() {
+            Some(nick) => nick,
+            None => return vec![msg.answer("usage: seen <nick>")],
+        };
+
+        let answer = self
+            .lookup(ctx, nick)
+            .unwrap_or_else(|| format!("I have not seen {}.", nick));
+        vec![msg.answer(&answer)]
+    }
+
+    fn privmsg(&mut self, ctx: &mut Context, msg: &PrivMsg) -> Vec<String> {
+        if !msg.private {
+            let doing = format!("saying \"{}\" in {}", msg.text, msg.target);
+            self.record(ctx, &msg.nick, &doing);
+        }
+        vec![]
+    }
+
+    fn event(&mut self, ctx: &mut Context, ev: &Event) -> Vec<String> {
+        match ev {
+            Event::Join { nick, channel } => {
+                self.record(ctx, nick, &format!("joining {}", channel));
+            }
+            Event::Part {
+                nick,
+                channel,
+                reason,
+            } => {
+                self.record(ctx, nick, &format!("leaving {} ({})", channel, reason));
+            }
+            Event::Quit { nick, reason } => {
+                self.record(ctx, nick, &format!("quitting ({})", reason));
+            }
+            Event::Away { nick, message } => {
+                self.record(ctx, nick, &format!("going away ({})", message));
+            }
+            Event::Back { nick } => {
+                self.record(ctx, nick, "coming back");
+            }
+            Event::Online { nick } => {
+                self.record(ctx, nick, "connecting");
+            }
+            Event::Offline { nick } => {
+                self.record(ctx, nick, "disconnecting");
+            }
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::format_duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(3600 + 120 + 5), "1h 2m");
+        assert_eq!(format_duration(86400 + 5), "1d");
+        assert_eq!(format_duration(2 * 86400 + 3 * 3600), "2d 3h");
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::time::Instant;
+
+use crate::irc::client::{
+    native::{BotPlugin, Command, Context, PrivMsg},
+    stats::Usage,
+};
+
+// how many of the busiest channels and features to list.
+const TOP: usize = 3;
+
+fn summarize(usage: &[Usage]) -> String {
+    if usage.is_empty() {
+        return "nothing".to_owned();
+    }
+    usage
+        .iter()
+        .take(TOP)
+        .map(|u| format!("{} {} lines/{} bytes", u.name, u.lines, u.bytes))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Reports which channels and features used the most of our send budget lately.
+pub struct Stats;
+
+impl BotPlugin for Stats {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["stats"]
+    }
+
+    fn help(&self, _cmd: &str) -> &str {
+        "which channels and features we talked the most in lately."
+    }
+
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, _cmd: &Command) -> Vec<String> {
+        let now = Instant::now();
+        let sent = &ctx.state.sent;
+        let mut answer = format!(
+            "sent in the last minute; by channel: {}; by feature: {}",
+            summarize(&sent.by_target(now)),
+            summarize(&sent.by_source(now)),
+        );
+        if sent.dropped_lines > 0 {
+            answer.push_str(&format!(
+                "; dropped {} lines/{} bytes to keep up",
+                sent.dropped_lines, sent.dropped_bytes
+            ));
+        }
+        vec![msg.answer(&answer)]
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use crate::irc::client::{
+    helpers::irc_uppercase,
+    native::{BotPlugin, Command, Context, Event, PrivMsg},
+};
+
+use super::seen::{format_duration, now};
+
+const TELL_NS: &str = "tell";
+// memos waiting for one user, so .tell can't be used to fill the database.
+const MAX_MEMOS: usize = 5;
+
+/// Leaves memos for users, delivered the next time they speak, join or come back
+/// from being away.
+/// Memos are stored one per line as `"<unix time>\t<from nick>\t<message>"`.
+pub struct Tell;
+
+impl Tell {
+    fn key(&self, ctx: &Context, nick: &str) -> String {
+        String::from_utf8_lossy(&irc_uppercase(&ctx.state.casemapping, nick.as_bytes())).to_string()
+    }
+
+    /// Remove and format the memos waiting for nick.
+    fn take_memos(&self, ctx: &Context, nick: &str) -> Vec<String> {
+        let key = self.key(ctx, nick);
+        let memos = match ctx.storage.get(TELL_NS, &key) {
+            Ok(Some(memos)) => memos,
+            Ok(None) => return vec![],
+            Err(e) => {
+                warn!("Could not look up memos for {:?}: {}", nick, e);
+                return vec![];
+            }
+        };
+        if let Err(e) = ctx.storage.delete(TELL_NS, &key) {
+            // better to not deliver than to deliver the same memos over and over.
+            warn!("Could not remove memos for {:?}: {}", nick, e);
+            return vec![];
+        }
+
+        memos
+            .lines()
+            .filter_map(|memo| {
+                let mut fields = memo.splitn(3, '\t');
+                let (when, from, text) = (fields.next()?, fields.next()?, fields.next()?);
+                let ago = now().saturating_sub(when.parse().ok()?);
+                Some(format!(
+                    "{}: {} said {} ago: {}",
+                    nick,
+                    from,
+                    format_duration(ago),
+                    text
+                ))
+            })
+            .collect()
+    }
+}
+
+impl BotPlugin for Tell {
+    fn name(&self) -> &str {
+        "tell"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["tell"]
+    }
+
+    fn help(&self, _cmd: &str) -> &str {
+        "leave a memo for a nick, delivered when they next speak, join or are back from away."
+    }
+
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+        let (nick, text) = match cmd.args.trim().split_once(char::is_whitespace) {
+            Some((nick, text)) if !text.trim().is_empty() => (nick, text.trim()),
+            _ => return vec![msg.answer("usage: tell <nick> <message>")],
+        };
+
+        let key = self.key(ctx, nick);
+        let mut memos = match ctx.storage.get(TELL_NS, &key) {
+            Ok(memos) => memos.unwrap_or_default(),
+            Err(e) => {
+                warn!("Could not look up memos for {:?}: {}", nick, e);
+                return vec![];
+            }
+        };
+        if memos.lines().count() >= MAX_MEMOS {
+            return vec![msg.answer(&format!("{} has too many memos waiting already.", nick))];
+        }
+
+        // memos are newline delimited, and IRC lines can't contain one anyway.
+        memos.push_str(&format!("{}\t{}\t{}\n", now(), msg.nick, text));
+        match ctx.storage.set(TELL_NS, &key, &memos) {
+            Ok(()) => vec![msg.answer(&format!("I'll tell {} when I see them.", nick))],
+            Err(e) => {
+                warn!("Could not store memo for {:?}: {}", nick, e);
+                vec![]
+            }
+        }
+    }
+
+    fn privmsg(&mut self, ctx: &mut Context, msg: &PrivMsg) -> Vec<String> {
+        self.take_memos(ctx, &msg.nick)
+            .iter()
+            .map(|memo| msg.reply(memo))
+            .collect()
+    }
+
+    fn event(&mut self, ctx: &mut Context, ev: &Event) -> Vec<String> {
+        match ev {
+            // they'd miss it, wait until they're back.
+            Event::Join { nick, .. } if ctx.state.is_away(nick) => vec![],
+            // don't interrupt the channel, tell them privately.
+            Event::Join { nick, .. } | Event::Back { nick } => self
+                .take_memos(ctx, nick)
+                .iter()
+                .map(|memo| format!("NOTICE {} :{}", nick, memo))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use crate::irc::client::native::{BotPlugin, Command, Context, PrivMsg};
+
+use super::seen::{format_duration, now};
+
+/// Tells the channel its topic, or sets it for ops and admins.
+pub struct Topic;
+
+impl Topic {
+    fn describe(&self, ctx: &Context, channel: &str) -> String {
+        let topic = match ctx.state.topic(channel) {
+            Some(topic) => topic,
+            None => return format!("{} has no topic.", channel),
+        };
+        match (topic.setter.is_empty(), topic.set_at) {
+            (true, _) => format!("Topic: {}", topic.text),
+            (false, 0) => format!("Topic: {} (set by {})", topic.text, topic.setter),
+            (false, set_at) => format!(
+                "Topic: {} (set by {}, {} ago)",
+                topic.text,
+                topic.setter,
+                format_duration(now().saturating_sub(set_at))
+            ),
+        }
+    }
+}
+
+impl BotPlugin for Topic {
+    fn name(&self) -> &str {
+        "topic"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["topic"]
+    }
+
+    fn help(&self, _cmd: &str) -> &str {
+        "show the channel topic; ops and admins can set it with topic <text>."
+    }
+
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+        if msg.private {
+            return vec![msg.answer("usage: topic [text], in a channel")];
+        }
+
+        let text = cmd.args.trim();
+        if text.is_empty() {
+            vec![msg.answer(&self.describe(ctx, &msg.target))]
+        } else if msg.admin || ctx.state.is_op(&msg.target, msg.sender()) {
+            vec![format!("TOPIC {} :{}", msg.target, text)]
+        } else {
+            vec![msg.answer("only channel operators can set the topic.")]
+        }
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+use std::time::Instant;
+
+use crate::irc::client::{
+    native::{BotPlugin, Command, Context, PrivMsg},
+    stats::PluginUsage,
+};
+
+use super::seen::format_duration;
+
+// how many of the busiest plugins to list.
+const TOP: usize = 3;
+
+/// The first few plugins of a ranking, e.g. "fact 1.2s/run, weather 0.3s/run".
+fn top(ranked: Vec<(&str, &PluginUsage)>, show: impl Fn(&PluginUsage) -> String) -> String {
+    if ranked.is_empty() {
+        return "none".to_owned();
+    }
+    ranked
+        .into_iter()
+        .take(TOP)
+        .map(|(name, usage)| format!("{} {}", name, show(usage)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Answers how long we have been up and connected, and for admins, what went over
+/// the connection and which plugins ran the most.
+pub struct Uptime;
+
+impl BotPlugin for Uptime {
+    fn name(&self) -> &str {
+        "uptime"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["uptime", "status"]
+    }
+
+    fn help(&self, cmd: &str) -> &str {
+        match cmd {
+            "status" => "how long we have been up, with traffic and plugin totals for admins.",
+            _ => "how long we have been up and connected.",
+        }
+    }
+
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+        let now = Instant::now();
+        let conn = &ctx.state.conn;
+        let mut answer = format!(
+            "up {}",
+            format_duration(now.duration_since(conn.started).as_secs())
+        );
+        match &conn.connected {
+            Some((at, server)) => answer.push_str(&format!(
+                ", connected to {} for {}",
+                server,
+                format_duration(now.duration_since(*at).as_secs())
+            )),
+            None => answer.push_str(", not registered yet"),
+        }
+        if conn.reconnects > 0 {
+            answer.push_str(&format!(", {} reconnects", conn.reconnects));
+        }
+        if cmd.name == "status" && msg.admin {
+            let busiest = conn
+                .busiest()
+                .iter()
+                .take(TOP)
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect::<Vec<String>>();
+            answer.push_str(&format!(
+                "; in {} lines/{} bytes; out {} lines/{} bytes; plugins run: {}",
+                conn.lines_in,
+                conn.bytes_in,
+                conn.lines_out,
+                conn.bytes_out,
+                if busiest.is_empty() {
+                    "none".to_owned()
+                } else {
+                    busiest.join(", ")
+                }
+            ));
+
+            // the external plugins worth a look.
+            let runs = &ctx.state.plugin_runs;
+            let slowest = top(runs.slowest(), |usage| {
+                format!("{:.1}s/run", usage.average().as_secs_f64())
+            });
+            let loudest = top(runs.loudest(), |usage| format!("{} bytes", usage.bytes));
+            let failing = top(runs.failing(), |usage| {
+                format!("{}/{} runs", usage.failed, usage.runs)
+            });
+            answer.push_str(&format!(
+                "; slowest: {}; loudest: {}; failing: {}",
+                slowest, loudest, failing
+            ));
+        }
+        vec![msg.answer(&answer)]
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    io::{self, Read},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use regex::Regex;
+
+use crate::{
+    config::config_file::Verbosity,
+    irc::client::{
+        helpers::case_cmp,
+        native::{BotPlugin, Command, Context, Deferred, PrivMsg},
+    },
+};
+
+// links looked up per message.
+const MAX_URLS: usize = 2;
+// the title is usually in the head, don't download whole pages.
+const MAX_BYTES: u64 = 64 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+// threads fetching pages, and links that may wait for one before we drop them.
+const WORKERS: usize = 2;
+const QUEUE: usize = 8;
+
+// the url, where its title goes and how long it may be.
+type Job = (String, String, Verbosity, Deferred);
+
+/// If an address is on the internet, rather than e.g. loopback or a private network
+/// the bot's host can reach but whoever posted the link shouldn't.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // 0.0.0.0/8 and shared address space, 100.64.0.0/10.
+                || a == 0
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7, and link local, fe80::/10.
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Look up netloc, refusing it if any of its addresses isn't public.
+/// Every connection, redirects included, is resolved through this.
+fn resolve_public(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs = netloc.to_socket_addrs()?.collect::<Vec<SocketAddr>>();
+    match addrs.iter().find(|addr| !is_public(addr.ip())) {
+        Some(addr) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a public address", addr.ip()),
+        )),
+        None => Ok(addrs),
+    }
+}
+
+/// Find http(s) links in a message.
+fn find_urls(text: &str) -> Vec<&str> {
+    text.split_whitespace()
+        .map(|word| {
+            word.trim_start_matches(['<', '('])
+                .trim_end_matches(['>', ')', ',', '.'])
+        })
+        .filter(|word| {
+            let lower = word.to_ascii_lowercase();
+            lower.starts_with("http://") || lower.starts_with("https://")
+        })
+        .take(MAX_URLS)
+        .collect()
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let num = entity.strip_prefix('#')?;
+            let code = match num.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => num.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Pull the `<title>` out of a page, decoding entities and collapsing whitespace.
+fn extract_title(title_re: &Regex, html: &str) -> Option<String> {
+    let raw = title_re.captures(html)?.get(1)?.as_str();
+    let mut title = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(amp) = rest.find('&') {
+        title.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        match rest
+            .find(';')
+            .and_then(|semi| Some((semi, decode_entity(&rest[1..semi])?)))
+        {
+            Some((semi, chr)) => {
+                title.push(chr);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                title.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    title.push_str(rest);
+
+    let title = title.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+fn fetch_title(title_re: &Regex, url: &str) -> Result<Option<String>, String> {
+    let resp = ureq::AgentBuilder::new()
+        .timeout(TIMEOUT)
+        .resolver(resolve_public)
+        .build()
+        .get(url)
+        .call()
+        .map_err(|e| e.to_string())?;
+    if !resp.content_type().contains("html") {
+        return Ok(None);
+    }
+    let mut body = vec![];
+    resp.into_reader()
+        .take(MAX_BYTES)
+        .read_to_end(&mut body)
+        .map_err(|e| e.to_string())?;
+    Ok(extract_title(title_re, &String::from_utf8_lossy(&body)))
+}
+
+fn fetch_jobs(title_re: Regex, jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        let (url, reply_to, verbosity, deferred) = match job {
+            Ok(job) => job,
+            // the plugin is gone.
+            Err(_) => return,
+        };
+        match fetch_title(&title_re, &url) {
+            Ok(Some(title)) => deferred.send(
+                "urltitle",
+                vec![format!(
+                    "PRIVMSG {} :Title: {}",
+                    reply_to,
+                    verbosity.truncate(&title)
+                )],
+            ),
+            Ok(None) => (),
+            Err(e) => info!("Could not fetch title of {:?}: {}", url, e),
+        }
+    }
+}
+
+/// Announces the titles of links posted in channels which enable url_titles.
+/// Pages are fetched by a few threads and the title is sent when it arrives;
+/// links posted while they are all busy and the queue is full are skipped.
+pub struct UrlTitle {
+    title_re: Regex,
+    // started with the first link.
+    jobs: Option<SyncSender<Job>>,
+}
+
+impl Default for UrlTitle {
+    fn default() -> Self {
+        UrlTitle::new()
+    }
+}
+
+impl UrlTitle {
+    pub fn new() -> Self {
+        UrlTitle {
+            title_re: Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap(),
+            jobs: None,
+        }
+    }
+
+    fn jobs(&mut self) -> &SyncSender<Job> {
+        let title_re = &self.title_re;
+        self.jobs.get_or_insert_with(|| {
+            let (send, recv) = mpsc::sync_channel(QUEUE);
+            let recv = Arc::new(Mutex::new(recv));
+            for _ in 0..WORKERS {
+                let (title_re, recv) = (title_re.clone(), recv.clone());
+                thread::spawn(move || fetch_jobs(title_re, recv));
+            }
+            send
+        })
+    }
+}
+
+impl BotPlugin for UrlTitle {
+    fn name(&self) -> &str {
+        "urltitle"
+    }
+
+    fn commands(&self) -> &[&str] {
+        &[]
+    }
+
+    fn command(&mut self, _ctx: &mut Context, _msg: &PrivMsg, _cmd: &Command) -> Vec<String> {
+        vec![]
+    }
+
+    fn privmsg(&mut self, ctx: &mut Context, msg: &PrivMsg) -> Vec<String> {
+        let enabled = !msg.private
+            && ctx.channels.iter().any(|(name, conf)| {
+                conf.url_titles
+                    && case_cmp(
+                        &ctx.state.casemapping,
+                        name.as_bytes(),
+                        msg.target.as_bytes(),
+                    )
+            });
+        if !enabled {
+            return vec![];
+        }
+
+        let verbosity = ctx.verbosity(&msg.target);
+        for url in find_urls(&msg.text) {
+            let job = (
+                url.to_owned(),
+                msg.reply_to.clone(),
+                verbosity,
+                ctx.deferred.clone(),
+            );
+            match self.jobs().try_send(job) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => info!("Too many titles pending, skipping {:?}.", url),
+                Err(TrySendError::Disconnected(_)) => warn!("The title fetchers exited."),
+            }
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{extract_title, find_urls, is_public, resolve_public, UrlTitle};
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            find_urls("see (https://example.com/a) and <HTTP://x.org>, ftp://no"),
+            vec!["https://example.com/a", "HTTP://x.org"]
+        );
+        assert_eq!(find_urls("a http://1 http://2 http://3").len(), 2);
+    }
+
+    #[test]
+    fn titles() {
+        let re = UrlTitle::new().title_re;
+        assert_eq!(
+            extract_title(
+                &re,
+                "<html><TITLE lang=en>\n  Tom &amp; Jerry &#8212; &#x41;&bogus;\n</TITLE>"
+            ),
+            Some("Tom & Jerry — A&bogus;".to_owned())
+        );
+        assert_eq!(extract_title(&re, "<title>  </title>"), None);
+        assert_eq!(extract_title(&re, "<p>no title</p>"), None);
+    }
+
+    #[test]
+    fn private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(resolve_public("localhost:80").is_err());
+        assert!(resolve_public("[::1]:80").is_err());
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! Every plugin process, reaped once SIGCHLD says one exited instead of by a thread
+//! blocking on each. Children past their timeout are killed by the same pass, see
+//! reap(). SIGCHLD is a pipe the handler writes to, like SIGHUP in [`super::hangup`].
+//! A plugin leads its own process group, so killing it kills whatever it started.
+
+use std::{
+    io::{self, Read},
+    os::unix::io::{AsRawFd, RawFd},
+    process::{Child, ExitStatus},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use mio::{event::Source, unix::pipe};
+
+/// The exit status of a child, None while it runs.
+pub type Status = Arc<Mutex<Option<io::Result<ExitStatus>>>>;
+
+struct Tracked {
+    child: Child,
+    // when to kill it.
+    deadline: Option<Instant>,
+    status: Status,
+}
+
+static CHILDREN: Mutex<Vec<Tracked>> = Mutex::new(Vec::new());
+
+// the write end of the latest Reaper's pipe, -1 for none.
+static CHILD_FD: AtomicI32 = AtomicI32::new(-1);
+
+fn children() -> MutexGuard<'static, Vec<Tracked>> {
+    // a panic elsewhere leaves the list as it was.
+    CHILDREN.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn set_status(status: &Status, res: io::Result<ExitStatus>) {
+    *status.lock().unwrap_or_else(|e| e.into_inner()) = Some(res);
+}
+
+// kill the child and its process group, if it leads one, and wait on it.
+fn kill_group(child: &mut Child) -> io::Result<ExitStatus> {
+    // Safety: the child isn't reaped yet, so its pid, and a group by that id, are still its own.
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    child.kill().and_then(|()| child.wait())
+}
+
+/// Own child from now on, killing it once timeout passes, if there is one.
+pub fn track(child: Child, timeout: Option<Duration>) -> Status {
+    let status = Status::default();
+    children().push(Tracked {
+        child,
+        deadline: timeout.map(|timeout| Instant::now() + timeout),
+        status: status.clone(),
+    });
+    status
+}
+
+/// Reap the children that exited and kill the ones past their deadline.
+/// Returns how many are still running.
+pub fn reap(now: Instant) -> usize {
+    children().retain_mut(|tracked| {
+        if tracked.deadline.is_some_and(|at| at <= now) {
+            set_status(&tracked.status, kill_group(&mut tracked.child));
+            return false;
+        }
+        match tracked.child.try_wait() {
+            Ok(Some(status)) => set_status(&tracked.status, Ok(status)),
+            Ok(None) => return true,
+            Err(e) => set_status(&tracked.status, Err(e)),
+        }
+        false
+    });
+    children().len()
+}
+
+/// The next time reap() has a child to kill.
+pub fn next_deadline() -> Option<Instant> {
+    children()
+        .iter()
+        .filter_map(|tracked| tracked.deadline)
+        .min()
+}
+
+/// Kill the child with pid now, if it is still running, e.g. when shutting down.
+pub fn kill(pid: u32) {
+    let mut children = children();
+    if let Some(idx) = children.iter().position(|t| t.child.id() == pid) {
+        let mut tracked = children.swap_remove(idx);
+        set_status(&tracked.status, kill_group(&mut tracked.child));
+    }
+}
+
+extern "C" fn on_child(_: libc::c_int) {
+    let fd = CHILD_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // Safety: write is async-signal-safe. If the pipe is full a reap is pending anyway.
+        unsafe { libc::write(fd, b"!".as_ptr().cast(), 1) };
+    }
+}
+
+/// Readable once a child exited, see received(), and call reap() then.
+/// The SIGCHLD handler it installs is put back as it was once it is dropped.
+pub struct Reaper {
+    receiver: pipe::Receiver,
+    sender: pipe::Sender,
+    old: libc::sigaction,
+}
+
+impl Reaper {
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = pipe::new()?;
+        CHILD_FD.store(sender.as_raw_fd(), Ordering::Relaxed);
+        // Safety: the handler only touches an atomic and write(2).
+        let old = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            let mut old: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_child as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGCHLD, &action, &mut old) != 0 {
+                let e = io::Error::last_os_error();
+                let _ = CHILD_FD.compare_exchange(
+                    sender.as_raw_fd(),
+                    -1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                return Err(e);
+            }
+            old
+        };
+        Ok(Reaper {
+            receiver,
+            sender,
+            old,
+        })
+    }
+
+    /// If a child exited since the last call, however many did in between.
+    pub fn received(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 64];
+        let mut ret = false;
+        loop {
+            match self.receiver.read(&mut buf) {
+                Ok(0) => return Ok(ret),
+                Ok(_) => ret = true,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(ret),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // children are only reaped by timeouts and kill() from here on.
+        let ours = CHILD_FD
+            .compare_exchange(
+                self.sender.as_raw_fd(),
+                -1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        if ours {
+            // Safety: old is what sigaction(2) gave us in new().
+            unsafe { libc::sigaction(libc::SIGCHLD, &self.old, std::ptr::null_mut()) };
+        }
+    }
+}
+
+impl AsRawFd for Reaper {
+    fn as_raw_fd(&self) -> RawFd {
+        self.receiver.as_raw_fd()
+    }
+}
+
+impl Source for Reaper {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        registry.register(&mut self.receiver, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        registry.reregister(&mut self.receiver, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        registry.deregister(&mut self.receiver)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        process::Command,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{kill, reap, track};
+
+    #[test]
+    fn reap_and_kill() {
+        let quick = track(Command::new("true").spawn().unwrap(), None);
+        let slow = track(
+            Command::new("sleep").arg("30").spawn().unwrap(),
+            Some(Duration::from_secs(60)),
+        );
+        let hung = Command::new("sleep").arg("30").spawn().unwrap();
+        let hung_pid = hung.id();
+        let hung = track(hung, None);
+
+        let start = Instant::now();
+        while quick.lock().unwrap().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+            reap(Instant::now());
+        }
+        assert!(quick
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .success());
+        assert!(slow.lock().unwrap().is_none());
+
+        reap(Instant::now() + Duration::from_secs(60));
+        let status = slow.lock().unwrap();
+        assert!(!status.as_ref().unwrap().as_ref().unwrap().success());
+
+        assert!(hung.lock().unwrap().is_none());
+        kill(hung_pid);
+        assert!(hung.lock().unwrap().is_some());
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+use std::collections::HashMap;
+
+// open batches, so a server that never closes them can't grow this forever.
+const MAX_BATCHES: usize = 64;
+
+/// An IRCv3 batch the server opened with BATCH +reference type [params...].
+#[derive(Debug, PartialEq)]
+pub struct Batch {
+    pub kind: String,
+    pub params: Vec<String>,
+    // messages tagged with this batch so far.
+    pub lines: usize,
+}
+
+impl Batch {
+    /// Messages of history playback, which we remember but don't act on.
+    pub fn is_playback(&self) -> bool {
+        self.kind == "chathistory" || self.kind == "znc.in/playback"
+    }
+}
+
+/// Batches that are open, by reference tag.
+#[derive(Default)]
+pub struct Batches {
+    open: HashMap<String, Batch>,
+}
+
+impl Batches {
+    /// Handle the parameters of a BATCH command.
+    /// Returns the batch if this ended one.
+    pub fn command<'a>(&mut self, mut params: impl Iterator<Item = &'a [u8]>) -> Option<Batch> {
+        let lossy = |part: &[u8]| String::from_utf8_lossy(part).to_string();
+        let reference = params.next()?;
+        match reference.first() {
+            Some(b'+') => {
+                if self.open.len() < MAX_BATCHES {
+                    let kind = lossy(params.next()?);
+                    let params = params.map(lossy).collect();
+                    let batch = Batch {
+                        kind,
+                        params,
+                        lines: 0,
+                    };
+                    self.open.insert(lossy(&reference[1..]), batch);
+                }
+                None
+            }
+            Some(b'-') => self.open.remove(&*String::from_utf8_lossy(&reference[1..])),
+            _ => None,
+        }
+    }
+
+    /// Count a message tagged batch=reference towards its batch.
+    pub fn note(&mut self, reference: &[u8]) -> Option<&Batch> {
+        let batch = self.open.get_mut(&*String::from_utf8_lossy(reference))?;
+        batch.lines += 1;
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Batch, Batches};
+
+    fn params(line: &str) -> impl Iterator<Item = &[u8]> {
+        line.split(' ').map(str::as_bytes)
+    }
+
+    #[test]
+    fn batches() {
+        let mut batches = Batches::default();
+        assert_eq!(batches.command(params("+abc chathistory #chan")), None);
+        assert_eq!(
+            batches.command(params("+xyz netsplit a.example b.example")),
+            None
+        );
+        assert!(batches.note(b"abc").unwrap().is_playback());
+        assert!(!batches.note(b"xyz").unwrap().is_playback());
+        batches.note(b"xyz");
+        assert!(batches.note(b"nope").is_none());
+
+        assert_eq!(
+            batches.command(params("-xyz")),
+            Some(Batch {
+                kind: "netsplit".to_owned(),
+                params: vec!["a.example".to_owned(), "b.example".to_owned()],
+                lines: 2,
+            })
+        );
+        assert!(batches.note(b"xyz").is_none());
+        assert!(batches.command(params("-xyz")).is_none());
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use crate::{config::config_file::LogFsync, irc::parse::Message};
+
+use super::{ctcp::parse_ctcp, schedule::civil_from_days};
+
+/// A channel's line for JOIN, PART, KICK, TOPIC and PRIVMSG, without the time,
+/// e.g. `("#chan", "<nick> hello")`.
+pub fn format_event(msg: &Message, chantypes: &[u8]) -> Option<(String, String)> {
+    let lossy = |part: &[u8]| String::from_utf8_lossy(part).to_string();
+    let nick = lossy(msg.nick?);
+    let mut params = msg.parameters();
+    let channel = params
+        .next()
+        .filter(|chan| chan.first().is_some_and(|chr| chantypes.contains(chr)))?;
+    let mask = format!(
+        "{} ({}@{})",
+        nick,
+        lossy(msg.user.unwrap_or_default()),
+        lossy(msg.host.unwrap_or_default())
+    );
+    let line = match msg.command? {
+        b"PRIVMSG" => {
+            let text = lossy(params.next()?);
+            match parse_ctcp(&text) {
+                Some(ctcp) if ctcp.command == "ACTION" => format!("* {} {}", nick, ctcp.args),
+                Some(_) => return None,
+                None => format!("<{}> {}", nick, text),
+            }
+        }
+        b"JOIN" => format!("-!- {} has joined {}", mask, lossy(channel)),
+        b"PART" => match params.next() {
+            Some(reason) if !reason.is_empty() => {
+                format!(
+                    "-!- {} has left {} ({})",
+                    mask,
+                    lossy(channel),
+                    lossy(reason)
+                )
+            }
+            _ => format!("-!- {} has left {}", mask, lossy(channel)),
+        },
+        b"KICK" => format!(
+            "-!- {} was kicked from {} by {} ({})",
+            lossy(params.next()?),
+            lossy(channel),
+            nick,
+            lossy(params.next().unwrap_or_default())
+        ),
+        b"TOPIC" => format!(
+            "-!- {} changed the topic of {} to: {}",
+            nick,
+            lossy(channel),
+            lossy(params.next().unwrap_or_default())
+        ),
+        _ => return None,
+    };
+    Some((lossy(channel), line))
+}
+
+/// Plaintext logs of channels, a file a day (in UTC) each, e.g. logs/#chan/2021-06-01.log.
+pub struct ChannelLog {
+    dir: PathBuf,
+    fsync: LogFsync,
+    // open files by lowercased channel, with the day since the epoch they are for.
+    files: HashMap<String, (i64, File)>,
+}
+
+impl ChannelLog {
+    pub fn new(dir: &str, fsync: LogFsync) -> Self {
+        ChannelLog {
+            dir: PathBuf::from(dir),
+            fsync,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Append a line to the channel's log, for at seconds since the epoch.
+    pub fn write(&mut self, channel: &str, at: u64, line: &str) -> io::Result<()> {
+        let day = (at / 86400) as i64;
+        let secs = at % 86400;
+        let key = channel.to_lowercase();
+        let fresh = !matches!(self.files.get(&key), Some((open, _)) if *open == day);
+        if fresh {
+            // the day is over, or we have yet to write here.
+            if let Some((_, old)) = self.files.remove(&key) {
+                self.close(old);
+            }
+            let dir = self.dir.join(key.replace(['/', '\0'], "_"));
+            fs::create_dir_all(&dir)?;
+            let (year, month, mday) = civil_from_days(day);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(format!("{:04}-{:02}-{:02}.log", year, month, mday)))?;
+            self.files.insert(key.clone(), (day, file));
+        }
+        let (_, file) = self.files.get_mut(&key).expect("opened above");
+        writeln!(
+            file,
+            "[{:02}:{:02}:{:02}] {}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            line
+        )?;
+        if self.fsync == LogFsync::Always {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn close(&self, file: File) {
+        if self.fsync == LogFsync::Rotate {
+            if let Err(e) = file.sync_all() {
+                warn!("Could not sync a channel log: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for ChannelLog {
+    fn drop(&mut self) {
+        for (_, (_, file)) in std::mem::take(&mut self.files) {
+            self.close(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{config::config_file::LogFsync, irc::parse::Message};
+
+    use super::{format_event, ChannelLog};
+
+    #[test]
+    fn channel_events() {
+        let event = |line: &[u8]| format_event(&Message::new(line), b"#&");
+        assert_eq!(
+            event(b":nick!user@host PRIVMSG #chan :hello"),
+            Some(("#chan".to_owned(), "<nick> hello".to_owned()))
+        );
+        assert_eq!(
+            event(b":nick!user@host PRIVMSG #chan :\x01ACTION waves\x01"),
+            Some(("#chan".to_owned(), "* nick waves".to_owned()))
+        );
+        assert_eq!(
+            event(b":nick!user@host JOIN #chan").unwrap().1,
+            "-!- nick (user@host) has joined #chan"
+        );
+        assert_eq!(
+            event(b":nick!user@host PART #chan :bye").unwrap().1,
+            "-!- nick (user@host) has left #chan (bye)"
+        );
+        assert_eq!(
+            event(b":op!user@host KICK #chan nick :out").unwrap().1,
+            "-!- nick was kicked from #chan by op (out)"
+        );
+        assert_eq!(
+            event(b":op!user@host TOPIC #chan :news").unwrap().1,
+            "-!- op changed the topic of #chan to: news"
+        );
+        assert_eq!(event(b":nick!user@host PRIVMSG bot :psst"), None);
+        assert_eq!(
+            event(b":nick!user@host PRIVMSG #chan :\x01VERSION\x01"),
+            None
+        );
+    }
+
+    #[test]
+    fn daily_rotation() {
+        let dir = std::env::temp_dir().join(format!("r8ball-logs-{}", std::process::id()));
+        let mut log = ChannelLog::new(dir.to_str().unwrap(), LogFsync::Always);
+        // 2021-06-01 23:59:59 and a second later.
+        log.write("#Chan", 1622591999, "<a> late").unwrap();
+        log.write("#chan", 1622592000, "<b> early").unwrap();
+        drop(log);
+        assert_eq!(
+            fs::read_to_string(dir.join("#chan/2021-06-01.log")).unwrap(),
+            "[23:59:59] <a> late\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("#chan/2021-06-02.log")).unwrap(),
+            "[00:00:00] <b> early\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
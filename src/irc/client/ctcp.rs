@@ -0,0 +1,125 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use super::schedule::civil_from_days;
+
+// what CLIENTINFO reports.
+const SUPPORTED: &str = "ACTION CLIENTINFO PING SOURCE TIME VERSION";
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A CTCP message, "\x01COMMAND args\x01".
+#[derive(Debug, PartialEq)]
+pub struct Ctcp<'a> {
+    pub command: &'a str,
+    pub args: &'a str,
+}
+
+/// Parse a CTCP message; some clients leave off the closing \x01.
+pub fn parse_ctcp(text: &str) -> Option<Ctcp<'_>> {
+    let inner = text.strip_prefix('\x01')?;
+    let inner = inner.strip_suffix('\x01').unwrap_or(inner);
+    let (command, args) = inner.split_once(' ').unwrap_or((inner, ""));
+    if command.is_empty() {
+        None
+    } else {
+        Some(Ctcp { command, args })
+    }
+}
+
+/// Seconds since the epoch as an RFC 2822 date in UTC.
+pub fn rfc2822(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = secs % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        // the epoch was a Thursday.
+        WEEKDAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+    )
+}
+
+/// The NOTICE answering a query from nick, None for ones we don't answer (e.g. ACTION).
+pub fn answer(query: &Ctcp, nick: &str, version: &str, now: u64) -> Option<String> {
+    let args = match query.command {
+        "VERSION" => version.to_owned(),
+        "PING" => query.args.to_owned(),
+        "TIME" => rfc2822(now),
+        "CLIENTINFO" => SUPPORTED.to_owned(),
+        "SOURCE" => env!("CARGO_PKG_REPOSITORY").to_owned(),
+        _ => return None,
+    };
+    if args.is_empty() {
+        Some(format!("NOTICE {} :\x01{}\x01", nick, query.command))
+    } else {
+        Some(format!(
+            "NOTICE {} :\x01{} {}\x01",
+            nick, query.command, args
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{answer, parse_ctcp, rfc2822, Ctcp};
+
+    #[test]
+    fn ctcp_parsing() {
+        assert_eq!(
+            parse_ctcp("\x01ACTION waves hello\x01"),
+            Some(Ctcp {
+                command: "ACTION",
+                args: "waves hello"
+            })
+        );
+        assert_eq!(
+            parse_ctcp("\x01PING 123"),
+            Some(Ctcp {
+                command: "PING",
+                args: "123"
+            })
+        );
+        assert_eq!(parse_ctcp("\x01\x01"), None);
+        assert_eq!(parse_ctcp("hi"), None);
+    }
+
+    #[test]
+    fn ctcp_answers() {
+        assert_eq!(rfc2822(1_600_000_000), "Sun, 13 Sep 2020 12:26:40 +0000");
+        let ping = parse_ctcp("\x01PING 123\x01").unwrap();
+        assert_eq!(
+            answer(&ping, "nick", "", 0).unwrap(),
+            "NOTICE nick :\x01PING 123\x01"
+        );
+        let version = parse_ctcp("\x01VERSION\x01").unwrap();
+        assert_eq!(
+            answer(&version, "nick", "bot 1.0", 0).unwrap(),
+            "NOTICE nick :\x01VERSION bot 1.0\x01"
+        );
+        assert!(answer(&parse_ctcp("\x01ACTION hi\x01").unwrap(), "nick", "", 0).is_none());
+    }
+}
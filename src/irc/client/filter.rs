@@ -0,0 +1,152 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::borrow::Cow;
+
+use regex::Regex;
+
+use crate::config::config_file::{Filter, FilterAction};
+
+/// Keeps the words and patterns of `[filter]` out of what we say, so a misbehaving
+/// plugin can't say them in our name.
+#[derive(Default)]
+pub struct WordFilter {
+    patterns: Vec<Regex>,
+    action: FilterAction,
+}
+
+/// A pattern matching word as a whole. An end that is a word character may not have
+/// another next to it, one that isn't, like the +s of "c++", may not have a word
+/// character next to it.
+fn whole_word(word: &str) -> String {
+    let edge = |chr: Option<char>| match chr {
+        Some(chr) if chr.is_alphanumeric() || chr == '_' => r"\b",
+        _ => r"\B",
+    };
+    format!(
+        "{}{}{}",
+        edge(word.chars().next()),
+        regex::escape(word),
+        edge(word.chars().last())
+    )
+}
+
+impl WordFilter {
+    /// Patterns which don't compile are logged and left out; Config already refuses
+    /// a `[filter]` with those.
+    pub fn new(conf: &Filter) -> Self {
+        let mut sources = conf.regexes.clone();
+        let words = conf
+            .words
+            .iter()
+            .filter(|word| !word.is_empty())
+            .map(|word| whole_word(word))
+            .collect::<Vec<String>>();
+        if !words.is_empty() {
+            sources.push(format!("(?i)(?:{})", words.join("|")));
+        }
+        let patterns = sources
+            .iter()
+            .filter_map(|source| match Regex::new(source) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    warn!("Skipping filter {:?}: {}", source, e);
+                    None
+                }
+            })
+            .collect();
+        WordFilter {
+            patterns,
+            action: conf.action,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// What to say instead of text, None to say nothing.
+    pub fn apply<'a>(&self, text: &'a str) -> Option<Cow<'a, str>> {
+        let mut ret = Cow::Borrowed(text);
+        for re in &self.patterns {
+            if !re.is_match(&ret) {
+                continue;
+            }
+            if self.action == FilterAction::Drop {
+                return None;
+            }
+            let masked = re
+                .replace_all(&ret, |caps: &regex::Captures| {
+                    "*".repeat(caps[0].chars().count())
+                })
+                .into_owned();
+            ret = Cow::Owned(masked);
+        }
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{borrow::Cow, str::FromStr};
+
+    use crate::config::config_file::{Config, ConfigError, Filter, FilterAction};
+
+    use super::WordFilter;
+
+    #[test]
+    fn mask_and_drop() {
+        let mut conf = Filter {
+            words: vec!["darn".to_owned(), "a.b".to_owned(), "c++".to_owned()],
+            regexes: vec![
+                r"https?://bad\.example\S*".to_owned(),
+                "(unclosed".to_owned(),
+            ],
+            action: FilterAction::Mask,
+        };
+        let filter = WordFilter::new(&conf);
+        assert_eq!(filter.apply("all good"), Some(Cow::Borrowed("all good")));
+        assert_eq!(
+            filter.apply("Darn it, see http://bad.example/x"),
+            Some(Cow::Owned("**** it, see ********************".to_owned()))
+        );
+        // whole words only, and the words are not patterns.
+        assert_eq!(
+            filter.apply("darned axb"),
+            Some(Cow::Borrowed("darned axb"))
+        );
+        // words that start or end with punctuation, too.
+        assert_eq!(
+            filter.apply("I like c++, not c++x"),
+            Some(Cow::Owned("I like ***, not c++x".to_owned()))
+        );
+
+        conf.action = FilterAction::Drop;
+        let filter = WordFilter::new(&conf);
+        assert_eq!(filter.apply("oh darn"), None);
+        assert!(filter.apply("fine").is_some());
+        assert!(WordFilter::new(&Filter::default()).is_empty());
+
+        let bad = "[general]\nnick = \"bot\"\nserver = \"irc.one\"\n[filter]\nregexes = [\"(unclosed\"]\n";
+        assert!(matches!(
+            Config::from_str(bad),
+            Err(ConfigError::Filter(..))
+        ));
+    }
+}
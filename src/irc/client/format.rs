@@ -0,0 +1,148 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+/// mIRC formatting codes; each but COLOR and RESET toggles its style.
+pub const BOLD: char = '\x02';
+pub const COLOR: char = '\x03';
+pub const MONOSPACE: char = '\x11';
+pub const REVERSE: char = '\x16';
+pub const ITALIC: char = '\x1d';
+pub const STRIKETHROUGH: char = '\x1e';
+pub const UNDERLINE: char = '\x1f';
+pub const RESET: char = '\x0f';
+
+/// The 16 colors every client agrees on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    White = 0,
+    Black,
+    Blue,
+    Green,
+    Red,
+    Brown,
+    Purple,
+    Orange,
+    Yellow,
+    LightGreen,
+    Cyan,
+    LightCyan,
+    LightBlue,
+    Pink,
+    Grey,
+    LightGrey,
+}
+
+fn wrap(code: char, text: &str) -> String {
+    format!("{}{}{}", code, text, code)
+}
+
+pub fn bold(text: &str) -> String {
+    wrap(BOLD, text)
+}
+
+pub fn italic(text: &str) -> String {
+    wrap(ITALIC, text)
+}
+
+pub fn underline(text: &str) -> String {
+    wrap(UNDERLINE, text)
+}
+
+/// Text in a color. The code always has two digits, so text starting with one
+/// isn't taken for part of it.
+pub fn color(text: &str, fg: Color) -> String {
+    format!("{}{:02}{}{}", COLOR, fg as u8, text, COLOR)
+}
+
+/// Text in a color on a background color.
+pub fn color_on(text: &str, fg: Color, bg: Color) -> String {
+    format!("{}{:02},{:02}{}{}", COLOR, fg as u8, bg as u8, text, COLOR)
+}
+
+/// If text has any formatting codes.
+pub fn is_formatted(text: &str) -> bool {
+    text.contains([
+        BOLD,
+        COLOR,
+        MONOSPACE,
+        REVERSE,
+        ITALIC,
+        STRIKETHROUGH,
+        UNDERLINE,
+        RESET,
+    ])
+}
+
+/// Strip mIRC bold, color, italic, underline, reverse and reset codes, e.g. for
+/// channels which are +c.
+pub fn strip_formatting(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(chr) = chars.next() {
+        match chr {
+            BOLD | MONOSPACE | REVERSE | ITALIC | STRIKETHROUGH | UNDERLINE | RESET => (),
+            // color, \x03[fg[,bg]] where each color is up to 2 digits.
+            COLOR => {
+                let fg = chars.next_if(char::is_ascii_digit).is_some();
+                chars.next_if(char::is_ascii_digit);
+                // a comma without a background after it is text.
+                let mut ahead = chars.clone();
+                if fg && ahead.next() == Some(',') && ahead.peek().is_some_and(char::is_ascii_digit)
+                {
+                    chars.next();
+                    chars.next();
+                    chars.next_if(char::is_ascii_digit);
+                }
+            }
+            _ => ret.push(chr),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bold, color, color_on, is_formatted, italic, strip_formatting, Color};
+
+    #[test]
+    fn format_and_strip() {
+        let text = format!(
+            "{} {} {} {}",
+            bold("bold"),
+            italic("it"),
+            color("1st", Color::Red),
+            color_on("on", Color::White, Color::Blue)
+        );
+        assert_eq!(
+            text,
+            "\x02bold\x02 \x1dit\x1d \x03041st\x03 \x0300,02on\x03"
+        );
+        assert!(is_formatted(&text));
+        assert_eq!(strip_formatting(&text), "bold it 1st on");
+        assert!(!is_formatted("plain"));
+
+        assert_eq!(
+            strip_formatting("\x0304,12red\x03 \x02bold\x02\x0f"),
+            "red bold"
+        );
+        // commas that aren't part of the code stay.
+        assert_eq!(strip_formatting("\x034,you"), ",you");
+        assert_eq!(strip_formatting("\x03,5"), ",5");
+    }
+}
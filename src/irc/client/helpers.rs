@@ -17,115 +17,597 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-use crate::irc::{client::CaseMapping, parse::Message};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+};
 
-macro_rules! hashmap {
-    // map-like
-    ($($k:expr => $v:expr),* $(,)?) => {
-        std::iter::Iterator::collect(std::array::IntoIter::new([$(($k, $v),)*]))
-    };
+use crate::{
+    config::config_file::{ChannelConfig, Verbosity},
+    irc::{
+        client::{
+            format::strip_formatting, native::Command, schedule::days_from_civil, CaseMapping,
+        },
+        parse::Message,
+    },
+};
+
+/// Split a channel entry like "#secret key123" into the channel and its key.
+pub fn split_key(entry: &str) -> (&str, Option<&str>) {
+    let mut parts = entry.split_whitespace();
+    (parts.next().unwrap_or_default(), parts.next())
 }
 
-fn join_part_channels(command: &[u8], channels: &Vec<String>) -> Vec<u8> {
-    let mut ret = vec![];
-    let mut lsize = ret.len();
-    let mut first = true;
-
-    for channel in channels {
-        if channel.len() + lsize >= 510 {
-            lsize = 0usize;
-            first = true;
-            ret.extend(b"\r\n");
+/// Build JOIN/PART lines (without CRLF) that fit within the 512 byte limit.
+/// Channels may be given with keys, e.g. "#secret key123"; for JOIN, keyed channels
+/// go first in each line so the key list lines up, e.g. "JOIN #secret,#open key123".
+/// A batch_size above 0 also limits how many channels go in each line.
+fn join_part_lines(command: &[u8], channels: &[String], batch_size: usize) -> Vec<Vec<u8>> {
+    let with_keys = command == b"JOIN";
+    let mut entries = channels
+        .iter()
+        .map(|entry| split_key(entry))
+        .filter(|(chan, _)| !chan.is_empty())
+        .map(|(chan, key)| (chan, key.filter(|_| with_keys)))
+        .collect::<Vec<(&str, Option<&str>)>>();
+    // stable, so channels keep their order otherwise.
+    entries.sort_by_key(|(_, key)| key.is_none());
+
+    // (channels, keys) for each line.
+    let mut batches: Vec<(Vec<u8>, Vec<u8>, usize)> = vec![];
+    for (chan, key) in entries {
+        let key_len = key.map_or(0, |key| key.len() + 1);
+        let full = match batches.last() {
+            Some((chans, keys, count)) => {
+                command.len() + 2 + chans.len() + chan.len() + keys.len() + key_len > 510
+                    || (batch_size != 0 && *count == batch_size)
+            }
+            None => true,
+        };
+        if full {
+            batches.push((vec![], vec![], 0));
         }
 
-        if !first {
-            ret.push(b',');
-        } else {
-            ret.extend(command);
-            ret.push(b' ');
-            lsize = command.len();
-            first = false;
+        let (chans, keys, count) = batches.last_mut().expect("a batch was just pushed");
+        if *count != 0 {
+            chans.push(b',');
+        }
+        chans.extend(chan.as_bytes());
+        if let Some(key) = key {
+            if !keys.is_empty() {
+                keys.push(b',');
+            }
+            keys.extend(key.as_bytes());
         }
-        ret.extend(channel.as_bytes());
-        lsize += channel.len() + 1;
+        *count += 1;
     }
-    ret.extend(b"\r\n");
 
+    batches
+        .into_iter()
+        .map(|(chans, keys, _)| {
+            let mut line = command.to_vec();
+            line.push(b' ');
+            line.extend(chans);
+            if !keys.is_empty() {
+                line.push(b' ');
+                line.extend(keys);
+            }
+            line
+        })
+        .collect()
+}
+
+fn join_part_channels(command: &[u8], channels: &[String]) -> Vec<u8> {
+    let mut ret = vec![];
+    for line in join_part_lines(command, channels, 0) {
+        ret.extend(line);
+        ret.extend(b"\r\n");
+    }
     ret
 }
 
-pub fn join_channels(channels: &Vec<String>) -> Vec<u8> {
+pub fn join_channels(channels: &[String]) -> Vec<u8> {
     join_part_channels(b"JOIN", channels)
 }
 
-pub fn part_channels(channels: &Vec<String>) -> Vec<u8> {
+/// JOIN lines of at most batch_size channels each, for pacing large joins.
+pub fn join_batches(channels: &[String], batch_size: usize) -> Vec<Vec<u8>> {
+    join_part_lines(b"JOIN", channels, batch_size)
+}
+
+pub fn part_channels(channels: &[String]) -> Vec<u8> {
     join_part_channels(b"PART", channels)
 }
 
+/// One byte, uppercased as casemap says.
+fn irc_upper(casemap: &CaseMapping, chr: u8) -> u8 {
+    match chr {
+        b'a'..=b'z' => chr - 32u8,
+        b'{'..=b'}' if *casemap == CaseMapping::Rfc1459 => chr - 32u8,
+        b'^' if *casemap == CaseMapping::Rfc1459 => chr + 32,
+        _ => chr,
+    }
+}
+
 /// Uppercases a slice and returns a copy.
 /// Note that this function currently only supports CASEMAPPING=ascii or CASEMAPPING=rfc1459
 pub fn irc_uppercase(casemap: &CaseMapping, the_str: &[u8]) -> Vec<u8> {
     the_str
         .iter()
-        .map(|&chr| match chr {
-            b'a'..=b'z' => chr - 32u8,
-            b'{'..=b'}' if *casemap == CaseMapping::Rfc1459 => chr - 32u8,
-            b'^' if *casemap == CaseMapping::Rfc1459 => chr + 32,
-            _ => chr,
-        })
+        .map(|&chr| irc_upper(casemap, chr))
         .collect::<Vec<u8>>()
 }
 
 pub fn case_cmp(casemap: &CaseMapping, lhs: &[u8], rhs: &[u8]) -> bool {
-    irc_uppercase(casemap, lhs) == irc_uppercase(casemap, rhs)
+    lhs.len() == rhs.len()
+        && lhs
+            .iter()
+            .zip(rhs)
+            .all(|(&l, &r)| irc_upper(casemap, l) == irc_upper(casemap, r))
+}
+
+/// A nick or channel as a map key: equal to, and hashing like, any spelling of it
+/// under the casemapping, while keeping the spelling it was first seen with.
+/// Keys only equal keys of the same casemapping, so a map should use one.
+#[derive(Debug, Clone)]
+pub struct CaseKey {
+    casemap: CaseMapping,
+    name: String,
+}
+
+impl CaseKey {
+    pub fn new(casemap: &CaseMapping, name: &[u8]) -> Self {
+        CaseKey {
+            casemap: *casemap,
+            name: String::from_utf8_lossy(name).to_string(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for CaseKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.casemap == other.casemap
+            && case_cmp(&self.casemap, self.name.as_bytes(), other.name.as_bytes())
+    }
+}
+
+impl Eq for CaseKey {}
+
+/// A channel name, normalized by the casemapping wherever it is stored, looked up or
+/// compared, so #Chan and #chan are the same channel. Shown as first spelled.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChannelName(CaseKey);
+
+impl ChannelName {
+    pub fn new(casemap: &CaseMapping, name: &[u8]) -> Self {
+        ChannelName(CaseKey::new(casemap, name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// If name, in any spelling, is this channel.
+    pub fn is(&self, name: &[u8]) -> bool {
+        case_cmp(&self.0.casemap, self.0.name.as_bytes(), name)
+    }
+}
+
+impl fmt::Display for ChannelName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Hash for CaseKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for &chr in self.name.as_bytes() {
+            state.write_u8(irc_upper(&self.casemap, chr));
+        }
+        // like str, so "ab" + "c" and "a" + "bc" differ in a tuple.
+        state.write_u8(0xff);
+    }
+}
+
+/// Parse a command out of a message, e.g. ".8 will it rain?" with a prefix of ".!".
+/// Any one of the prefix characters may start a command.
+pub fn parse_command<'a>(prefix: &[u8], text: &'a str) -> Option<Command<'a>> {
+    let first = *text.as_bytes().first()?;
+    // only ascii prefixes are supported, so this cannot split a character.
+    if !first.is_ascii() || !prefix.contains(&first) {
+        return None;
+    }
+    let rest = &text[1..];
+    let (name, args) = match rest.find(' ') {
+        Some(idx) => (&rest[..idx], rest[idx..].trim_start()),
+        None => (rest, ""),
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some(Command { name, args })
+    }
+}
+
+/// The message is only the given word, e.g. "botsnack".
+pub fn is_bare_word(word: &str, text: &str) -> bool {
+    text.trim().eq_ignore_ascii_case(word)
+}
+
+/// The given word appears anywhere in the message, ignoring surrounding punctuation.
+pub fn has_word(word: &str, text: &str) -> bool {
+    text.split_whitespace().any(|part| {
+        part.trim_matches(|chr: char| chr.is_ascii_punctuation())
+            .eq_ignore_ascii_case(word)
+    })
+}
+
+/// Whether a message starts with "nick:" or "nick,".
+pub fn is_addressed(casemap: &CaseMapping, nick: &str, text: &str) -> bool {
+    strip_address(casemap, nick, text).is_some()
+}
+
+/// The rest of a message which starts with "nick:" or "nick,".
+pub fn strip_address<'a>(casemap: &CaseMapping, nick: &str, text: &'a str) -> Option<&'a str> {
+    let bytes = text.as_bytes();
+    if bytes.len() > nick.len()
+        && matches!(bytes[nick.len()], b':' | b',')
+        && case_cmp(casemap, &bytes[..nick.len()], nick.as_bytes())
+    {
+        // the separator is ascii, so this is a character boundary.
+        Some(text[nick.len() + 1..].trim_start())
+    } else {
+        None
+    }
+}
+
+/// Parse a command addressed to us, e.g. "r8ball: roll 2d6" or "r8ball, .roll".
+pub fn parse_addressed<'a>(
+    casemap: &CaseMapping,
+    nick: &str,
+    prefix: &[u8],
+    text: &'a str,
+) -> Option<Command<'a>> {
+    let rest = strip_address(casemap, nick, text)?;
+    if let Some(cmd) = parse_command(prefix, rest) {
+        return Some(cmd);
+    }
+    let (name, args) = match rest.find(' ') {
+        Some(idx) => (&rest[..idx], rest[idx..].trim_start()),
+        None => (rest, ""),
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some(Command { name, args })
+    }
+}
+
+/// The [channels."#chan"] block for a channel, if it has one.
+pub fn channel_config<'a>(
+    casemap: &CaseMapping,
+    channels: &'a HashMap<String, ChannelConfig>,
+    channel: &str,
+) -> Option<&'a ChannelConfig> {
+    channels
+        .iter()
+        .find(|(name, _)| case_cmp(casemap, name.as_bytes(), channel.as_bytes()))
+        .map(|(_, conf)| conf)
+}
+
+/// The verbosity class configured for a channel, Normal if it has none.
+pub fn channel_verbosity(
+    casemap: &CaseMapping,
+    channels: &HashMap<String, ChannelConfig>,
+    channel: &str,
+) -> Verbosity {
+    channel_config(casemap, channels, channel)
+        .map(|conf| conf.verbosity)
+        .unwrap_or_default()
+}
+
+/// Match a nick!user@host against a mask with * and ? wildcards, ignoring ascii case.
+pub fn mask_match(mask: &[u8], subject: &[u8]) -> bool {
+    let (mut m, mut s) = (0usize, 0usize);
+    // where to resume matching if the last * has to consume more of the subject.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while s < subject.len() {
+        match mask.get(m) {
+            Some(b'*') => {
+                m += 1;
+                backtrack = Some((m, s));
+            }
+            Some(&chr) if chr == b'?' || chr.eq_ignore_ascii_case(&subject[s]) => {
+                m += 1;
+                s += 1;
+            }
+            _ => match backtrack {
+                Some((star_m, star_s)) => {
+                    m = star_m;
+                    s = star_s + 1;
+                    backtrack = Some((star_m, star_s + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    mask[m..].iter().all(|&chr| chr == b'*')
+}
+
+/// Split a relayed message of the form `"<nick> message"` into the real sender and message.
+pub fn unmask_relay(text: &str) -> Option<(String, String)> {
+    let text = strip_formatting(text);
+    let rest = text.trim_start().strip_prefix('<')?;
+    let end = rest.find('>')?;
+    let nick = rest[..end].trim();
+    if nick.is_empty() || nick.contains(' ') {
+        return None;
+    }
+    Some((nick.to_owned(), rest[end + 1..].trim_start().to_owned()))
+}
+
+/// A capability and its value, e.g. sasl=PLAIN,EXTERNAL; the value is empty if there's none.
+pub type CapValue<'a> = (&'a [u8], &'a [u8]);
+
+/// A CAP subcommand from the server.
+#[derive(Debug, PartialEq)]
+pub enum Cap<'a> {
+    /// What the server offers, more is true if another LS line follows.
+    Ls { caps: Vec<CapValue<'a>>, more: bool },
+    /// Our request was granted, a name starting with - was disabled.
+    Ack(Vec<CapValue<'a>>),
+    /// Our request was refused, all of it.
+    Nak(Vec<CapValue<'a>>),
+    /// cap-notify, the server offers more.
+    New(Vec<CapValue<'a>>),
+    /// cap-notify, the server no longer offers these.
+    Del(Vec<CapValue<'a>>),
 }
 
 /// Parse the CAP command from the server
 /// Messages usually look like -> :server CAP YOUR_NICK ACK :cap1 [cap2...]
-/// We currently only handle ACK for multi-prefix with a future use of
-/// sasl to come.
-pub fn parse_cap(m: &Message) -> bool {
+/// or for multiline LS -> :server CAP YOUR_NICK LS * :cap1 [cap2...]
+pub fn parse_cap<'a>(m: &Message<'a>) -> Option<Cap<'a>> {
     let mut piter = m.parameters();
 
-    // We throw away the nickmake parameter
-    if piter.next().is_none() {
-        return false; // we have an error.
+    // We throw away the nick parameter
+    piter.next()?;
+    let subcommand = piter.next()?;
+    let mut caplist = piter.next()?;
+    let more = caplist == b"*";
+    if more {
+        caplist = piter.next()?;
+    }
+    let caps = caplist
+        .split(|&chr| chr == b' ')
+        .filter(|cap| !cap.is_empty())
+        .map(|cap| match cap.iter().position(|&chr| chr == b'=') {
+            Some(eq) => (&cap[..eq], &cap[eq + 1..]),
+            None => (cap, &cap[cap.len()..]),
+        })
+        .collect();
+    match subcommand {
+        b"LS" => Some(Cap::Ls { caps, more }),
+        b"ACK" => Some(Cap::Ack(caps)),
+        b"NAK" => Some(Cap::Nak(caps)),
+        b"NEW" => Some(Cap::New(caps)),
+        b"DEL" => Some(Cap::Del(caps)),
+        _ => None,
     }
-    if let Some(ack) = piter.next() {
-        if ack != b"ACK" {
-            return false;
+}
+
+/// The port and duration of an sts capability, e.g. port=6697,duration=2592000.
+pub fn parse_sts(value: &str) -> (Option<u16>, Option<u64>) {
+    let (mut port, mut duration) = (None, None);
+    for key in value.split(',') {
+        match key.split_once('=') {
+            Some(("port", val)) => port = val.parse().ok(),
+            Some(("duration", val)) => duration = val.parse().ok(),
+            _ => (),
         }
-    } else {
-        // not enough params
-        return false;
     }
+    (port, duration)
+}
 
-    if let Some(caplist) = piter.next() {
-        caplist
-            .split(|&chr| chr == b' ')
-            .any(|cap| cap == b"multi-prefix")
-    } else {
-        false
+/// Parse a server-time tag, e.g. 2021-01-01T00:00:00.000Z, to seconds since the epoch.
+pub fn parse_server_time(time: &[u8]) -> Option<u64> {
+    let time = std::str::from_utf8(time).ok()?;
+    let (date, clock) = time.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<u64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    // fractional seconds are not interesting.
+    let clock = clock.split('.').next()?;
+    let mut clock = clock.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+    let (hour, min, sec) = (clock.next()??, clock.next()??, clock.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
     }
+    let days = days_from_civil(year as i64, month, day);
+    if days < 0 {
+        return None;
+    }
+    Some(days as u64 * 86400 + hour * 3600 + min * 60 + sec)
+}
+
+/// Make a nick the server rejected as erroneous acceptable: drop the characters
+/// RFC 2812 doesn't allow, anything leading that can't start a nick, and cut it to
+/// max_len. May be empty if nothing is left.
+pub fn sanitize_nick(nick: &str, max_len: usize) -> String {
+    let special = |chr: char| "[]\\`_^{|}".contains(chr);
+    nick.chars()
+        .filter(|&chr| chr.is_ascii_alphanumeric() || chr == '-' || special(chr))
+        .skip_while(|&chr| !chr.is_ascii_alphabetic() && !special(chr))
+        .take(max_len)
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
+    use std::{collections::HashMap, str::FromStr};
+
     use rand::{prelude::SmallRng, Rng, SeedableRng};
 
-    use crate::irc::{
-        client::{helpers::case_cmp, CaseMapping},
-        iter::{BufIterator, TruncStatus},
-        parse::Message,
+    use crate::{
+        config::config_file::{Config, Verbosity},
+        irc::{
+            client::{
+                helpers::{
+                    case_cmp, is_addressed, parse_addressed, parse_cap, parse_server_time,
+                    parse_sts, sanitize_nick, Cap, CaseKey,
+                },
+                CaseMapping,
+            },
+            iter::{BufIterator, TruncStatus},
+            parse::Message,
+        },
     };
 
-    use super::join_channels;
+    use super::{
+        channel_verbosity, has_word, is_bare_word, join_batches, join_channels, mask_match,
+        parse_command, part_channels, split_key, unmask_relay,
+    };
 
     #[test]
     fn uppercase() {
         assert!(case_cmp(&CaseMapping::Rfc1459, b"^{|}", b"~[\\]"));
         assert!(case_cmp(&CaseMapping::Rfc1459, b"^{|}abc", b"~[\\]ABC"));
         assert!(!case_cmp(&CaseMapping::Ascii, b"^{|}abc", b"~[\\]ABC"));
+        assert!(!case_cmp(&CaseMapping::Ascii, b"abc", b"ABCD"));
+    }
+
+    #[test]
+    fn case_keys() {
+        let mut topics = HashMap::new();
+        topics.insert(CaseKey::new(&CaseMapping::Rfc1459, b"#Chan{1}"), "topic");
+        let key = CaseKey::new(&CaseMapping::Rfc1459, b"#CHAN[1]");
+        assert_eq!(topics.get(&key), Some(&"topic"));
+        // the first spelling stays.
+        assert_eq!(topics.keys().next().unwrap().as_str(), "#Chan{1}");
+        assert!(!topics.contains_key(&CaseKey::new(&CaseMapping::Ascii, b"#CHAN[1]")));
+        assert!(!topics.contains_key(&CaseKey::new(&CaseMapping::Rfc1459, b"#Chan")));
+    }
+
+    #[test]
+    fn command_parsing() {
+        let cmd = parse_command(b".!", ".8 will it  rain?").unwrap();
+        assert_eq!(cmd.name, "8");
+        assert_eq!(cmd.args, "will it  rain?");
+        let cmd = parse_command(b".!", "!help").unwrap();
+        assert_eq!(cmd.name, "help");
+        assert_eq!(cmd.args, "");
+        assert!(parse_command(b".!", "hello").is_none());
+        assert!(parse_command(b".!", ". spaced").is_none());
+        assert!(parse_command(b".!", "").is_none());
+    }
+
+    #[test]
+    fn word_triggers() {
+        assert!(is_bare_word("botsnack", "  BotSnack "));
+        assert!(!is_bare_word("botsnack", "botsnack please"));
+        assert!(has_word("rust", "I like Rust!"));
+        assert!(has_word("rust", "rust"));
+        assert!(!has_word("rust", "trusty"));
+    }
+
+    #[test]
+    fn channel_verbosities() {
+        let conf = Config::from_str(
+            r##"
+[general]
+nick = "bot"
+server = "localhost"
+
+[commands]
+
+[channels."#Busy[]"]
+verbosity = "compact"
+
+[channels."#quiet"]
+verbosity = "verbose"
+"##,
+        )
+        .unwrap();
+        let casemap = CaseMapping::Rfc1459;
+        assert_eq!(
+            channel_verbosity(&casemap, &conf.channels, "#busy{}"),
+            Verbosity::Compact
+        );
+        assert_eq!(
+            channel_verbosity(&casemap, &conf.channels, "#QUIET"),
+            Verbosity::Verbose
+        );
+        assert_eq!(
+            channel_verbosity(&casemap, &conf.channels, "#other"),
+            Verbosity::Normal
+        );
+    }
+
+    #[test]
+    fn masks() {
+        assert!(mask_match(b"*!*@matrix.org", b"bridge!bot@Matrix.org"));
+        assert!(mask_match(b"bridge!*", b"bridge!bot@matrix.org"));
+        assert!(mask_match(b"*", b""));
+        assert!(mask_match(b"b?idge!*@*.org", b"bridge!bot@matrix.org"));
+        assert!(mask_match(b"*a*b*c", b"xxaxxbxxbxc"));
+        assert!(!mask_match(
+            b"*!*@matrix.org",
+            b"bridge!bot@matrix.org.evil"
+        ));
+        assert!(!mask_match(b"bridge", b"bridge!bot@host"));
+    }
+
+    #[test]
+    fn relayed_messages() {
+        assert_eq!(
+            unmask_relay("<\x0304alice\x03> .8 hello"),
+            Some(("alice".to_owned(), ".8 hello".to_owned()))
+        );
+        assert_eq!(unmask_relay("no nick here"), None);
+        assert_eq!(unmask_relay("< > empty"), None);
+    }
+
+    #[test]
+    fn join_batching() {
+        let channels = ["#a", "#b", "#c"]
+            .iter()
+            .map(|&chan| chan.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            join_batches(&channels, 2),
+            vec![b"JOIN #a,#b".to_vec(), b"JOIN #c".to_vec()]
+        );
+        assert_eq!(join_batches(&channels, 0), vec![b"JOIN #a,#b,#c".to_vec()]);
+        assert!(join_batches(&[], 2).is_empty());
+    }
+
+    #[test]
+    fn join_keys() {
+        let channels = ["#open", "#secret key123", "#other", "#locked hunter2"]
+            .iter()
+            .map(|&chan| chan.to_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            join_batches(&channels, 0),
+            vec![b"JOIN #secret,#locked,#open,#other key123,hunter2".to_vec()]
+        );
+        assert_eq!(
+            join_batches(&channels, 3),
+            vec![
+                b"JOIN #secret,#locked,#open key123,hunter2".to_vec(),
+                b"JOIN #other".to_vec()
+            ]
+        );
+        assert_eq!(
+            part_channels(&channels),
+            b"PART #open,#secret,#other,#locked\r\n"
+        );
+        assert_eq!(split_key("#a  key"), ("#a", Some("key")));
+        assert_eq!(split_key("#a"), ("#a", None));
     }
 
     #[test]
@@ -135,7 +617,7 @@ mod test {
         while channels.len() < 256 {
             let mut channel = "#".to_owned();
             for _ in 0..prng.gen_range(5..30) {
-                channel.push(prng.gen_range('a'..'z'));
+                channel.push(prng.gen_range('a'..='z'));
             }
             channels.push(channel);
         }
@@ -161,4 +643,82 @@ mod test {
             assert_eq!(lhs, rhs);
         }
     }
+
+    #[test]
+    fn addressed_commands() {
+        let casemap = CaseMapping::Rfc1459;
+        let cmd = parse_addressed(&casemap, "r8ball", b".", "R8BALL: roll  2d6").unwrap();
+        assert_eq!((cmd.name, cmd.args), ("roll", "2d6"));
+        let cmd = parse_addressed(&casemap, "r8ball", b".", "r8ball, .roll").unwrap();
+        assert_eq!((cmd.name, cmd.args), ("roll", ""));
+        assert!(parse_addressed(&casemap, "r8ball", b".", "r8ball:").is_none());
+        assert!(parse_addressed(&casemap, "r8ball", b".", "roll 2d6").is_none());
+    }
+
+    #[test]
+    fn addressed() {
+        let casemap = CaseMapping::Rfc1459;
+        assert!(is_addressed(&casemap, "bot", "Bot: hi"));
+        assert!(is_addressed(&casemap, "bot", "bot,hi"));
+        assert!(!is_addressed(&casemap, "bot", "bot"));
+        assert!(!is_addressed(&casemap, "bot", "bots: hi"));
+    }
+
+    #[test]
+    fn nick_sanitizing() {
+        assert_eq!(sanitize_nick("r8ball", 9), "r8ball");
+        assert_eq!(sanitize_nick("8ball bot!", 30), "ballbot");
+        assert_eq!(sanitize_nick("-[r8]-ball.", 30), "[r8]-ball");
+        assert_eq!(sanitize_nick("averyveryverylongnick", 9), "averyvery");
+        assert_eq!(sanitize_nick("ボット", 9), "");
+    }
+
+    #[test]
+    fn server_time() {
+        assert_eq!(parse_server_time(b"1970-01-01T00:00:00.000Z"), Some(0));
+        assert_eq!(
+            parse_server_time(b"2021-03-04T05:06:07.890Z"),
+            Some(1614834367)
+        );
+        assert_eq!(parse_server_time(b"2021-03-04T05:06:07Z"), Some(1614834367));
+        assert!(parse_server_time(b"2021-13-04T05:06:07Z").is_none());
+        assert!(parse_server_time(b"2021-03-04 05:06:07").is_none());
+    }
+
+    #[test]
+    fn cap_replies() {
+        let m = Message::new(b":server CAP * ACK :multi-prefix -server-time");
+        assert_eq!(
+            parse_cap(&m),
+            Some(Cap::Ack(vec![
+                (&b"multi-prefix"[..], &b""[..]),
+                (&b"-server-time"[..], &b""[..])
+            ]))
+        );
+        let m = Message::new(b":server CAP bot LS * :multi-prefix sasl=PLAIN,EXTERNAL");
+        assert_eq!(
+            parse_cap(&m),
+            Some(Cap::Ls {
+                caps: vec![
+                    (&b"multi-prefix"[..], &b""[..]),
+                    (&b"sasl"[..], &b"PLAIN,EXTERNAL"[..])
+                ],
+                more: true,
+            })
+        );
+        let m = Message::new(b":server CAP bot DEL :away-notify");
+        assert_eq!(
+            parse_cap(&m),
+            Some(Cap::Del(vec![(&b"away-notify"[..], &b""[..])]))
+        );
+        let m = Message::new(b":server CAP bot LIST :multi-prefix");
+        assert_eq!(parse_cap(&m), None);
+
+        assert_eq!(parse_sts("port=6697"), (Some(6697), None));
+        assert_eq!(
+            parse_sts("duration=300,preload,port=6697"),
+            (Some(6697), Some(300))
+        );
+        assert_eq!(parse_sts("port=x,duration=-1"), (None, None));
+    }
 }
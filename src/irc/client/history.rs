@@ -0,0 +1,104 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::{helpers::ChannelName, CaseMapping};
+
+pub struct HistoryLine {
+    pub nick: String,
+    pub text: String,
+}
+
+/// The last few PRIVMSGs of every channel we are in, however the channel is spelled.
+pub struct History {
+    size: usize,
+    channels: HashMap<ChannelName, VecDeque<HistoryLine>>,
+}
+
+impl History {
+    pub fn new(size: usize) -> Self {
+        History {
+            size,
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, channel: ChannelName, nick: &str, text: &str) {
+        if self.size == 0 {
+            return;
+        }
+        let lines = self.channels.entry(channel).or_default();
+        if lines.len() == self.size {
+            lines.pop_front();
+        }
+        lines.push_back(HistoryLine {
+            nick: nick.to_owned(),
+            text: text.to_owned(),
+        });
+    }
+
+    /// Lines of a channel, most recent first.
+    pub fn recent(&self, channel: &ChannelName) -> impl Iterator<Item = &HistoryLine> {
+        self.channels
+            .get(channel)
+            .into_iter()
+            .flat_map(|lines| lines.iter().rev())
+    }
+
+    pub fn forget(&mut self, channel: &ChannelName) {
+        self.channels.remove(channel);
+    }
+
+    /// Normalize the channels again, after the casemapping changed.
+    pub fn recase(&mut self, casemap: &CaseMapping) {
+        self.channels = std::mem::take(&mut self.channels)
+            .into_iter()
+            .map(|(chan, lines)| (ChannelName::new(casemap, chan.as_str().as_bytes()), lines))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::irc::client::{helpers::ChannelName, CaseMapping};
+
+    use super::History;
+
+    #[test]
+    fn bounded_history() {
+        let chan = |name: &str| ChannelName::new(&CaseMapping::Rfc1459, name.as_bytes());
+        let mut hist = History::new(2);
+        hist.push(chan("#chan"), "a", "one");
+        hist.push(chan("#Chan"), "b", "two");
+        hist.push(chan("#CHAN"), "c", "three");
+        hist.push(chan("#other"), "d", "four");
+
+        let lines = hist
+            .recent(&chan("#chan"))
+            .map(|line| line.text.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(lines, vec!["three", "two"]);
+        assert_eq!(hist.recent(&chan("#none")).count(), 0);
+
+        hist.forget(&chan("#cHaN"));
+        assert_eq!(hist.recent(&chan("#chan")).count(), 0);
+        assert_eq!(hist.recent(&chan("#other")).count(), 1);
+    }
+}
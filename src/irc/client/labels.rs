@@ -0,0 +1,147 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// labels awaiting an answer, so a server that never answers can't grow this forever.
+const MAX_PENDING: usize = 256;
+// how long the server has to answer a label before we consider the message lost.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A labeled message we sent.
+#[derive(Debug, PartialEq)]
+pub struct Pending {
+    pub at: Instant,
+    pub target: String,
+    pub text: String,
+}
+
+/// What an echo of our message tells us.
+#[derive(Debug, PartialEq)]
+pub enum Echo {
+    /// The message arrived as we sent it.
+    Delivered(Pending),
+    /// The server changed it, e.g. stripped colors or truncated it.
+    Rewritten(Pending),
+}
+
+/// Outbound messages tagged with labeled-response labels, until the server
+/// echoes, acknowledges or refuses them.
+#[derive(Default)]
+pub struct Labels {
+    next: u64,
+    pending: HashMap<String, Pending>,
+}
+
+impl Labels {
+    /// Remember a message and return the label to send it with.
+    /// None if too many messages are unanswered.
+    pub fn attach(&mut self, now: Instant, target: &str, text: &str) -> Option<String> {
+        if self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        self.next += 1;
+        let label = format!("r8b{}", self.next);
+        self.pending.insert(
+            label.clone(),
+            Pending {
+                at: now,
+                target: target.to_owned(),
+                text: text.to_owned(),
+            },
+        );
+        Some(label)
+    }
+
+    /// The server echoed a labeled message back as target and text.
+    pub fn echo(&mut self, label: &str, target: &str, text: &str) -> Option<Echo> {
+        let pending = self.pending.remove(label)?;
+        if pending.target == target && pending.text == text {
+            Some(Echo::Delivered(pending))
+        } else {
+            Some(Echo::Rewritten(pending))
+        }
+    }
+
+    /// Any other answer to a label, e.g. ACK or an error numeric.
+    pub fn answer(&mut self, label: &str) -> Option<Pending> {
+        self.pending.remove(label)
+    }
+
+    /// When the oldest one expires.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| pending.at + TIMEOUT)
+            .min()
+    }
+
+    /// Messages the server never answered.
+    pub fn expire(&mut self, now: Instant) -> Vec<Pending> {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.at) >= TIMEOUT)
+            .map(|(label, _)| label.clone())
+            .collect::<Vec<String>>();
+        let mut ret = expired
+            .iter()
+            .filter_map(|label| self.pending.remove(label))
+            .collect::<Vec<Pending>>();
+        ret.sort_by_key(|pending| pending.at);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Echo, Labels};
+
+    #[test]
+    fn labels() {
+        let start = Instant::now();
+        let mut labels = Labels::default();
+        let a = labels.attach(start, "#chan", "hello").unwrap();
+        let b = labels.attach(start, "#chan", "\x02bold").unwrap();
+        let c = labels.attach(start, "nick", "hi").unwrap();
+        let d = labels.attach(start, "#other", "lost").unwrap();
+        assert_ne!(a, b);
+
+        assert!(matches!(
+            labels.echo(&a, "#chan", "hello"),
+            Some(Echo::Delivered(_))
+        ));
+        assert!(labels.echo(&a, "#chan", "hello").is_none());
+        assert!(matches!(
+            labels.echo(&b, "#chan", "bold"),
+            Some(Echo::Rewritten(_))
+        ));
+        assert_eq!(labels.answer(&c).unwrap().target, "nick");
+
+        assert!(labels.expire(start + Duration::from_secs(1)).is_empty());
+        let lost = labels.expire(start + Duration::from_secs(30));
+        assert_eq!(lost.len(), 1);
+        assert_eq!(lost[0].text, "lost");
+        assert!(labels.answer(&d).is_none());
+    }
+}
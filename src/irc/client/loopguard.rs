@@ -0,0 +1,188 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How far back replies per nick are counted.
+pub const WINDOW: Duration = Duration::from_secs(60);
+// how long we ignore someone caught echoing us.
+const ECHO_IGNORE: Duration = Duration::from_secs(600);
+// how many of our recent lines we compare messages against.
+const SAID_MAX: usize = 32;
+// shorter lines, e.g. "ok", are said by people too.
+const ECHO_MIN: usize = 12;
+
+/// Keeps us out of reply loops with other bots: we stop answering a nick we answered
+/// too often lately, and users who repeat what we just said back at us.
+#[derive(Default)]
+pub struct LoopGuard {
+    // replies to one nick per WINDOW, 0 for no cap.
+    per_nick: u32,
+    detect_echo: bool,
+    // when we answered each nick, by uppercased nick.
+    replied: HashMap<Vec<u8>, VecDeque<Instant>>,
+    // the text of our recent PRIVMSGs and NOTICEs.
+    said: VecDeque<(Instant, String)>,
+    // who was caught echoing us, until when we ignore them.
+    echoing: HashMap<String, Instant>,
+}
+
+impl LoopGuard {
+    pub fn new(per_nick: u32, detect_echo: bool) -> Self {
+        LoopGuard {
+            per_nick,
+            detect_echo,
+            ..LoopGuard::default()
+        }
+    }
+
+    pub fn set_limits(&mut self, per_nick: u32, detect_echo: bool) {
+        self.per_nick = per_nick;
+        self.detect_echo = detect_echo;
+    }
+
+    /// Something we sent to a channel or user.
+    pub fn said(&mut self, now: Instant, text: &str) {
+        if !self.detect_echo || text.len() < ECHO_MIN {
+            return;
+        }
+        if self.said.len() == SAID_MAX {
+            self.said.pop_front();
+        }
+        self.said.push_back((now, text.to_owned()));
+    }
+
+    /// We answered nick.
+    pub fn replied(&mut self, now: Instant, nick: Vec<u8>) {
+        if self.per_nick != 0 {
+            self.replied.entry(nick).or_default().push_back(now);
+        }
+    }
+
+    /// If we should leave a message from nick (uppercased) alone.
+    /// who is their hostmask, or their nick if a relay sent it for them.
+    pub fn ignores(&mut self, now: Instant, nick: &[u8], who: &str, text: &str) -> bool {
+        self.echoing.retain(|_, until| *until > now);
+        if self.echoing.contains_key(who) {
+            return true;
+        }
+        if self.echoes(now, text) {
+            warn!(
+                "{} repeated what we said, ignoring them for {}s in case it's a bot.",
+                who,
+                ECHO_IGNORE.as_secs()
+            );
+            self.echoing.insert(who.to_owned(), now + ECHO_IGNORE);
+            return true;
+        }
+
+        self.replied.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= WINDOW)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let count = self.replied.get(nick).map_or(0, VecDeque::len);
+        if self.per_nick != 0 && count >= self.per_nick as usize {
+            if count == self.per_nick as usize {
+                warn!(
+                    "Answered {} {} times within a minute, ignoring them for now.",
+                    who, count
+                );
+                // so we warn once per window.
+                self.replied(now, nick.to_vec());
+            }
+            return true;
+        }
+        false
+    }
+
+    /// If text is one of our recent lines, or ends with one, e.g. quoted back to us.
+    fn echoes(&mut self, now: Instant, text: &str) -> bool {
+        while self
+            .said
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= WINDOW)
+        {
+            self.said.pop_front();
+        }
+        text.len() >= ECHO_MIN
+            && self
+                .said
+                .iter()
+                .any(|(_, said)| text.ends_with(said.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{LoopGuard, WINDOW};
+
+    #[test]
+    fn reply_cap() {
+        let now = Instant::now();
+        let mut guard = LoopGuard::new(2, false);
+        let ignores = |guard: &mut LoopGuard, at| guard.ignores(at, b"BOT", "bot!b@host", "hi");
+        assert!(!ignores(&mut guard, now));
+        guard.replied(now, b"BOT".to_vec());
+        guard.replied(now, b"BOT".to_vec());
+        assert!(ignores(&mut guard, now));
+        assert!(ignores(&mut guard, now + Duration::from_secs(30)));
+        // the window moved on.
+        assert!(!ignores(&mut guard, now + WINDOW));
+        assert!(!guard.ignores(now, b"OTHER", "other!o@host", "hi"));
+    }
+
+    #[test]
+    fn echoes() {
+        let now = Instant::now();
+        let mut guard = LoopGuard::new(0, true);
+        guard.said(now, "alice: Outlook not so good.");
+        guard.said(now, "ok");
+        assert!(!guard.ignores(now, b"ALICE", "alice!a@host", "ok"));
+        assert!(!guard.ignores(now, b"ALICE", "alice!a@host", "Outlook not so good."));
+        assert!(guard.ignores(
+            now,
+            b"ECHO",
+            "echo!e@host",
+            "<r8ball> alice: Outlook not so good."
+        ));
+        // for a while, whatever they say.
+        let later = now + Duration::from_secs(120);
+        assert!(guard.ignores(later, b"ECHO", "echo!e@host", "hello"));
+        assert!(!guard.ignores(
+            later + Duration::from_secs(600),
+            b"ECHO",
+            "echo!e@host",
+            "hello"
+        ));
+
+        let mut off = LoopGuard::new(0, false);
+        off.said(now, "alice: Outlook not so good.");
+        assert!(!off.ignores(now, b"ECHO", "echo!e@host", "alice: Outlook not so good."));
+    }
+}
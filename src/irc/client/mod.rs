@@ -17,60 +17,293 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-mod helpers;
+pub mod batch;
+pub mod chanlog;
+pub mod ctcp;
+pub mod filter;
+pub mod format;
+pub mod helpers;
+pub mod history;
+pub mod labels;
+pub mod loopguard;
+pub mod modes;
+pub mod native;
+pub mod numeric;
+pub mod outgoing;
+pub mod output;
+pub mod ratelimit;
+pub mod schedule;
+pub mod snapshot;
+pub mod stats;
+pub mod timers;
+pub mod users;
+pub mod watch;
+pub mod whois;
 
 use std::{
-    cmp,
+    borrow::Cow,
     collections::{HashMap, HashSet, VecDeque},
-    io::{self, Read, Write},
-    time::{SystemTime, UNIX_EPOCH},
+    convert::Infallible,
+    io::{self, IoSlice, Read, Write},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use mio::Waker;
 use rand::{prelude::SmallRng, Rng, SeedableRng};
+use regex::Regex;
 
 use crate::{
-    config::config_file::Config,
+    config::config_file::{
+        ChannelConfig, CommandConfig, Config, Hooks, Matcher, Overflow, Sandbox, SaslMechanism,
+        Schedule, Trigger, Verbosity,
+    },
     irc::{
-        client::helpers::{case_cmp, join_channels, parse_cap},
+        builtins,
+        client::helpers::{
+            case_cmp, channel_config, channel_verbosity, has_word, irc_uppercase, is_addressed,
+            is_bare_word, join_batches, mask_match, parse_addressed, parse_cap, parse_command,
+            parse_server_time, parse_sts, sanitize_nick, split_key, unmask_relay, Cap, CapValue,
+            ChannelName,
+        },
         iter::TruncStatus,
         parse::Message,
     },
+    storage::Storage,
 };
 
 use super::{
     iter::BufIterator,
-    plugin::{Plugin, PluginReadStat},
+    plugin::{self, Plugin, PluginReadStat, PluginSpawn},
+    workers::WorkerPool,
 };
 
+use batch::Batches;
+use chanlog::{format_event, ChannelLog};
+use ctcp::parse_ctcp;
+use filter::WordFilter;
+use format::{is_formatted, strip_formatting};
+use history::History;
+use labels::{Echo, Labels};
+use loopguard::LoopGuard;
+use modes::{parse_umodes, ModeSpec};
+use native::{BotPlugin, Command, Context, Deferred, Event, PrivMsg, Registry};
+use numeric::Numeric;
+use outgoing::{split_text, OutMessage, MAX_LINE};
+use output::{parse_output, Action, Moderation, ReplyPolicy, Route};
+use ratelimit::RateLimiter;
+use schedule::{Cron, Scheduler, When};
+use snapshot::SnapshotHandle;
+use stats::{ConnStats, PluginStats, SendStats};
+use timers::TimerQueue;
+use users::{UserKey, UserSettings, UserStore};
+use watch::Watchlist;
+use whois::{Waiter, WhoisInfo, Whoises};
+
 const BUF_SIZ: usize = 1024 * 16;
+// the read buffer grows up to this to fit a line longer than BUF_SIZ.
+const MAX_READ_BUF: usize = BUF_SIZ * 4;
+// list modes we keep track of: bans and, where the server has them, quiets.
+const TRACKED_LISTS: [u8; 2] = [b'b', b'q'];
+// masks kept per list, servers usually cap lists well below this.
+const MAX_LIST_MASKS: usize = 512;
+// how long to wait before giving friends their mode, in milliseconds.
+const FRIEND_DELAY_MS: std::ops::Range<u64> = 1000..5000;
+// why JOINs fail: full (+l), invite only (+i), banned (+b), bad key (+k), and
+// needing a registered nick.
+const JOIN_FAILURES: [(Numeric, &str); 5] = [
+    (Numeric::ErrChannelisfull, "it is full"),
+    (Numeric::ErrInviteonlychan, "it is invite only"),
+    (Numeric::ErrBannedfromchan, "we are banned"),
+    (Numeric::ErrBadchannelkey, "the key is wrong"),
+    (Numeric::ErrNeedreggednick, "we need to identify first"),
+];
+// rejoining a channel backs off from REJOIN_MIN (or join_retry) to MAX_REJOIN, and
+// starts over once we stayed in it for REJOIN_RESET.
+const REJOIN_MIN: Duration = Duration::from_secs(5);
+const MAX_REJOIN: Duration = Duration::from_secs(30 * 60);
+const REJOIN_RESET: Duration = Duration::from_secs(10 * 60);
+// how often we answer CTCP queries from one nick.
+const CTCP_INTERVAL: Duration = Duration::from_secs(10);
+// how many erroneous nick replies we try another nick for.
+const MAX_ERRONEOUS_NICKS: usize = 5;
+// how long after registering we wait for the end of the MOTD before joining.
+const MOTD_TIMEOUT: Duration = Duration::from_secs(10);
+// the longest user@host we assume until we learn ours: a ~ and USERLEN of 10, an @
+// and a HOSTLEN of 63.
+const USERHOST_MAX: usize = 1 + 10 + 1 + 63;
 
+/// The protocol state of one network: it reads messages from and writes lines to a
+/// connection it's handed, and runs commands and plugins, without doing any I/O
+/// setup of its own. receive_data() and write_data() do so over any Read and Write;
+/// feed_bytes() and take_output() do the same with plain bytes, for other transports.
+/// Plugins are spawned by the transport too, see take_spawns().
 pub struct Client {
     pub state: State,
-    // If we overrun this massive buffer, we have issues.
-    read_buffer: [u8; BUF_SIZ],
+    // Lines are parsed where they were read; a partial line stays put until the next
+    // read completes it, only moving to the front when the buffer's end is reached.
+    read_buffer: Vec<u8>,
+    // where the partial line starts, and where the next read goes.
+    read_start: usize,
     read_head: usize,
+    // bytes dropped so far of a line too long for the read buffer.
+    overlong: Option<usize>,
+    // lines going out now: control traffic (PONG, NICK, QUIT...) goes straight here,
     write_buffer: VecDeque<u8>,
+    // everything else waits here, paced so a flood of replies can't starve the above.
+    bulk: RateLimiter,
+    // bytes that may wait in the above, 0 for no limit, and what to do past it.
+    send_queue_max: usize,
+    overflow: Overflow,
+    // past send_queue_max, so we warn once until it fits again.
+    overflowing: bool,
+    // we gave up on keeping up and hang up.
+    send_overflowed: bool,
     rng: SmallRng,
+    // characters any of which may start a command.
+    command_prefix: Vec<u8>,
+    // command name -> external plugin to run.
+    commands: HashMap<String, CommandConfig>,
+    // masks of relay bots to unmask the real sender of.
+    gateways: Vec<String>,
+    // when each throttled command was last run.
+    last_run: HashMap<String, Instant>,
+    // (channel, command) for per-channel throttles.
+    channel_last_run: HashMap<(String, String), Instant>,
+    natives: Registry,
+    // external plugins started that the transport has yet to spawn.
+    spawned: Vec<PluginSpawn>,
+    // plugin id to name, for everything we started that hasn't exited.
+    running: HashMap<usize, String>,
+    pending: VecDeque<PendingPlugin>,
+    max_plugins: usize,
+    plugin_queue: usize,
+    reply_notice: bool,
+    reply_prefix_nick: bool,
+    ctcp_version: String,
+    // when we last answered a CTCP query, by uppercased nick.
+    ctcp_answered: HashMap<Vec<u8>, Instant>,
+    admins: Vec<String>,
+    // other bots we never answer, nicks or masks.
+    bots: Vec<String>,
+    loop_guard: LoopGuard,
+    filter: WordFilter,
+    // see General::usermode.
+    usermode: String,
+    // (hostmask or account, mode) to grant when they join, see Config::friends.
+    friends: Vec<(String, u8)>,
+    // capabilities the server offers (CAP LS and NEW) and their values.
+    cap_offered: HashMap<String, String>,
+    // CAP REQs the server has yet to answer.
+    cap_requested: HashSet<String>,
+    // we send CAP END once the REQs after LS are answered.
+    cap_negotiating: bool,
+    // see General::sasl_mechanism.
+    sasl: Option<SaslMechanism>,
+    // logging in with SASL, which holds up CAP END.
+    sasl_pending: bool,
+    // the connection is encrypted.
+    secure: bool,
+    // see take_sts().
+    sts: Option<Sts>,
+    // see General::playback_max_age.
+    playback_max_age: u64,
+    batches: Batches,
+    // our PRIVMSGs waiting for the server to echo them.
+    labels: Labels,
+    // lines of CHATHISTORY to ask for when we join a channel.
+    chathistory: usize,
+    sandbox: Sandbox,
+    snapshot: SnapshotHandle,
+    storage: Storage,
+    // shared with plugins through R8_DB_PATH.
+    storage_path: String,
+    // paces JOINs so servers don't throttle us for joining everything at once.
+    join_limiter: RateLimiter,
+    join_batch_size: usize,
+    channel_conf: HashMap<String, ChannelConfig>,
+    // parses plugin output off the event loop when configured.
+    workers: Option<WorkerPool>,
+    // replies native plugins send from other threads.
+    deferred: Deferred,
+    deferred_recv: Receiver<(String, Vec<String>)>,
+    schedule: Scheduler<Schedule>,
+    matchers: Vec<(Regex, Matcher)>,
+    hooks: Hooks,
+    // nicks we notify hooks about coming online and going offline.
+    watch: Watchlist,
+    // WHOIS lookups plugins are waiting on.
+    whoises: Whoises,
+    nickserv_password: String,
+    oper_name: String,
+    oper_password: String,
+    join_after_identify: bool,
+    identify_timeout: Duration,
+    // when we give up waiting on services and join anyway.
+    identify_deadline: Option<Instant>,
+    // nicks the server rejected as erroneous, we give up after a few.
+    erroneous_nicks: usize,
+    // services confirmed who we are, with RPL_LOGGEDIN or SASL.
+    identified: bool,
+    // when we give up waiting on the end of the MOTD and join anyway.
+    motd_deadline: Option<Instant>,
+    // silence from the server before we PING it, and then before we give up on it.
+    ping_interval: Duration,
+    last_heard: Instant,
+    // when we sent the PING, until the server says anything.
+    ping_sent: Option<Instant>,
+    // lines plugins asked to send later with :timer.
+    timers: TimerQueue,
+    // keys of +k channels, used whenever we (re)join them.
+    channel_keys: HashMap<String, String>,
+    rejoin_on_kick: bool,
+    join_retry: u64,
+    // per channel, the delay before the next rejoin and when the last one was due.
+    rejoins: HashMap<ChannelName, (Duration, Instant)>,
+    knock: bool,
+    // General::channels without keys, to tell what a reload adds or drops.
+    configured: Vec<String>,
+    // join_configured() ran, so a reload joins and parts right away.
+    autojoined: bool,
+    chanlog: Option<ChannelLog>,
+    // see General::log_events.
+    log_events: bool,
 }
 
 #[derive(PartialEq)]
 enum IrcState {
     Unknown,
+    // matched along with Unknown, nothing moves us here yet.
+    #[allow(dead_code)]
     PreAuth,
     Authenticated,
     Ready(bool),
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CaseMapping {
     Ascii,
     Rfc1459,
     Unicode, // ???
 }
 
+/// A channel topic and who set it when.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topic {
+    pub text: String,
+    pub setter: String,
+    // seconds since the epoch, 0 if we don't know.
+    pub set_at: u64,
+}
+
 pub struct State {
     pub nick: String,
-    pub channels: Vec<String>,
+    pub channels: Vec<ChannelName>,
+    // channels we asked to join and haven't heard back about.
+    pub joining: Vec<String>,
     // Modes are detected at runtime since each server has different ones
     pub umode: HashSet<u8>,
     // This only tracks the modes related to administrative privileges
@@ -78,474 +311,3594 @@ pub struct State {
     // Much like umodes, these vary from server to server and are detected
     // at runtime.
     // Some servers only support (vo)+@ or some support (vhoaq)+%@&~
-    pub channel_modes: HashMap<String, u64>,
+    // Ours, by uppercased channel; bits are positions in modes.prefix (see ModeSpec::bit).
+    pub channel_modes: HashMap<ChannelName, u64>,
+    // everyone's privileges the same way, by uppercased channel and nick.
+    members: HashMap<ChannelName, HashMap<Vec<u8>, u64>>,
+    // by uppercased channel.
+    topics: HashMap<ChannelName, Topic>,
+    // ban and quiet masks by uppercased channel and mode.
+    lists: HashMap<ChannelName, HashMap<u8, Vec<String>>>,
+    // per-user settings, keyed by services account or hostmask.
+    pub users: UserStore<UserSettings>,
+    // recent messages of each channel.
+    pub history: History,
+    /// What we sent recently, per channel and feature.
+    pub sent: SendStats,
+    /// Traffic and plugin totals, for .status and the control socket.
+    pub conn: ConnStats,
+    /// What external plugins cost, likewise.
+    pub plugin_runs: PluginStats,
     // the state of the client
     // determins if we are ready to join channels
     // of if we have functioning mode tracking
     ready_state: IrcState,
     // the old name we expected to have
     original_nick: Option<String>,
+    // capabilities the server acknowledged.
+    pub caps: HashSet<String>,
+    // away messages of users we've seen go away, by uppercased nick.
+    away: HashMap<Vec<u8>, String>,
 
     // This is state related to 005 command
-    casemapping: CaseMapping,
+    pub casemapping: CaseMapping,
     // list of channel prefixes that are valid. e.g. #&!
     chantypes: Vec<u8>,
-    // e.g. +v maps to +, o maps to @, etc.
-    mode_prefix: Vec<(u8, u8)>,
+    // e.g. +v maps to +, o maps to @, etc. and which modes take parameters.
+    pub modes: ModeSpec,
+    // how many nicks we may MONITOR, None if the server doesn't have it.
+    pub monitor: Option<usize>,
+    // the longest nick the server allows.
+    pub nicklen: usize,
+    // the longest line the server relays, CRLF and its prefix included.
+    pub linelen: usize,
+    // our nick!user@host as others see it, from USERHOST, 396 or what of ours the
+    // server relays back.
+    pub hostmask: Option<String>,
 }
 
+/// What handling one message did.
 #[derive(Debug, PartialEq)]
 pub enum IrcProto {
     Okay,
+    /// There are lines to send.
     Data,
+    /// The server ended the connection, e.g. with ERROR.
     Error(String),
 }
 
+/// What reading from the server came to.
 #[derive(Debug, PartialEq)]
 pub enum ClientReadStat {
     Error(String),
-    ReadBufferFull,
+    /// Messages were handled and there are lines to send.
     HasWritableData,
+    /// Nothing more to read until the socket is readable again.
     Blocked,
     Okay,
+    /// The server hung up.
     Eof,
 }
 
+/// What the server's sts capability asks of us.
 #[derive(Debug, PartialEq)]
-pub enum ClientWriteStat {
-    Blocked,
-    Okay,
-    Eof,
+pub enum Sts {
+    // reconnect with TLS on this port.
+    Upgrade(u16),
+    // keep to TLS for this many seconds, 0 to forget the policy.
+    Persist(u64),
 }
 
-fn login_command(nick: &str, user: &str) -> String {
-    format!(
-        "CAP REQ :multi-prefix\r
-NICK {0}\r
-USER {1} +i * :{0}\r
-",
-        nick, user
-    )
+/// What the transport should do, for [`Client::feed_bytes`] and
+/// [`Client::take_output`], which leave reading and writing to it.
+#[derive(Debug, PartialEq)]
+pub enum ClientAction {
+    /// Write these bytes to the server, in order.
+    Send(Vec<u8>),
+    /// Reconnect with TLS, or keep to it, as the server's sts capability asks.
+    Sts(Sts),
+    /// Hang up, for the reason given.
+    Close(String),
+    /// Start this plugin, and hand it to process_plugin() as it prints.
+    Spawn(Box<PluginSpawn>),
 }
 
-enum ModeType {
-    Type1, // has a parameter
-    Type2, // has a parameter
-    Type3, // has a parameter if positive signed + (not -)
-           // Type4, // This mode isn't relevant for our uses, effectively no parameter.
+/// What writing to the server came to.
+#[derive(Debug, PartialEq)]
+pub enum ClientWriteStat {
+    /// There is more to send once the socket is writable again.
+    Blocked,
+    /// Everything queued was sent.
+    Okay,
+    /// The server hung up.
+    Eof,
 }
 
-impl Client {
-    pub fn new(config: &Config) -> Self {
-        let state = State {
-            nick: config.general.nick.clone(),
-            channels: config.general.channels.clone(),
-            umode: HashSet::new(),
-            channel_modes: HashMap::new(),
-            ready_state: IrcState::Unknown,
-            original_nick: None,
-            casemapping: CaseMapping::Rfc1459,
-            chantypes: vec![b'#', b'&'],
-            mode_prefix: vec![],
-        };
-        let rng_v = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let mut ret = Client {
-            state,
-            read_buffer: [0u8; BUF_SIZ],
-            read_head: 0,
-            write_buffer: VecDeque::with_capacity(BUF_SIZ),
-            rng: SmallRng::seed_from_u64(rng_v),
-        };
-        // setup login write.
-        ret.write_buffer
-            .extend(login_command(&ret.state.nick, &ret.state.nick).as_bytes());
-        ret
+impl State {
+    pub fn chan_key(&self, channel: &[u8]) -> ChannelName {
+        ChannelName::new(&self.casemapping, channel)
     }
 
-    fn is_me(&self, msg: &Message) -> bool {
-        if let Some(my_nick) = msg.nick {
-            // Looks like the server changed my name.
-            case_cmp(&self.state.casemapping, my_nick, self.state.nick.as_bytes())
-        } else {
-            false
+    /// Normalize the channels we know of again, after the casemapping changed.
+    fn recase(&mut self) {
+        fn rekey<V>(casemap: &CaseMapping, map: &mut HashMap<ChannelName, V>) {
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(chan, value)| (ChannelName::new(casemap, chan.as_str().as_bytes()), value))
+                .collect();
         }
+        let casemap = self.casemapping;
+        for chan in self.channels.iter_mut() {
+            *chan = ChannelName::new(&casemap, chan.as_str().as_bytes());
+        }
+        rekey(&casemap, &mut self.channel_modes);
+        rekey(&casemap, &mut self.members);
+        rekey(&casemap, &mut self.topics);
+        rekey(&casemap, &mut self.lists);
+        self.history.recase(&casemap);
     }
 
-    // or in modern words "direct message"
-    fn is_private_message(&self, target: &[u8]) -> bool {
-        case_cmp(&self.state.casemapping, target, self.state.nick.as_bytes())
+    /// Our privileges in a channel, e.g. "@" if we're an op there.
+    pub fn privileges(&self, channel: &str) -> String {
+        let bits = self
+            .channel_modes
+            .get(&self.chan_key(channel.as_bytes()))
+            .copied()
+            .unwrap_or_default();
+        self.modes.prefixes(bits)
     }
 
-    fn handle_data(&mut self, len: usize) -> IrcProto {
-        let mut ret = IrcProto::Okay;
-        let mut partial_idx = 0usize;
-        let mut partial_end = 0usize;
-
-        let buf = &self.read_buffer[..len];
-        let iter = BufIterator::new(buf);
-        for line in iter {
-            let msg = match line {
-                TruncStatus::Full(data) => Message::new(data),
-                TruncStatus::Part(data) => {
-                    partial_idx = data.as_ptr() as usize - buf.as_ptr() as usize;
-                    partial_end = data.len() + partial_idx;
-                    break;
+    /// One ISUPPORT token, e.g. PREFIX=(ov)@+
+    fn isupport(&mut self, token: &[u8]) {
+        let (key, value) = match token.iter().position(|&chr| chr == b'=') {
+            Some(eq) => (&token[..eq], &token[eq + 1..]),
+            None => (token, &token[token.len()..]),
+        };
+        match key {
+            b"CASEMAPPING" => {
+                let casemap = match value {
+                    b"ascii" => CaseMapping::Ascii,
+                    b"rfc1459" | b"strict-rfc1459" => CaseMapping::Rfc1459,
+                    _ => return,
+                };
+                if casemap != self.casemapping {
+                    self.casemapping = casemap;
+                    self.recase();
                 }
-            };
-            if msg.is_empty() {
-                continue;
             }
-
-            if msg.nick.is_none() {
-                match msg.command {
-                    Some(cmd) if cmd == b"PING" => {
-                        self.write_buffer.extend(b"PONG ");
-                        if let Some(params) = msg.params {
-                            self.write_buffer.extend(params)
-                        }
-                        self.write_buffer.extend(b"\r\n");
-                        ret = IrcProto::Data;
-                    }
-                    Some(cmd) if cmd == b"ERROR" => {
-                        if let Some(params) = msg.params {
-                            let str_v = String::from_utf8_lossy(params);
-                            return IrcProto::Error(str_v.to_string());
-                        }
-                        // quit the stream
-                        self.write_buffer.extend(b"QUIT :bye\r\n");
-                        ret = IrcProto::Data;
-                    }
-                    Some(cmd) => {
-                        let str_v = String::from_utf8_lossy(cmd);
-                        println!("WARN: Recv unknown command: {:?}", str_v);
-                    }
-                    // !is_empty implies this HAS to be Some()
-                    None => unreachable!(),
+            b"CHANTYPES" => self.chantypes = value.to_vec(),
+            b"PREFIX" => self.modes.parse_prefix(value),
+            b"CHANMODES" => self.modes.parse_chanmodes(value),
+            // MONITOR without a limit is unlimited.
+            b"NICKLEN" => {
+                if let Ok(len) = String::from_utf8_lossy(value).parse() {
+                    self.nicklen = len;
                 }
-
-                continue;
             }
-
-            match msg.command {
-                Some(nick) if nick == b"NICK" => {
-                    if let Some(my_nick) = msg.nick {
-                        // Looks like the server changed my name.
-                        if case_cmp(&self.state.casemapping, my_nick, self.state.nick.as_bytes()) {
-                            let str_v = String::from_utf8_lossy(my_nick);
-                            self.state.nick = str_v.to_string();
-                            println!(
-                                "INFO: The server changed our nick to: {:?}",
-                                self.state.nick
-                            );
-                        }
-                    }
-                }
-                Some(privmsg) if privmsg == b"PRIVMSG" => {
-                    let mut params = msg.parameters();
-                    match (msg.nick, params.next(), params.next()) {
-                        (Some(nick), Some(target), Some(message)) => {
-                            if self.is_private_message(&target) && message == b"\x01VERSION\x01" {
-                                self.write_buffer.extend(b"NOTICE ");
-                                self.write_buffer.extend(nick);
-                                self.write_buffer.extend(b" :\x01r8ball: v0.0.0\x01\r\n");
-                                ret = IrcProto::Data;
-                            }
-                        }
-                        _ => (),
-                    };
-                }
-                // :me JOIN #chan
-                Some(join) if join == b"JOIN" => {
-                    if self.is_me(&msg) {
-                        if let Some(chan) = msg.parameters().next() {
-                            let ch = String::from_utf8_lossy(chan).to_string();
-                            self.state.channels.push(ch);
-                        }
-                    }
-                }
-                // :me PART #chan
-                Some(part) if part == b"PART" => {
-                    if self.is_me(&msg) {
-                        if let Some(chan) = msg.parameters().next() {
-                            self.state.channels.retain(|x| x.as_bytes() != chan);
-                        }
-                    }
-                }
-                // :the_kicker KICK #chan the_victim :reason
-                Some(kick) if kick == b"KICK" => {
-                    let mut params = msg.parameters();
-                    match (params.next(), params.next()) {
-                        (Some(channel), Some(victim)) => {
-                            if case_cmp(&self.state.casemapping, victim, self.state.nick.as_bytes())
-                            {
-                                self.state.channels.retain(|x| x.as_bytes() != channel);
-                                if let Some(reason) = params.next() {
-                                    let channel = String::from_utf8_lossy(channel);
-                                    let reason_given = String::from_utf8_lossy(reason);
-                                    println!("Kicked from {}. reason: {}", channel, reason_given);
-                                }
-                            }
-                        }
-                        _ => (),
-                    }
-                }
-                Some(invite) if invite == b"INVITE" => {}
-                Some(identified) if identified == b"004" => {
-                    self.state.ready_state = IrcState::Authenticated;
-                    self.write_buffer
-                        .extend(join_channels(&self.state.channels));
-                    self.state.channels.clear(); // remove all channels, we re-add them when we get a JOIN
-                }
-                Some(isupport) if isupport == b"005" => {
-                    self.state.ready_state = IrcState::Ready(true);
-                    // todo!(); // parse ISUPPORT
-                }
-                // reply to NAMES(X) Command or message sent on joining a channel
-                Some(names_repl) if names_repl == b"353" => {
-                    //if self.state.ready_state == IrcState::Ready(true) {
-                    //    todo!()
-                    //}
-                }
-                // nickname collision
-                Some(nick_col) if nick_col == b"433" || nick_col == b"436" => {
-                    if self.state.original_nick.is_none() {
-                        self.state.original_nick = Some(self.state.nick.clone());
-                    }
-
-                    self.state.nick.push('_');
-                    for _ in 0..4 {
-                        // generate a number that is in [0, 9)
-                        let a: char = self.rng.gen_range('0'..':');
-                        self.state.nick.push(a);
-                    }
-
-                    self.write_buffer
-                        .extend(format!("NICK {}\r\n", self.state.nick).as_bytes());
-                    println!("WARN: NICK COLLIDE; Trying new nick: {:?}", self.state.nick);
-                    ret = IrcProto::Data;
-                }
-                Some(bad_pass) if bad_pass == b"464" => {
-                    return IrcProto::Error("Invalid password given in PASS command.".to_owned());
-                }
-                Some(banned) if banned == b"465" => {
-                    return IrcProto::Error("We are banned.".to_owned());
-                }
-                Some(cap) if cap == b"CAP" => {
-                    if !parse_cap(&msg) {
-                        return IrcProto::Error(
-                            "We did not receive and ACK for multi-prefix".to_owned(),
-                        );
-                    } else {
-                        self.write_buffer.extend(b"CAP END\r\n");
-                        ret = IrcProto::Data;
-                    }
-                }
-                Some(cap) if cap == b"903" => {
-                    todo!() // implement sasl challenge & response
-                }
-                Some(cap)
-                    if cap == b"902"
-                        || cap == b"903"
-                        || cap == b"904"
-                        || cap == b"905"
-                        || cap == b"906" =>
-                {
-                    return IrcProto::Error("We had an SASL problem.".to_owned());
-                }
-                Some(pong) if pong == b"PONG" => {
-                    println!("DEBUG: PONG recv. TODO");
-                }
-                Some(any) => {
-                    let str_n = if let Some(nick) = msg.nick {
-                        String::from_utf8_lossy(nick).to_string()
-                    } else {
-                        "<NO NICK>".to_owned()
-                    };
-                    let str_c = String::from_utf8_lossy(any);
-                    let str_p = if let Some(params) = msg.params {
-                        String::from_utf8_lossy(params).to_string()
-                    } else {
-                        "".to_owned()
-                    };
-                    println!("Unknown command: {} {} {}", str_n, str_c, str_p);
+            b"MONITOR" => {
+                self.monitor = Some(String::from_utf8_lossy(value).parse().unwrap_or(usize::MAX))
+            }
+            b"LINELEN" => {
+                if let Ok(len) = String::from_utf8_lossy(value).parse() {
+                    self.linelen = len;
                 }
-                None => unreachable!(),
             }
+            _ => (),
         }
+    }
 
-        // move partial read to front of buffer, set read head up
-        if partial_idx != partial_end {
-            let edit = &mut self.read_buffer[..len];
-            edit.copy_within(partial_idx..partial_end, 0);
-            self.read_head = partial_end - partial_idx;
-        } else {
-            self.read_head = 0;
+    /// Our user@host from a message of ours the server relayed back, e.g. a JOIN.
+    fn note_userhost(&mut self, msg: &Message) {
+        if let (Some(user), Some(host)) = (msg.user, msg.host) {
+            self.set_userhost(user, host);
         }
-
-        ret
     }
 
-    pub fn receive_data<T: Read>(&mut self, readable: &mut T) -> Result<ClientReadStat, io::Error> {
-        if self.read_head == self.read_buffer.len() {
-            return Ok(ClientReadStat::ReadBufferFull);
+    fn set_userhost(&mut self, user: &[u8], host: &[u8]) {
+        let hostmask = format!(
+            "{}!{}@{}",
+            self.nick,
+            String::from_utf8_lossy(user),
+            String::from_utf8_lossy(host)
+        );
+        if self.hostmask.as_deref() != Some(&hostmask) {
+            info!("Others see us as {}", hostmask);
+            self.hostmask = Some(hostmask);
         }
+    }
 
-        let buf = &mut self.read_buffer[self.read_head..];
-        let size = match readable.read(buf) {
-            Ok(size) if size == 0 => return Ok(ClientReadStat::Eof),
-            Ok(size) => size + self.read_head,
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(ClientReadStat::Blocked),
-            Err(e) => return Err(e),
+    /// The server gave us another host, e.g. a cloak.
+    /// Returns false if we don't know our user@host yet.
+    fn set_host(&mut self, host: &[u8]) -> bool {
+        let user = match self
+            .hostmask
+            .as_deref()
+            .and_then(|mask| mask.split_once('!'))
+        {
+            Some((_, userhost)) => userhost.split('@').next().unwrap_or_default().to_owned(),
+            None => return false,
         };
+        self.set_userhost(user.as_bytes(), host);
+        true
+    }
+
+    /// How many bytes of text fit in a PRIVMSG or NOTICE to target, once the server
+    /// puts our :nick!user@host in front of it and it still has to fit in LINELEN.
+    /// Until we know our user@host, it's taken to be as long as it may be.
+    pub fn text_budget(&self, command: &str, target: &str) -> usize {
+        let hostmask = self
+            .hostmask
+            .as_ref()
+            .map_or(self.nick.len() + 1 + USERHOST_MAX, String::len);
+        // ":nick!user@host COMMAND target :text\r\n"
+        let relayed = self
+            .linelen
+            .saturating_sub(1 + hostmask + 1 + command.len() + 1 + target.len() + 2 + 2);
+        // and our own line, "COMMAND target :text", has to fit in ours.
+        relayed.min(MAX_LINE.saturating_sub(command.len() + 1 + target.len() + 2))
+    }
 
-        match self.handle_data(size) {
-            IrcProto::Okay => Ok(ClientReadStat::Okay),
-            IrcProto::Data => Ok(ClientReadStat::HasWritableData),
-            IrcProto::Error(e) => Ok(ClientReadStat::Error(e)),
+    fn apply_umodes(&mut self, modes: &[u8]) {
+        for (set, mode) in parse_umodes(modes) {
+            if set {
+                self.umode.insert(mode);
+            } else {
+                self.umode.remove(&mode);
+            }
         }
     }
 
-    fn process_plugbuff(&mut self, plug: &mut Plugin) -> bool {
-        let mut has_data = false;
-        let mut has_trunc = false;
-        let mut slice_at = 0usize;
-        for line in plug.iter() {
-            match line {
-                // todo, implement command lang?
-                TruncStatus::Full(data) => {
-                    has_data = true;
-                    self.write_buffer.extend(data);
-                    self.write_buffer.extend(b"\r\n");
+    /// Keep our privileges in channel current.
+    fn apply_chanmodes<'a>(
+        &mut self,
+        channel: &[u8],
+        modes: &[u8],
+        args: impl Iterator<Item = &'a [u8]>,
+    ) {
+        let key = self.chan_key(channel);
+        for change in self.modes.parse_changes(modes, args) {
+            let tracked = TRACKED_LISTS.contains(&change.mode) && self.modes.is_list(change.mode);
+            if let (true, Some(mask)) = (tracked, change.arg) {
+                let ours = self
+                    .hostmask
+                    .as_ref()
+                    .is_some_and(|mine| mask_match(mask, mine.as_bytes()));
+                if ours && change.set && change.mode == b'b' {
+                    warn!(
+                        "The ban {} in {} matches us.",
+                        String::from_utf8_lossy(mask),
+                        String::from_utf8_lossy(channel)
+                    );
                 }
-                TruncStatus::Part(partial) => {
-                    has_trunc = true;
-                    slice_at = plug.get_slice_pos(partial);
+                self.update_list(channel, change.mode, mask, change.set);
+                continue;
+            }
+            let (bit, nick) = match (self.modes.bit(change.mode), change.arg) {
+                (Some(bit), Some(nick)) => (bit, nick),
+                _ => continue,
+            };
+            let update = |bits: &mut u64| {
+                if change.set {
+                    *bits |= bit;
+                } else {
+                    *bits &= !bit;
                 }
+            };
+            let nick_key = irc_uppercase(&self.casemapping, nick);
+            if let Some(member) = self
+                .members
+                .get_mut(&key)
+                .and_then(|members| members.get_mut(&nick_key))
+            {
+                update(member);
+            }
+            if case_cmp(&self.casemapping, nick, self.nick.as_bytes()) {
+                update(self.channel_modes.entry(key.clone()).or_default());
             }
         }
+    }
 
-        if !has_trunc {
-            plug.reset_buf();
-            plug.split_at(slice_at);
+    /// Members and their privileges from a NAMES reply; with multi-prefix every prefix
+    /// is listed.
+    fn note_names(&mut self, channel: &[u8], names: &[u8]) {
+        let key = self.chan_key(channel);
+        for name in names
+            .split(|&chr| chr == b' ')
+            .filter(|name| !name.is_empty())
+        {
+            let start = name
+                .iter()
+                .position(|&chr| self.modes.prefix_bit(chr).is_none())
+                .unwrap_or(name.len());
+            let bits = name[..start]
+                .iter()
+                .filter_map(|&chr| self.modes.prefix_bit(chr))
+                .fold(0, |bits, bit| bits | bit);
+            let nick = &name[start..];
+            if case_cmp(&self.casemapping, nick, self.nick.as_bytes()) {
+                self.channel_modes.insert(key.clone(), bits);
+            }
+            self.members
+                .entry(key.clone())
+                .or_default()
+                .insert(irc_uppercase(&self.casemapping, nick), bits);
         }
+    }
 
-        has_data
+    /// The privileges of nick in channel, e.g. "@"; empty if they're not there or have none.
+    pub fn member_privileges(&self, channel: &str, nick: &str) -> String {
+        let bits = self.member_bits(channel, nick).unwrap_or_default();
+        self.modes.prefixes(bits)
     }
 
-    pub fn process_plugin(&mut self, plug: &mut Plugin) -> io::Result<bool> {
-        let mut has_data = false;
-        loop {
-            match plug.receive()? {
-                PluginReadStat::Okay => (),
-                PluginReadStat::Eof => break,
-                PluginReadStat::Blocked => break,
-                // buffer needs to processed to make progress
-                PluginReadStat::ReadBufferFull => {
-                    // If true, we have writable data
-                    if self.process_plugbuff(plug) {
-                        has_data = true;
-                    }
-                }
-            }
+    /// If nick is a channel operator (or higher) in channel.
+    pub fn is_op(&self, channel: &str, nick: &str) -> bool {
+        self.member_bits(channel, nick)
+            .is_some_and(|bits| self.modes.is_op(bits))
+    }
+
+    fn member_bits(&self, channel: &str, nick: &str) -> Option<u64> {
+        self.members
+            .get(&self.chan_key(channel.as_bytes()))?
+            .get(&irc_uppercase(&self.casemapping, nick.as_bytes()))
+            .copied()
+    }
+
+    fn member_join(&mut self, channel: &[u8], nick: &[u8]) {
+        let nick = irc_uppercase(&self.casemapping, nick);
+        self.members
+            .entry(self.chan_key(channel))
+            .or_default()
+            .insert(nick, 0);
+    }
+
+    /// nick left channel; if it's us, we forget the channel.
+    fn member_part(&mut self, channel: &[u8], nick: &[u8]) {
+        let key = self.chan_key(channel);
+        if case_cmp(&self.casemapping, nick, self.nick.as_bytes()) {
+            self.members.remove(&key);
+            self.channel_modes.remove(&key);
+            self.topics.remove(&key);
+            self.lists.remove(&key);
+        } else if let Some(members) = self.members.get_mut(&key) {
+            members.remove(&irc_uppercase(&self.casemapping, nick));
         }
-        if self.process_plugbuff(plug) {
-            has_data = true;
+        self.forget_departed();
+    }
+
+    fn member_quit(&mut self, nick: &[u8]) {
+        let nick = irc_uppercase(&self.casemapping, nick);
+        for members in self.members.values_mut() {
+            members.remove(&nick);
         }
-        Ok(has_data)
+        self.forget_departed();
     }
 
-    pub fn write_data<T: Write>(&mut self, writable: &mut T) -> Result<ClientWriteStat, io::Error> {
-        if self.is_empty() {
-            return Ok(ClientWriteStat::Eof);
+    fn member_rename(&mut self, old: &[u8], new: &[u8]) {
+        let (old, new) = (
+            irc_uppercase(&self.casemapping, old),
+            irc_uppercase(&self.casemapping, new),
+        );
+        for members in self.members.values_mut() {
+            if let Some(bits) = members.remove(&old) {
+                members.insert(new.clone(), bits);
+            }
         }
+        self.users.rename(&old, &new);
+    }
 
-        let wlen = cmp::min(BUF_SIZ, self.write_buffer.len());
-        let mut wbuf = self.write_buffer.drain(..wlen).collect::<Vec<u8>>();
+    /// Add or remove a channel we're waiting to hear back from about a JOIN.
+    fn note_joining(&mut self, channel: &[u8], joining: bool) {
+        let casemap = &self.casemapping;
+        self.joining
+            .retain(|chan| !case_cmp(casemap, chan.as_bytes(), channel));
+        if joining {
+            self.joining
+                .push(String::from_utf8_lossy(channel).to_string());
+        }
+    }
 
-        match writable.write(&wbuf) {
-            Ok(size) if size != wlen => {
-                let (_, unwritten) = wbuf.split_at(size);
-                for &byte in unwritten.iter().rev() {
-                    self.write_buffer.push_front(byte);
-                }
-                return Ok(ClientWriteStat::Okay);
-            }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // no extend_front
-                wbuf.reverse();
-                for byte in wbuf {
-                    self.write_buffer.push_front(byte);
-                }
-                return Ok(ClientWriteStat::Blocked);
-            }
-            Err(e) => {
-                return Err(e);
+    /// The masks on a list mode of channel, e.g. b for bans or q for quiets.
+    pub fn list(&self, channel: &str, mode: u8) -> &[String] {
+        self.lists
+            .get(&self.chan_key(channel.as_bytes()))
+            .and_then(|lists| lists.get(&mode))
+            .map_or(&[], |masks| masks.as_slice())
+    }
+
+    /// The ban in channel matching nick!user@host, if any.
+    pub fn ban_matching(&self, channel: &str, hostmask: &str) -> Option<&str> {
+        self.list(channel, b'b')
+            .iter()
+            .find(|ban| mask_match(ban.as_bytes(), hostmask.as_bytes()))
+            .map(|ban| ban.as_str())
+    }
+
+    /// Add or remove a mask from a list mode of channel.
+    fn update_list(&mut self, channel: &[u8], mode: u8, mask: &[u8], set: bool) {
+        let mask = String::from_utf8_lossy(mask).to_string();
+        let masks = self
+            .lists
+            .entry(self.chan_key(channel))
+            .or_default()
+            .entry(mode)
+            .or_default();
+        let pos = masks.iter().position(|old| old.eq_ignore_ascii_case(&mask));
+        match (set, pos) {
+            (true, None) if masks.len() < MAX_LIST_MASKS => masks.push(mask),
+            (false, Some(pos)) => {
+                masks.remove(pos);
             }
             _ => (),
-        };
-
-        Ok(ClientWriteStat::Okay)
+        }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.write_buffer.is_empty()
+    /// The current topic of channel, if it has one we know of.
+    pub fn topic(&self, channel: &str) -> Option<&Topic> {
+        self.topics.get(&self.chan_key(channel.as_bytes()))
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::io::{Cursor, Write};
+    /// Set or, with an empty text, clear the topic of channel.
+    fn set_topic(&mut self, channel: &[u8], text: &[u8], setter: &[u8], set_at: u64) {
+        let key = self.chan_key(channel);
+        if text.is_empty() {
+            self.topics.remove(&key);
+            return;
+        }
+        let topic = Topic {
+            text: String::from_utf8_lossy(text).to_string(),
+            setter: String::from_utf8_lossy(setter).to_string(),
+            set_at,
+        };
+        self.topics.insert(key, topic);
+    }
 
-    use crate::{config::config_file::Config, irc::parse::Message};
+    /// Who set the topic, and when, from RPL_TOPICWHOTIME.
+    fn note_topic_setter(&mut self, channel: &[u8], setter: &[u8], set_at: u64) {
+        if let Some(topic) = self.topics.get_mut(&self.chan_key(channel)) {
+            // some servers send the full hostmask.
+            let nick = setter.split(|&chr| chr == b'!').next().unwrap_or_default();
+            topic.setter = String::from_utf8_lossy(nick).to_string();
+            topic.set_at = set_at;
+        }
+    }
 
-    use super::{Client, ClientReadStat, ClientWriteStat};
+    /// If we are a network operator.
+    pub fn is_oper(&self) -> bool {
+        self.umode.contains(&b'o') || self.umode.contains(&b'O')
+    }
 
-    const DEFAULT_CONF: &str = r##"
-[general]
-nick = "bot"
-server = "localhost"
-port = 9643
-tls = false
+    /// If we know nick to be away, e.g. from away-notify.
+    pub fn is_away(&self, nick: &str) -> bool {
+        self.away_message(nick).is_some()
+    }
 
-[commands]
-test = "./test"
-"##;
-    const DEFAULT_GREETER: &str = "CAP REQ :multi-prefix\r
-NICK bot\r
-USER bot +i * :bot\r
-";
+    pub fn away_message(&self, nick: &str) -> Option<&str> {
+        self.away
+            .get(&irc_uppercase(&self.casemapping, nick.as_bytes()))
+            .map(String::as_str)
+    }
 
-    #[test]
-    fn irc_client_greeter() {
-        let conf = Config::from_str(DEFAULT_CONF).unwrap();
-        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        let mut c = Client::new(&conf);
-        c.write_data(&mut fake_io).unwrap();
-        assert_eq!(fake_io.get_ref(), DEFAULT_GREETER.as_bytes());
+    /// Set or, with None, clear the away message of nick.
+    fn set_away(&mut self, nick: &[u8], message: Option<&[u8]>) {
+        let key = irc_uppercase(&self.casemapping, nick);
+        match message {
+            Some(message) => {
+                self.away
+                    .insert(key, String::from_utf8_lossy(message).to_string());
+            }
+            None => {
+                self.away.remove(&key);
+            }
+        }
     }
 
-    fn replace_with(cur: &mut Cursor<Vec<u8>>, data: Option<&[u8]>) {
-        cur.get_mut().clear();
-        cur.set_position(0);
-        if let Some(data) = data {
-            cur.write_all(data).unwrap();
+    /// Record the services account of the sender of msg.
+    /// "*" means the user is not logged in.
+    fn note_account(&mut self, msg: &Message, account: &[u8]) {
+        if let (Some(nick), Some(user), Some(host)) = (msg.nick, msg.user, msg.host) {
+            if account == b"*" {
+                self.users.forget_account(user, host);
+            } else {
+                let nick = irc_uppercase(&self.casemapping, nick);
+                self.users.learn_account(&nick, user, host, account);
+            }
         }
-        cur.set_position(0);
     }
 
-    fn read_expect(c: &mut Client, cur: &mut Cursor<Vec<u8>>, exp_res: ClientReadStat) {
-        let status = c.receive_data(cur).unwrap();
-        assert_eq!(status, exp_res);
-        replace_with(cur, None);
+    /// Drop accounts learned for nicks that share no channel with us anymore.
+    fn forget_departed(&mut self) {
+        let members = &self.members;
+        self.users
+            .retain_nicks(|nick| members.values().any(|chan| chan.contains_key(nick)));
     }
+}
 
-    fn write_expect(
-        c: &mut Client,
-        cur: &mut Cursor<Vec<u8>>,
-        exp_res: ClientWriteStat,
+/// Capabilities we request when the server offers them, each on its own line
+/// so one refused doesn't take the others with it. multi-prefix is required.
+const CAPS: &[&str] = &[
+    "multi-prefix",
+    "server-time",
+    "batch",
+    "draft/chathistory",
+    "echo-message",
+    "labeled-response",
+    "extended-join",
+    "account-notify",
+    "account-tag",
+    "away-notify",
+];
+
+/// The line with new_text in place of text, which becomes the trailing param.
+fn retext(line: &[u8], text: &[u8], new_text: &[u8]) -> Vec<u8> {
+    let start = text.as_ptr() as usize - line.as_ptr() as usize;
+    let mut new_line = line[..start].to_vec();
+    if !new_line.ends_with(b":") {
+        new_line.push(b':');
+    }
+    new_line.extend(new_text);
+    new_line
+}
+
+fn login_command(nick: &str, user: &str, realname: &str) -> [OutMessage; 3] {
+    [
+        OutMessage::new("CAP").param("LS").param("302"),
+        OutMessage::new("NICK").param(nick),
+        OutMessage::new("USER")
+            .param(user)
+            .param("0")
+            .param("*")
+            .trailing(realname),
+    ]
+}
+
+/// A plugin invocation waiting for a free slot.
+struct PendingPlugin {
+    path: String,
+    name: String,
+    channel: String,
+    route: Option<Route>,
+    sandbox: Sandbox,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+}
+
+/// The channels to join, without their keys.
+fn configured_channels(config: &Config) -> Vec<String> {
+    config
+        .general
+        .channels
+        .iter()
+        .map(|entry| split_key(entry).0)
+        .filter(|chan| !chan.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Keys given with the channels to join and in [channel_keys].
+fn channel_keys(config: &Config) -> HashMap<String, String> {
+    config
+        .general
+        .channels
+        .iter()
+        .filter_map(|entry| match split_key(entry) {
+            (chan, Some(key)) => Some((chan.to_owned(), key.to_owned())),
+            _ => None,
+        })
+        .chain(config.channel_keys.clone())
+        .collect()
+}
+
+fn channel_log(config: &Config) -> Option<ChannelLog> {
+    if config.general.log_channels {
+        Some(ChannelLog::new(
+            &config.general.log_dir,
+            config.general.log_fsync,
+        ))
+    } else {
+        None
+    }
+}
+
+fn friends(config: &Config) -> Vec<(String, u8)> {
+    config
+        .friends
+        .iter()
+        .filter_map(|(who, mode)| match mode.as_bytes() {
+            &[mode] => Some((who.clone(), mode)),
+            _ => {
+                warn!(
+                    "[friends] {:?} = {:?} is not one mode, ignoring.",
+                    who, mode
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+impl Client {
+    pub fn new(config: &Config, storage: Storage) -> Self {
+        let state = State {
+            nick: config.general.nick.clone(),
+            channels: configured_channels(config)
+                .iter()
+                .map(|chan| ChannelName::new(&CaseMapping::Rfc1459, chan.as_bytes()))
+                .collect(),
+            joining: vec![],
+            umode: HashSet::new(),
+            channel_modes: HashMap::new(),
+            members: HashMap::new(),
+            topics: HashMap::new(),
+            lists: HashMap::new(),
+            users: UserStore::default(),
+            history: History::new(config.general.history_size),
+            sent: SendStats::default(),
+            conn: ConnStats::default(),
+            plugin_runs: PluginStats::default(),
+            ready_state: IrcState::Unknown,
+            original_nick: None,
+            caps: HashSet::new(),
+            away: HashMap::new(),
+            casemapping: CaseMapping::Rfc1459,
+            chantypes: vec![b'#', b'&'],
+            modes: ModeSpec::default(),
+            monitor: None,
+            // RFC 1459, until ISUPPORT says otherwise.
+            nicklen: 9,
+            linelen: 512,
+            hostmask: None,
+        };
+        let rng_v = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (deferred_send, deferred_recv) = mpsc::channel();
+        let mut natives = Registry::default();
+        builtins::register(&mut natives, config);
+        let mut ret = Client {
+            state,
+            read_buffer: vec![0u8; BUF_SIZ],
+            read_start: 0,
+            read_head: 0,
+            overlong: None,
+            write_buffer: VecDeque::with_capacity(BUF_SIZ),
+            bulk: RateLimiter::with_burst(
+                Duration::from_millis(config.general.send_delay_ms),
+                config.general.send_burst,
+            ),
+            send_queue_max: config.general.send_queue_max,
+            overflow: config.general.send_queue_overflow,
+            overflowing: false,
+            send_overflowed: false,
+            rng: SmallRng::seed_from_u64(rng_v),
+            command_prefix: config.general.command_prefix.as_bytes().to_vec(),
+            commands: config.commands.clone(),
+            last_run: HashMap::new(),
+            channel_last_run: HashMap::new(),
+            gateways: config.gateways.iter().map(|gw| gw.mask.clone()).collect(),
+            natives,
+            spawned: vec![],
+            running: HashMap::new(),
+            pending: VecDeque::new(),
+            max_plugins: config.general.max_plugins,
+            plugin_queue: config.general.plugin_queue,
+            reply_notice: config.general.reply_notice,
+            reply_prefix_nick: config.general.reply_prefix_nick,
+            ctcp_version: config.general.ctcp_version.clone(),
+            ctcp_answered: HashMap::new(),
+            admins: config.general.admins.clone(),
+            bots: config.general.bots.clone(),
+            loop_guard: LoopGuard::new(config.general.replies_per_nick, config.general.loop_guard),
+            filter: WordFilter::new(&config.filter),
+            usermode: config.general.usermode.trim().to_owned(),
+            friends: friends(config),
+            cap_offered: HashMap::new(),
+            cap_requested: HashSet::new(),
+            sasl: config.general.sasl_mechanism.inspect(|_| {
+                if config.general.tls_client_cert.is_empty() {
+                    warn!("SASL EXTERNAL needs tls_client_cert.");
+                }
+            }),
+            sasl_pending: false,
+            secure: false,
+            sts: None,
+            cap_negotiating: true,
+            playback_max_age: config.general.playback_max_age,
+            batches: Batches::default(),
+            labels: Labels::default(),
+            chathistory: config.general.chathistory,
+            sandbox: config.sandbox.clone(),
+            snapshot: SnapshotHandle::default(),
+            storage,
+            storage_path: config.storage.path.clone(),
+            join_limiter: RateLimiter::new(Duration::from_millis(config.general.join_delay_ms)),
+            join_batch_size: config.general.join_batch_size,
+            channel_conf: config.channels.clone(),
+            workers: None,
+            deferred: Deferred::new(deferred_send, None),
+            deferred_recv,
+            schedule: Scheduler::default(),
+            matchers: vec![],
+            hooks: config.hooks.clone(),
+            watch: Watchlist::new(
+                config.general.monitor.clone(),
+                Duration::from_secs(config.general.ison_interval),
+            ),
+            whoises: Whoises::default(),
+            nickserv_password: config.general.nickserv_password.clone(),
+            oper_name: config.general.oper_name.clone(),
+            oper_password: config.general.oper_password.clone(),
+            join_after_identify: config.general.join_after_identify,
+            identify_timeout: Duration::from_secs(config.general.identify_timeout),
+            identify_deadline: None,
+            erroneous_nicks: 0,
+            identified: false,
+            motd_deadline: None,
+            ping_interval: Duration::from_secs(config.general.ping_interval),
+            last_heard: Instant::now(),
+            ping_sent: None,
+            timers: TimerQueue::default(),
+            channel_keys: channel_keys(config),
+            rejoin_on_kick: config.general.rejoin_on_kick,
+            join_retry: config.general.join_retry,
+            rejoins: HashMap::new(),
+            knock: config.general.knock,
+            configured: configured_channels(config),
+            autojoined: false,
+            chanlog: channel_log(config),
+            log_events: config.general.log_events,
+        };
+        ret.load_schedule(config);
+        ret.load_matchers(config);
+        ret.snapshot.publish(&ret.state);
+        // setup login write.
+        let or_nick = |value: &str| match value.trim() {
+            "" => ret.state.nick.clone(),
+            value => value.to_owned(),
+        };
+        // ident is one word.
+        let username = or_nick(&config.general.username)
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let realname = or_nick(&config.general.realname);
+        for line in login_command(&ret.state.nick, &username, &realname) {
+            ret.send(&line);
+        }
+        ret
+    }
+
+    fn load_schedule(&mut self, config: &Config) {
+        self.schedule = Scheduler::default();
+        for job in &config.schedule {
+            let when = if !job.cron.is_empty() {
+                match Cron::parse(&job.cron) {
+                    Ok(cron) => When::Cron(cron),
+                    Err(e) => {
+                        warn!("Skipping schedule for {}: {}", job.target, e);
+                        continue;
+                    }
+                }
+            } else if job.interval > 0 {
+                When::Every(Duration::from_secs(job.interval))
+            } else {
+                warn!("Skipping schedule for {}: no cron or interval.", job.target);
+                continue;
+            };
+            self.schedule.add(Instant::now(), when, job.clone());
+        }
+    }
+
+    fn load_matchers(&mut self, config: &Config) {
+        self.matchers.clear();
+        for matcher in &config.matchers {
+            match Regex::new(&matcher.regex) {
+                Ok(re) => self.matchers.push((re, matcher.clone())),
+                Err(e) => warn!("Skipping matcher {}: {}", matcher.name, e),
+            }
+        }
+    }
+
+    /// Apply a reloaded config while staying connected: join the channels it adds,
+    /// part the ones it drops, and pick up the commands, plugins and rate limits.
+    /// What it changes about the connection itself waits for the next one.
+    /// Returns true if we have data to write.
+    pub fn reload(&mut self, config: &Config) -> bool {
+        self.bulk.set_pace(
+            Duration::from_millis(config.general.send_delay_ms),
+            config.general.send_burst,
+        );
+        self.send_queue_max = config.general.send_queue_max;
+        self.overflow = config.general.send_queue_overflow;
+        self.join_limiter
+            .set_pace(Duration::from_millis(config.general.join_delay_ms), 0);
+        self.join_batch_size = config.general.join_batch_size;
+
+        self.command_prefix = config.general.command_prefix.as_bytes().to_vec();
+        self.commands = config.commands.clone();
+        builtins::reload(&mut self.natives, config);
+        self.gateways = config.gateways.iter().map(|gw| gw.mask.clone()).collect();
+        self.channel_conf = config.channels.clone();
+        self.load_matchers(config);
+        self.load_schedule(config);
+        self.hooks = config.hooks.clone();
+        self.sandbox = config.sandbox.clone();
+        self.max_plugins = config.general.max_plugins;
+        self.plugin_queue = config.general.plugin_queue;
+        self.reply_notice = config.general.reply_notice;
+        self.reply_prefix_nick = config.general.reply_prefix_nick;
+        self.ctcp_version = config.general.ctcp_version.clone();
+        self.admins = config.general.admins.clone();
+        self.bots = config.general.bots.clone();
+        self.loop_guard
+            .set_limits(config.general.replies_per_nick, config.general.loop_guard);
+        self.filter = WordFilter::new(&config.filter);
+        self.friends = friends(config);
+        self.channel_keys = channel_keys(config);
+        self.rejoin_on_kick = config.general.rejoin_on_kick;
+        self.join_retry = config.general.join_retry;
+        self.knock = config.general.knock;
+        self.ping_interval = Duration::from_secs(config.general.ping_interval);
+        self.chanlog = channel_log(config);
+        self.log_events = config.general.log_events;
+
+        let casemap = self.state.casemapping;
+        let configured = configured_channels(config);
+        let missing = |from: &[String], chans: &[String]| {
+            from.iter()
+                .filter(|chan| {
+                    !chans
+                        .iter()
+                        .any(|other| case_cmp(&casemap, chan.as_bytes(), other.as_bytes()))
+                })
+                .cloned()
+                .collect::<Vec<String>>()
+        };
+        let added = missing(&configured, &self.configured);
+        let dropped = missing(&self.configured, &configured);
+        self.configured = configured;
+
+        // before we join, the channels to join are all there is to change.
+        if !self.autojoined {
+            self.state
+                .channels
+                .retain(|chan| !dropped.iter().any(|gone| chan.is(gone.as_bytes())));
+            self.state.channels.extend(
+                added
+                    .iter()
+                    .map(|chan| ChannelName::new(&casemap, chan.as_bytes())),
+            );
+            return false;
+        }
+        let mut ret = false;
+        for chan in dropped {
+            let joined = self.state.channels.iter().any(|c| c.is(chan.as_bytes()));
+            let joining = self
+                .state
+                .joining
+                .iter()
+                .any(|c| case_cmp(&casemap, c.as_bytes(), chan.as_bytes()));
+            if joined || joining {
+                self.state.note_joining(chan.as_bytes(), false);
+                self.queue("irc", OutMessage::new("PART").param(&chan));
+                ret = true;
+            }
+        }
+        self.join(&added);
+        ret
+    }
+
+    fn is_me(&self, msg: &Message) -> bool {
+        if let Some(my_nick) = msg.nick {
+            // Looks like the server changed my name.
+            case_cmp(&self.state.casemapping, my_nick, self.state.nick.as_bytes())
+        } else {
+            false
+        }
+    }
+
+    /// The connection is encrypted, for the sts capability.
+    pub fn set_secure(&mut self) {
+        self.secure = true;
+    }
+
+    /// What the server's STS policy wants the connection to do, if anything new.
+    pub fn take_sts(&mut self) -> Option<Sts> {
+        self.sts.take()
+    }
+
+    /// Remember capabilities offered by CAP LS or NEW.
+    fn offer_caps(&mut self, caps: &[CapValue]) {
+        for (cap, value) in caps {
+            if *cap == b"sts" {
+                // a plain connection only learns where to upgrade, a secure one how long for.
+                match (self.secure, parse_sts(&String::from_utf8_lossy(value))) {
+                    (false, (Some(port), _)) => self.sts = Some(Sts::Upgrade(port)),
+                    (true, (_, Some(duration))) => self.sts = Some(Sts::Persist(duration)),
+                    _ => (),
+                }
+            }
+            self.cap_offered.insert(
+                String::from_utf8_lossy(cap).to_string(),
+                String::from_utf8_lossy(value).to_string(),
+            );
+        }
+    }
+
+    /// If the server offers sasl, with our mechanism.
+    fn sasl_offered(&self) -> bool {
+        let (mechanism, offered) = match (self.sasl, self.cap_offered.get("sasl")) {
+            (Some(mechanism), Some(offered)) => (mechanism.name(), offered),
+            _ => return false,
+        };
+        // a server may leave out the mechanisms it has.
+        if offered.is_empty() || offered.split(',').any(|mech| mech == mechanism) {
+            true
+        } else {
+            warn!("The server offers SASL {}, not {}.", offered, mechanism);
+            false
+        }
+    }
+
+    /// REQ the capabilities we want that are offered, but not enabled or requested yet.
+    /// Returns true if we have data to write.
+    fn request_caps(&mut self) -> bool {
+        let sasl = if self.sasl_offered() {
+            Some(&"sasl")
+        } else {
+            None
+        };
+        let wanted = CAPS
+            .iter()
+            .filter(|cap| self.cap_offered.contains_key(**cap))
+            .chain(sasl)
+            .filter(|cap| !self.state.caps.contains(**cap) && !self.cap_requested.contains(**cap))
+            .map(|cap| cap.to_string())
+            .collect::<Vec<String>>();
+        for cap in &wanted {
+            self.send(&OutMessage::new("CAP").param("REQ").trailing(cap));
+            self.cap_requested.insert(cap.clone());
+        }
+        !wanted.is_empty()
+    }
+
+    /// The server answered a REQ with ACK or NAK.
+    /// Returns true if this ended negotiation, and we have CAP END to write.
+    fn cap_answered(&mut self, caps: &[CapValue]) -> bool {
+        // the answer lists everything the REQ did, one of them is enough to find it.
+        if let Some((cap, _)) = caps.first() {
+            let cap = String::from_utf8_lossy(cap);
+            self.cap_requested.remove(cap.trim_start_matches('-'));
+        }
+        self.end_caps()
+    }
+
+    /// The server is ready for our SASL credentials.
+    fn authenticate(&mut self, msg: &Message) -> IrcProto {
+        // EXTERNAL has nothing to say, the server knows us by our certificate.
+        if self.sasl_pending && msg.parameters().next() == Some(b"+") {
+            self.send(&OutMessage::new("AUTHENTICATE").param("+"));
+            return IrcProto::Data;
+        }
+        IrcProto::Okay
+    }
+
+    /// Send CAP END, if negotiation is done and there's no SASL login going on.
+    fn end_caps(&mut self) -> bool {
+        if self.cap_negotiating && self.cap_requested.is_empty() && !self.sasl_pending {
+            self.cap_negotiating = false;
+            self.send(&OutMessage::new("CAP").param("END"));
+            return true;
+        }
+        false
+    }
+
+    /// A capability was disabled or the server no longer offers it.
+    fn cap_removed(&mut self, cap: &str) {
+        if !self.state.caps.remove(cap) {
+            return;
+        }
+        match cap {
+            // nothing will answer what's pending.
+            "labeled-response" => self.labels = Labels::default(),
+            "multi-prefix" => {
+                warn!("Without multi-prefix we may not know all channel modes.")
+            }
+            _ => (),
+        }
+    }
+
+    /// A message stamped (server-time) longer than playback_max_age ago,
+    /// e.g. a bouncer replaying what we missed after we reconnect.
+    fn is_playback(&self, msg: &Message) -> bool {
+        if self.playback_max_age == 0 {
+            return false;
+        }
+        match msg.tag(b"time").and_then(parse_server_time) {
+            Some(sent) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                now.saturating_sub(sent) > self.playback_max_age
+            }
+            None => false,
+        }
+    }
+
+    /// Write a channel event to its log and as a log record, if we keep them.
+    fn log_event(&mut self, msg: &Message) {
+        if self.chanlog.is_none() && !self.log_events {
+            return;
+        }
+        let (channel, line) = match format_event(msg, &self.state.chantypes) {
+            Some(event) => event,
+            None => return,
+        };
+        if self.log_events {
+            event!(
+                &channel,
+                &String::from_utf8_lossy(msg.nick.unwrap_or_default()),
+                "{}",
+                line
+            );
+        }
+        if let Some(log) = self.chanlog.as_mut() {
+            let at = msg
+                .tag(b"time")
+                .and_then(parse_server_time)
+                .unwrap_or_else(|| {
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs())
+                });
+            if let Err(e) = log.write(&channel, at, &line) {
+                warn!("Could not log to {}: {}", channel, e);
+            }
+        }
+    }
+
+    /// Log a message of ours the server echoed, when it says it was sent.
+    fn note_echo(&mut self, msg: &Message, target: &str, text: &str) {
+        let time = String::from_utf8_lossy(msg.tag(b"time").unwrap_or(b"?")).to_string();
+        let label = msg.tag(b"label").map(String::from_utf8_lossy);
+        match label.and_then(|label| self.labels.echo(&label, target, text)) {
+            Some(Echo::Rewritten(sent)) => warn!(
+                "The server changed our message to {} ({}): {:?} became {:?}",
+                target, time, sent.text, text
+            ),
+            _ => info!("Sent to {} ({}): {}", target, time, text),
+        }
+    }
+
+    // or in modern words "direct message"
+    fn is_private_message(&self, target: &[u8]) -> bool {
+        case_cmp(&self.state.casemapping, target, self.state.nick.as_bytes())
+    }
+
+    /// Add a native plugin; these are dispatched before external plugins.
+    pub fn register_native(&mut self, plugin: Box<dyn BotPlugin>) {
+        self.natives.register(plugin);
+    }
+
+    /// Release paced output that is due.
+    /// Returns true if we have data to write.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        let mut has_data = false;
+        // plugins reaped since.
+        self.state.plugin_runs.settle();
+        match self.motd_deadline {
+            Some(deadline) if deadline <= now => {
+                warn!("The server never finished the MOTD, joining anyway.");
+                has_data = self.end_of_motd(now);
+            }
+            _ => (),
+        }
+        match self.identify_deadline {
+            Some(deadline) if deadline <= now => {
+                warn!("Services did not confirm we are identified, joining anyway.");
+                self.join_configured();
+            }
+            _ => (),
+        }
+        let lines = self.join_limiter.ready(now);
+        has_data |= !lines.is_empty();
+        for line in lines {
+            self.queue_line("join", &line);
+        }
+        if self.ping_sent.is_none() && self.keepalive_deadline().is_some_and(|at| at <= now) {
+            // anything the server says will do as an answer.
+            self.queue("irc", OutMessage::new("PING").trailing("keepalive"));
+            self.ping_sent = Some(now);
+            has_data = true;
+        }
+        let deferred = self
+            .deferred_recv
+            .try_iter()
+            .collect::<Vec<(String, Vec<String>)>>();
+        for (source, lines) in deferred {
+            has_data |= self.queue_lines(&source, lines);
+        }
+        for job in self.schedule.due(now) {
+            has_data |= self.run_scheduled(&job);
+        }
+        for (source, line) in self.timers.due(now) {
+            self.queue_line(&source, &line);
+            has_data = true;
+        }
+        for line in self.watch.due(now) {
+            self.queue_line("watch", line.as_bytes());
+            has_data = true;
+        }
+        for info in self.whoises.expire(now) {
+            warn!("The server never finished our WHOIS of {}.", info.nick);
+        }
+        for lost in self.labels.expire(now) {
+            warn!(
+                "The server never echoed our message to {}, it may be lost: {:?}",
+                lost.target, lost.text
+            );
+        }
+        has_data | self.release(now)
+    }
+
+    /// Returns true if we have data to write.
+    fn run_scheduled(&mut self, job: &Schedule) -> bool {
+        // we can't talk before we're registered.
+        if matches!(
+            self.state.ready_state,
+            IrcState::Unknown | IrcState::PreAuth
+        ) {
+            return false;
+        }
+        if !job.plugin.is_empty() {
+            let msg = PrivMsg {
+                nick: self.state.nick.clone(),
+                user: self.state.nick.clone(),
+                host: String::new(),
+                target: job.target.clone(),
+                reply_to: job.target.clone(),
+                private: false,
+                text: String::new(),
+                action: false,
+                policy: self.reply_policy(&job.target),
+                account: None,
+                admin: false,
+                relay: None,
+            };
+            let cmd = Command {
+                name: "schedule",
+                args: "",
+            };
+            self.spawn_plugin(&job.plugin, &msg, &cmd, vec![], vec![]);
+        }
+        if !job.message.is_empty() {
+            let line = OutMessage::new("PRIVMSG")
+                .param(&job.target)
+                .trailing(&job.message);
+            self.queue("schedule", line);
+            return true;
+        }
+        false
+    }
+
+    /// Wake the event loop when native plugins reply from other threads.
+    pub fn set_waker(&mut self, waker: Arc<Waker>) {
+        self.deferred.set_waker(waker);
+    }
+
+    /// When to PING the server, or after we did, when to give up on it.
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        if self.ping_interval.is_zero() {
+            return None;
+        }
+        Some(self.ping_sent.unwrap_or(self.last_heard) + self.ping_interval)
+    }
+
+    /// Carry the network's totals over from the connections before this one.
+    pub fn resume_stats(&mut self, started: Instant, reconnects: u64) {
+        self.state.conn.started = started;
+        self.state.conn.reconnects = reconnects;
+    }
+
+    /// If the server welcomed us, as opposed to failing before or during registration.
+    pub fn is_registered(&self) -> bool {
+        matches!(self.state.ready_state, IrcState::Ready(_))
+    }
+
+    /// The server did not answer our keepalive PING, the connection is likely dead.
+    pub fn timed_out(&self, now: Instant) -> bool {
+        self.ping_sent.is_some() && self.keepalive_deadline().is_some_and(|at| at <= now)
+    }
+
+    /// When tick() next has output to release.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        [
+            self.join_limiter.deadline(),
+            // with the write buffer full, the socket being writable is what we wait for.
+            self.bulk
+                .deadline()
+                .filter(|_| self.write_buffer.len() < BUF_SIZ),
+            self.schedule.deadline(),
+            self.identify_deadline,
+            self.motd_deadline,
+            self.timers.deadline(),
+            self.watch.deadline(),
+            self.whoises.deadline(),
+            self.labels.deadline(),
+            self.keepalive_deadline(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    /// The server is done greeting us: join, or wait until services identify us, and
+    /// start watching nicks.
+    /// Returns true if we have data to write.
+    fn end_of_motd(&mut self, now: Instant) -> bool {
+        self.motd_deadline = None;
+        if self.join_after_identify && !self.identified {
+            self.identify_deadline = Some(now + self.identify_timeout);
+        } else {
+            self.join_configured();
+        }
+        let lines = self.watch.start(self.state.monitor, now);
+        let has_data = !lines.is_empty();
+        for line in lines {
+            self.queue_line("watch", line.as_bytes());
+        }
+        has_data
+    }
+
+    /// Report why we couldn't join channel, knock or try again later if configured.
+    /// Returns true if we have data to write.
+    fn join_failed(&mut self, numeric: Numeric, channel: &[u8], text: &[u8]) -> bool {
+        self.state.note_joining(channel, false);
+        let channel = String::from_utf8_lossy(channel).to_string();
+        let why = JOIN_FAILURES
+            .iter()
+            .find(|(failure, _)| *failure == numeric)
+            .map_or("", |(_, why)| why);
+        warn!(
+            "Could not join {}, {}: {}",
+            channel,
+            why,
+            String::from_utf8_lossy(text)
+        );
+        if self.join_retry > 0 {
+            let delay = self.rejoin_delay(&channel, Duration::from_secs(self.join_retry));
+            self.join_later(&channel, delay);
+        }
+        if self.knock && numeric == Numeric::ErrInviteonlychan {
+            self.queue("join", OutMessage::new("KNOCK").param(&channel));
+            return true;
+        }
+        false
+    }
+
+    /// How long to wait before joining channel again: first, then twice as long for
+    /// every try that didn't keep us there, like reconnects.
+    fn rejoin_delay(&mut self, channel: &str, first: Duration) -> Duration {
+        let now = Instant::now();
+        let key = self.state.chan_key(channel.as_bytes());
+        let delay = match self.rejoins.get(&key) {
+            Some(&(next, due)) if now < due + REJOIN_RESET => next.max(first),
+            _ => first,
+        };
+        let next = (delay * 2).clamp(REJOIN_MIN, MAX_REJOIN.max(first));
+        self.rejoins.insert(key, (next, now + delay));
+        delay
+    }
+
+    /// JOIN channel, with its key, once delay passed.
+    fn join_later(&mut self, channel: &str, delay: Duration) {
+        let line = match self.channel_key(channel) {
+            Some(key) => OutMessage::new("JOIN").param(channel).param(key),
+            None => OutMessage::new("JOIN").param(channel),
+        };
+        let id = irc_uppercase(&self.state.casemapping, channel.as_bytes());
+        let id = String::from_utf8_lossy(&id);
+        let at = Instant::now() + delay;
+        if !self.timers.add(at, "join", Some(&id), line.line().to_vec()) {
+            warn!("Too many timers pending, not joining {} again.", channel);
+        }
+    }
+
+    /// base followed by _ and four random digits.
+    fn generate_nick(&mut self, base: &str) -> String {
+        let mut nick = format!("{}_", base);
+        for _ in 0..4 {
+            // generate a number that is in [0, 9)
+            let a: char = self.rng.gen_range('0'..':');
+            nick.push(a);
+        }
+        nick
+    }
+
+    /// Queue JOINs for the configured channels.
+    fn join_configured(&mut self) {
+        self.identify_deadline = None;
+        self.autojoined = true;
+        // we re-add them when we get a JOIN
+        let channels = std::mem::take(&mut self.state.channels)
+            .iter()
+            .map(|chan| chan.as_str().to_owned())
+            .collect::<Vec<String>>();
+        self.join(&channels);
+    }
+
+    /// Queue paced JOINs for channels, with their keys.
+    fn join(&mut self, channels: &[String]) {
+        for chan in channels {
+            self.state.note_joining(chan.as_bytes(), true);
+        }
+        let entries = channels
+            .iter()
+            .map(|chan| match self.channel_key(chan) {
+                Some(key) => format!("{} {}", chan, key),
+                None => chan.clone(),
+            })
+            .collect::<Vec<String>>();
+        for line in join_batches(&entries, self.join_batch_size) {
+            self.join_limiter.push(line);
+        }
+    }
+
+    fn channel_key(&self, channel: &str) -> Option<&str> {
+        self.channel_keys
+            .iter()
+            .find(|(name, _)| {
+                case_cmp(&self.state.casemapping, name.as_bytes(), channel.as_bytes())
+            })
+            .map(|(_, key)| key.as_str())
+    }
+
+    /// Join a channel, e.g. from the control socket, with its key if given or known.
+    pub fn join_channel(&mut self, entry: &str) {
+        if let (chan, Some(key)) = split_key(entry) {
+            self.channel_keys.insert(chan.to_owned(), key.to_owned());
+        }
+        self.join(&[split_key(entry).0.to_owned()]);
+    }
+
+    pub fn part_channel(&mut self, channel: &str) {
+        self.state.note_joining(channel.as_bytes(), false);
+        // nor join it again later.
+        let id = irc_uppercase(&self.state.casemapping, channel.as_bytes());
+        self.timers.cancel("join", &String::from_utf8_lossy(&id));
+        self.rejoins
+            .remove(&self.state.chan_key(channel.as_bytes()));
+        self.queue("control", OutMessage::new("PART").param(channel));
+    }
+
+    /// PRIVMSG a channel or nick, e.g. from the control socket.
+    pub fn say(&mut self, target: &str, text: &str) {
+        self.queue(
+            "control",
+            OutMessage::new("PRIVMSG").param(target).trailing(text),
+        );
+    }
+
+    /// Send a QUIT ahead of anything queued, e.g. when shutting down.
+    pub fn quit(&mut self, reason: &str) {
+        self.send(&OutMessage::new("QUIT").trailing(reason));
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// A handle to snapshots of our state, which can be read from other threads.
+    pub fn snapshot_handle(&self) -> SnapshotHandle {
+        self.snapshot.clone()
+    }
+
+    /// External plugins started by commands, hooks and jobs, for the transport to spawn
+    /// with [`Plugin::spawn`]. Tell plugin_failed() about the ones that would not.
+    pub fn take_spawns(&mut self) -> Vec<PluginSpawn> {
+        std::mem::take(&mut self.spawned)
+    }
+
+    /// Write a line as is, before anything we queue.
+    fn send(&mut self, line: &OutMessage) {
+        line.write_to(&mut self.write_buffer);
+    }
+
+    /// Queue a line put together elsewhere, e.g. by a plugin.
+    fn queue_line(&mut self, source: &str, line: &[u8]) {
+        self.queue(source, OutMessage::raw(line));
+    }
+
+    /// Queue a line, counting it against source in the send statistics.
+    fn queue(&mut self, source: &str, out: OutMessage) {
+        let out = match self.filter_out(source, out) {
+            Some(out) => out,
+            None => return,
+        };
+        for out in self.split_out(out) {
+            self.queue_one(source, out);
+        }
+    }
+
+    fn queue_one(&mut self, source: &str, mut out: OutMessage) {
+        let line = out.line().to_vec();
+        let msg = Message::new(&line);
+        let target = match (msg.command, msg.parameters().next()) {
+            (Some(b"PRIVMSG"), Some(target)) | (Some(b"NOTICE"), Some(target)) => {
+                match target.first() {
+                    Some(chr) if self.state.chantypes.contains(chr) => {
+                        Some(String::from_utf8_lossy(target).to_string())
+                    }
+                    _ => Some("(private)".to_owned()),
+                }
+            }
+            _ => None,
+        };
+        self.state
+            .sent
+            .record(Instant::now(), target.as_deref(), source, line.len() + 2);
+        if let (Some(_), Some(text)) = (&target, msg.parameters().nth(1)) {
+            self.loop_guard
+                .said(Instant::now(), &String::from_utf8_lossy(text));
+        }
+        // the server tells us of our JOINs and such, and with echo-message our PRIVMSGs.
+        if (self.chanlog.is_some() || self.log_events)
+            && msg.command == Some(b"PRIVMSG")
+            && !self.state.caps.contains("echo-message")
+        {
+            let mut ours = format!(":{} ", self.state.nick).into_bytes();
+            ours.extend(&line);
+            self.log_event(&Message::new(&ours));
+        }
+
+        if msg.command == Some(b"PRIVMSG") && self.state.caps.contains("labeled-response") {
+            let mut params = msg.parameters();
+            let (target, text) = (params.next(), params.next());
+            let lossy =
+                |part: Option<&[u8]>| String::from_utf8_lossy(part.unwrap_or_default()).to_string();
+            if let Some(label) = self
+                .labels
+                .attach(Instant::now(), &lossy(target), &lossy(text))
+            {
+                out = out.tag("label", &label);
+            }
+        }
+        let mut bytes = vec![];
+        out.write_to(&mut bytes);
+        self.push_bulk(bytes);
+    }
+
+    /// Strip formatting from a PRIVMSG or NOTICE to a channel with strip_formatting,
+    /// then mask or drop it if it has words of [filter] in it.
+    fn filter_out(&self, source: &str, out: OutMessage) -> Option<OutMessage> {
+        let line = out.body();
+        let msg = Message::new(line);
+        let mut params = msg.parameters();
+        let (target, text) = match (msg.command, params.next(), params.next()) {
+            (Some(b"PRIVMSG"), Some(target), Some(text))
+            | (Some(b"NOTICE"), Some(target), Some(text)) => (target, text),
+            _ => return Some(out),
+        };
+        let target = String::from_utf8_lossy(target);
+        let lossy = String::from_utf8_lossy(text);
+        let strip = channel_config(&self.state.casemapping, &self.channel_conf, &target)
+            .is_some_and(|conf| conf.strip_formatting);
+        let mut rewritten = None;
+        if strip && is_formatted(&lossy) {
+            rewritten = Some(strip_formatting(&lossy));
+        }
+        match self.filter.apply(rewritten.as_deref().unwrap_or(&lossy)) {
+            Some(Cow::Borrowed(_)) => (),
+            Some(Cow::Owned(masked)) => {
+                info!("Masked {:?} from {} to {}.", lossy, source, target);
+                rewritten = Some(masked);
+            }
+            None => {
+                warn!(
+                    "Not sending {:?} from {} to {}, the filter matched it.",
+                    lossy, source, target
+                );
+                return None;
+            }
+        }
+        let rewritten = match rewritten {
+            Some(rewritten) => rewritten,
+            None => return Some(out),
+        };
+        let new_line = retext(line, text, rewritten.as_bytes());
+        Some(out.with_line(&new_line))
+    }
+
+    /// Split a PRIVMSG or NOTICE that would be cut short once the server puts our
+    /// prefix in front of it into as many as it takes, each keeping the tags.
+    /// /me lines stay /me lines.
+    fn split_out(&self, out: OutMessage) -> Vec<OutMessage> {
+        let line = out.body();
+        let msg = Message::new(line);
+        let mut params = msg.parameters();
+        let (command, target, text) = match (msg.command, params.next(), params.next()) {
+            (Some(command @ b"PRIVMSG"), Some(target), Some(text))
+            | (Some(command @ b"NOTICE"), Some(target), Some(text)) => (command, target, text),
+            _ => return vec![out],
+        };
+        let budget = self.state.text_budget(
+            &String::from_utf8_lossy(command),
+            &String::from_utf8_lossy(target),
+        );
+        if text.len() <= budget {
+            return vec![out];
+        }
+        let lossy = String::from_utf8_lossy(text);
+        let action = lossy
+            .strip_prefix("\x01ACTION ")
+            .and_then(|action| action.strip_suffix('\x01'));
+        let pieces = match action {
+            Some(action) => split_text(action, budget.saturating_sub(9))
+                .into_iter()
+                .map(|piece| format!("\x01ACTION {}\x01", piece))
+                .collect::<Vec<String>>(),
+            None => split_text(&lossy, budget)
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+        };
+        pieces
+            .iter()
+            .map(|piece| out.clone().with_line(&retext(line, text, piece.as_bytes())))
+            .collect()
+    }
+
+    /// Queue a line behind the others, keeping what waits under send_queue_max.
+    fn push_bulk(&mut self, line: Vec<u8>) {
+        let max = self.send_queue_max;
+        if max == 0 || self.write_buffer.len() + self.bulk.bytes() + line.len() <= max {
+            self.overflowing = false;
+            self.bulk.push(line);
+            return;
+        }
+        if !self.overflowing {
+            warn!(
+                "Over {} bytes are waiting to be sent, the server is reading slowly.",
+                max
+            );
+            self.overflowing = true;
+        }
+        match self.overflow {
+            Overflow::DropNewest => self.state.sent.dropped(line.len()),
+            Overflow::DropOldest => {
+                self.bulk.push(line);
+                while self.write_buffer.len() + self.bulk.bytes() > max {
+                    match self.bulk.drop_oldest() {
+                        Some(old) => self.state.sent.dropped(old.len()),
+                        None => break,
+                    }
+                }
+            }
+            Overflow::Disconnect => {
+                self.bulk.push(line);
+                self.send_overflowed = true;
+            }
+        }
+    }
+
+    /// Move the queued lines that may go out by now to the write buffer, unless it is
+    /// still full; the rest waits where it can be dropped.
+    /// Returns true if we have data to write.
+    fn release(&mut self, now: Instant) -> bool {
+        if self.write_buffer.len() >= BUF_SIZ {
+            return false;
+        }
+        let lines = self.bulk.ready(now);
+        for line in &lines {
+            self.write_buffer.extend(line);
+        }
+        !lines.is_empty()
+    }
+
+    fn queue_lines(&mut self, source: &str, lines: Vec<String>) -> bool {
+        let has_data = !lines.is_empty();
+        for line in lines {
+            self.queue_line(source, line.as_bytes());
+        }
+        has_data
+    }
+
+    /// Queue lines from native plugins, labeled with the plugin that sent them.
+    fn queue_replies(&mut self, replies: Vec<(String, String)>) -> bool {
+        let has_data = !replies.is_empty();
+        for (source, line) in replies {
+            self.queue_line(&source, line.as_bytes());
+        }
+        has_data
+    }
+
+    /// Extra arguments and environment are added after the ones every plugin gets.
+    /// Returns true if we have data to write, i.e. we were too busy to run a command.
+    fn spawn_plugin(
+        &mut self,
+        path: &str,
+        msg: &PrivMsg,
+        cmd: &Command,
+        extra_args: Vec<String>,
+        mut env: Vec<(String, String)>,
+    ) -> bool {
+        let mut args = vec![
+            format!("--reply={}", msg.reply_to),
+            format!("--nick={}", msg.nick),
+            format!("--user={}", msg.user),
+            format!("--host={}", msg.host),
+            format!("--command={}", cmd.name),
+            format!("--message={}", cmd.args),
+        ];
+        args.extend(extra_args);
+        if !self.storage_path.is_empty() {
+            env.push(("R8_DB_PATH".to_owned(), self.storage_path.clone()));
+        }
+        let verbosity =
+            match channel_verbosity(&self.state.casemapping, &self.channel_conf, &msg.reply_to) {
+                Verbosity::Compact => "compact",
+                Verbosity::Normal => "normal",
+                Verbosity::Verbose => "verbose",
+            };
+        env.push(("R8_VERBOSITY".to_owned(), verbosity.to_owned()));
+        if msg.action {
+            env.push(("R8_ACTION".to_owned(), "1".to_owned()));
+        }
+        if let Some(account) = &msg.account {
+            env.push(("R8_ACCOUNT".to_owned(), account.clone()));
+        }
+        if msg.admin {
+            env.push(("R8_ADMIN".to_owned(), "1".to_owned()));
+        }
+        let admin_cmd = self.commands.get(cmd.name).is_some_and(|conf| conf.admin);
+        if admin_cmd && self.state.is_oper() {
+            env.push(("R8_BOT_OPER".to_owned(), "1".to_owned()));
+        }
+        if let (false, Some(topic)) = (msg.private, self.state.topic(&msg.reply_to)) {
+            env.push(("R8_TOPIC".to_owned(), topic.text.clone()));
+        }
+        if !msg.private {
+            let sender = self.state.member_privileges(&msg.reply_to, msg.sender());
+            env.push(("R8_SENDER_MODES".to_owned(), sender));
+            env.push((
+                "R8_BOT_MODES".to_owned(),
+                self.state.privileges(&msg.reply_to),
+            ));
+        }
+        if let (false, true) = (msg.private, self.is_moderator(cmd.name)) {
+            let bans = self.state.list(&msg.reply_to, b'b').join(" ");
+            env.push(("R8_BANS".to_owned(), bans));
+            let quiets = self.state.list(&msg.reply_to, b'q').join(" ");
+            env.push(("R8_QUIETS".to_owned(), quiets));
+        }
+
+        let pending = PendingPlugin {
+            path: path.to_owned(),
+            name: cmd.name.to_owned(),
+            channel: msg.reply_to.clone(),
+            route: match msg.reply_to.is_empty() {
+                true => None,
+                false => Some(msg.route()),
+            },
+            sandbox: self
+                .commands
+                .get(cmd.name)
+                .and_then(|conf| conf.sandbox.clone())
+                .unwrap_or_else(|| self.sandbox.clone()),
+            args,
+            env,
+        };
+        if self.can_start(&pending.name) {
+            self.start_plugin(pending);
+        } else if self.pending.len() < self.plugin_queue {
+            self.pending.push_back(pending);
+        } else if self.commands.contains_key(cmd.name) {
+            return self.queue_lines("irc", vec![msg.answer("Too busy, try again later.")]);
+        } else {
+            warn!("Too busy to run plugin {:?}", path);
+        }
+        false
+    }
+
+    /// How replies are sent in a channel.
+    fn reply_policy(&self, channel: &str) -> ReplyPolicy {
+        let conf = channel_config(&self.state.casemapping, &self.channel_conf, channel);
+        ReplyPolicy {
+            notice: conf
+                .and_then(|conf| conf.reply_notice)
+                .unwrap_or(self.reply_notice),
+            prefix_nick: conf
+                .and_then(|conf| conf.reply_prefix_nick)
+                .unwrap_or(self.reply_prefix_nick),
+        }
+    }
+
+    /// Whether another copy of a plugin fits in the concurrency limits.
+    fn can_start(&self, name: &str) -> bool {
+        let limit = self.commands.get(name).map_or(0, |conf| conf.max_running);
+        (self.max_plugins == 0 || self.running.len() < self.max_plugins)
+            && (limit == 0 || self.running.values().filter(|run| *run == name).count() < limit)
+    }
+
+    fn start_plugin(&mut self, pending: PendingPlugin) {
+        let id = plugin::next_id();
+        self.state.conn.invoked(&pending.name);
+        self.running.insert(id, pending.name.clone());
+        self.spawned.push(PluginSpawn {
+            id,
+            name: pending.name,
+            channel: pending.channel,
+            route: pending.route,
+            path: pending.path,
+            args: pending.args,
+            env: pending.env,
+            sandbox: pending.sandbox,
+        });
+    }
+
+    /// A plugin exited; account for it and start whatever was waiting on its slot.
+    pub fn plugin_done(&mut self, plug: &Plugin) {
+        self.state.plugin_runs.finished(
+            &plug.name,
+            plug.started.elapsed(),
+            plug.bytes_read,
+            plug.exit_code.clone(),
+        );
+        self.free_slot(plug.id);
+    }
+
+    /// The transport could not spawn a plugin, see take_spawns().
+    pub fn plugin_failed(&mut self, spawn: &PluginSpawn) {
+        self.free_slot(spawn.id);
+    }
+
+    fn free_slot(&mut self, id: usize) {
+        self.running.remove(&id);
+        while let Some(idx) = self.pending.iter().position(|p| self.can_start(&p.name)) {
+            if let Some(pending) = self.pending.remove(idx) {
+                self.start_plugin(pending);
+            }
+        }
+    }
+
+    /// Run an external command unless it is throttled.
+    /// Returns true if we have data to write.
+    fn run_command(&mut self, msg: &PrivMsg, cmd: &Command) -> bool {
+        let (path, throttle) = match self.commands.get(cmd.name) {
+            Some(conf) => (conf.path.clone(), conf.throttle),
+            None => return false,
+        };
+
+        if throttle != 0 {
+            let now = Instant::now();
+            if let Some(last) = self.last_run.get(cmd.name) {
+                if now.duration_since(*last) < Duration::from_secs(throttle) {
+                    return false;
+                }
+            }
+            self.last_run.insert(cmd.name.to_owned(), now);
+        }
+
+        self.spawn_plugin(&path, msg, cmd, vec![], vec![])
+    }
+
+    /// Run the plugins of every matcher the message matches.
+    fn run_matchers(&mut self, conf: Option<&ChannelConfig>, msg: &PrivMsg) {
+        let matched = self
+            .matchers
+            .iter()
+            .filter_map(|(re, matcher)| {
+                let caps = re.captures(&msg.text)?;
+                let groups = caps
+                    .iter()
+                    .skip(1)
+                    .map(|group| group.map_or("", |group| group.as_str()).to_owned())
+                    .collect::<Vec<String>>();
+                Some((matcher.clone(), groups))
+            })
+            .collect::<Vec<(Matcher, Vec<String>)>>();
+        for (matcher, groups) in matched {
+            if !self.permitted(conf, msg, &matcher.name) {
+                continue;
+            }
+            let cmd = Command {
+                name: &matcher.name,
+                args: &msg.text,
+            };
+            // capture groups are passed as --capture and R8_MATCH_<n>.
+            let args = groups.iter().map(|group| format!("--capture={}", group));
+            let env = groups
+                .iter()
+                .enumerate()
+                .map(|(i, group)| (format!("R8_MATCH_{}", i + 1), group.clone()));
+            self.spawn_plugin(&matcher.plugin, msg, &cmd, args.collect(), env.collect());
+        }
+    }
+
+    /// Whether a channel's settings, and `[general]` admins or channel operators for
+    /// commands that require them, let a command run there now.
+    /// Counts the run against the channel's throttle.
+    fn permitted(&mut self, conf: Option<&ChannelConfig>, msg: &PrivMsg, name: &str) -> bool {
+        if let (false, Some(cmd)) = (msg.admin, self.commands.get(name)) {
+            if cmd.admin || (cmd.require_op && !self.state.is_op(&msg.target, msg.sender())) {
+                return false;
+            }
+        }
+        let channel = &msg.target;
+        let conf = match conf {
+            Some(conf) => conf,
+            None => return true,
+        };
+        if conf.disabled_commands.iter().any(|cmd| cmd == name) {
+            return false;
+        }
+        if let Some(allowed) = &conf.commands {
+            if !allowed.iter().any(|cmd| cmd == name) {
+                return false;
+            }
+        }
+
+        if conf.throttle != 0 {
+            let chan = irc_uppercase(&self.state.casemapping, channel.as_bytes());
+            let key = (String::from_utf8_lossy(&chan).to_string(), name.to_owned());
+            let now = Instant::now();
+            if let Some(last) = self.channel_last_run.get(&key) {
+                if now.duration_since(*last) < Duration::from_secs(conf.throttle) {
+                    return false;
+                }
+            }
+            self.channel_last_run.insert(key, now);
+        }
+        true
+    }
+
+    /// If the message is from a relay bot, attribute it to the user behind the relay.
+    /// Returns true if it was relayed.
+    fn unmask_gateway(&self, msg: &mut PrivMsg) -> bool {
+        let hostmask = msg.hostmask();
+        let is_gateway = self
+            .gateways
+            .iter()
+            .any(|mask| mask_match(mask.as_bytes(), hostmask.as_bytes()));
+        if !is_gateway {
+            return false;
+        }
+
+        if let Some((nick, text)) = unmask_relay(&msg.text) {
+            // private answers still go to the relay, see Route::reply.
+            msg.relay = Some(std::mem::replace(&mut msg.nick, nick));
+            msg.text = text;
+            // that's the relay's account, not theirs.
+            msg.account = None;
+            return true;
+        }
+        false
+    }
+
+    /// The services account of the sender, from the account tag or what we learned.
+    /// With account-tag the server tags every logged in sender, so no tag means none.
+    fn account_of(&self, msg: &Message) -> Option<String> {
+        let learned = match (msg.user, msg.host) {
+            _ if self.state.caps.contains("account-tag") => None,
+            (Some(user), Some(host)) => self.state.users.account(user, host),
+            _ => None,
+        };
+        msg.tag(b"account")
+            .or(learned)
+            .map(|account| String::from_utf8_lossy(account).to_string())
+    }
+
+    fn is_admin(&self, msg: &PrivMsg) -> bool {
+        let hostmask = msg.hostmask();
+        self.admins
+            .iter()
+            .any(|admin| self.identifies(admin, &hostmask, msg.account.as_deref()))
+    }
+
+    /// If we answered a CTCP query of nick's in the last CTCP_INTERVAL.
+    fn ctcp_throttled(&mut self, nick: &[u8], now: Instant) -> bool {
+        self.ctcp_answered
+            .retain(|_, last| now.duration_since(*last) < CTCP_INTERVAL);
+        self.ctcp_answered
+            .contains_key(&irc_uppercase(&self.state.casemapping, nick))
+    }
+
+    /// The key the sender's settings are under. None for relayed messages, as
+    /// everyone behind the relay shares its user@host.
+    fn user_key(&self, msg: &PrivMsg) -> Option<UserKey> {
+        if msg.relay.is_some() {
+            return None;
+        }
+        Some(self.state.users.key(
+            msg.account.as_deref().map(str::as_bytes),
+            msg.user.as_bytes(),
+            msg.host.as_bytes(),
+        ))
+    }
+
+    /// Messages we leave alone so we don't loop with other bots.
+    fn ignores(&mut self, msg: &PrivMsg) -> bool {
+        let hostmask = msg.hostmask();
+        let bot = self.bots.iter().any(|bot| {
+            if bot.contains('!') {
+                mask_match(bot.as_bytes(), hostmask.as_bytes())
+            } else {
+                case_cmp(&self.state.casemapping, bot.as_bytes(), msg.nick.as_bytes())
+            }
+        });
+        let nick = irc_uppercase(&self.state.casemapping, msg.nick.as_bytes());
+        // everyone behind a relay shares its user@host, so go by who it relayed.
+        let who = match msg.relay {
+            Some(_) => String::from_utf8_lossy(&nick).into_owned(),
+            None => hostmask,
+        };
+        bot || self
+            .loop_guard
+            .ignores(Instant::now(), &nick, &who, &msg.text)
+    }
+
+    /// Give a friend their mode in channel, a moment after they join so a netjoin
+    /// doesn't flood the channel with modes.
+    fn op_friend(&mut self, channel: &str, nick: &str, hostmask: &str, account: Option<&str>) {
+        if !self.state.is_op(channel, &self.state.nick) {
+            return;
+        }
+        let mode = match self
+            .friends
+            .iter()
+            .find(|(who, _)| self.identifies(who, hostmask, account))
+        {
+            Some(&(_, mode)) if self.state.modes.bit(mode).is_some() => mode,
+            _ => return,
+        };
+        let delay = Duration::from_millis(self.rng.gen_range(FRIEND_DELAY_MS));
+        let line = OutMessage::new("MODE")
+            .param(channel)
+            .param([b'+', mode])
+            .param(nick);
+        let id = format!("{} {}", channel, nick);
+        let at = Instant::now() + delay;
+        if !self
+            .timers
+            .add(at, "friends", Some(&id), line.line().to_vec())
+        {
+            warn!(
+                "Too many timers pending, not giving {} +{}.",
+                nick, mode as char
+            );
+        }
+    }
+
+    /// If who, a hostmask when it has a ! or else an account, matches a user.
+    fn identifies(&self, who: &str, hostmask: &str, account: Option<&str>) -> bool {
+        if who.contains('!') {
+            mask_match(who.as_bytes(), hostmask.as_bytes())
+        } else {
+            account.is_some_and(|account| {
+                case_cmp(&self.state.casemapping, who.as_bytes(), account.as_bytes())
+            })
+        }
+    }
+
+    /// Run the `[hooks]` plugin for an event, if there is one.
+    /// The channel is the reply target, text is the reason, new topic or new nick.
+    fn run_hook(
+        &mut self,
+        event: &str,
+        msg: &Message,
+        channel: &str,
+        text: &str,
+        extra: Vec<String>,
+    ) {
+        let path = match self.hook_path(event) {
+            Some(path) if !path.is_empty() => path.to_owned(),
+            _ => return,
+        };
+        let lossy =
+            |part: Option<&[u8]>| String::from_utf8_lossy(part.unwrap_or_default()).to_string();
+        let mut hook_msg = PrivMsg {
+            nick: lossy(msg.nick),
+            user: lossy(msg.user),
+            host: lossy(msg.host),
+            target: channel.to_owned(),
+            reply_to: channel.to_owned(),
+            private: false,
+            text: text.to_owned(),
+            action: false,
+            policy: self.reply_policy(channel),
+            account: self.account_of(msg),
+            admin: false,
+            relay: None,
+        };
+        hook_msg.admin = self.is_admin(&hook_msg);
+        let cmd = Command {
+            name: event,
+            args: text,
+        };
+        self.spawn_plugin(&path, &hook_msg, &cmd, extra, vec![]);
+    }
+
+    fn hook_path(&self, event: &str) -> Option<&str> {
+        let path = match event {
+            "join" => &self.hooks.join,
+            "part" => &self.hooks.part,
+            "kick" => &self.hooks.kick,
+            "topic" => &self.hooks.topic,
+            "nick" => &self.hooks.nick,
+            "online" => &self.hooks.online,
+            "offline" => &self.hooks.offline,
+            _ => return None,
+        };
+        Some(path)
+    }
+
+    /// The executable of a command, matcher or hook, by the name it runs as.
+    fn plugin_path(&self, source: &str) -> Option<String> {
+        self.commands
+            .get(source)
+            .map(|cmd| cmd.path.as_str())
+            .or_else(|| {
+                self.matchers
+                    .iter()
+                    .find(|(_, matcher)| matcher.name == source)
+                    .map(|(_, matcher)| matcher.plugin.as_str())
+            })
+            .or_else(|| self.hook_path(source))
+            .filter(|path| !path.is_empty())
+            .map(str::to_owned)
+    }
+
+    /// Look nick up with WHOIS on behalf of waiter; lookups of the same nick are
+    /// shared.
+    /// Returns true if we have data to write.
+    pub fn whois(&mut self, nick: &str, waiter: Waiter) -> bool {
+        let key = irc_uppercase(&self.state.casemapping, nick.as_bytes());
+        match self.whoises.request(Instant::now(), key, nick, waiter) {
+            Some(true) => {
+                self.queue("whois", OutMessage::new("WHOIS").param(nick));
+                true
+            }
+            Some(false) => false,
+            None => {
+                warn!("Too many WHOIS lookups pending, dropped one of {}.", nick);
+                false
+            }
+        }
+    }
+
+    /// Hand a finished WHOIS to whoever waited on it.
+    fn whois_done(&mut self, info: WhoisInfo, waiters: Vec<Waiter>) {
+        for waiter in waiters {
+            match waiter {
+                Waiter::Plugin {
+                    source,
+                    context,
+                    route,
+                } => {
+                    let path = match self.plugin_path(&source) {
+                        Some(path) => path,
+                        None => continue,
+                    };
+                    let (nick, reply_to, private, policy, relay) = match route {
+                        Some(route) => (
+                            route.nick,
+                            route.target,
+                            route.private,
+                            route.policy,
+                            route.relay,
+                        ),
+                        None => (
+                            String::new(),
+                            String::new(),
+                            true,
+                            ReplyPolicy::default(),
+                            None,
+                        ),
+                    };
+                    let msg = PrivMsg {
+                        nick,
+                        user: String::new(),
+                        host: String::new(),
+                        target: reply_to.clone(),
+                        reply_to,
+                        private,
+                        text: context.clone(),
+                        action: false,
+                        policy,
+                        account: None,
+                        admin: false,
+                        relay,
+                    };
+                    // run as itself, so its limits and sandbox apply.
+                    let cmd = Command {
+                        name: &source,
+                        args: &context,
+                    };
+                    self.spawn_plugin(&path, &msg, &cmd, vec![], info.env());
+                }
+            }
+        }
+    }
+
+    /// Tell the online or offline hook and native plugins that watched nicks came or
+    /// went.
+    /// Returns true if we have data to write.
+    fn presence(&mut self, nicks: Vec<String>, online: bool) -> bool {
+        let mut has_data = false;
+        let (event, path) = match online {
+            true => ("online", self.hooks.online.clone()),
+            false => ("offline", self.hooks.offline.clone()),
+        };
+        for nick in nicks {
+            info!("{} is {}.", nick, event);
+            if !path.is_empty() {
+                let msg = PrivMsg {
+                    nick: nick.clone(),
+                    user: String::new(),
+                    host: String::new(),
+                    target: String::new(),
+                    reply_to: String::new(),
+                    private: true,
+                    text: String::new(),
+                    action: false,
+                    policy: ReplyPolicy::default(),
+                    account: None,
+                    admin: false,
+                    relay: None,
+                };
+                let cmd = Command {
+                    name: event,
+                    args: "",
+                };
+                self.spawn_plugin(&path, &msg, &cmd, vec![], vec![]);
+            }
+            let ev = match online {
+                true => Event::Online { nick: &nick },
+                false => Event::Offline { nick: &nick },
+            };
+            has_data |= self.fire(&ev);
+        }
+        has_data
+    }
+
+    /// Let the native plugins observe an event.
+    /// Returns true if we have data to write.
+    fn fire(&mut self, ev: &Event) -> bool {
+        let mut ctx = Context {
+            rng: &mut self.rng,
+            state: &self.state,
+            storage: &self.storage,
+            channels: &self.channel_conf,
+            deferred: &self.deferred,
+        };
+        let replies = self.natives.event(&mut ctx, ev);
+        self.queue_replies(replies)
+    }
+
+    /// Route a PRIVMSG to the native plugins and, if it is a command, to whichever
+    /// native or external plugin handles it.
+    /// Returns true if we have data to write.
+    fn dispatch(&mut self, msg: &PrivMsg) -> bool {
+        let chan_conf = if msg.private {
+            None
+        } else {
+            channel_config(&self.state.casemapping, &self.channel_conf, &msg.target).cloned()
+        };
+        let prefix = match chan_conf
+            .as_ref()
+            .and_then(|conf| conf.command_prefix.as_ref())
+        {
+            Some(prefix) => prefix.as_bytes().to_vec(),
+            None => self.command_prefix.clone(),
+        };
+        let addressed = is_addressed(&self.state.casemapping, &self.state.nick, &msg.text);
+        // a /me is never a command.
+        let cmd = match msg.action {
+            true => None,
+            false => parse_command(&prefix, &msg.text).or_else(|| {
+                parse_addressed(
+                    &self.state.casemapping,
+                    &self.state.nick,
+                    &prefix,
+                    &msg.text,
+                )
+            }),
+        };
+        let addressed = addressed || cmd.is_some();
+        let key = self.user_key(msg);
+        // whatever the channel allows, so anyone can always opt out.
+        let opt = cmd.as_ref().and_then(|cmd| match cmd.name {
+            "optout" => Some(true),
+            "optin" => Some(false),
+            _ => None,
+        });
+        if let (Some(optout), Some(key)) = (opt, &key) {
+            self.state.users.entry(key.clone()).optout = optout;
+            let text = match optout {
+                true => format!(
+                    "Okay, I'll leave your messages alone until you {}optin.",
+                    String::from_utf8_lossy(&prefix)
+                        .chars()
+                        .next()
+                        .unwrap_or('.')
+                ),
+                false => "Welcome back.".to_owned(),
+            };
+            return self.queue_lines("irc", vec![msg.reply(&text)]);
+        }
+        if key
+            .and_then(|key| self.state.users.get(&key))
+            .is_some_and(|user| user.optout)
+        {
+            return false;
+        }
+        let cmd = cmd.filter(|cmd| {
+            let is_command = self.natives.has_command(cmd.name)
+                || matches!(
+                    self.commands.get(cmd.name).map(|c| c.trigger),
+                    Some(Trigger::Prefix)
+                );
+            is_command && self.permitted(chan_conf.as_ref(), msg, cmd.name)
+        });
+        let natives = &self.natives;
+        if let Some(name) = cmd.as_ref().and_then(|cmd| natives.owner(cmd.name)) {
+            self.state.conn.invoked(name);
+        }
+
+        let mut ctx = Context {
+            rng: &mut self.rng,
+            state: &self.state,
+            storage: &self.storage,
+            channels: &self.channel_conf,
+            deferred: &self.deferred,
+        };
+        let mut replies = self.natives.privmsg(&mut ctx, msg);
+        let mut has_data = false;
+
+        if let Some(cmd) = cmd {
+            if let Some(cmd_replies) = self.natives.command(&mut ctx, msg, &cmd) {
+                replies.extend(cmd_replies);
+            } else {
+                has_data |= self.run_command(msg, &cmd);
+            }
+        }
+
+        // commands triggered without a prefix get the whole message as their arguments.
+        let triggered = self
+            .commands
+            .iter()
+            .filter(|(name, conf)| match conf.trigger {
+                Trigger::Prefix => false,
+                Trigger::Bare => is_bare_word(name, &msg.text),
+                Trigger::Anywhere => has_word(name, &msg.text),
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<String>>();
+        for name in triggered {
+            if !self.permitted(chan_conf.as_ref(), msg, &name) {
+                continue;
+            }
+            let cmd = Command {
+                name: &name,
+                args: &msg.text,
+            };
+            has_data |= self.run_command(msg, &cmd);
+        }
+
+        if !msg.private && !addressed {
+            self.run_matchers(chan_conf.as_ref(), msg);
+        }
+
+        if !msg.private {
+            let chan = self.state.chan_key(msg.target.as_bytes());
+            self.state.history.push(chan, &msg.nick, &msg.text);
+        }
+
+        self.queue_replies(replies) || has_data
+    }
+
+    fn handle_message(&mut self, msg: &Message) -> IrcProto {
+        let mut ret = IrcProto::Okay;
+        // only tags or a prefix, like a line cut short, there is nothing to handle.
+        if msg.command.is_none() {
+            return ret;
+        }
+
+        // e.g. @batch=abc, the server groups this with other messages.
+        let batched_playback = msg
+            .tag(b"batch")
+            .and_then(|reference| self.batches.note(reference))
+            .is_some_and(|batch| batch.is_playback());
+
+        // account-tag, the sender is logged in to services as this account.
+        if let Some(account) = msg.tag(b"account") {
+            self.state.note_account(msg, account);
+        }
+
+        // labeled-response: any answer besides an echo, e.g. ACK or 404 ERR_CANNOTSENDTOCHAN.
+        if let Some(label) = msg.tag(b"label") {
+            if msg.command != Some(b"PRIVMSG") {
+                let label = String::from_utf8_lossy(label);
+                let refused = matches!(msg.command, Some(b"FAIL") | Some([b'4', _, _]));
+                match self.labels.answer(&label) {
+                    Some(lost) if refused => warn!(
+                        "The server refused our message to {}: {:?}",
+                        lost.target, lost.text
+                    ),
+                    _ => (),
+                }
+            }
+        }
+
+        if msg.nick.is_none() {
+            match msg.command {
+                Some(cmd) if cmd == b"PING" => {
+                    // echo the params back as they were.
+                    let mut pong = b"PONG ".to_vec();
+                    pong.extend(msg.params.unwrap_or_default());
+                    self.send(&OutMessage::raw(&pong));
+                    ret = IrcProto::Data;
+                }
+                // usually without a prefix.
+                Some(cmd) if cmd == b"AUTHENTICATE" => ret = self.authenticate(msg),
+                Some(cmd) if cmd == b"ERROR" => {
+                    if let Some(params) = msg.params {
+                        let str_v = String::from_utf8_lossy(params);
+                        return IrcProto::Error(str_v.to_string());
+                    }
+                    // quit the stream
+                    self.send(&OutMessage::new("QUIT").trailing("bye"));
+                    ret = IrcProto::Data;
+                }
+                Some(cmd) => {
+                    // a command is a word, anything longer is junk, like a line cut short.
+                    let str_v = String::from_utf8_lossy(&cmd[..cmd.len().min(32)]);
+                    warn!("Recv unknown command: {:?}", str_v);
+                }
+                None => unreachable!("lines without a command are skipped above"),
+            }
+
+            return ret;
+        }
+
+        if let Some(numeric) = msg.command.and_then(Numeric::parse) {
+            return self.handle_numeric(numeric, msg);
+        }
+
+        // history the server replays was logged when it happened.
+        if !batched_playback {
+            self.log_event(msg);
+        }
+
+        match msg.command {
+            Some(nick) if nick == b"NICK" => {
+                if !self.is_me(msg) {
+                    if let (Some(old_nick), Some(new_nick)) = (msg.nick, msg.parameters().next()) {
+                        self.state.member_rename(old_nick, new_nick);
+                        let old_key = irc_uppercase(&self.state.casemapping, old_nick);
+                        if let Some(message) = self.state.away.remove(&old_key) {
+                            self.state.set_away(new_nick, Some(message.as_bytes()));
+                        }
+                        let new_nick = String::from_utf8_lossy(new_nick).to_string();
+                        self.run_hook("nick", msg, "", &new_nick, vec![]);
+                    }
+                }
+                if let Some(my_nick) = msg.nick {
+                    // Looks like the server changed my name.
+                    if case_cmp(&self.state.casemapping, my_nick, self.state.nick.as_bytes()) {
+                        let new_nick = msg.parameters().next().unwrap_or(my_nick);
+                        self.state.nick = String::from_utf8_lossy(new_nick).to_string();
+                        info!("The server changed our nick to: {:?}", self.state.nick);
+                        self.state.note_userhost(msg);
+                    }
+                }
+            }
+            Some(privmsg) if privmsg == b"PRIVMSG" => {
+                let mut params = msg.parameters();
+                if let (Some(nick), Some(target), Some(message)) =
+                    (msg.nick, params.next(), params.next())
+                {
+                    let lossy = |part: Option<&[u8]>| {
+                        String::from_utf8_lossy(part.unwrap_or_default()).to_string()
+                    };
+                    // echo-message, never act on what we said.
+                    if self.is_me(msg) {
+                        self.state.note_userhost(msg);
+                        self.note_echo(msg, &lossy(Some(target)), &lossy(Some(message)));
+                        return ret;
+                    }
+                    let mut text = lossy(Some(message));
+                    let mut action = false;
+                    let playback = batched_playback || self.is_playback(msg);
+                    if let Some(ctcp) = parse_ctcp(&text) {
+                        // /me lines go to plugins, other CTCP queries are answered here:
+                        // only private ones, so a channel query doesn't get everyone's
+                        // bot answering, and once per CTCP_INTERVAL for each nick.
+                        if ctcp.command != "ACTION" {
+                            let now = Instant::now();
+                            if !playback
+                                && self.is_private_message(target)
+                                && !self.ctcp_throttled(nick, now)
+                            {
+                                let secs = SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .map_or(0, |d| d.as_secs());
+                                if let Some(line) = ctcp::answer(
+                                    &ctcp,
+                                    &lossy(Some(nick)),
+                                    &self.ctcp_version,
+                                    secs,
+                                ) {
+                                    let key = irc_uppercase(&self.state.casemapping, nick);
+                                    self.ctcp_answered.insert(key, now);
+                                    self.queue_line("ctcp", line.as_bytes());
+                                    ret = IrcProto::Data;
+                                }
+                            }
+                            return ret;
+                        }
+                        text = ctcp.args.to_owned();
+                        action = true;
+                    }
+                    let private = self.is_private_message(target);
+                    let reply_to = if private { nick } else { target };
+                    let mut privmsg = PrivMsg {
+                        nick: lossy(Some(nick)),
+                        user: lossy(msg.user),
+                        host: lossy(msg.host),
+                        target: lossy(Some(target)),
+                        reply_to: lossy(Some(reply_to)),
+                        private,
+                        text,
+                        action,
+                        policy: self.reply_policy(&String::from_utf8_lossy(target)),
+                        account: self.account_of(msg),
+                        admin: false,
+                        relay: None,
+                    };
+                    // we only have the relay's word for who sent it.
+                    privmsg.admin = !self.unmask_gateway(&mut privmsg) && self.is_admin(&privmsg);
+                    if playback {
+                        // old lines still give s/// something to work with.
+                        if !privmsg.private {
+                            let chan = self.state.chan_key(privmsg.target.as_bytes());
+                            self.state.history.push(chan, &privmsg.nick, &privmsg.text);
+                        }
+                    } else if self.ignores(&privmsg) {
+                        // other bots, or someone we answered too often.
+                    } else {
+                        let started = self.spawned.len() + self.pending.len();
+                        let has_data = self.dispatch(&privmsg);
+                        if has_data {
+                            ret = IrcProto::Data;
+                        }
+                        if has_data || self.spawned.len() + self.pending.len() > started {
+                            let nick =
+                                irc_uppercase(&self.state.casemapping, privmsg.nick.as_bytes());
+                            self.loop_guard.replied(Instant::now(), nick);
+                        }
+                    }
+                }
+            }
+            // :me JOIN #chan
+            // or with extended-join -> :nick!user@host JOIN #chan account :realname
+            Some(join) if join == b"JOIN" => {
+                if let Some(account) = msg.parameters().nth(1) {
+                    self.state.note_account(msg, account);
+                }
+                if let (Some(nick), Some(chan)) = (msg.nick, msg.parameters().next()) {
+                    self.state.member_join(chan, nick);
+                }
+                if self.is_me(msg) {
+                    self.state.note_userhost(msg);
+                    if let Some(chan) = msg.parameters().next() {
+                        self.state.note_joining(chan, false);
+                        let ch = String::from_utf8_lossy(chan).to_string();
+                        if self.chathistory > 0 && self.state.caps.contains("draft/chathistory") {
+                            let line = OutMessage::new("CHATHISTORY")
+                                .param("LATEST")
+                                .param(&ch)
+                                .param("*")
+                                .param(self.chathistory.to_string());
+                            self.queue("chathistory", line);
+                            ret = IrcProto::Data;
+                        }
+                        for &mode in TRACKED_LISTS.iter() {
+                            if self.state.modes.is_list(mode) {
+                                let line = OutMessage::new("MODE").param(&ch).param([b'+', mode]);
+                                self.queue("lists", line);
+                                ret = IrcProto::Data;
+                            }
+                        }
+                        self.state.channels.push(self.state.chan_key(chan));
+                    }
+                } else if let (Some(nick), Some(chan)) = (msg.nick, msg.parameters().next()) {
+                    let channel = String::from_utf8_lossy(chan);
+                    let hostmask = format!(
+                        "{}!{}@{}",
+                        String::from_utf8_lossy(nick),
+                        String::from_utf8_lossy(msg.user.unwrap_or_default()),
+                        String::from_utf8_lossy(msg.host.unwrap_or_default())
+                    );
+                    if let Some(ban) = self.state.ban_matching(&channel, &hostmask) {
+                        warn!(
+                            "{} joined {} though banned by {}, they may be evading it.",
+                            hostmask, channel, ban
+                        );
+                    }
+                    // only what this JOIN says, a remembered user@host may be someone else now.
+                    let account = msg
+                        .tag(b"account")
+                        .or_else(|| msg.parameters().nth(1).filter(|&acct| acct != b"*"))
+                        .map(|account| String::from_utf8_lossy(account).to_string());
+                    self.op_friend(
+                        &channel,
+                        &String::from_utf8_lossy(nick),
+                        &hostmask,
+                        account.as_deref(),
+                    );
+                    self.run_hook("join", msg, &String::from_utf8_lossy(chan), "", vec![]);
+                    let ev = Event::Join {
+                        nick: &String::from_utf8_lossy(nick),
+                        channel: &String::from_utf8_lossy(chan),
+                    };
+                    if self.fire(&ev) {
+                        ret = IrcProto::Data;
+                    }
+                }
+            }
+            // :nick PART #chan [:reason]
+            Some(part) if part == b"PART" => {
+                let mut params = msg.parameters();
+                if let (Some(nick), Some(chan)) = (msg.nick, params.next()) {
+                    let channel = String::from_utf8_lossy(chan);
+                    let reason = String::from_utf8_lossy(params.next().unwrap_or_default());
+                    if !self.is_me(msg) {
+                        self.run_hook("part", msg, &channel, &reason, vec![]);
+                    }
+                    let ev = Event::Part {
+                        nick: &String::from_utf8_lossy(nick),
+                        channel: &channel,
+                        reason: &reason,
+                    };
+                    if self.fire(&ev) {
+                        ret = IrcProto::Data;
+                    }
+                }
+                if let (Some(nick), Some(chan)) = (msg.nick, msg.parameters().next()) {
+                    self.state.member_part(chan, nick);
+                }
+                if self.is_me(msg) {
+                    if let Some(chan) = msg.parameters().next() {
+                        self.state.channels.retain(|x| !x.is(chan));
+                        let chan = self.state.chan_key(chan);
+                        self.state.history.forget(&chan);
+                    }
+                }
+            }
+            // :the_kicker KICK #chan the_victim :reason
+            Some(kick) if kick == b"KICK" => {
+                let mut params = msg.parameters();
+                if let (Some(channel), Some(victim)) = (params.next(), params.next()) {
+                    self.state.member_part(channel, victim);
+                    let kicked_us =
+                        case_cmp(&self.state.casemapping, victim, self.state.nick.as_bytes());
+                    // we can't answer in a channel we were kicked from.
+                    if !kicked_us {
+                        let victim_arg = format!("--victim={}", String::from_utf8_lossy(victim));
+                        self.run_hook(
+                            "kick",
+                            msg,
+                            &String::from_utf8_lossy(channel),
+                            &String::from_utf8_lossy(msg.parameters().nth(2).unwrap_or_default()),
+                            vec![victim_arg],
+                        );
+                    }
+                    if kicked_us {
+                        self.state.channels.retain(|x| !x.is(channel));
+                        let chan = self.state.chan_key(channel);
+                        self.state.history.forget(&chan);
+                        if let Some(reason) = params.next() {
+                            let channel = String::from_utf8_lossy(channel);
+                            let reason_given = String::from_utf8_lossy(reason);
+                            warn!("Kicked from {}. reason: {}", channel, reason_given);
+                        }
+                        if self.rejoin_on_kick {
+                            let channel = String::from_utf8_lossy(channel).to_string();
+                            let delay = self.rejoin_delay(&channel, Duration::ZERO);
+                            if delay.is_zero() {
+                                self.join(&[channel]);
+                                if self.tick(Instant::now()) {
+                                    ret = IrcProto::Data;
+                                }
+                            } else {
+                                info!("Kicked again, rejoining {} in {:?}.", channel, delay);
+                                self.join_later(&channel, delay);
+                            }
+                        }
+                    }
+                }
+            }
+            // :nick QUIT :reason
+            Some(quit) if quit == b"QUIT" => {
+                if let Some(nick) = msg.nick {
+                    self.state.set_away(nick, None);
+                    self.state.member_quit(nick);
+                    let ev = Event::Quit {
+                        nick: &String::from_utf8_lossy(nick),
+                        reason: &String::from_utf8_lossy(
+                            msg.parameters().next().unwrap_or_default(),
+                        ),
+                    };
+                    if self.fire(&ev) {
+                        ret = IrcProto::Data;
+                    }
+                }
+            }
+            // :nick TOPIC #chan :new topic
+            Some(topic) if topic == b"TOPIC" => {
+                let mut params = msg.parameters();
+                if let Some(chan) = params.next() {
+                    let text = params.next().unwrap_or_default();
+                    let set_at =
+                        msg.tag(b"time")
+                            .and_then(parse_server_time)
+                            .unwrap_or_else(|| {
+                                SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .map_or(0, |d| d.as_secs())
+                            });
+                    self.state
+                        .set_topic(chan, text, msg.nick.unwrap_or_default(), set_at);
+                    if !self.is_me(msg) {
+                        let topic = String::from_utf8_lossy(text);
+                        self.run_hook("topic", msg, &String::from_utf8_lossy(chan), &topic, vec![]);
+                    }
+                }
+            }
+            // away-notify -> :nick!user@host AWAY [:message], without one they're back.
+            Some(away) if away == b"AWAY" => {
+                if let Some(nick) = msg.nick {
+                    let message = msg.parameters().next();
+                    let nick_s = String::from_utf8_lossy(nick).to_string();
+                    let was_away = self.state.is_away(&nick_s);
+                    self.state.set_away(nick, message);
+                    let message = String::from_utf8_lossy(message.unwrap_or_default());
+                    let ev = match (was_away, self.state.is_away(&nick_s)) {
+                        (_, true) => Some(Event::Away {
+                            nick: &nick_s,
+                            message: &message,
+                        }),
+                        (true, false) => Some(Event::Back { nick: &nick_s }),
+                        (false, false) => None,
+                    };
+                    if let Some(ev) = ev {
+                        if self.fire(&ev) {
+                            ret = IrcProto::Data;
+                        }
+                    }
+                }
+            }
+            // account-notify -> :nick!user@host ACCOUNT account
+            Some(account) if account == b"ACCOUNT" => {
+                if let Some(account) = msg.parameters().next() {
+                    self.state.note_account(msg, account);
+                }
+            }
+            Some(invite) if invite == b"INVITE" => {}
+            // :nick MODE #chan +o-v nick other or :nick MODE me :+iw
+            Some(mode) if mode == b"MODE" => {
+                let mut params = msg.parameters();
+                if let (Some(target), Some(modes)) = (params.next(), params.next()) {
+                    if case_cmp(&self.state.casemapping, target, self.state.nick.as_bytes()) {
+                        self.state.apply_umodes(modes);
+                    } else {
+                        self.state.apply_chanmodes(target, modes, params);
+                    }
+                }
+            }
+            // :server BATCH +reference type [params...] or BATCH -reference
+            Some(batch) if batch == b"BATCH" => {
+                if let Some(batch) = self.batches.command(msg.parameters()) {
+                    info!(
+                        "{} batch ({}) ended with {} messages.",
+                        batch.kind,
+                        batch.params.join(" "),
+                        batch.lines
+                    );
+                }
+            }
+            Some(auth) if auth == b"AUTHENTICATE" => ret = self.authenticate(msg),
+            Some(cap) if cap == b"CAP" => match parse_cap(msg) {
+                Some(Cap::Ls { caps, more }) => {
+                    self.offer_caps(&caps);
+                    if !more && self.cap_negotiating {
+                        if !self.cap_offered.contains_key("multi-prefix") {
+                            return IrcProto::Error(
+                                "The server does not offer multi-prefix".to_owned(),
+                            );
+                        }
+                        self.request_caps();
+                        // nothing we want.
+                        self.cap_answered(&[]);
+                        ret = IrcProto::Data;
+                    }
+                }
+                Some(Cap::New(caps)) => {
+                    self.offer_caps(&caps);
+                    if self.request_caps() {
+                        ret = IrcProto::Data;
+                    }
+                }
+                Some(Cap::Ack(caps)) => {
+                    for (cap, _) in &caps {
+                        let cap = String::from_utf8_lossy(cap);
+                        match cap.strip_prefix('-') {
+                            Some(disabled) => self.cap_removed(disabled),
+                            None => {
+                                if cap == "sasl" && self.cap_negotiating {
+                                    if let Some(mechanism) = self.sasl {
+                                        self.send(
+                                            &OutMessage::new("AUTHENTICATE")
+                                                .param(mechanism.name()),
+                                        );
+                                        self.sasl_pending = true;
+                                        ret = IrcProto::Data;
+                                    }
+                                }
+                                self.state.caps.insert(cap.to_string());
+                            }
+                        }
+                    }
+                    if self.cap_answered(&caps) {
+                        ret = IrcProto::Data;
+                    }
+                }
+                Some(Cap::Nak(caps)) => {
+                    if caps.iter().any(|(cap, _)| cap == b"multi-prefix") {
+                        return IrcProto::Error(
+                            "We did not receive and ACK for multi-prefix".to_owned(),
+                        );
+                    }
+                    for (cap, _) in &caps {
+                        let cap = String::from_utf8_lossy(cap);
+                        warn!("The server refused capability {}", cap);
+                        // don't ask again, unless it's offered anew.
+                        self.cap_offered.remove(&*cap);
+                    }
+                    if self.cap_answered(&caps) {
+                        ret = IrcProto::Data;
+                    }
+                }
+                Some(Cap::Del(caps)) => {
+                    for (cap, _) in caps {
+                        let cap = String::from_utf8_lossy(cap);
+                        info!("The server no longer offers capability {}", cap);
+                        self.cap_offered.remove(&*cap);
+                        self.cap_removed(&cap);
+                    }
+                }
+                None => (),
+            },
+            // to our keepalive PING, handle_data() already saw the server answer.
+            Some(pong) if pong == b"PONG" => (),
+            Some(any) => {
+                let str_n = if let Some(nick) = msg.nick {
+                    String::from_utf8_lossy(nick).to_string()
+                } else {
+                    "<NO NICK>".to_owned()
+                };
+                let str_c = String::from_utf8_lossy(any);
+                let str_p = if let Some(params) = msg.params {
+                    String::from_utf8_lossy(params).to_string()
+                } else {
+                    "".to_owned()
+                };
+                debug!("Unknown command: {} {} {}", str_n, str_c, str_p);
+            }
+
+            None => unreachable!("lines without a command are skipped above"),
+        }
+
+        ret
+    }
+
+    /// Handle a numeric reply from the server.
+    fn handle_numeric(&mut self, numeric: Numeric, msg: &Message) -> IrcProto {
+        let mut ret = IrcProto::Okay;
+
+        match numeric {
+            // RPL_BANLIST -> :server 367 me #chan mask [setter time]
+            Numeric::RplBanlist => {
+                let mut params = msg.parameters().skip(1);
+                if let (Some(chan), Some(mask)) = (params.next(), params.next()) {
+                    self.state.update_list(chan, b'b', mask, true);
+                }
+            }
+            // RPL_QUIETLIST -> :server 728 me #chan q mask [setter time]
+            Numeric::RplQuietlist => {
+                let mut params = msg.parameters().skip(1);
+                if let (Some(chan), Some(&[mode]), Some(mask)) =
+                    (params.next(), params.next(), params.next())
+                {
+                    self.state.update_list(chan, mode, mask, true);
+                }
+            }
+            // RPL_ENDOFMOTD or ERR_NOMOTD, ISUPPORT has been sent by now.
+            // Only the first time, MOTD can be asked for again later.
+            Numeric::RplEndofmotd | Numeric::ErrNomotd => {
+                if self.motd_deadline.is_some() {
+                    let now = Instant::now();
+                    if self.end_of_motd(now) | self.tick(now) {
+                        ret = IrcProto::Data;
+                    }
+                }
+            }
+            // RPL_MONONLINE -> :server 730 me :nick!user@host,nick2!user@host
+            Numeric::RplMononline => {
+                let targets = msg.parameters().nth(1).unwrap_or_default();
+                let nicks = targets
+                    .split(|&chr| chr == b',')
+                    .filter_map(|target| target.split(|&chr| chr == b'!').next())
+                    .filter(|nick| !nick.is_empty());
+                let came = self.watch.came_online(&self.state.casemapping, nicks);
+                if self.presence(came, true) {
+                    ret = IrcProto::Data;
+                }
+            }
+            // RPL_MONOFFLINE -> :server 731 me :nick,nick2
+            Numeric::RplMonoffline => {
+                let targets = msg.parameters().nth(1).unwrap_or_default();
+                let nicks = targets
+                    .split(|&chr| chr == b',')
+                    .filter(|nick| !nick.is_empty());
+                let went = self.watch.went_offline(&self.state.casemapping, nicks);
+                if self.presence(went, false) {
+                    ret = IrcProto::Data;
+                }
+            }
+            // RPL_ISON -> :server 303 me :nick nick2
+            Numeric::RplIson => {
+                let nicks = msg.parameters().nth(1).unwrap_or_default();
+                let nicks = nicks
+                    .split(|&chr| chr == b' ')
+                    .filter(|nick| !nick.is_empty());
+                let (came, went) = self.watch.ison(&self.state.casemapping, nicks);
+                if self.presence(came, true) | self.presence(went, false) {
+                    ret = IrcProto::Data;
+                }
+            }
+            // :server 311 me nick user host * :realname, and the rest of a WHOIS reply.
+            whois @ (Numeric::RplWhoisuser
+            | Numeric::RplWhoisserver
+            | Numeric::RplWhoisoperator
+            | Numeric::RplWhoisidle
+            | Numeric::RplWhoisaccount) => {
+                let params = msg.parameters().skip(1).collect::<Vec<&[u8]>>();
+                if let Some(nick) = params.first() {
+                    let key = irc_uppercase(&self.state.casemapping, nick);
+                    self.whoises.note(&key, whois, &params);
+                }
+            }
+            // RPL_ENDOFWHOIS -> :server 318 me nick :End of /WHOIS list.
+            Numeric::RplEndofwhois => {
+                if let Some(nick) = msg.parameters().nth(1) {
+                    let key = irc_uppercase(&self.state.casemapping, nick);
+                    if let Some((info, waiters)) = self.whoises.finish(&key) {
+                        self.whois_done(info, waiters);
+                    }
+                }
+            }
+            // RPL_USERHOST -> :server 302 me :nick=+user@host nick2*=-user@host
+            Numeric::RplUserhost => {
+                let replies = msg.parameters().nth(1).unwrap_or_default();
+                for reply in replies.split(|&chr| chr == b' ') {
+                    let eq = match reply.iter().position(|&chr| chr == b'=') {
+                        Some(eq) => eq,
+                        None => continue,
+                    };
+                    // a * marks an oper, the + or - whether they are away.
+                    let nick = reply[..eq].strip_suffix(b"*").unwrap_or(&reply[..eq]);
+                    let userhost = reply.get(eq + 2..).unwrap_or_default();
+                    let at = match userhost.iter().position(|&chr| chr == b'@') {
+                        Some(at) => at,
+                        None => continue,
+                    };
+                    if case_cmp(&self.state.casemapping, nick, self.state.nick.as_bytes()) {
+                        self.state
+                            .set_userhost(&userhost[..at], &userhost[at + 1..]);
+                    }
+                }
+            }
+            // RPL_VISIBLEHOST -> :server 396 me host :is now your displayed host
+            Numeric::RplVisiblehost => {
+                if let Some(host) = msg.parameters().nth(1) {
+                    // USERHOST will tell us the rest.
+                    if !self.state.set_host(host) {
+                        debug!("Our host is now {}", String::from_utf8_lossy(host));
+                    }
+                }
+            }
+            // RPL_YOUREOPER, the MODE +o usually follows but don't count on it.
+            Numeric::RplYoureoper => {
+                info!("We are now a network operator.");
+                self.state.umode.insert(b'o');
+            }
+            // ERR_PASSWDMISMATCH after registration, or ERR_NOOPERHOST
+            Numeric::ErrNooperhost | Numeric::ErrPasswdmismatch
+                if numeric == Numeric::ErrNooperhost
+                    || !matches!(
+                        self.state.ready_state,
+                        IrcState::Unknown | IrcState::PreAuth
+                    ) =>
+            {
+                let reason = msg.parameters().last().unwrap_or_default();
+                warn!(
+                    "Could not become a network operator: {}",
+                    String::from_utf8_lossy(reason)
+                );
+            }
+            // :server 473 me #chan :Cannot join channel (+i), and the like.
+            failed if JOIN_FAILURES.iter().any(|(numeric, _)| *numeric == failed) => {
+                let mut params = msg.parameters().skip(1);
+                if let Some(chan) = params.next() {
+                    if self.join_failed(failed, chan, params.next().unwrap_or_default()) {
+                        ret = IrcProto::Data;
+                    }
+                }
+            }
+            // RPL_TOPIC -> :server 332 me #chan :topic
+            Numeric::RplTopic => {
+                let mut params = msg.parameters().skip(1);
+                if let (Some(chan), Some(text)) = (params.next(), params.next()) {
+                    self.state.set_topic(chan, text, b"", 0);
+                }
+            }
+            // RPL_TOPICWHOTIME -> :server 333 me #chan setter 1612345678
+            Numeric::RplTopicwhotime => {
+                let mut params = msg.parameters().skip(1);
+                if let (Some(chan), Some(setter), Some(set_at)) =
+                    (params.next(), params.next(), params.next())
+                {
+                    let set_at = String::from_utf8_lossy(set_at).parse().unwrap_or(0);
+                    self.state.note_topic_setter(chan, setter, set_at);
+                }
+            }
+            // RPL_AWAY -> :server 301 me nick :message
+            Numeric::RplAway => {
+                let mut params = msg.parameters().skip(1);
+                if let (Some(nick), Some(message)) = (params.next(), params.next()) {
+                    self.state.set_away(nick, Some(message));
+                }
+            }
+            Numeric::RplMyinfo => {
+                self.state.ready_state = IrcState::Authenticated;
+                // :server 004 me server.name version umodes chanmodes
+                let server = msg.parameters().nth(1).unwrap_or_default();
+                self.state.conn.connected =
+                    Some((Instant::now(), String::from_utf8_lossy(server).to_string()));
+                // how others see us, to know how long what we say may be.
+                if self.state.hostmask.is_none() {
+                    let line = OutMessage::new("USERHOST").param(&self.state.nick);
+                    self.queue("irc", line);
+                    ret = IrcProto::Data;
+                }
+                if !self.usermode.is_empty() {
+                    let line = OutMessage::new("MODE")
+                        .param(&self.state.nick)
+                        .param(&self.usermode);
+                    self.queue("irc", line);
+                    ret = IrcProto::Data;
+                }
+                if !self.oper_name.is_empty() && !self.oper_password.is_empty() {
+                    let line = OutMessage::new("OPER")
+                        .param(&self.oper_name)
+                        .param(&self.oper_password);
+                    self.queue("irc", line);
+                    ret = IrcProto::Data;
+                }
+                if !self.nickserv_password.is_empty() {
+                    let line = OutMessage::new("PRIVMSG")
+                        .param("NickServ")
+                        .trailing(format!("IDENTIFY {}", self.nickserv_password));
+                    self.queue("irc", line);
+                    ret = IrcProto::Data;
+                }
+                // joining before the MOTD ends races cloaking on some networks.
+                self.motd_deadline = Some(Instant::now() + MOTD_TIMEOUT);
+            }
+            // RPL_LOGGEDIN and RPL_SASLSUCCESS, services confirmed who we are.
+            Numeric::RplLoggedin | Numeric::RplSaslsuccess => {
+                self.identified = true;
+                if numeric == Numeric::RplSaslsuccess {
+                    self.sasl_pending = false;
+                    if self.end_caps() {
+                        ret = IrcProto::Data;
+                    }
+                }
+                if self.identify_deadline.is_some() {
+                    self.join_configured();
+                    if self.tick(Instant::now()) {
+                        ret = IrcProto::Data;
+                    }
+                }
+            }
+            // :server 005 me TOKEN[=value]... :are supported by this server
+            Numeric::RplIsupport => {
+                self.state.ready_state = IrcState::Ready(true);
+                let params = msg.parameters().skip(1).collect::<Vec<&[u8]>>();
+                // the last is the human readable trailer.
+                for token in params.iter().take(params.len().saturating_sub(1)) {
+                    self.state.isupport(token);
+                }
+            }
+            // :server 221 me +iw
+            Numeric::RplUmodeis => {
+                if let Some(modes) = msg.parameters().nth(1) {
+                    self.state.umode.clear();
+                    self.state.apply_umodes(modes);
+                }
+            }
+            // reply to NAMES(X) Command or message sent on joining a channel
+            // :server 353 me = #chan :@nick +other plain
+            Numeric::RplNamreply => {
+                let mut params = msg.parameters().skip(2);
+                if let (Some(chan), Some(names)) = (params.next(), params.next()) {
+                    self.state.note_names(chan, names);
+                }
+            }
+            // nickname collision
+            Numeric::ErrNicknameinuse | Numeric::ErrNickcollision => {
+                if self.state.original_nick.is_none() {
+                    self.state.original_nick = Some(self.state.nick.clone());
+                }
+
+                self.state.nick = self.generate_nick(&self.state.nick.clone());
+                self.send(&OutMessage::new("NICK").param(&self.state.nick));
+                warn!("NICK COLLIDE; Trying new nick: {:?}", self.state.nick);
+                ret = IrcProto::Data;
+            }
+            // ERR_ERRONEUSNICKNAME, first try without what the server might not like.
+            Numeric::ErrErroneusnickname => {
+                self.erroneous_nicks += 1;
+                if self.erroneous_nicks > MAX_ERRONEOUS_NICKS {
+                    return IrcProto::Error("The server rejected every nick we tried.".to_owned());
+                }
+                if self.state.original_nick.is_none() {
+                    self.state.original_nick = Some(self.state.nick.clone());
+                }
+
+                let clean = sanitize_nick(&self.state.nick, self.state.nicklen);
+                self.state.nick = if !clean.is_empty() && clean != self.state.nick {
+                    clean
+                } else {
+                    // keep the generated part within NICKLEN too.
+                    let base = match clean.is_empty() {
+                        true => "r8ball".to_owned(),
+                        false => clean,
+                    };
+                    let keep = self.state.nicklen.saturating_sub(5).max(1);
+                    self.generate_nick(&base.chars().take(keep).collect::<String>())
+                };
+                self.send(&OutMessage::new("NICK").param(&self.state.nick));
+                warn!("Erroneous nick; Trying new nick: {:?}", self.state.nick);
+                ret = IrcProto::Data;
+            }
+            Numeric::ErrPasswdmismatch => {
+                return IrcProto::Error("Invalid password given in PASS command.".to_owned());
+            }
+            Numeric::ErrYourebannedcreep => {
+                return IrcProto::Error("We are banned.".to_owned());
+            }
+            Numeric::ErrNicklocked
+            | Numeric::ErrSaslfail
+            | Numeric::ErrSasltoolong
+            | Numeric::ErrSaslaborted => {
+                return IrcProto::Error("We had an SASL problem.".to_owned());
+            }
+            _ => {
+                let str_p = String::from_utf8_lossy(msg.params.unwrap_or_default());
+                debug!(
+                    "Unhandled numeric: {:03} {} {}",
+                    numeric.code(),
+                    numeric.name(),
+                    str_p
+                );
+            }
+        }
+
+        ret
+    }
+
+    fn handle_data(&mut self, len: usize) -> IrcProto {
+        let mut ret = IrcProto::Okay;
+        let mut partial = None;
+        self.state.conn.bytes_in += (len - self.read_head) as u64;
+        self.last_heard = Instant::now();
+        self.ping_sent = None;
+
+        // drop what is left of a line we cut short.
+        if let Some(dropped) = self.overlong {
+            let rest = &self.read_buffer[self.read_start..len];
+            match rest.iter().position(|&chr| chr == b'\r' || chr == b'\n') {
+                Some(end) => {
+                    self.overlong = None;
+                    self.read_start += end;
+                }
+                None if dropped + rest.len() > MAX_READ_BUF => {
+                    return IrcProto::Error("The server sent a line without end.".to_owned());
+                }
+                None => {
+                    self.overlong = Some(dropped + rest.len());
+                    self.read_start = 0;
+                    self.read_head = 0;
+                    return ret;
+                }
+            }
+        }
+
+        // Take the buffer so the message handlers can borrow the client mutably.
+        let read_buffer = std::mem::take(&mut self.read_buffer);
+        let buf = &read_buffer[self.read_start..len];
+        let iter = BufIterator::new(buf);
+        for line in iter {
+            let msg = match line {
+                TruncStatus::Full(data) => Message::new(data),
+                TruncStatus::Part(data) => {
+                    partial = Some(data.as_ptr() as usize - read_buffer.as_ptr() as usize);
+                    break;
+                }
+            };
+            if msg.is_empty() {
+                continue;
+            }
+
+            self.state.conn.lines_in += 1;
+            match self.handle_message(&msg) {
+                IrcProto::Okay => (),
+                IrcProto::Data => ret = IrcProto::Data,
+                IrcProto::Error(e) => {
+                    ret = IrcProto::Error(e);
+                    break;
+                }
+            }
+        }
+
+        // leave the partial read where it is, the next read goes after it.
+        match partial {
+            Some(start) => {
+                self.read_start = start;
+                self.read_head = len;
+            }
+            None => {
+                self.read_start = 0;
+                self.read_head = 0;
+            }
+        }
+        self.read_buffer = read_buffer;
+        if self.read_head == 0 && self.read_buffer.len() > BUF_SIZ {
+            // done with an overlong line.
+            self.read_buffer.truncate(BUF_SIZ);
+            self.read_buffer.shrink_to_fit();
+        }
+        self.snapshot.publish(&self.state);
+
+        ret
+    }
+
+    /// Make room after the partial line, if there is one: move it to the front, or
+    /// grow the buffer when it fills all of it.
+    /// Returns false if the line is too long for even the biggest buffer.
+    fn make_room(&mut self) -> bool {
+        if self.read_head < self.read_buffer.len() {
+            return true;
+        }
+        if self.read_start > 0 {
+            self.read_buffer
+                .copy_within(self.read_start..self.read_head, 0);
+            self.read_head -= self.read_start;
+            self.read_start = 0;
+        } else if self.read_buffer.len() < MAX_READ_BUF {
+            let len = (self.read_buffer.len() * 2).min(MAX_READ_BUF);
+            self.read_buffer.resize(len, 0);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Handle the line filling up the read buffer, there being no room for its end.
+    fn cut_overlong(&mut self) -> IrcProto {
+        // like plugin output: cut the line short, and drop the rest of it as it comes.
+        warn!(
+            "The server sent a line over {} bytes, cutting it short.",
+            MAX_READ_BUF
+        );
+        let line = self.read_buffer[self.read_start..self.read_head].to_vec();
+        self.read_start = 0;
+        self.read_head = 0;
+        self.overlong = Some(0);
+        self.handle_message(&Message::new(&line))
+    }
+
+    /// Read from the server into the buffer, and handle every full line read.
+    pub fn receive_data<T: Read>(&mut self, readable: &mut T) -> Result<ClientReadStat, io::Error> {
+        match self.parse_read(|room| readable.read(room)) {
+            Ok(Some(IrcProto::Okay)) => Ok(ClientReadStat::Okay),
+            Ok(Some(IrcProto::Data)) => Ok(ClientReadStat::HasWritableData),
+            Ok(Some(IrcProto::Error(e))) => Ok(ClientReadStat::Error(e)),
+            Ok(None) => Ok(ClientReadStat::Eof),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(ClientReadStat::Blocked),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Make room at the end of the read buffer, let read fill it, and handle every full
+    /// line in place. read returns how much it read, None comes back if that's nothing.
+    /// A line filling the whole buffer is cut short instead of reading.
+    fn parse_read<E>(
+        &mut self,
+        read: impl FnOnce(&mut [u8]) -> Result<usize, E>,
+    ) -> Result<Option<IrcProto>, E> {
+        if !self.make_room() {
+            return Ok(Some(self.cut_overlong()));
+        }
+        match read(&mut self.read_buffer[self.read_head..])? {
+            0 => Ok(None),
+            size => Ok(Some(self.handle_data(self.read_head + size))),
+        }
+    }
+
+    fn process_plugbuff(&mut self, plug: &mut Plugin) -> bool {
+        let mut chunk = vec![];
+        let mut has_trunc = false;
+        let mut slice_at = 0usize;
+        for line in plug.iter() {
+            match line {
+                TruncStatus::Full(data) => {
+                    chunk.extend(data);
+                    chunk.push(b'\n');
+                }
+                TruncStatus::Part(partial) => {
+                    has_trunc = true;
+                    slice_at = plug.get_slice_pos(partial);
+                }
+            }
+        }
+
+        if !has_trunc {
+            plug.reset_buf();
+        } else {
+            plug.split_at(slice_at);
+        }
+
+        if chunk.is_empty() {
+            false
+        } else if let Some(workers) = &self.workers {
+            workers.submit(plug.id, &plug.name, plug.route.clone(), chunk);
+            false
+        } else {
+            let name = plug.name.clone();
+            self.apply(&name, parse_output(&chunk, plug.route.as_ref()))
+        }
+    }
+
+    /// Plugins flagged moderator in `[commands]` or `[[matchers]]`.
+    fn is_moderator(&self, source: &str) -> bool {
+        self.commands.get(source).is_some_and(|cmd| cmd.moderator)
+            || self
+                .matchers
+                .iter()
+                .any(|(_, matcher)| matcher.name == source && matcher.moderator)
+    }
+
+    /// Carry out what plugins asked for.
+    /// Returns true if we have data to write.
+    fn apply(&mut self, source: &str, actions: Vec<Action>) -> bool {
+        let mut has_data = false;
+        for action in actions {
+            match action {
+                Action::Send(line) => {
+                    has_data = true;
+                    self.queue_line(source, &line);
+                }
+                Action::Timer { delay, id, line } => {
+                    let at = Instant::now() + delay;
+                    if !self.timers.add(at, source, id.as_deref(), line) {
+                        warn!("Too many timers pending, dropped one from {}.", source);
+                    }
+                }
+                Action::CancelTimer(id) => self.timers.cancel(source, &id),
+                Action::Whois { nick, .. } if self.plugin_path(source).is_none() => {
+                    warn!(
+                        "{} asked for a WHOIS of {}, but we can't run it again.",
+                        source, nick
+                    );
+                }
+                Action::Whois {
+                    nick,
+                    context,
+                    route,
+                } => {
+                    let waiter = Waiter::Plugin {
+                        source: source.to_owned(),
+                        context,
+                        route,
+                    };
+                    has_data |= self.whois(&nick, waiter);
+                }
+                Action::Moderate(moderation) => {
+                    let channel = moderation.channel();
+                    if !self.is_moderator(source) {
+                        warn!("{} is not allowed to moderate {}.", source, channel);
+                    } else if !self.state.is_op(channel, &self.state.nick) {
+                        warn!(
+                            "{} asked us to moderate {}, but we are not an op there.",
+                            source, channel
+                        );
+                    } else if let Some(mask) = match &moderation {
+                        Moderation::Ban { mask, .. } => self
+                            .state
+                            .list(channel, b'b')
+                            .iter()
+                            .find(|ban| ban.eq_ignore_ascii_case(mask)),
+                        _ => None,
+                    } {
+                        info!("{} is already banned in {}.", mask, channel);
+                    } else {
+                        has_data = true;
+                        self.queue_line(source, moderation.line().as_bytes());
+                    }
+                }
+            }
+        }
+        has_data
+    }
+
+    /// Parse plugin output on a worker pool instead of the event loop.
+    pub fn use_workers(&mut self, workers: WorkerPool) {
+        self.workers = Some(workers);
+    }
+
+    /// Apply results from the worker pool.
+    /// When finishing, waits on outstanding work and stops using the pool.
+    /// Returns true if we have data to write.
+    pub fn process_workers(&mut self, finish: bool) -> bool {
+        let results = match (finish, self.workers.take()) {
+            (_, None) => return false,
+            (true, Some(workers)) => workers.finish(),
+            (false, Some(workers)) => {
+                let results = workers.ready();
+                self.workers = Some(workers);
+                results
+            }
+        };
+        let mut has_data = false;
+        for (source, actions) in results {
+            has_data |= self.apply(&source, actions);
+        }
+        has_data
+    }
+
+    pub fn process_plugin(&mut self, plug: &mut Plugin) -> io::Result<bool> {
+        let mut has_data = false;
+        loop {
+            match plug.receive()? {
+                PluginReadStat::Okay => (),
+                PluginReadStat::Eof => break,
+                PluginReadStat::Blocked => break,
+                // buffer needs to processed to make progress
+                PluginReadStat::ReadBufferFull => {
+                    // If true, we have writable data
+                    if self.process_plugbuff(plug) {
+                        has_data = true;
+                    }
+                }
+            }
+        }
+        if self.process_plugbuff(plug) {
+            has_data = true;
+        }
+        for line in plug.receive_err()? {
+            plugin!(&plug.channel, "{} ({}): {}", plug.name, plug.channel, line);
+        }
+        Ok(has_data)
+    }
+
+    /// Handle bytes from the server, however the transport got them, without any I/O
+    /// of our own. A line may be split across calls. Followed by the output ready by
+    /// now, like take_output().
+    pub fn feed_bytes(&mut self, bytes: &[u8]) -> Vec<ClientAction> {
+        if let IrcProto::Error(e) = self.feed(bytes) {
+            return vec![ClientAction::Close(e)];
+        }
+        let mut actions = vec![];
+        actions.extend(self.take_sts().map(ClientAction::Sts));
+        actions.extend(
+            self.take_spawns()
+                .into_iter()
+                .map(|spawn| ClientAction::Spawn(Box::new(spawn))),
+        );
+        actions.extend(self.take_output(Instant::now()));
+        actions
+    }
+
+    /// Copy bytes from the server into the read buffer and handle them, for feed_bytes().
+    fn feed(&mut self, mut bytes: &[u8]) -> IrcProto {
+        let mut ret = IrcProto::Okay;
+        loop {
+            let copied = self.parse_read(|room| {
+                let len = room.len().min(bytes.len());
+                room[..len].copy_from_slice(&bytes[..len]);
+                bytes = &bytes[len..];
+                Ok::<_, Infallible>(len)
+            });
+            match copied {
+                Ok(Some(IrcProto::Okay)) => (),
+                Ok(Some(IrcProto::Data)) => ret = IrcProto::Data,
+                Ok(Some(error)) => return error,
+                Ok(None) => return ret,
+                Err(never) => match never {},
+            }
+        }
+    }
+
+    /// Everything that may be sent by now, taken out of the write buffer, for a
+    /// transport which does its own writing; use it instead of write_data().
+    pub fn take_output(&mut self, now: Instant) -> Option<ClientAction> {
+        if self.send_overflowed {
+            return Some(ClientAction::Close(
+                "too much output was waiting to be sent".to_owned(),
+            ));
+        }
+        let mut out = vec![];
+        loop {
+            self.release(now);
+            if self.write_buffer.is_empty() {
+                break;
+            }
+            out.extend(self.write_buffer.drain(..));
+        }
+        self.state.conn.wrote(&out);
+        if out.is_empty() {
+            None
+        } else {
+            Some(ClientAction::Send(out))
+        }
+    }
+
+    /// Write what may be sent by now to the server.
+    pub fn write_data<T: Write>(&mut self, writable: &mut T) -> Result<ClientWriteStat, io::Error> {
+        if self.send_overflowed {
+            return Err(io::Error::other("too much output was waiting to be sent"));
+        }
+        self.release(Instant::now());
+        if self.is_empty() {
+            return Ok(ClientWriteStat::Eof);
+        }
+
+        // the deque may wrap around, write both halves in one go.
+        let (front, back) = self.write_buffer.as_slices();
+        match writable.write_vectored(&[IoSlice::new(front), IoSlice::new(back)]) {
+            Ok(size) => {
+                let split = size.min(front.len());
+                self.state.conn.wrote(&front[..split]);
+                self.state.conn.wrote(&back[..size - split]);
+                self.write_buffer.drain(..size);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(ClientWriteStat::Blocked);
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        };
+
+        Ok(ClientWriteStat::Okay)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.write_buffer.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::{self, Cursor, Write},
+        str::FromStr,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use crate::{
+        config::config_file::Config,
+        irc::{parse::Message, plugin::Plugin},
+        storage::Storage,
+    };
+
+    use super::{
+        native::{BotPlugin, Command, Context, PrivMsg},
+        outgoing::OutMessage,
+        output::parse_output,
+        schedule,
+        users::UserKey,
+        CaseMapping, Client, ClientAction, ClientReadStat, ClientWriteStat, BUF_SIZ, MAX_READ_BUF,
+    };
+
+    const DEFAULT_CONF: &str = r##"
+[general]
+nick = "bot"
+server = "localhost"
+port = 9643
+tls = false
+
+[commands]
+test = "./test"
+"##;
+    const DEFAULT_GREETER: &str = "CAP LS 302\r
+NICK bot\r
+USER bot 0 * :bot\r
+";
+
+    #[test]
+    fn irc_client_greeter() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        assert_eq!(fake_io.get_ref(), DEFAULT_GREETER.as_bytes());
+    }
+
+    #[test]
+    fn irc_client_identity() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nusername = \"r8 ball\"\nrealname = \"The Magic\\r\\n8 Ball\"\nusermode = \"+iw\"",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        assert!(fake_io
+            .get_ref()
+            .ends_with(b"USER r8 0 * :The Magic  8 Ball\r\n"));
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +iw\r\n",
+        );
+    }
+
+    fn replace_with(cur: &mut Cursor<Vec<u8>>, data: Option<&[u8]>) {
+        cur.get_mut().clear();
+        cur.set_position(0);
+        if let Some(data) = data {
+            cur.write_all(data).unwrap();
+        }
+        cur.set_position(0);
+    }
+
+    fn read_expect(c: &mut Client, cur: &mut Cursor<Vec<u8>>, exp_res: ClientReadStat) {
+        let status = c.receive_data(cur).unwrap();
+        assert_eq!(status, exp_res);
+        replace_with(cur, None);
+    }
+
+    fn write_expect(
+        c: &mut Client,
+        cur: &mut Cursor<Vec<u8>>,
+        exp_res: ClientWriteStat,
         exp_data: &[u8],
     ) {
         let status = c.write_data(cur).unwrap();
@@ -555,113 +3908,2704 @@ USER bot +i * :bot\r
     }
 
     #[test]
-    fn irc_client_ping_pong() {
+    fn irc_client_ping_pong() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+
+        // test truncated while I'm at it. (the dangling P)
+        replace_with(&mut fake_io, Some(b"PING :xyz\r\nPIN"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PONG :xyz\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_feed_bytes() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        assert_eq!(
+            c.take_output(Instant::now()),
+            Some(ClientAction::Send(DEFAULT_GREETER.as_bytes().to_vec()))
+        );
+        assert_eq!(c.take_output(Instant::now()), None);
+
+        // split anywhere, lines come out the same.
+        let mut sent = vec![];
+        for byte in b"PING :abc\r\nPING :xyz\r\n".chunks(1) {
+            for action in c.feed_bytes(byte) {
+                match action {
+                    ClientAction::Send(bytes) => sent.extend(bytes),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        }
+        assert_eq!(sent, b"PONG :abc\r\nPONG :xyz\r\n");
+
+        // plugins are the transport's to spawn.
+        let actions = c.feed_bytes(b":nick!user@host PRIVMSG #chan :.test\r\n");
+        assert!(matches!(
+            actions.as_slice(),
+            [ClientAction::Spawn(spawn)] if spawn.name == "test"
+        ));
+
+        let actions = c.feed_bytes(b"ERROR :Closing Link\r\nPING :late\r\n");
+        assert!(matches!(actions.as_slice(), [ClientAction::Close(_)]));
+    }
+
+    #[test]
+    fn irc_client_keepalive() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.take_output(Instant::now());
+        c.feed_bytes(b"PING :xyz\r\n");
+        let heard = Instant::now();
+        let interval = Duration::from_secs(120);
+        assert!(c.next_deadline().is_some_and(|at| at <= heard + interval));
+        assert!(c.tick(heard + interval));
+        assert_eq!(
+            c.take_output(heard + interval),
+            Some(ClientAction::Send(b"PING :keepalive\r\n".to_vec()))
+        );
+        assert!(!c.timed_out(heard + interval));
+        assert!(c.timed_out(heard + interval * 2));
+
+        // any answer will do.
+        c.feed_bytes(b":irc.example.net NOTICE * :hi\r\n");
+        assert!(!c.timed_out(heard + interval * 2));
+    }
+
+    #[test]
+    fn irc_client_pathological_lines() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.take_output(Instant::now());
+        // no command to handle.
+        assert!(c
+            .feed_bytes(b"@batch=abc;label=1 \r\n:nick!user@host\r\n@a \r\n")
+            .is_empty());
+        // a 10 MB line is cut short, then given up on.
+        let actions = c.feed_bytes(&vec![b'a'; 10 << 20]);
+        assert_eq!(
+            actions,
+            vec![ClientAction::Close(
+                "The server sent a line without end.".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn irc_client_write_priority() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(&mut fake_io, None);
+
+        for n in 0..10 {
+            c.queue_line("spam", format!("PRIVMSG #chan :{}", n).as_bytes());
+        }
+        // the PONG goes first, then a burst of the rest.
+        replace_with(&mut fake_io, Some(b"PING :xyz\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        let mut expected = b"PONG :xyz\r\n".to_vec();
+        for n in 0..5 {
+            expected.extend(format!("PRIVMSG #chan :{}\r\n", n).as_bytes());
+        }
+        write_expect(&mut c, &mut fake_io, ClientWriteStat::Okay, &expected);
+        write_expect(&mut c, &mut fake_io, ClientWriteStat::Eof, b"");
+
+        // the rest are paced.
+        let deadline = c.next_deadline().unwrap();
+        assert!(deadline > Instant::now());
+        assert!(c.tick(deadline + Duration::from_millis(1)));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :5\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_send_overflow() {
+        let client = |policy: &str| {
+            let conf = Config::from_str(&DEFAULT_CONF.replace(
+                "tls = false",
+                &format!(
+                    "tls = false\nsend_queue_max = 60\nsend_queue_overflow = \"{}\"",
+                    policy
+                ),
+            ))
+            .unwrap();
+            let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+            c.write_data(&mut Cursor::new(vec![])).unwrap();
+            // 18 bytes each, only three fit.
+            for n in 0..5 {
+                c.queue_line("spam", format!("PRIVMSG #chan :{}", n).as_bytes());
+            }
+            c
+        };
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+
+        let mut c = client("drop-oldest");
+        assert_eq!(
+            (c.state.sent.dropped_lines, c.state.sent.dropped_bytes),
+            (2, 36)
+        );
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :2\r\nPRIVMSG #chan :3\r\nPRIVMSG #chan :4\r\n",
+        );
+
+        let mut c = client("drop-newest");
+        assert_eq!(c.state.sent.dropped_lines, 2);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :0\r\nPRIVMSG #chan :1\r\nPRIVMSG #chan :2\r\n",
+        );
+
+        let mut c = client("disconnect");
+        assert_eq!(c.state.sent.dropped_lines, 0);
+        assert!(c.write_data(&mut fake_io).is_err());
+    }
+
+    #[test]
+    fn irc_client_read_buffer() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(&mut fake_io, None);
+
+        // the partial line stays where it was read.
+        replace_with(&mut fake_io, Some(b"PING :a\r\nPING :b"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        assert_eq!((c.read_start, c.read_head), (9, 16));
+
+        // a line longer than the buffer grows it, many reads fill the buffer's end.
+        let mut long = b"\r\n:nick!user@host NOTICE bot :".to_vec();
+        long.extend(vec![b'x'; BUF_SIZ * 2]);
+        long.extend(b"\r\nPING :c\r\n");
+        let mut reader = Cursor::new(long);
+        while (reader.position() as usize) < reader.get_ref().len() {
+            assert_ne!(
+                c.receive_data(&mut reader).unwrap(),
+                ClientReadStat::Blocked
+            );
+        }
+        assert_eq!((c.read_start, c.read_head), (0, 0));
+        assert_eq!(c.read_buffer.len(), BUF_SIZ);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PONG :a\r\nPONG :b\r\nPONG :c\r\n",
+        );
+
+        // too long even for the biggest buffer: cut short, the rest is dropped.
+        let mut long = b"PING :".to_vec();
+        long.extend(vec![b'y'; MAX_READ_BUF + 100]);
+        long.extend(b"\r\nPING :d\r\n");
+        let mut reader = Cursor::new(long);
+        while (reader.position() as usize) < reader.get_ref().len() {
+            c.receive_data(&mut reader).unwrap();
+        }
+        c.write_data(&mut fake_io).unwrap();
+        let sent = fake_io.get_ref();
+        // and our PONG is cut to fit a line.
+        assert_eq!(sent.len(), 512 + 9);
+        assert!(sent.starts_with(b"PONG :yyy") && sent.ends_with(b"y\r\nPONG :d\r\n"));
+        replace_with(&mut fake_io, None);
+
+        // and a line that never ends is an error.
+        let mut reader = Cursor::new(vec![b'z'; MAX_READ_BUF * 3]);
+        let mut last = ClientReadStat::Okay;
+        while (reader.position() as usize) < reader.get_ref().len() {
+            last = c.receive_data(&mut reader).unwrap();
+            if let ClientReadStat::Error(_) = last {
+                break;
+            }
+        }
+        assert!(matches!(last, ClientReadStat::Error(_)));
+    }
+
+    /// A socket that takes room bytes, then would block.
+    struct Trickle {
+        out: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let size = buf.len().min(self.room);
+            self.out.extend(&buf[..size]);
+            self.room -= size;
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn irc_client_partial_writes() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        let mut sock = Trickle {
+            out: vec![],
+            room: 10,
+        };
+        assert_eq!(c.write_data(&mut sock).unwrap(), ClientWriteStat::Okay);
+        assert_eq!(sock.out, b"CAP LS 302");
+        assert_eq!(c.write_data(&mut sock).unwrap(), ClientWriteStat::Blocked);
+
+        // more queued while the rest is still pending.
+        c.send(&OutMessage::new("NICK").param("other"));
+        sock.room = usize::MAX;
+        while c.write_data(&mut sock).unwrap() == ClientWriteStat::Okay {}
+        assert_eq!(
+            sock.out,
+            format!("{}NICK other\r\n", DEFAULT_GREETER).as_bytes()
+        );
+    }
+
+    #[test]
+    fn irc_client_truncations() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+
+        // test truncated while I'm at it. (the dangling P)
+        replace_with(&mut fake_io, Some(b"PING :xyz\r\nPIN"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PONG :xyz\r\n",
+        );
+
+        // test truncation handling by writing out the rest
+        replace_with(&mut fake_io, Some(b"G asdf\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PONG asdf\r\n",
+        );
+
+        // One more time
+        replace_with(&mut fake_io, Some(b"PING :1234\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PONG :1234\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_multiple_messages() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let test_data = b"PING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\n";
+        let test_data_exp = b"PONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\n";
+        replace_with(&mut fake_io, Some(test_data));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(&mut c, &mut fake_io, ClientWriteStat::Okay, test_data_exp);
+    }
+
+    #[test]
+    fn irc_client_unknown_cmd() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(&mut fake_io, Some(b"UNKNOWN"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        write_expect(&mut c, &mut fake_io, ClientWriteStat::Eof, b"");
+    }
+
+    #[test]
+    fn irc_client_nick_conflict() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":bot!bot@bot.localhost 433 :name in use\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+
+        let status = c.write_data(&mut fake_io).unwrap();
+        assert_eq!(status, ClientWriteStat::Okay);
+        let m = Message::new(&fake_io.get_ref()[..fake_io.get_ref().len() - 2]);
+        assert_eq!(m.command.unwrap(), b"NICK");
+        assert_eq!(&m.params.unwrap()[..4], b"bot_");
+        assert_ne!(m.params.unwrap(), b"bot");
+    }
+
+    #[test]
+    fn irc_client_optout() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.register_native(Box::new(Echo));
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.optout\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :Okay, I'll leave your messages alone until you .optin.\r\n",
+        );
+
+        // by user@host, so a new nick doesn't get them back in.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host NICK other\r\n:other!user@host PRIVMSG #chan :.echo hi\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+
+        replace_with(
+            &mut fake_io,
+            Some(b":other!user@host PRIVMSG #chan :.optin\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :Welcome back.\r\n",
+        );
+        replace_with(
+            &mut fake_io,
+            Some(b":other!user@host PRIVMSG #chan :.echo hi\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :hi\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_account_tracking() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let key = c.state.users.key(None, b"user", b"host");
+        c.state.users.entry(key).optout = true;
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host JOIN #chan acct :Real Name\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let key = c.state.users.key(None, b"user", b"host");
+        assert_eq!(key, UserKey::Account(b"acct".to_vec()));
+        assert!(c.state.users.get(&key).unwrap().optout);
+
+        replace_with(&mut fake_io, Some(b":nick!user@host ACCOUNT *\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let key = c.state.users.key(None, b"user", b"host");
+        assert_eq!(key, UserKey::Hostmask(b"user@host".to_vec()));
+
+        // whoever has their user@host after they quit doesn't inherit the account.
+        replace_with(&mut fake_io, Some(b":nick!user@host ACCOUNT acct\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.state.users.account(b"user", b"host"), Some(&b"acct"[..]));
+        replace_with(&mut fake_io, Some(b":nick!user@host QUIT :bye\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.state.users.account(b"user", b"host"), None);
+
+        // nor once they share no channel with us.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host JOIN #chan acct :Real Name\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        replace_with(&mut fake_io, Some(b":op!o@host KICK #chan nick :out\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.state.users.account(b"user", b"host"), None);
+
+        // with account-tag, a sender without the tag isn't logged in.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host JOIN #chan acct :Real Name\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let msg = Message::new(b":nick!user@host PRIVMSG #chan :hi");
+        assert_eq!(c.account_of(&msg).as_deref(), Some("acct"));
+        c.state.caps.insert("account-tag".to_owned());
+        assert_eq!(c.account_of(&msg), None);
+    }
+
+    struct Echo;
+
+    impl BotPlugin for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn commands(&self) -> &[&str] {
+            &["echo"]
+        }
+
+        fn command(&mut self, _ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String> {
+            vec![msg.reply(cmd.args)]
+        }
+    }
+
+    #[test]
+    fn irc_client_native_plugin() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.register_native(Box::new(Echo));
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.echo hi there\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :hi there\r\n",
+        );
+
+        // private messages are answered to the sender.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG bot :!echo hi\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG nick :hi\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :echo hi\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+    }
+
+    #[test]
+    fn irc_client_external_plugin() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.test arg\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.take_spawns().len(), 1);
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.unknown arg\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+    }
+
+    #[test]
+    fn irc_client_matchers() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF,
+            r##"
+[[matchers]]
+regex = "(bug|ticket) #(\\d+)"
+plugin = "./test"
+name = "ticket"
+
+[[matchers]]
+regex = "("
+plugin = "./broken"
+"##
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+        assert_eq!(c.matchers.len(), 1);
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :see bug #12\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let plugins = c.take_spawns();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].name, "ticket");
+
+        // addressed to us, a command or private
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :BOT, bug #12\r\n:nick!user@host PRIVMSG #chan :.unknown bug #12\r\n:nick!user@host PRIVMSG bot :bug #12\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+    }
+
+    #[test]
+    fn irc_client_hooks() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF, "[hooks]\njoin = \"./greet\"\nkick = \"./log\"\ntopic = \"./log\"\n"
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host JOIN #chan\r\n:op!user@host KICK #chan nick :bye\r\n:op!user@host TOPIC #chan :new\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let names = c
+            .take_spawns()
+            .into_iter()
+            .map(|spawn| spawn.name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["join", "kick", "topic"]);
+
+        // no hook configured, or it's us
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PART #chan\r\n:op!user@host KICK #chan bot :bye\r\n:bot!user@host JOIN #chan\r\n"),
+        );
+        // just the ban list request.
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        assert!(c.take_spawns().is_empty());
+    }
+
+    #[test]
+    fn irc_client_plugin_limits() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF.replace(
+                "tls = false",
+                "tls = false\nmax_plugins = 2\nplugin_queue = 1"
+            ),
+            "slow = { path = \"./slow\", max_running = 1 }\n",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        // the second .slow waits for the first.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.slow\r\n:nick!user@host PRIVMSG #chan :.slow\r\n:nick!user@host PRIVMSG #chan :.test\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let first = c.take_spawns();
+        assert_eq!(first.len(), 2);
+
+        // the queue is full.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.test\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :nick: Too busy, try again later.\r\n",
+        );
+        // answered like any other reply.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG bot :.test\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG nick :Too busy, try again later.\r\n",
+        );
+
+        // .test exiting doesn't free a slot for .slow.
+        c.plugin_done(&Plugin::spawn(&first[1]).unwrap());
+        assert!(c.take_spawns().is_empty());
+        c.plugin_failed(&first[0]);
+        let next = c.take_spawns();
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].name, "slow");
+    }
+
+    #[test]
+    fn irc_client_eightball() {
+        let conf = Config::from_str(&format!(
+            "{}\n[eightball]\nanswers = [{{ text = \"yes\" }}]\n",
+            DEFAULT_CONF
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.8 will it rain?\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :nick: yes\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_channel_names() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":bot!bot@host JOIN #Chan{x}\r\n:server 353 bot = #chan{X} :@bot other\r\n"),
+        );
+        c.receive_data(&mut fake_io).unwrap();
+        assert_eq!(c.state.channels[0].as_str(), "#Chan{x}");
+        assert_eq!(c.state.privileges("#CHAN[X]"), "@");
+
+        // the server's casemapping says [] and {} differ after all.
+        replace_with(
+            &mut fake_io,
+            Some(b":server 005 bot CASEMAPPING=ascii :are supported\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.state.privileges("#CHAN[X]"), "");
+        assert_eq!(c.state.privileges("#CHAN{X}"), "@");
+
+        replace_with(&mut fake_io, Some(b":bot!bot@host PART #CHAN{X}\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.state.channels.is_empty());
+        assert_eq!(c.state.privileges("#chan{x}"), "");
+    }
+
+    #[test]
+    fn irc_client_snapshot() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        let handle = c.snapshot_handle();
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let before = handle.load();
+        assert_eq!(before.nick, "bot");
+        assert!(before.channels.is_empty());
+
+        replace_with(
+            &mut fake_io,
+            Some(b":bot!bot@host JOIN #chan\r\n:alice!a@host JOIN #chan\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+
+        let reader = std::thread::spawn(move || handle.load());
+        let after = reader.join().unwrap();
+        assert_eq!(after.channels, vec!["#chan".to_owned()]);
+        let mut members = after.members["#chan"].keys().collect::<Vec<_>>();
+        members.sort();
+        assert_eq!(members, vec!["ALICE", "BOT"]);
+        // old snapshots are left untouched.
+        assert!(before.channels.is_empty());
+    }
+
+    #[test]
+    fn irc_client_sed() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :teh cat\r\n:other!user@host PRIVMSG #chan :teh dog\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :s/teh/the/\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :nick meant to say: the cat\r\n",
+        );
+
+        // nothing to correct
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :s/xyz/abc/\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+    }
+
+    #[test]
+    fn irc_client_command_triggers() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF,
+            r#"snack = { path = "./snack", trigger = "bare", throttle = 60 }
+rust = { path = "./rust", trigger = "anywhere" }
+"#
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :snack\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.take_spawns().len(), 1);
+
+        // throttled
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :snack\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+
+        // bare triggers need the word alone.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.snack\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :I like rust, a lot\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.take_spawns().len(), 1);
+    }
+
+    #[test]
+    fn irc_client_gateway() {
+        let conf = Config::from_str(&format!(
+            "{}\n[[gateways]]\nmask = \"relay!*@matrix.org\"\n",
+            DEFAULT_CONF
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":relay!bot@matrix.org PRIVMSG #chan :<alice> teh cat\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        replace_with(
+            &mut fake_io,
+            Some(b":relay!bot@matrix.org PRIVMSG #chan :<alice> s/teh/the/\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :alice meant to say: the cat\r\n",
+        );
+
+        // in private, the answer goes back through the relay, not to whoever on IRC
+        // has the same nick.
+        replace_with(
+            &mut fake_io,
+            Some(b":relay!bot@matrix.org PRIVMSG bot :<alice> .uptime\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG relay :alice: up 0s, not registered yet\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_seen() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":Al[i]ce!user@host JOIN #chan\r\n:Al[i]ce!user@host PRIVMSG #chan :hi\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.seen al{I}CE\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :nick: Al[i]ce was last seen 0s ago, saying \"hi\" in #chan\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":Al[i]ce!user@host QUIT :bye\r\n:nick!user@host PRIVMSG #chan :.seen alice\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :nick: I have not seen alice.\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_karma() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":alice!user@host PRIVMSG #chan :Rust++\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :Rust now has karma of 1.\r\n",
+        );
+
+        // rate limited, and no self-boosting
+        replace_with(
+            &mut fake_io,
+            Some(b":alice!user@host PRIVMSG #chan :rust++\r\n:bob!other@host PRIVMSG #chan :BOB++\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :bob: you can't change your own karma.\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":bob!other@host PRIVMSG #chan :.karma rust\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :bob: rust has karma of 1.\r\n",
+        );
+
+        // one account is one voter, from wherever it connects.
+        replace_with(
+            &mut fake_io,
+            Some(b"@account=carol :carol!c@one PRIVMSG #chan :x++\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :x now has karma of 1.\r\n",
+        );
+        replace_with(
+            &mut fake_io,
+            Some(b"@account=carol :carol_!c@two PRIVMSG #chan :x++\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+    }
+
+    #[test]
+    fn irc_client_tell() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":alice!user@host PRIVMSG #chan :.tell Bob  lunch is ready \r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :alice: I'll tell Bob when I see them.\r\n",
+        );
+
+        replace_with(&mut fake_io, Some(b":bob!user@host JOIN #chan\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"NOTICE bob :bob: alice said 0s ago: lunch is ready\r\n",
+        );
+
+        // delivered only once
+        replace_with(&mut fake_io, Some(b":bob!user@host PRIVMSG #chan :hi\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+    }
+
+    #[test]
+    fn irc_client_send_stats() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":alice!user@host PRIVMSG #chan :.seen bob\r\n:alice!user@host PRIVMSG bot :.stats\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :alice: I have not seen bob.\r\n\
+PRIVMSG alice :sent in the last minute; by channel: #chan 1 lines/44 bytes; by feature: seen 1 lines/44 bytes\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_uptime_status() {
+        let conf = Config::from_str(
+            &DEFAULT_CONF.replace("tls = false", "tls = false\nadmins = [\"*!*@admin\"]"),
+        )
+        .unwrap();
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.take_output(Instant::now());
+        let said = |c: &mut Client, line: &[u8]| match c.feed_bytes(line).as_slice() {
+            [ClientAction::Send(bytes)] => String::from_utf8(bytes.clone()).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        assert_eq!(
+            said(&mut c, b":alice!user@host PRIVMSG #chan :.uptime\r\n"),
+            "PRIVMSG #chan :alice: up 0s, not registered yet\r\n"
+        );
+        c.feed_bytes(b":irc.test 004 bot irc.test v1 io ov\r\n");
+        // only admins get the details.
+        assert_eq!(
+            said(&mut c, b":alice!user@host PRIVMSG #chan :.status\r\n"),
+            "PRIVMSG #chan :alice: up 0s, connected to irc.test for 0s\r\n"
+        );
+        // ./test isn't there, so it fails to start.
+        for action in c.feed_bytes(b":alice!user@host PRIVMSG #chan :.test\r\n") {
+            if let ClientAction::Spawn(spawn) = action {
+                c.plugin_done(&Plugin::spawn(&spawn).unwrap());
+            }
+        }
+        let status = said(&mut c, b":boss!user@admin PRIVMSG #chan :.status\r\n");
+        assert!(status
+            .starts_with("PRIVMSG #chan :boss: up 0s, connected to irc.test for 0s; in 5 lines/"));
+        assert!(status.ends_with(
+            "; plugins run: uptime 3, test 1; slowest: test 0.0s/run; \
+loudest: test 0 bytes; failing: test 1/1 runs\r\n"
+        ));
+    }
+
+    #[test]
+    fn irc_client_loop_guard() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nbots = [\"OtherBot\", \"*!*@bots.test\"]\nreplies_per_nick = 2",
+        ))
+        .unwrap();
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.take_output(Instant::now());
+        let answered = |c: &mut Client, line: &[u8]| !c.feed_bytes(line).is_empty();
+
+        assert!(!answered(
+            &mut c,
+            b":otherbot!b@host PRIVMSG #chan :.uptime\r\n"
+        ));
+        assert!(!answered(
+            &mut c,
+            b":helper!h@bots.test PRIVMSG #chan :.uptime\r\n"
+        ));
+        // only so many answers a minute.
+        assert!(answered(
+            &mut c,
+            b":alice!a@host PRIVMSG #chan :.uptime\r\n"
+        ));
+        assert!(answered(
+            &mut c,
+            b":alice!a@host PRIVMSG #chan :.uptime\r\n"
+        ));
+        assert!(!answered(
+            &mut c,
+            b":alice!a@host PRIVMSG #chan :.uptime\r\n"
+        ));
+        assert!(answered(
+            &mut c,
+            b":carol!c@host PRIVMSG #chan :.uptime\r\n"
+        ));
+
+        // a bot repeating us is ignored from then on.
+        assert!(!answered(
+            &mut c,
+            b":echo!e@host PRIVMSG #chan :carol: up 0s, not registered yet\r\n"
+        ));
+        assert!(!answered(
+            &mut c,
+            b":echo!e@host PRIVMSG #chan :.uptime\r\n"
+        ));
+
+        // behind a relay, only the user who echoed us is ignored.
+        let conf = format!(
+            "{}\n[[gateways]]\nmask = \"relay!*@matrix.org\"\n",
+            DEFAULT_CONF
+        );
+        let mut c = Client::new(
+            &Config::from_str(&conf).unwrap(),
+            Storage::in_memory().unwrap(),
+        );
+        c.take_output(Instant::now());
+        assert!(answered(
+            &mut c,
+            b":carol!c@host PRIVMSG #chan :.uptime\r\n"
+        ));
+        assert!(!answered(
+            &mut c,
+            b":relay!r@matrix.org PRIVMSG #chan :<echo> carol: up 0s, not registered yet\r\n"
+        ));
+        assert!(!answered(
+            &mut c,
+            b":relay!r@matrix.org PRIVMSG #chan :<Echo> .uptime\r\n"
+        ));
+        assert!(answered(
+            &mut c,
+            b":relay!r@matrix.org PRIVMSG #chan :<bob> .uptime\r\n"
+        ));
+    }
+
+    #[test]
+    fn irc_client_word_filter() {
+        let conf = format!("{}[filter]\nwords = [\"Registered\"]\n", DEFAULT_CONF);
+        let mut c = Client::new(
+            &Config::from_str(&conf).unwrap(),
+            Storage::in_memory().unwrap(),
+        );
+        c.take_output(Instant::now());
+        assert_eq!(
+            c.feed_bytes(b":alice!a@host PRIVMSG #chan :.uptime\r\n"),
+            vec![ClientAction::Send(
+                b"PRIVMSG #chan :alice: up 0s, not ********** yet\r\n".to_vec()
+            )]
+        );
+
+        let conf = conf.replace("[filter]", "[filter]\naction = \"drop\"");
+        let mut c = Client::new(
+            &Config::from_str(&conf).unwrap(),
+            Storage::in_memory().unwrap(),
+        );
+        c.take_output(Instant::now());
+        assert!(c
+            .feed_bytes(b":alice!a@host PRIVMSG #chan :.uptime\r\n")
+            .is_empty());
+    }
+
+    #[test]
+    fn irc_client_strip_formatting() {
+        let conf = Config::from_str(&format!(
+            "{}[channels.\"#Plain\"]\nstrip_formatting = true\n",
+            DEFAULT_CONF
+        ))
+        .unwrap();
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.take_output(Instant::now());
+        assert!(c.apply(
+            "test",
+            parse_output(
+                b"PRIVMSG #plain :\x02bold\x02 and \x0304red\x03\nPRIVMSG #chan :\x02bold\x02\n",
+                None
+            )
+        ));
+        assert_eq!(
+            c.take_output(Instant::now()),
+            Some(ClientAction::Send(
+                b"PRIVMSG #plain :bold and red\r\nPRIVMSG #chan :\x02bold\x02\r\n".to_vec()
+            ))
+        );
+    }
+
+    #[test]
+    fn irc_client_line_budget() {
+        fn lines(action: Option<ClientAction>) -> Vec<Vec<u8>> {
+            match action {
+                Some(ClientAction::Send(bytes)) => bytes
+                    .split(|&chr| chr == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.to_vec())
+                    .collect(),
+                _ => vec![],
+            }
+        }
+
+        let mut c = Client::new(
+            &Config::from_str(DEFAULT_CONF).unwrap(),
+            Storage::in_memory().unwrap(),
+        );
+        c.take_output(Instant::now());
+        // until we know our user@host, assume the longest.
+        assert_eq!(c.state.text_budget("PRIVMSG", "#chan"), 512 - 98);
+        c.feed_bytes(
+            b":server 005 bot LINELEN=300 :are supported\r\n:bot!~bot@cloaked/bot JOIN #chan\r\n",
+        );
+        c.take_output(Instant::now());
+        assert_eq!(c.state.hostmask.as_deref(), Some("bot!~bot@cloaked/bot"));
+        let budget = c.state.text_budget("PRIVMSG", "#chan");
+        assert_eq!(
+            budget,
+            300 - ":bot!~bot@cloaked/bot PRIVMSG #chan :\r\n".len()
+        );
+
+        let words = "word ".repeat(150);
+        assert!(c.apply(
+            "test",
+            parse_output(
+                format!("PRIVMSG #chan :{}\n", words.trim()).as_bytes(),
+                None
+            )
+        ));
+        let sent = lines(c.take_output(Instant::now()));
+        assert_eq!(sent.len(), 3);
+        for line in &sent {
+            assert!(line.starts_with(b"PRIVMSG #chan :word"));
+            assert!(line.ends_with(b"word\r"));
+            // the line keeps its \r, add the \n.
+            assert!(b":bot!~bot@cloaked/bot ".len() + line.len() + b"\n".len() <= 300);
+        }
+        let text = sent
+            .iter()
+            .map(|line| String::from_utf8_lossy(&line[15..line.len() - 1]).to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        assert_eq!(text, words.trim());
+
+        // /me lines stay /me lines, and short lines go as they are.
+        assert!(c.apply(
+            "test",
+            parse_output(
+                format!(
+                    "PRIVMSG #chan :\x01ACTION {}\x01\nNOTICE #chan :short\n",
+                    words.trim()
+                )
+                .as_bytes(),
+                None
+            )
+        ));
+        // past the pacing of the lines before.
+        let sent = lines(c.take_output(Instant::now() + Duration::from_secs(60)));
+        assert_eq!(sent.len(), 4);
+        for line in &sent[..3] {
+            assert!(line.starts_with(b"PRIVMSG #chan :\x01ACTION word"));
+            assert!(line.ends_with(b"word\x01\r"));
+        }
+        assert_eq!(sent[3], b"NOTICE #chan :short\r");
+    }
+
+    #[test]
+    fn irc_client_own_hostmask() {
+        let mut c = Client::new(
+            &Config::from_str(DEFAULT_CONF).unwrap(),
+            Storage::in_memory().unwrap(),
+        );
+        c.take_output(Instant::now());
+        // a cloak before we know who we are waits for USERHOST.
+        c.feed_bytes(b":server 396 bot early.host :is now your displayed host\r\n");
+        assert_eq!(c.state.hostmask, None);
+        assert_eq!(
+            c.feed_bytes(b":server 004 bot server v1 io ov\r\n"),
+            vec![ClientAction::Send(
+                b"USERHOST bot\r\nMODE bot +i\r\n".to_vec()
+            )]
+        );
+        c.feed_bytes(b":server 302 bot :other=+o@elsewhere BOT*=+~bot@203.0.113.7\r\n");
+        assert_eq!(c.state.hostmask.as_deref(), Some("bot!~bot@203.0.113.7"));
+        let budget = c.state.text_budget("PRIVMSG", "#chan");
+
+        c.feed_bytes(b":server 396 bot users/bot :is now your displayed host\r\n");
+        assert_eq!(c.state.hostmask.as_deref(), Some("bot!~bot@users/bot"));
+        assert_eq!(c.state.text_budget("PRIVMSG", "#chan"), budget + 2);
+
+        c.feed_bytes(b":bot!~bot@users/bot NICK :r8ball\r\n");
+        assert_eq!(c.state.nick, "r8ball");
+        assert_eq!(c.state.hostmask.as_deref(), Some("r8ball!~bot@users/bot"));
+    }
+
+    #[test]
+    fn irc_client_schedule() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF,
+            r##"
+[[schedule]]
+interval = 60
+target = "#chan"
+message = "hourly reminder"
+
+[[schedule]]
+cron = "not cron"
+target = "#chan"
+message = "never"
+"##
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let deadline = c.next_deadline().unwrap();
+        // not registered yet
+        assert!(!c.tick(deadline));
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        let deadline = c.next_deadline().unwrap();
+        assert!(c.tick(deadline));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +i\r\nPRIVMSG #chan :hourly reminder\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_join_after_identify() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#secret\"]\nnickserv_password = \"hunter2\"\njoin_after_identify = true",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +i\r\nPRIVMSG NickServ :IDENTIFY hunter2\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 900 bot bot!bot@host bot :You are now logged in as bot\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"JOIN #secret\r\n",
+        );
+        assert_eq!(c.next_deadline(), c.keepalive_deadline());
+    }
+
+    #[test]
+    fn irc_client_motd_timeout() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#chan\"]\nusermode = \"\"",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        // no JOIN until the MOTD is over.
+        replace_with(&mut fake_io, Some(b":server 004 bot :welcome\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\n",
+        );
+        let deadline = c.next_deadline().unwrap();
+        assert!(!c.tick(deadline - Duration::from_secs(1)));
+        assert!(c.tick(deadline));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"JOIN #chan\r\n",
+        );
+
+        // a MOTD asked for later doesn't join again.
+        replace_with(
+            &mut fake_io,
+            Some(b":server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+    }
+
+    #[test]
+    fn irc_client_join_failures() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#full\", \"#invite\", \"#ok\"]\njoin_retry = 60\nknock = true\nusermode = \"\"",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        assert_eq!(c.state.joining, vec!["#full", "#invite", "#ok"]);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nJOIN #full,#invite,#ok\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":bot!u@h JOIN #OK\r\n:server 471 bot #full :Cannot join channel (+l)\r\n:server 473 bot #invite :Cannot join channel (+i)\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        assert!(c.state.joining.is_empty());
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"MODE #OK +b\r\nKNOCK #invite\r\n",
+        );
+
+        let deadline = c.next_deadline().unwrap();
+        assert!(c.tick(deadline + Duration::from_secs(1)));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"JOIN #full\r\nJOIN #invite\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_erroneous_nick() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace("nick = \"bot\"", "nick = \"8-ball!\""))
+            .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let mut rejected = |c: &mut Client| {
+            replace_with(
+                &mut fake_io,
+                Some(b":server 432 * 8-ball! :Erroneous Nickname\r\n"),
+            );
+            c.receive_data(&mut fake_io).unwrap()
+        };
+        assert_eq!(rejected(&mut c), ClientReadStat::HasWritableData);
+        assert_eq!(c.state.nick, "ball");
+        // nothing left to clean up, make one up.
+        assert_eq!(rejected(&mut c), ClientReadStat::HasWritableData);
+        assert!(c.state.nick.starts_with("ball_") && c.state.nick.len() == 9);
+        assert_eq!(c.state.original_nick.as_deref(), Some("8-ball!"));
+        for _ in 0..3 {
+            assert_eq!(rejected(&mut c), ClientReadStat::HasWritableData);
+        }
+        assert!(matches!(rejected(&mut c), ClientReadStat::Error(_)));
+    }
+
+    #[test]
+    fn irc_client_identify_timeout() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#secret\"]\njoin_after_identify = true",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        let deadline = c.next_deadline().unwrap();
+        assert!(c.tick(deadline));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +i\r\nJOIN #secret\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_plugin_timers() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(&mut fake_io, None);
+
+        assert!(!c.apply(
+            "remind",
+            parse_output(
+                b":timer 60 PRIVMSG #chan :later\n:timer 30 id=x PRIVMSG #chan :cancelled\n",
+                None
+            )
+        ));
+        // other plugins can't cancel it
+        c.apply("other", parse_output(b":timer cancel x\n", None));
+        let deadline = c.next_deadline().unwrap();
+        assert!(c.tick(deadline));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :cancelled\r\n",
+        );
+
+        c.apply(
+            "remind",
+            parse_output(b":timer 1 id=x PRIVMSG #chan :no\n:timer cancel x\n", None),
+        );
+        let deadline = c.next_deadline().unwrap();
+        assert!(c.tick(deadline));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :later\r\n",
+        );
+        assert_eq!(c.next_deadline(), c.keepalive_deadline());
+    }
+
+    #[test]
+    fn irc_client_channel_keys() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF.replace(
+                "tls = false",
+                "tls = false\nchannels = [\"#open\", \"#secret key123\", \"#Locked\"]\nrejoin_on_kick = true\njoin_delay_ms = 0\nsend_delay_ms = 0",
+            ),
+            "[channel_keys]\n\"#locked\" = \"hunter2\"\n",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +i\r\nJOIN #secret,#Locked,#open key123,hunter2\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":bot!bot@host JOIN #secret\r\n:op!op@host KICK #secret bot :out\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"MODE #secret +b\r\nJOIN #secret key123\r\n",
+        );
+
+        // kicked again right away, we back off.
+        replace_with(
+            &mut fake_io,
+            Some(b":bot!bot@host JOIN #secret\r\n:op!op@host KICK #secret bot :out\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"MODE #secret +b\r\n",
+        );
+        let deadline = c.next_deadline().unwrap();
+        assert!(deadline >= Instant::now() + Duration::from_secs(4));
+        assert!(c.tick(deadline + Duration::from_secs(1)));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"JOIN #secret key123\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_channel_commands() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF,
+            r##"
+[channels."#quiet"]
+commands = ["8"]
+
+[channels."#busy"]
+disabled_commands = ["seen"]
+command_prefix = "@"
+throttle = 60
+"##
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        // not in the allow list
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #QUIET :.seen bob\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+
+        // disabled, then the wrong prefix
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #busy :@seen bob\r\n:nick!user@host PRIVMSG #busy :.karma x\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #busy :@karma x\r\n:nick!user@host PRIVMSG #busy :@karma x\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        // the second one is throttled
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #busy :nick: x has karma of 0.\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_reply_policy() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF.replace("tls = false", "tls = false\nreply_notice = true"),
+            "[channels.\"#quiet\"]\nreply_notice = false\nreply_prefix_nick = false\n",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.karma x\r\n:nick!user@host PRIVMSG #QUIET :.karma x\r\n:nick!user@host PRIVMSG bot :.karma x\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"NOTICE #chan :nick: x has karma of 0.\r\nPRIVMSG #QUIET :x has karma of 0.\r\nPRIVMSG nick :x has karma of 0.\r\n",
+        );
+
+        // external plugins answer with :reply
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.test\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let plug = c.take_spawns().pop().unwrap();
+        assert!(c.apply("test", parse_output(b":reply hi\n", plug.route.as_ref())));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"NOTICE #chan :nick: hi\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_addressed_command() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :BOT: karma x\r\n:nick!user@host PRIVMSG #chan :bot, test arg\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :nick: x has karma of 0.\r\n",
+        );
+        assert_eq!(c.take_spawns().len(), 1);
+    }
+
+    #[test]
+    fn irc_client_ctcp() {
+        let conf = Config::from_str(
+            &DEFAULT_CONF.replace("tls = false", "tls = false\nctcp_version = \"bot 1.0\""),
+        )
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG bot :\x01VERSION\x01\r\n:nick!user@host PRIVMSG bot :\x01PING 42\x01\r\n:other!user@host PRIVMSG #chan :\x01PING 42\x01\r\n:other!user@host PRIVMSG bot :\x01FINGER\x01\r\n:other!user@host PRIVMSG bot :\x01PING 7\x01\r\n"),
+        );
+        // not in channels, and once in a while for each nick.
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"NOTICE nick :\x01VERSION bot 1.0\x01\r\nNOTICE other :\x01PING 7\x01\r\n",
+        );
+
+        // a /me is seen, but is no command.
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :\x01ACTION .karma x\x01\r\n:other!user@host PRIVMSG #chan :.seen nick\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        c.write_data(&mut fake_io).unwrap();
+        let sent = String::from_utf8(fake_io.get_ref().clone()).unwrap();
+        assert!(!sent.contains("karma of"));
+        assert!(sent.contains(".karma x"));
+    }
+
+    #[test]
+    fn irc_client_cap_negotiation() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        // we only ask for what's offered, and end after every answer.
+        replace_with(
+            &mut fake_io,
+            Some(b":server CAP * LS * :multi-prefix sasl=PLAIN\r\n:server CAP * LS :server-time unknown\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"CAP REQ :multi-prefix\r\nCAP REQ :server-time\r\n",
+        );
+        replace_with(
+            &mut fake_io,
+            Some(b":server CAP * ACK :multi-prefix\r\n:server CAP * NAK :server-time\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(&mut c, &mut fake_io, ClientWriteStat::Okay, b"CAP END\r\n");
+        assert!(c.state.caps.contains("multi-prefix"));
+        assert!(!c.state.caps.contains("server-time"));
+
+        // cap-notify, later offers are requested without another CAP END.
+        replace_with(&mut fake_io, Some(b":server CAP bot NEW :away-notify\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"CAP REQ :away-notify\r\n",
+        );
+        replace_with(&mut fake_io, Some(b":server CAP bot ACK :away-notify\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.state.caps.contains("away-notify"));
+        replace_with(&mut fake_io, Some(b":server CAP bot DEL :away-notify\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(!c.state.caps.contains("away-notify"));
+
+        // nothing we'd like, besides what we require.
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(&mut fake_io, Some(b":server CAP * LS :away-notify\r\n"));
+        match c.receive_data(&mut fake_io) {
+            Ok(ClientReadStat::Error(_)) => (),
+            _ => panic!("multi-prefix is required."),
+        }
+    }
+
+    #[test]
+    fn irc_client_sasl_external() {
+        let conf = Config::from_str(
+            &DEFAULT_CONF.replace("tls = false", "tls = false\nsasl_mechanism = \"EXTERNAL\""),
+        )
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server CAP * LS :multi-prefix sasl=PLAIN,EXTERNAL\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"CAP REQ :multi-prefix\r\nCAP REQ :sasl\r\n",
+        );
+        // CAP END waits on the login.
+        replace_with(
+            &mut fake_io,
+            Some(b":server CAP * ACK :multi-prefix\r\n:server CAP * ACK :sasl\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"AUTHENTICATE EXTERNAL\r\n",
+        );
+        replace_with(&mut fake_io, Some(b"AUTHENTICATE +\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"AUTHENTICATE +\r\n",
+        );
+        replace_with(
+            &mut fake_io,
+            Some(b":server 900 bot bot!bot@host bot :You are now logged in as bot\r\n:server 903 bot :SASL authentication successful\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(&mut c, &mut fake_io, ClientWriteStat::Okay, b"CAP END\r\n");
+        assert!(c.identified);
+
+        // not without our mechanism.
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(
+            &mut fake_io,
+            Some(b":server CAP * LS :multi-prefix sasl=PLAIN\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"CAP REQ :multi-prefix\r\n",
+        );
+
+        // a failed login is fatal.
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(
+            &mut fake_io,
+            Some(b":server CAP * LS :multi-prefix sasl\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(&mut fake_io, Some(b":server CAP * ACK :sasl\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(
+            &mut fake_io,
+            Some(b":server 904 bot :SASL authentication failed\r\n"),
+        );
+        match c.receive_data(&mut fake_io) {
+            Ok(ClientReadStat::Error(_)) => (),
+            _ => panic!("SASL failures are fatal."),
+        }
+    }
+
+    #[test]
+    fn irc_client_playback() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b"@time=2021-01-01T00:00:00.000Z :nick!user@host PRIVMSG #chan :.karma x\r\n@time=2021-01-01T00:00:01.000Z :nick!user@host PRIVMSG #chan :.test\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+        assert_eq!(
+            c.state.history.recent(&c.state.chan_key(b"#chan")).count(),
+            2
+        );
+
+        // lines stamped just now are acted on.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (year, month, day) = schedule::civil_from_days((now / 86400) as i64);
+        let secs = now % 86400;
+        let line = format!(
+            "@time={:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z :nick!user@host PRIVMSG #chan :.karma x\r\n",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        );
+        replace_with(&mut fake_io, Some(line.as_bytes()));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #chan :nick: x has karma of 0.\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_chathistory() {
+        let conf =
+            Config::from_str(&DEFAULT_CONF.replace("tls = false", "tls = false\nchathistory = 50"))
+                .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+        c.state.caps.insert("draft/chathistory".to_owned());
+
+        replace_with(&mut fake_io, Some(b":bot!user@host JOIN #chan\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"CHATHISTORY LATEST #chan * 50\r\nMODE #chan +b\r\n",
+        );
+
+        // history is remembered, but runs nothing; whatever its time.
+        replace_with(
+            &mut fake_io,
+            Some(b":server BATCH +h1 chathistory #chan\r\n@batch=h1 :nick!user@host PRIVMSG #chan :.karma x\r\n@batch=h1 :nick!user@host PRIVMSG #chan :.test\r\n:server BATCH -h1\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+        assert_eq!(
+            c.state.history.recent(&c.state.chan_key(b"#chan")).count(),
+            2
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.karma x\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+    }
+
+    #[test]
+    fn irc_client_labeled_echo() {
+        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+        c.state.caps.insert("echo-message".to_owned());
+        c.state.caps.insert("labeled-response".to_owned());
+
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #chan :.karma x\r\n:nick!user@host PRIVMSG #chan :.karma y\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"@label=r8b1 PRIVMSG #chan :nick: x has karma of 0.\r\n@label=r8b2 PRIVMSG #chan :nick: y has karma of 0.\r\n",
+        );
+
+        // our own echo is never a command.
+        replace_with(
+            &mut fake_io,
+            Some(b"@label=r8b1;time=2021-01-01T00:00:00.000Z :bot!user@host PRIVMSG #chan :nick: x has karma of 0.\r\n@label=r8b2 :server 404 bot #chan :Cannot send to channel\r\n:bot!user@host PRIVMSG #chan :.test\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
+        assert!(c.labels.answer("r8b1").is_none());
+        assert!(c.labels.answer("r8b2").is_none());
+    }
+
+    #[test]
+    fn irc_client_admin_accounts() {
+        let conf = Config::from_str(
+            &DEFAULT_CONF
+                .replace("tls = false", "tls = false\nadmins = [\"Boss\"]")
+                .replace(
+                    "test = \"./test\"",
+                    "test = { path = \"./test\", admin = true }",
+                ),
+        )
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let mut runs = |c: &mut Client, lines: &[u8]| {
+            replace_with(&mut fake_io, Some(lines));
+            c.receive_data(&mut fake_io).unwrap();
+            c.take_spawns().len()
+        };
+        assert_eq!(runs(&mut c, b":nick!user@host PRIVMSG #chan :.test\r\n"), 0);
+        // the account tag, which is remembered for the hostmask.
+        assert_eq!(
+            runs(
+                &mut c,
+                b"@account=boss :nick!user@host PRIVMSG #chan :.test\r\n"
+            ),
+            1
+        );
+        assert_eq!(runs(&mut c, b":nick!user@host PRIVMSG #chan :.test\r\n"), 1);
+        // account-notify
+        assert_eq!(
+            runs(
+                &mut c,
+                b":nick!user@host ACCOUNT *\r\n:nick!user@host PRIVMSG #chan :.test\r\n"
+            ),
+            0
+        );
+        // extended-join
+        assert_eq!(
+            runs(
+                &mut c,
+                b":other!u@h JOIN #chan boss :Real Name\r\n:other!u@h PRIVMSG #chan :.test\r\n"
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn irc_client_away() {
         let conf = Config::from_str(DEFAULT_CONF).unwrap();
         let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        let mut c = Client::new(&conf);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
         c.write_data(&mut fake_io).unwrap();
 
-        // test truncated while I'm at it. (the dangling P)
-        replace_with(&mut fake_io, Some(b"PING :xyz\r\nPIN"));
-        read_expect(&mut c, &mut &mut fake_io, ClientReadStat::HasWritableData);
+        replace_with(
+            &mut fake_io,
+            Some(b":bob!user@host AWAY :lunch\r\n:alice!user@host PRIVMSG #chan :.tell bob hi\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        assert_eq!(c.state.away_message("BOB"), Some("lunch"));
+        replace_with(&mut fake_io, None);
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(&mut fake_io, None);
+
+        // memos wait until bob is back, even across nick changes.
+        replace_with(
+            &mut fake_io,
+            Some(b":bob!user@host PART #chan\r\n:bob!user@host JOIN #chan\r\n:bob!user@host NICK bobby\r\n:bobby!user@host NICK bob\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.state.is_away("bob"));
+
+        replace_with(&mut fake_io, Some(b":bob!user@host AWAY\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
         write_expect(
             &mut c,
-            &mut &mut fake_io,
+            &mut fake_io,
             ClientWriteStat::Okay,
-            b"PONG :xyz\r\n",
+            b"NOTICE bob :bob: alice said 0s ago: hi\r\n",
         );
+        assert!(!c.state.is_away("bob"));
     }
 
     #[test]
-    fn irc_client_truncations() {
+    fn irc_client_mode_tracking() {
         let conf = Config::from_str(DEFAULT_CONF).unwrap();
         let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        let mut c = Client::new(&conf);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
         c.write_data(&mut fake_io).unwrap();
 
-        // test truncated while I'm at it. (the dangling P)
-        replace_with(&mut fake_io, Some(b"PING :xyz\r\nPIN"));
-        read_expect(&mut c, &mut &mut fake_io, ClientReadStat::HasWritableData);
+        replace_with(
+            &mut fake_io,
+            Some(b":server 005 bot CASEMAPPING=ascii CHANTYPES=# PREFIX=(qaohv)~&@%+ CHANMODES=beI,k,l,imnpst :are supported by this server\r\n:server 353 bot = #chan :~@Bot +other\r\n:server 353 bot = #Other :vic\r\n:bot MODE bot :+iw\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.state.casemapping == CaseMapping::Ascii);
+        assert_eq!(c.state.privileges("#CHAN"), "~@");
+        assert_eq!(c.state.privileges("#other"), "");
+        assert!(c.state.umode.contains(&b'i') && c.state.umode.contains(&b'w'));
+
+        replace_with(
+            &mut fake_io,
+            Some(b":op!u@h MODE #chan -q+b-o+h bot *!*@spam bot bot\r\n:op!u@h MODE #other +lv 10 bot\r\n:bot MODE bot -w\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.state.privileges("#chan"), "%");
+        assert_eq!(c.state.privileges("#other"), "+");
+        assert!(!c.state.umode.contains(&b'w'));
+
+        replace_with(&mut fake_io, Some(b":bot!u@h PART #chan\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.state.privileges("#chan"), "");
+    }
+
+    #[test]
+    fn irc_client_require_op() {
+        let conf = Config::from_str(&format!(
+            "{}\n[[gateways]]\nmask = \"relay!*@matrix.org\"\n",
+            DEFAULT_CONF.replace(
+                "test = \"./test\"",
+                "test = { path = \"./test\", require_op = true }",
+            )
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let mut runs = |c: &mut Client, lines: &[u8]| {
+            replace_with(&mut fake_io, Some(lines));
+            c.receive_data(&mut fake_io).unwrap();
+            c.take_spawns().len()
+        };
+        runs(&mut c, b":server 353 bot = #chan :@op +voiced bot\r\n");
+        assert_eq!(c.state.member_privileges("#chan", "Voiced"), "+");
+        assert_eq!(runs(&mut c, b":voiced!u@h PRIVMSG #chan :.test\r\n"), 0);
+        assert_eq!(runs(&mut c, b":op!u@h PRIVMSG #chan :.test\r\n"), 1);
+        assert_eq!(runs(&mut c, b":op!u@h PRIVMSG bot :.test\r\n"), 0);
+        // anyone behind a relay can claim to be op.
+        assert_eq!(
+            runs(&mut c, b":relay!r@matrix.org PRIVMSG #chan :<op> .test\r\n"),
+            0
+        );
+
+        assert_eq!(
+            runs(
+                &mut c,
+                b":op!u@h MODE #chan +o-v voiced voiced\r\n:voiced!u@h NICK promoted\r\n:promoted!u@h PRIVMSG #chan :.test\r\n"
+            ),
+            1
+        );
+        assert_eq!(
+            runs(
+                &mut c,
+                b":op!u@h PART #chan\r\n:op!u@h JOIN #chan\r\n:op!u@h PRIVMSG #chan :.test\r\n"
+            ),
+            0
+        );
+        assert_eq!(c.state.member_privileges("#chan", "promoted"), "@");
+    }
+
+    #[test]
+    fn irc_client_topic() {
+        let conf = Config::from_str(&format!(
+            "{}\n[[gateways]]\nmask = \"relay!*@matrix.org\"\n",
+            DEFAULT_CONF
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 332 bot #chan :old news\r\n:server 333 bot #chan op!u@h 1600000000\r\n:server 353 bot = #chan :@op user bot\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let topic = c.state.topic("#CHAN").unwrap();
+        assert_eq!(topic.text, "old news");
+        assert_eq!(topic.setter, "op");
+        assert_eq!(topic.set_at, 1600000000);
+
+        replace_with(
+            &mut fake_io,
+            Some(b"@time=2021-01-01T00:00:00.000Z :op!u@h TOPIC #chan :new news\r\n:user!u@h PRIVMSG #chan :.topic nope\r\n:op!u@h PRIVMSG #chan :.topic newer news\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        assert_eq!(c.state.topic("#chan").unwrap().set_at, 1609459200);
         write_expect(
             &mut c,
-            &mut &mut fake_io,
+            &mut fake_io,
             ClientWriteStat::Okay,
-            b"PONG :xyz\r\n",
+            b"PRIVMSG #chan :user: only channel operators can set the topic.\r\nTOPIC #chan :newer news\r\n",
         );
 
-        // test truncation handling by writing out the rest
-        replace_with(&mut fake_io, Some(b"G asdf\r\n"));
-        read_expect(&mut c, &mut &mut fake_io, ClientReadStat::HasWritableData);
+        // the relay isn't op, whoever it claims to speak for.
+        replace_with(
+            &mut fake_io,
+            Some(b":relay!r@matrix.org PRIVMSG #chan :<op> .topic relayed news\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
         write_expect(
             &mut c,
-            &mut &mut fake_io,
+            &mut fake_io,
             ClientWriteStat::Okay,
-            b"PONG asdf\r\n",
+            b"PRIVMSG #chan :op: only channel operators can set the topic.\r\n",
         );
 
-        // One more time
-        replace_with(&mut fake_io, Some(b"PING :1234\r\n"));
-        read_expect(&mut c, &mut &mut fake_io, ClientReadStat::HasWritableData);
+        replace_with(&mut fake_io, Some(b":op!u@h TOPIC #chan :\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.state.topic("#chan").is_none());
+    }
+
+    #[test]
+    fn irc_client_ban_lists() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "test = \"./test\"",
+            "test = { path = \"./test\", moderator = true }",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 005 bot PREFIX=(ov)@+ CHANMODES=bqeI,k,l,imnpst :are supported\r\n:bot!u@h JOIN #chan\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
         write_expect(
             &mut c,
-            &mut &mut fake_io,
+            &mut fake_io,
             ClientWriteStat::Okay,
-            b"PONG :1234\r\n",
+            b"MODE #chan +b\r\nMODE #chan +q\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 367 bot #chan *!*@spam op 1600000000\r\n:server 368 bot #chan :End of Channel Ban List\r\n:server 728 bot #chan q loud!*@* op 1600000000\r\n:server 353 bot = #chan :@bot\r\n:op!u@h MODE #chan +b-q *!*@evil loud!*@*\r\n"),
         );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.state.list("#CHAN", b'b'), ["*!*@spam", "*!*@evil"]);
+        assert!(c.state.list("#chan", b'q').is_empty());
+        assert_eq!(
+            c.state.ban_matching("#chan", "nick!user@EVIL"),
+            Some("*!*@evil")
+        );
+        assert_eq!(c.state.ban_matching("#chan", "nick!user@host"), None);
+
+        // already banned, nothing to send.
+        assert!(!c.apply("test", parse_output(b":ban #chan *!*@SPAM\n", None)));
+
+        replace_with(
+            &mut fake_io,
+            Some(b":op!u@h MODE #chan -b *!*@spam\r\n:nick!u@h PRIVMSG #chan :.test\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.take_spawns().len(), 1);
+        assert_eq!(c.state.list("#chan", b'b'), ["*!*@evil"]);
+
+        replace_with(&mut fake_io, Some(b":bot!u@h PART #chan\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.state.list("#chan", b'b').is_empty());
     }
 
     #[test]
-    fn irc_client_multiple_messages() {
-        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+    fn irc_client_watch() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF.replace(
+                "tls = false",
+                "tls = false\nmonitor = [\"friend\", \"pal\"]"
+            ),
+            "[hooks]\nonline = \"./notify\"\noffline = \"./notify\"\n"
+        ))
+        .unwrap();
+        let names = |c: &mut Client| {
+            c.take_spawns()
+                .into_iter()
+                .map(|plug| plug.name)
+                .collect::<Vec<String>>()
+        };
         let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        let mut c = Client::new(&conf);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
         // throw away greeter
         c.write_data(&mut fake_io).unwrap();
 
-        let test_data = b"PING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\nPING :1234\r\n";
-        let test_data_exp = b"PONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\nPONG :1234\r\n";
-        replace_with(&mut fake_io, Some(test_data));
-        read_expect(&mut c, &mut &mut fake_io, ClientReadStat::HasWritableData);
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 005 bot MONITOR=100 :are supported\r\n:server 376 bot :End of MOTD\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
         write_expect(
             &mut c,
-            &mut &mut fake_io,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +i\r\nMONITOR + friend,pal\r\n",
+        );
+        assert_eq!(c.watch.deadline(), None);
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 730 bot :FRIEND!u@h,pal!u@h\r\n:server 730 bot :friend!u@h\r\n:server 731 bot :pal\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(names(&mut c), vec!["online", "online", "offline"]);
+
+        // without MONITOR we poll.
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.write_data(&mut fake_io).unwrap();
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 422 bot :No MOTD\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
             ClientWriteStat::Okay,
-            test_data_exp,
+            b"USERHOST bot\r\nMODE bot +i\r\nISON friend pal\r\n",
+        );
+        replace_with(
+            &mut fake_io,
+            Some(b":server 303 bot :pal\r\n:server 303 bot :friend\r\n"),
         );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(names(&mut c), vec!["online", "online", "offline"]);
+        assert!(c.watch.deadline().is_some());
     }
 
     #[test]
-    fn irc_client_unknown_cmd() {
+    fn irc_client_whois() {
         let conf = Config::from_str(DEFAULT_CONF).unwrap();
         let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        let mut c = Client::new(&conf);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
         // throw away greeter
         c.write_data(&mut fake_io).unwrap();
+        replace_with(&mut fake_io, None);
 
-        replace_with(&mut fake_io, Some(b"UNKNOWN"));
-        read_expect(&mut c, &mut &mut fake_io, ClientReadStat::Okay);
-        write_expect(&mut c, &mut &mut fake_io, ClientWriteStat::Eof, b"");
+        assert!(c.apply("test", parse_output(b":whois Friend first\n", None)));
+        // shares the lookup in flight.
+        assert!(!c.apply("test", parse_output(b":whois FRIEND second\n", None)));
+        // not a plugin we know how to run again.
+        assert!(!c.apply("other", parse_output(b":whois someone\n", None)));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"WHOIS Friend\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 311 bot friend user host * :Real Name\r\n:server 330 bot friend acct :is logged in as\r\n:server 318 bot friend :End of /WHOIS list.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        let names = c
+            .take_spawns()
+            .into_iter()
+            .map(|spawn| spawn.name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["test", "test"]);
+
+        // no lookup in flight.
+        replace_with(
+            &mut fake_io,
+            Some(b":server 318 bot friend :End of /WHOIS list.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.take_spawns().is_empty());
     }
 
     #[test]
-    fn irc_client_nick_conflict() {
-        let conf = Config::from_str(DEFAULT_CONF).unwrap();
+    fn irc_client_friends() {
+        let conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF,
+            "[friends]\n\"*!*@trusted\" = \"o\"\nalice = \"v\"\nbad = \"ov\"\n\"pal!*@*\" = \"v\"\n"
+        ))
+        .unwrap();
         let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        let mut c = Client::new(&conf);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
         // throw away greeter
         c.write_data(&mut fake_io).unwrap();
 
+        // we aren't an op yet.
+        replace_with(&mut fake_io, Some(b":pal!u@trusted JOIN #chan\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert_eq!(c.next_deadline(), c.keepalive_deadline());
+
         replace_with(
             &mut fake_io,
-            Some(b":bot!bot@bot.localhost 433 :name in use\r\n"),
+            Some(b":server 353 bot = #chan :@bot\r\n:pal!u@trusted JOIN #chan\r\n:al!u@home JOIN #chan alice :Alice\r\n:bad!u@home JOIN #chan bad :Bad\r\n:stranger!u@home JOIN #chan\r\n"),
         );
-        read_expect(&mut c, &mut &mut fake_io, ClientReadStat::HasWritableData);
+        // not right away.
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.tick(Instant::now() + Duration::from_secs(5)));
+        c.write_data(&mut fake_io).unwrap();
+        let written = String::from_utf8(fake_io.get_ref().clone()).unwrap();
+        let mut lines = written.lines().collect::<Vec<&str>>();
+        lines.sort_unstable();
+        // the first friend matching wins.
+        assert_eq!(lines, vec!["MODE #chan +o pal", "MODE #chan +v al"]);
+    }
 
-        let status = c.write_data(&mut fake_io).unwrap();
-        assert_eq!(status, ClientWriteStat::Okay);
-        let m = Message::new(&fake_io.get_ref()[..fake_io.get_ref().len() - 2]);
-        assert_eq!(m.command.unwrap(), b"NICK");
-        assert_eq!(&m.params.unwrap()[..4], b"bot_");
-        assert_ne!(m.params.unwrap(), b"bot");
+    #[test]
+    fn irc_client_oper() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\noper_name = \"r8\"\noper_password = \"hunter2\"\nusermode = \"\"",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nOPER r8 hunter2\r\n",
+        );
+        assert!(!c.state.is_oper());
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 381 bot :You are now an IRC operator\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(c.state.is_oper());
+        replace_with(&mut fake_io, Some(b":bot MODE bot :-o\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        assert!(!c.state.is_oper());
+    }
+
+    #[test]
+    fn irc_client_moderation() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "test = \"./test\"",
+            "test = { path = \"./test\", moderator = true }",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        let output = b":kick #chan spammer bye\n:ban #chan *!*@spam\n";
+        // we're not an op yet.
+        assert!(!c.apply("test", parse_output(output, None)));
+        replace_with(
+            &mut fake_io,
+            Some(b":server 353 bot = #chan :@bot spammer\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::Okay);
+        // not trusted.
+        assert!(!c.apply("other", parse_output(output, None)));
+
+        assert!(c.apply("test", parse_output(output, None)));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"KICK #chan spammer :bye\r\nMODE #chan +b *!*@spam\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_paced_joins() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#a\", \"#b\", \"#c\"]\njoin_batch_size = 2",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +i\r\nJOIN #a,#b\r\n",
+        );
+
+        let deadline = c.next_deadline().unwrap();
+        assert!(!c.tick(Instant::now()));
+        assert!(c.tick(deadline));
+        write_expect(&mut c, &mut fake_io, ClientWriteStat::Okay, b"JOIN #c\r\n");
+        assert_eq!(c.next_deadline(), c.keepalive_deadline());
+    }
+
+    #[test]
+    fn irc_client_reload() {
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#a\", \"#b\"]\njoin_delay_ms = 0",
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        c.register_native(Box::new(Echo));
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        // before joining, only what we join changes.
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#A\", \"#c\"]\njoin_delay_ms = 0",
+        ))
+        .unwrap();
+        assert!(!c.reload(&conf));
+        replace_with(
+            &mut fake_io,
+            Some(b":server 004 bot :welcome\r\n:server 376 bot :End of /MOTD command.\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"USERHOST bot\r\nMODE bot +i\r\nJOIN #a,#c\r\n",
+        );
+
+        replace_with(
+            &mut fake_io,
+            Some(b":bot!bot@host JOIN #a\r\n:bot!bot@host JOIN #c\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"MODE #a +b\r\nMODE #c +b\r\n",
+        );
+
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            "tls = false\nchannels = [\"#c\", \"#d\"]\njoin_delay_ms = 0\ncommand_prefix = \"!\"\nsend_delay_ms = 0",
+        ))
+        .unwrap();
+        assert!(c.reload(&conf));
+        assert!(c.tick(Instant::now()));
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PART #A\r\nJOIN #d\r\n",
+        );
+
+        // the old prefix no longer runs commands, and replies are no longer paced.
+        replace_with(
+            &mut fake_io,
+            Some(
+                b":nick!user@host PRIVMSG #c :.karma x\r\n:nick!user@host PRIVMSG #c :!karma x\r\n",
+            ),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #c :nick: x has karma of 0.\r\n",
+        );
+
+        // natives an embedder added, and what the built-ins learned, outlive a reload.
+        replace_with(&mut fake_io, Some(b":nick!user@host PRIVMSG #c :x++\r\n"));
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #c :x now has karma of 1.\r\n",
+        );
+        assert!(!c.reload(&conf));
+        replace_with(
+            &mut fake_io,
+            Some(b":nick!user@host PRIVMSG #c :x++\r\n:nick!user@host PRIVMSG #c :!echo hi\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        write_expect(
+            &mut c,
+            &mut fake_io,
+            ClientWriteStat::Okay,
+            b"PRIVMSG #c :hi\r\n",
+        );
+    }
+
+    #[test]
+    fn irc_client_channel_log() {
+        let dir = std::env::temp_dir().join(format!("r8ball-chanlog-{}", std::process::id()));
+        let conf = Config::from_str(&DEFAULT_CONF.replace(
+            "tls = false",
+            &format!("tls = false\nlog_channels = true\nlog_dir = {:?}", dir),
+        ))
+        .unwrap();
+        let mut fake_io: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        let mut c = Client::new(&conf, Storage::in_memory().unwrap());
+        // throw away greeter
+        c.write_data(&mut fake_io).unwrap();
+
+        replace_with(
+            &mut fake_io,
+            Some(b"@time=2021-06-01T12:00:00.000Z :nick!user@host JOIN #chan\r\n:nick!user@host PRIVMSG #chan :.karma x\r\n:nick!user@host PRIVMSG bot :secret\r\n"),
+        );
+        read_expect(&mut c, &mut fake_io, ClientReadStat::HasWritableData);
+        drop(c);
+
+        let day = fs::read_to_string(dir.join("#chan/2021-06-01.log")).unwrap();
+        assert_eq!(day, "[12:00:00] -!- nick (user@host) has joined #chan\n");
+        let today = fs::read_dir(dir.join("#chan"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| !path.ends_with("2021-06-01.log"))
+            .map(|path| fs::read_to_string(path).unwrap())
+            .unwrap();
+        let lines = today
+            .lines()
+            .map(|line| line.split_once("] ").unwrap().1)
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            lines,
+            vec!["<nick> .karma x", "<bot> nick: x has karma of 0."]
+        );
+        // only channels are logged.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
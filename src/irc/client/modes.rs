@@ -0,0 +1,244 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+/// How a channel mode takes its parameter, from ISUPPORT CHANMODES=A,B,C,D.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModeType {
+    Type1, // has a parameter
+    Type2, // has a parameter
+    Type3, // has a parameter if positive signed + (not -)
+           // Type4, // This mode isn't relevant for our uses, effectively no parameter.
+}
+
+/// What the server told us about its channel modes through ISUPPORT.
+pub struct ModeSpec {
+    // e.g. (o, @) and (v, +), highest privilege first.
+    pub prefix: Vec<(u8, u8)>,
+    pub chanmodes: Vec<(u8, ModeType)>,
+}
+
+impl Default for ModeSpec {
+    /// What RFC 1459 servers have, until ISUPPORT says otherwise.
+    fn default() -> Self {
+        ModeSpec {
+            prefix: vec![(b'o', b'@'), (b'v', b'+')],
+            chanmodes: vec![
+                (b'b', ModeType::Type1),
+                (b'k', ModeType::Type2),
+                (b'l', ModeType::Type3),
+            ],
+        }
+    }
+}
+
+/// One mode set or unset by a MODE message.
+#[derive(Debug, PartialEq)]
+pub struct ModeChange<'a> {
+    pub set: bool,
+    pub mode: u8,
+    pub arg: Option<&'a [u8]>,
+}
+
+impl ModeSpec {
+    /// PREFIX=(ohv)@%+
+    pub fn parse_prefix(&mut self, value: &[u8]) {
+        let value = match value.strip_prefix(b"(") {
+            Some(value) => value,
+            None => return,
+        };
+        if let Some(end) = value.iter().position(|&chr| chr == b')') {
+            let (modes, prefixes) = (&value[..end], &value[end + 1..]);
+            self.prefix = modes
+                .iter()
+                .copied()
+                .zip(prefixes.iter().copied())
+                .collect();
+        }
+    }
+
+    /// CHANMODES=beI,k,l,imnpst
+    pub fn parse_chanmodes(&mut self, value: &[u8]) {
+        let types = [ModeType::Type1, ModeType::Type2, ModeType::Type3];
+        self.chanmodes = value
+            .split(|&chr| chr == b',')
+            .zip(types.iter())
+            .flat_map(|(modes, kind)| modes.iter().map(move |&mode| (mode, *kind)))
+            .collect();
+    }
+
+    /// If mode is a list of masks, e.g. b, rather than a privilege like q on some
+    /// servers.
+    pub fn is_list(&self, mode: u8) -> bool {
+        self.bit(mode).is_none()
+            && self
+                .chanmodes
+                .iter()
+                .any(|&(chanmode, kind)| chanmode == mode && kind == ModeType::Type1)
+    }
+
+    /// The bit for a prefix mode, e.g. o; the highest privilege is bit 0.
+    pub fn bit(&self, mode: u8) -> Option<u64> {
+        self.position(|&(chr, _)| chr == mode)
+    }
+
+    /// The bit for a prefix, e.g. @.
+    pub fn prefix_bit(&self, prefix: u8) -> Option<u64> {
+        self.position(|&(_, chr)| chr == prefix)
+    }
+
+    /// Op (o) or anything above it; without o in PREFIX, the highest privilege.
+    pub fn is_op(&self, bits: u64) -> bool {
+        let op = self.bit(b'o').unwrap_or(1);
+        // op and every lower bit, which is a higher privilege.
+        bits & (op | (op - 1)) != 0
+    }
+
+    fn position(&self, pred: impl Fn(&(u8, u8)) -> bool) -> Option<u64> {
+        self.prefix
+            .iter()
+            .position(pred)
+            .filter(|&pos| pos < 64)
+            .map(|pos| 1 << pos)
+    }
+
+    /// The prefixes for privilege bits, highest first, e.g. "@+".
+    pub fn prefixes(&self, bits: u64) -> String {
+        self.prefix
+            .iter()
+            .enumerate()
+            .filter(|&(pos, _)| pos < 64 && bits & (1 << pos) != 0)
+            .map(|(_, &(_, prefix))| prefix as char)
+            .collect()
+    }
+
+    /// Split channel mode changes, e.g. "+o-v" "nick" "other", into each change.
+    pub fn parse_changes<'a>(
+        &self,
+        modes: &[u8],
+        mut args: impl Iterator<Item = &'a [u8]>,
+    ) -> Vec<ModeChange<'a>> {
+        let mut set = true;
+        let mut ret = vec![];
+        for &mode in modes {
+            match mode {
+                b'+' => set = true,
+                b'-' => set = false,
+                _ => {
+                    let kind = self
+                        .chanmodes
+                        .iter()
+                        .find(|(chanmode, _)| *chanmode == mode)
+                        .map(|(_, kind)| *kind);
+                    let has_arg = self.bit(mode).is_some()
+                        || match kind {
+                            Some(ModeType::Type1) | Some(ModeType::Type2) => true,
+                            Some(ModeType::Type3) => set,
+                            None => false,
+                        };
+                    let arg = if has_arg { args.next() } else { None };
+                    ret.push(ModeChange { set, mode, arg });
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// User mode changes, e.g. "+iw-x".
+pub fn parse_umodes(modes: &[u8]) -> Vec<(bool, u8)> {
+    let mut set = true;
+    modes
+        .iter()
+        .filter_map(|&mode| match mode {
+            b'+' => {
+                set = true;
+                None
+            }
+            b'-' => {
+                set = false;
+                None
+            }
+            _ => Some((set, mode)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_umodes, ModeChange, ModeSpec};
+
+    #[test]
+    fn isupport() {
+        let mut spec = ModeSpec::default();
+        spec.parse_prefix(b"(qaohv)~&@%+");
+        spec.parse_chanmodes(b"beI,k,l,imnpst");
+        assert_eq!(spec.bit(b'o'), Some(4));
+        assert_eq!(spec.prefix_bit(b'+'), Some(16));
+        assert_eq!(spec.bit(b'+'), None);
+        assert_eq!(spec.prefix_bit(b'v'), None);
+        assert_eq!(spec.prefixes(4 | 16), "@+");
+        assert!(spec.is_op(1) && spec.is_op(4 | 16));
+        assert!(!spec.is_op(8 | 16));
+        // q is owner here, not a quiet list.
+        assert!(spec.is_list(b'b') && !spec.is_list(b'q') && !spec.is_list(b'k'));
+    }
+
+    #[test]
+    fn mode_changes() {
+        let spec = ModeSpec::default();
+        let args = vec![&b"bot"[..], b"*!*@spam", b"secret", b"other"];
+        assert_eq!(
+            spec.parse_changes(b"+ob-nk+v", args.into_iter()),
+            vec![
+                ModeChange {
+                    set: true,
+                    mode: b'o',
+                    arg: Some(b"bot")
+                },
+                ModeChange {
+                    set: true,
+                    mode: b'b',
+                    arg: Some(b"*!*@spam")
+                },
+                ModeChange {
+                    set: false,
+                    mode: b'n',
+                    arg: None
+                },
+                ModeChange {
+                    set: false,
+                    mode: b'k',
+                    arg: Some(b"secret")
+                },
+                ModeChange {
+                    set: true,
+                    mode: b'v',
+                    arg: Some(b"other")
+                },
+            ]
+        );
+        // -l takes no argument.
+        let changes = spec.parse_changes(b"-l+l", vec![&b"10"[..]].into_iter());
+        assert_eq!(changes[0].arg, None);
+        assert_eq!(changes[1].arg, Some(&b"10"[..]));
+        assert_eq!(
+            parse_umodes(b"+iw-x"),
+            vec![(true, b'i'), (true, b'w'), (false, b'x')]
+        );
+    }
+}
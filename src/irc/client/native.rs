@@ -0,0 +1,307 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::HashMap,
+    sync::{mpsc::Sender, Arc},
+};
+
+use mio::Waker;
+use rand::prelude::SmallRng;
+
+use crate::{
+    config::config_file::{ChannelConfig, Config, Verbosity},
+    storage::Storage,
+};
+
+use super::{
+    helpers::channel_verbosity,
+    output::{ReplyPolicy, Route},
+    State,
+};
+
+/// A PRIVMSG as seen by plugins.
+pub struct PrivMsg {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    /// The channel or nick the message was sent to.
+    pub target: String,
+    /// Where replies should go; the channel, or the sender for private messages.
+    pub reply_to: String,
+    /// The message was sent directly to us rather than to a channel.
+    pub private: bool,
+    pub text: String,
+    /// The message was a /me (CTCP ACTION), text is what they did.
+    pub action: bool,
+    /// How replies are sent in the channel.
+    pub policy: ReplyPolicy,
+    /// The services account of the sender, if they are logged in and we know it.
+    pub account: Option<String>,
+    /// The sender is in `[general]` admins.
+    pub admin: bool,
+    /// The relay bot's nick, when nick is the user it relayed the message for.
+    pub relay: Option<String>,
+}
+
+impl PrivMsg {
+    pub fn hostmask(&self) -> String {
+        format!("{}!{}@{}", self.nick, self.user, self.host)
+    }
+
+    /// The nick which actually sent the message, the relay's for relayed ones.
+    /// Anyone behind a relay can claim any nick, so check privileges against this.
+    pub fn sender(&self) -> &str {
+        self.relay.as_deref().unwrap_or(&self.nick)
+    }
+
+    /// Where and how to answer this message.
+    pub fn route(&self) -> Route {
+        Route {
+            target: self.reply_to.clone(),
+            nick: self.nick.clone(),
+            private: self.private,
+            policy: self.policy,
+            relay: self.relay.clone(),
+        }
+    }
+
+    /// Format a line replying to this message.
+    pub fn reply(&self, text: &str) -> String {
+        self.route().reply(text)
+    }
+
+    /// Format a line replying to this message, addressed to the sender if the
+    /// channel wants that.
+    pub fn answer(&self, text: &str) -> String {
+        self.route().answer(text)
+    }
+}
+
+/// A command parsed out of a PRIVMSG, e.g. ".8 will it rain?".
+pub struct Command<'a> {
+    /// The command word, without the prefix.
+    pub name: &'a str,
+    /// Everything after the command word.
+    pub args: &'a str,
+}
+
+/// Channel membership and presence events plugins may observe.
+pub enum Event<'a> {
+    Join {
+        nick: &'a str,
+        channel: &'a str,
+    },
+    Part {
+        nick: &'a str,
+        channel: &'a str,
+        reason: &'a str,
+    },
+    Quit {
+        nick: &'a str,
+        reason: &'a str,
+    },
+    /// The user set themselves away (away-notify).
+    Away {
+        nick: &'a str,
+        message: &'a str,
+    },
+    /// The user is no longer away.
+    Back {
+        nick: &'a str,
+    },
+    /// A watched nick came online (MONITOR or ISON).
+    Online {
+        nick: &'a str,
+    },
+    /// A watched nick went offline.
+    Offline {
+        nick: &'a str,
+    },
+}
+
+/// Lets plugins reply later, e.g. from a thread doing slow work.
+#[derive(Clone)]
+pub struct Deferred {
+    send: Sender<(String, Vec<String>)>,
+    waker: Option<Arc<Waker>>,
+}
+
+impl Deferred {
+    pub fn new(send: Sender<(String, Vec<String>)>, waker: Option<Arc<Waker>>) -> Self {
+        Deferred { send, waker }
+    }
+
+    pub fn set_waker(&mut self, waker: Arc<Waker>) {
+        self.waker = Some(waker);
+    }
+
+    /// Queue raw IRC lines (without the CRLF) from source and wake the event loop.
+    pub fn send(&self, source: &str, lines: Vec<String>) {
+        if self.send.send((source.to_owned(), lines)).is_err() {
+            return;
+        }
+        if let Some(waker) = &self.waker {
+            if let Err(e) = waker.wake() {
+                warn!("Could not wake the event loop: {}", e);
+            }
+        }
+    }
+}
+
+/// The parts of the client a native plugin may use.
+pub struct Context<'a> {
+    pub rng: &'a mut SmallRng,
+    pub state: &'a State,
+    pub storage: &'a Storage,
+    pub channels: &'a HashMap<String, ChannelConfig>,
+    pub deferred: &'a Deferred,
+}
+
+impl Context<'_> {
+    /// The output verbosity class of a channel.
+    pub fn verbosity(&self, channel: &str) -> Verbosity {
+        channel_verbosity(&self.state.casemapping, self.channels, channel)
+    }
+}
+
+/// A plugin compiled into the bot.
+/// Like external plugins, they answer with raw IRC lines (without the CRLF).
+/// They are Send, so a network can be closed off the thread that ran it.
+pub trait BotPlugin: Send {
+    /// What the plugin is reported as, e.g. in send statistics.
+    fn name(&self) -> &str;
+
+    /// The command words this plugin answers to.
+    fn commands(&self) -> &[&str];
+
+    /// A line explaining one of our commands, for .help.
+    fn help(&self, _cmd: &str) -> &str {
+        ""
+    }
+
+    /// Handle one of our commands.
+    fn command(&mut self, ctx: &mut Context, msg: &PrivMsg, cmd: &Command) -> Vec<String>;
+
+    /// Observe every PRIVMSG, whether or not it is a command.
+    fn privmsg(&mut self, _ctx: &mut Context, _msg: &PrivMsg) -> Vec<String> {
+        vec![]
+    }
+
+    /// Observe users joining and leaving.
+    fn event(&mut self, _ctx: &mut Context, _ev: &Event) -> Vec<String> {
+        vec![]
+    }
+
+    /// Pick up a reloaded config, keeping whatever the plugin learned so far.
+    fn reload(&mut self, _config: &Config) {}
+}
+
+fn label(plug: &dyn BotPlugin, replies: Vec<String>) -> Vec<(String, String)> {
+    replies
+        .into_iter()
+        .map(|line| (plug.name().to_owned(), line))
+        .collect()
+}
+
+/// The native plugins and the commands they are dispatched on.
+#[derive(Default)]
+pub struct Registry {
+    plugins: Vec<Box<dyn BotPlugin>>,
+    commands: HashMap<String, usize>,
+}
+
+impl Registry {
+    /// Add a plugin, in place of the one of the same name if there is one.
+    pub fn register(&mut self, plugin: Box<dyn BotPlugin>) {
+        let idx = match self.plugins.iter().position(|p| p.name() == plugin.name()) {
+            Some(idx) => {
+                self.commands.retain(|_, owner| *owner != idx);
+                self.plugins[idx] = plugin;
+                idx
+            }
+            None => {
+                self.plugins.push(plugin);
+                self.plugins.len() - 1
+            }
+        };
+        for &cmd in self.plugins[idx].commands() {
+            self.commands.insert(cmd.to_owned(), idx);
+        }
+    }
+
+    /// Hand every plugin a reloaded config.
+    pub fn reload(&mut self, config: &Config) {
+        for plug in &mut self.plugins {
+            plug.reload(config);
+        }
+    }
+
+    /// Every command word and its help line.
+    pub fn help(&self) -> Vec<(String, String)> {
+        self.commands
+            .iter()
+            .map(|(cmd, &idx)| (cmd.clone(), self.plugins[idx].help(cmd).to_owned()))
+            .collect()
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    /// The name of the plugin which answers a command.
+    pub fn owner(&self, cmd: &str) -> Option<&str> {
+        Some(self.plugins[*self.commands.get(cmd)?].name())
+    }
+
+    /// Dispatch a command, returns None if no native plugin handles it.
+    /// Replies are paired with the name of the plugin which sent them.
+    pub fn command(
+        &mut self,
+        ctx: &mut Context,
+        msg: &PrivMsg,
+        cmd: &Command,
+    ) -> Option<Vec<(String, String)>> {
+        let plug = &mut self.plugins[*self.commands.get(cmd.name)?];
+        let replies = plug.command(ctx, msg, cmd);
+        Some(label(plug.as_ref(), replies))
+    }
+
+    /// Let every plugin observe an event.
+    pub fn event(&mut self, ctx: &mut Context, ev: &Event) -> Vec<(String, String)> {
+        self.plugins
+            .iter_mut()
+            .flat_map(|plug| {
+                let replies = plug.event(ctx, ev);
+                label(plug.as_ref(), replies)
+            })
+            .collect()
+    }
+
+    /// Let every plugin observe a PRIVMSG.
+    pub fn privmsg(&mut self, ctx: &mut Context, msg: &PrivMsg) -> Vec<(String, String)> {
+        self.plugins
+            .iter_mut()
+            .flat_map(|plug| {
+                let replies = plug.privmsg(ctx, msg);
+                label(plug.as_ref(), replies)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// The Numeric enum and its lookups are generated by build.rs from numerics.txt.
+include!(concat!(env!("OUT_DIR"), "/numerics.rs"));
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn numerics() {
+        assert_eq!(Numeric::parse(b"001"), Some(Numeric::RplWelcome));
+        assert_eq!(Numeric::parse(b"433"), Some(Numeric::ErrNicknameinuse));
+        assert_eq!(Numeric::parse(b"904"), Some(Numeric::ErrSaslfail));
+        assert_eq!(Numeric::RplTopicwhotime.code(), 333);
+        assert_eq!(Numeric::RplEndofmotd.name(), "RPL_ENDOFMOTD");
+        // unknown or not a numeric at all.
+        assert_eq!(Numeric::parse(b"999"), None);
+        assert_eq!(Numeric::parse(b"PRIVMSG"), None);
+        assert_eq!(Numeric::parse(b"0a1"), None);
+        assert_eq!(Numeric::parse(b"0001"), None);
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::borrow::Cow;
+
+/// The most a line may be, without the CRLF.
+pub const MAX_LINE: usize = 510;
+
+/// A line to send, built from a command and its params so it is always well formed.
+/// Params lose the bytes that would break the line, and the line is cut to fit.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OutMessage {
+    tags: Vec<u8>,
+    body: Vec<u8>,
+    trailing: bool,
+}
+
+/// Nothing may carry CR, LF or NUL, they end (or corrupt) the line.
+fn forbidden(chr: u8) -> bool {
+    matches!(chr, b'\r' | b'\n' | b'\0')
+}
+
+/// Escape a tag value, e.g. "a b;c" -> "a\sb\:c".
+fn escape_tag(value: &str) -> Cow<'_, str> {
+    if !value.contains([';', ' ', '\\', '\r', '\n', '\0']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 4);
+    for chr in value.chars() {
+        match chr {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            '\0' => (),
+            chr => escaped.push(chr),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+impl OutMessage {
+    pub fn new(command: &str) -> Self {
+        OutMessage {
+            body: command
+                .bytes()
+                .filter(|chr| chr.is_ascii_alphanumeric())
+                .collect(),
+            ..OutMessage::default()
+        }
+    }
+
+    /// A line that was put together elsewhere, e.g. by a plugin.
+    /// CR, LF and NUL become spaces so it stays one line.
+    pub fn raw(line: &[u8]) -> Self {
+        OutMessage {
+            body: line
+                .iter()
+                .map(|&chr| if forbidden(chr) { b' ' } else { chr })
+                .collect(),
+            ..OutMessage::default()
+        }
+    }
+
+    /// Another line in place of this one's, keeping its tags.
+    pub fn with_line(self, line: &[u8]) -> Self {
+        OutMessage {
+            tags: self.tags,
+            ..OutMessage::raw(line)
+        }
+    }
+
+    /// A message tag, e.g. label=abc; the value is escaped.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags
+            .push(if self.tags.is_empty() { b'@' } else { b';' });
+        self.tags.extend(
+            key.bytes()
+                .filter(|&chr| chr.is_ascii_alphanumeric() || b"-/.+".contains(&chr)),
+        );
+        if !value.is_empty() {
+            self.tags.push(b'=');
+            self.tags.extend(escape_tag(value).as_bytes());
+        }
+        self
+    }
+
+    /// A middle param, which can hold no spaces nor start with a colon.
+    /// Params after the trailing one are ignored.
+    pub fn param(mut self, param: impl AsRef<[u8]>) -> Self {
+        if self.trailing {
+            return self;
+        }
+        let param = param.as_ref();
+        let start = param
+            .iter()
+            .position(|&chr| chr != b':')
+            .unwrap_or(param.len());
+        let clean = param[start..]
+            .iter()
+            .filter(|&&chr| chr != b' ' && !forbidden(chr))
+            .collect::<Vec<&u8>>();
+        if !clean.is_empty() {
+            self.body.push(b' ');
+            self.body.extend(clean);
+        }
+        self
+    }
+
+    /// The last param, which may hold spaces; CR, LF and NUL become spaces.
+    pub fn trailing(mut self, text: impl AsRef<[u8]>) -> Self {
+        if self.trailing {
+            return self;
+        }
+        self.trailing = true;
+        self.body.extend(b" :");
+        self.body.extend(
+            text.as_ref()
+                .iter()
+                .map(|&chr| if forbidden(chr) { b' ' } else { chr }),
+        );
+        self
+    }
+
+    /// The line, without tags or CRLF, cut to MAX_LINE without splitting a UTF-8 character.
+    pub fn line(&self) -> &[u8] {
+        if self.body.len() <= MAX_LINE {
+            return &self.body;
+        }
+        // continuation bytes are 0b10xxxxxx.
+        let cut = (0..=MAX_LINE)
+            .rev()
+            .find(|&at| self.body[at] & 0xC0 != 0x80)
+            .unwrap_or(0);
+        &self.body[..cut]
+    }
+
+    /// The line, without tags or CRLF, however long it is.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Append the whole line, tags and CRLF included.
+    pub fn write_to(&self, buf: &mut impl Extend<u8>) {
+        if !self.tags.is_empty() {
+            buf.extend(self.tags.iter().copied());
+            buf.extend([b' ']);
+        }
+        buf.extend(self.line().iter().copied());
+        buf.extend(*b"\r\n");
+    }
+}
+
+/// Split text into pieces of at most max bytes, at a space when there is one
+/// in the latter half of a piece, else between characters.
+/// The spaces split at are dropped; every piece has at least one character.
+pub fn split_text(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = vec![];
+    let mut rest = text;
+    while rest.len() > max {
+        let cut = (1..=max)
+            .rev()
+            .find(|&at| rest.is_char_boundary(at))
+            // max is less than the first character.
+            .unwrap_or_else(|| rest.chars().next().map_or(rest.len(), char::len_utf8));
+        match rest.bytes().take(cut + 1).rposition(|chr| chr == b' ') {
+            Some(space) if space > cut / 2 => {
+                pieces.push(&rest[..space]);
+                rest = &rest[space + 1..];
+            }
+            _ => {
+                pieces.push(&rest[..cut]);
+                rest = &rest[cut..];
+            }
+        }
+    }
+    if !rest.is_empty() || pieces.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_text, OutMessage, MAX_LINE};
+
+    fn bytes(msg: &OutMessage) -> Vec<u8> {
+        let mut buf = vec![];
+        msg.write_to(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn out_message() {
+        let msg = OutMessage::new("PRIVMSG")
+            .param("#chan")
+            .trailing("hello world");
+        assert_eq!(bytes(&msg), b"PRIVMSG #chan :hello world\r\n");
+        assert_eq!(msg.line(), b"PRIVMSG #chan :hello world");
+
+        // nothing sneaks in another line or param.
+        let msg = OutMessage::new("PRIVMSG")
+            .param("#chan\r\nQUIT")
+            .trailing("hi\r\nQUIT :bye\0");
+        assert_eq!(bytes(&msg), b"PRIVMSG #chanQUIT :hi  QUIT :bye \r\n");
+        let msg = OutMessage::new("MODE")
+            .param(":#chan x")
+            .param("")
+            .param("+b");
+        assert_eq!(msg.line(), b"MODE #chanx +b");
+        let msg = OutMessage::new("JOIN").trailing("#a").param("#b");
+        assert_eq!(msg.line(), b"JOIN :#a");
+        assert_eq!(OutMessage::raw(b"QUIT :a\r\nb").line(), b"QUIT :a  b");
+
+        let msg = OutMessage::new("PRIVMSG")
+            .tag("label", "a b;c\\")
+            .tag("+draft/reply", "")
+            .param("#chan")
+            .trailing("hi");
+        assert_eq!(
+            bytes(&msg),
+            b"@label=a\\sb\\:c\\\\;+draft/reply PRIVMSG #chan :hi\r\n"
+        );
+
+        // cut to fit, not in the middle of a character.
+        let long = "\u{e9}".repeat(300);
+        let msg = OutMessage::new("PRIVMSG").param("#chan").trailing(&long);
+        let line = msg.line();
+        assert!(line.len() <= MAX_LINE);
+        assert!(std::str::from_utf8(line).is_ok());
+        assert_eq!(bytes(&msg).len(), line.len() + 2);
+    }
+
+    #[test]
+    fn split_text_fits() {
+        assert_eq!(split_text("hello world", 20), vec!["hello world"]);
+        assert_eq!(split_text("", 20), vec![""]);
+        assert_eq!(
+            split_text("hello world again", 11),
+            vec!["hello world", "again"]
+        );
+        assert_eq!(split_text("hello world", 5), vec!["hello", "world"]);
+        // no space late enough, cut mid-word.
+        assert_eq!(split_text("a verylongword", 8), vec!["a verylo", "ngword"]);
+        // never in the middle of a character.
+        let accents = "\u{e9}".repeat(5);
+        assert_eq!(
+            split_text(&accents, 3),
+            vec!["\u{e9}", "\u{e9}", "\u{e9}", "\u{e9}", "\u{e9}"]
+        );
+        assert_eq!(split_text("\u{e9}\u{e9}", 1), vec!["\u{e9}", "\u{e9}"]);
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::time::Duration;
+
+use crate::irc::{
+    iter::{BufIterator, TruncStatus},
+    parse::Message,
+};
+
+/// What a line of plugin output asks the client to do.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Send a raw IRC line, without the CRLF.
+    Send(Vec<u8>),
+    /// `:timer <seconds> [id=<id>] <line>`, send a line later.
+    Timer {
+        delay: Duration,
+        id: Option<String>,
+        line: Vec<u8>,
+    },
+    /// `:timer cancel <id>`, drop a pending timer.
+    CancelTimer(String),
+    /// `:kick`, `:ban` or `:mode`, only for plugins trusted to moderate.
+    Moderate(Moderation),
+    /// `:whois <nick> [context]`, run the plugin again with the answer.
+    Whois {
+        nick: String,
+        context: String,
+        route: Option<Route>,
+    },
+}
+
+/// A moderation directive, the client checks we have ops before sending it.
+#[derive(Debug, PartialEq)]
+pub enum Moderation {
+    /// `:kick #chan nick [reason]`
+    Kick {
+        channel: String,
+        nick: String,
+        reason: String,
+    },
+    /// `:ban #chan mask`
+    Ban { channel: String, mask: String },
+    /// `:mode #chan <modes> [args...]`
+    Mode {
+        channel: String,
+        modes: String,
+        args: Vec<String>,
+    },
+}
+
+impl Moderation {
+    pub fn channel(&self) -> &str {
+        match self {
+            Moderation::Kick { channel, .. }
+            | Moderation::Ban { channel, .. }
+            | Moderation::Mode { channel, .. } => channel,
+        }
+    }
+
+    /// The IRC line carrying it out.
+    pub fn line(&self) -> String {
+        match self {
+            Moderation::Kick {
+                channel,
+                nick,
+                reason,
+            } => format!("KICK {} {} :{}", channel, nick, reason),
+            Moderation::Ban { channel, mask } => format!("MODE {} +b {}", channel, mask),
+            Moderation::Mode {
+                channel,
+                modes,
+                args,
+            } if args.is_empty() => format!("MODE {} {}", channel, modes),
+            Moderation::Mode {
+                channel,
+                modes,
+                args,
+            } => format!("MODE {} {} {}", channel, modes, args.join(" ")),
+        }
+    }
+}
+
+/// How replies in a channel are sent, see `[general]` reply_notice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplyPolicy {
+    /// Reply with NOTICE rather than PRIVMSG.
+    pub notice: bool,
+    /// Start answers with the nick of who asked.
+    pub prefix_nick: bool,
+}
+
+impl Default for ReplyPolicy {
+    fn default() -> Self {
+        ReplyPolicy {
+            notice: false,
+            prefix_nick: true,
+        }
+    }
+}
+
+/// Where and how to answer whoever invoked a plugin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    /// The channel, or the nick for private messages.
+    pub target: String,
+    pub nick: String,
+    pub private: bool,
+    pub policy: ReplyPolicy,
+    /// The relay bot's nick, when nick is the user it relayed the message for.
+    pub relay: Option<String>,
+}
+
+impl Route {
+    /// A line sending text to the target; private messages are always answered by PRIVMSG.
+    pub fn reply(&self, text: &str) -> String {
+        let command = if self.policy.notice && !self.private {
+            "NOTICE"
+        } else {
+            "PRIVMSG"
+        };
+        // private answers go back through the relay, which needs to know for whom.
+        if self.private && self.relay.is_some() {
+            return format!("{} {} :{}: {}", command, self.target, self.nick, text);
+        }
+        format!("{} {} :{}", command, self.target, text)
+    }
+
+    /// Like reply, but addressed to the nick when the policy says so.
+    pub fn answer(&self, text: &str) -> String {
+        if self.policy.prefix_nick && !self.private {
+            self.reply(&format!("{}: {}", self.nick, text))
+        } else {
+            self.reply(text)
+        }
+    }
+}
+
+// the longest a timer may wait.
+const MAX_DELAY: u64 = 7 * 24 * 60 * 60;
+
+fn is_message(line: &[u8]) -> bool {
+    !line.contains(&0) && Message::new(line).command.is_some()
+}
+
+fn split_word(line: &[u8]) -> (&[u8], &[u8]) {
+    match line.iter().position(|&chr| chr == b' ') {
+        Some(pos) => (&line[..pos], &line[pos + 1..]),
+        None => (line, &[]),
+    }
+}
+
+fn parse_timer(args: &[u8]) -> Option<Action> {
+    let (first, rest) = split_word(args);
+    if first == b"cancel" {
+        let id = std::str::from_utf8(rest).ok()?.trim();
+        return if id.is_empty() {
+            None
+        } else {
+            Some(Action::CancelTimer(id.to_owned()))
+        };
+    }
+
+    let secs = std::str::from_utf8(first).ok()?.parse::<u64>().ok()?;
+    let (id, line) = match split_word(rest) {
+        (word, line) if word.starts_with(b"id=") => {
+            (Some(String::from_utf8_lossy(&word[3..]).to_string()), line)
+        }
+        _ => (None, rest),
+    };
+    if !is_message(line) || secs > MAX_DELAY {
+        return None;
+    }
+    Some(Action::Timer {
+        delay: Duration::from_secs(secs),
+        id,
+        line: line.to_vec(),
+    })
+}
+
+fn moderation_directive(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (directive, args) = split_word(line.strip_prefix(b":")?);
+    match directive {
+        b"kick" | b"ban" | b"mode" => Some((directive, args)),
+        _ => None,
+    }
+}
+
+fn parse_moderation(directive: &[u8], args: &[u8]) -> Option<Action> {
+    let args = std::str::from_utf8(args).ok()?;
+    if args.contains(['\0', '\r', '\n']) {
+        return None;
+    }
+    let mut words = args.split_whitespace();
+    let channel = words.next()?.to_owned();
+    let moderation = match directive {
+        b"kick" => {
+            let nick = words.next()?.to_owned();
+            let reason = words.collect::<Vec<&str>>().join(" ");
+            Moderation::Kick {
+                channel,
+                nick,
+                reason,
+            }
+        }
+        b"ban" => Moderation::Ban {
+            channel,
+            mask: words.next()?.to_owned(),
+        },
+        b"mode" => {
+            let modes = words.next()?.to_owned();
+            let valid = modes.starts_with(['+', '-'])
+                && modes
+                    .chars()
+                    .all(|chr| chr == '+' || chr == '-' || chr.is_ascii_alphabetic());
+            if !valid {
+                return None;
+            }
+            Moderation::Mode {
+                channel,
+                modes,
+                args: words.map(str::to_owned).collect(),
+            }
+        }
+        _ => return None,
+    };
+    // trailing arguments would let a plugin smuggle in anything.
+    let args_ok = match &moderation {
+        Moderation::Kick { nick, .. } => !nick.starts_with(':'),
+        Moderation::Ban { mask, .. } => !mask.starts_with(':'),
+        Moderation::Mode { args, .. } => !args.iter().any(|arg| arg.starts_with(':')),
+    };
+    if !args_ok || moderation.channel().starts_with(':') {
+        return None;
+    }
+    Some(Action::Moderate(moderation))
+}
+
+fn parse_reply(text: &[u8], route: Option<&Route>) -> Option<Action> {
+    if text.contains(&0) || text.is_empty() {
+        return None;
+    }
+    let line = route?.answer(&String::from_utf8_lossy(text));
+    Some(Action::Send(line.into_bytes()))
+}
+
+fn parse_whois(args: &[u8], route: Option<&Route>) -> Option<Action> {
+    let args = std::str::from_utf8(args).ok()?;
+    if args.contains(['\0', '\r', '\n']) {
+        return None;
+    }
+    let (nick, context) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+    // one nick, not a mask or a list.
+    if nick.is_empty() || nick.contains([',', '*', '?']) || nick.starts_with(':') {
+        return None;
+    }
+    Some(Action::Whois {
+        nick: nick.to_owned(),
+        context: context.trim().to_owned(),
+        route: route.cloned(),
+    })
+}
+
+/// Turn complete lines of plugin output into actions.
+/// `:reply <text>` answers whoever invoked the plugin, following the route.
+/// `:kick`, `:ban` and `:mode` are moderation directives, see Moderation.
+/// `:whois <nick> [context]` looks nick up and runs the plugin again with the result.
+/// Lines which aren't IRC messages or valid directives are dropped.
+pub fn parse_output(chunk: &[u8], route: Option<&Route>) -> Vec<Action> {
+    BufIterator::new(chunk)
+        .map(|line| match line {
+            TruncStatus::Full(line) | TruncStatus::Part(line) => line,
+        })
+        .filter_map(|line| {
+            if let Some(args) = line.strip_prefix(b":timer ") {
+                parse_timer(args)
+            } else if let Some(text) = line.strip_prefix(b":reply ") {
+                parse_reply(text, route)
+            } else if let Some(args) = line.strip_prefix(b":whois ") {
+                parse_whois(args, route)
+            } else if let Some((directive, args)) = moderation_directive(line) {
+                parse_moderation(directive, args)
+            } else if is_message(line) {
+                Some(Action::Send(line.to_vec()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{parse_output, Action, Moderation, ReplyPolicy, Route};
+
+    #[test]
+    fn output_lines() {
+        assert_eq!(
+            parse_output(
+                b"PRIVMSG #chan :hi\n\n:\nNOTICE nick :a\0b\nPRIVMSG #chan :bye",
+                None
+            ),
+            vec![
+                Action::Send(b"PRIVMSG #chan :hi".to_vec()),
+                Action::Send(b"PRIVMSG #chan :bye".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn timer_directives() {
+        assert_eq!(
+            parse_output(
+                b":timer 300 PRIVMSG #chan :reminder\n\
+:timer 60 id=tea NOTICE nick :tea is ready\n\
+:timer cancel tea\n\
+:timer soon PRIVMSG #chan :bad\n\
+:timer 9999999 PRIVMSG #chan :too far\n\
+:timer 10 id=x\n",
+                None
+            ),
+            vec![
+                Action::Timer {
+                    delay: Duration::from_secs(300),
+                    id: None,
+                    line: b"PRIVMSG #chan :reminder".to_vec(),
+                },
+                Action::Timer {
+                    delay: Duration::from_secs(60),
+                    id: Some("tea".to_owned()),
+                    line: b"NOTICE nick :tea is ready".to_vec(),
+                },
+                Action::CancelTimer("tea".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reply_directive() {
+        let mut route = Route {
+            target: "#chan".to_owned(),
+            nick: "nick".to_owned(),
+            private: false,
+            policy: ReplyPolicy::default(),
+            relay: None,
+        };
+        assert_eq!(
+            parse_output(b":reply hi\n:reply \n", Some(&route)),
+            vec![Action::Send(b"PRIVMSG #chan :nick: hi".to_vec())]
+        );
+        assert!(parse_output(b":reply hi\n", None).is_empty());
+
+        route.policy = ReplyPolicy {
+            notice: true,
+            prefix_nick: false,
+        };
+        assert_eq!(
+            parse_output(b":reply hi\n", Some(&route)),
+            vec![Action::Send(b"NOTICE #chan :hi".to_vec())]
+        );
+        route.private = true;
+        route.target = "nick".to_owned();
+        assert_eq!(
+            parse_output(b":reply hi\n", Some(&route)),
+            vec![Action::Send(b"PRIVMSG nick :hi".to_vec())]
+        );
+        route.relay = Some("relay".to_owned());
+        route.target = "relay".to_owned();
+        assert_eq!(
+            parse_output(b":reply hi\n", Some(&route)),
+            vec![Action::Send(b"PRIVMSG relay :nick: hi".to_vec())]
+        );
+    }
+
+    #[test]
+    fn moderation_directives() {
+        let actions = parse_output(
+            b":kick #chan spammer no ads please\n\
+:ban #chan *!*@spam.example\n\
+:mode #chan +m\n\
+:mode #chan -o+v nick other\n\
+:mode #chan m\n\
+:kick #chan :spammer\n\
+:ban #chan\n",
+            None,
+        );
+        let lines = actions
+            .iter()
+            .map(|action| match action {
+                Action::Moderate(moderation) => moderation.line(),
+                _ => panic!("not a moderation directive"),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            lines,
+            vec![
+                "KICK #chan spammer :no ads please",
+                "MODE #chan +b *!*@spam.example",
+                "MODE #chan +m",
+                "MODE #chan -o+v nick other",
+            ]
+        );
+        assert_eq!(
+            actions[1],
+            Action::Moderate(Moderation::Ban {
+                channel: "#chan".to_owned(),
+                mask: "*!*@spam.example".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn whois_directive() {
+        assert_eq!(
+            parse_output(
+                b":whois nick  check #chan\n:whois\n:whois *!*@host\n:whois a,b\n",
+                None
+            ),
+            vec![Action::Whois {
+                nick: "nick".to_owned(),
+                context: "check #chan".to_owned(),
+                route: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Queues lines and releases them no faster than one per interval, after an
+/// initial burst.
+pub struct RateLimiter {
+    interval: Duration,
+    // lines that may go out at once before pacing kicks in, at least one.
+    burst: u32,
+    // each line sent pushes this an interval further; lines go while it is at most
+    // burst - 1 intervals ahead of now.
+    next_send: Option<Instant>,
+    queue: VecDeque<Vec<u8>>,
+    // bytes in queue.
+    bytes: usize,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        RateLimiter::with_burst(interval, 0)
+    }
+
+    pub fn with_burst(interval: Duration, burst: u32) -> Self {
+        RateLimiter {
+            interval,
+            burst,
+            next_send: None,
+            queue: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Change the pacing, e.g. on a config reload, keeping what is queued.
+    /// The old pacing is forgotten, so a burst may go right away.
+    pub fn set_pace(&mut self, interval: Duration, burst: u32) {
+        self.interval = interval;
+        self.burst = burst;
+        self.next_send = None;
+    }
+
+    pub fn push(&mut self, line: Vec<u8>) {
+        self.bytes += line.len();
+        self.queue.push_back(line);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Bytes of the lines waiting.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Give up on the line that waited longest.
+    pub fn drop_oldest(&mut self) -> Option<Vec<u8>> {
+        let line = self.queue.pop_front()?;
+        self.bytes -= line.len();
+        Some(line)
+    }
+
+    /// Pop the lines that may be sent by now.
+    pub fn ready(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let allowance = now + self.allowance();
+        let mut ret = vec![];
+        while !self.queue.is_empty() {
+            match self.next_send {
+                Some(next) if next > allowance => break,
+                _ => (),
+            }
+            ret.extend(self.drop_oldest());
+            let from = self.next_send.map_or(now, |next| next.max(now));
+            self.next_send = Some(from + self.interval);
+        }
+        ret
+    }
+
+    // how far next_send may run ahead of now, the first line of a burst needs none.
+    fn allowance(&self) -> Duration {
+        self.interval * self.burst.saturating_sub(1)
+    }
+
+    /// When the next queued line may be sent, if any are queued.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.queue.is_empty() {
+            None
+        } else {
+            Some(
+                self.next_send
+                    .and_then(|next| next.checked_sub(self.allowance()))
+                    .unwrap_or_else(Instant::now),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[test]
+    fn paced_release() {
+        let start = Instant::now();
+        let mut limit = RateLimiter::new(Duration::from_secs(2));
+        limit.push(b"1".to_vec());
+        limit.push(b"2".to_vec());
+        limit.push(b"3".to_vec());
+        assert_eq!(limit.bytes(), 3);
+
+        assert_eq!(limit.ready(start), vec![b"1".to_vec()]);
+        assert_eq!(limit.bytes(), 2);
+        assert_eq!(limit.deadline(), Some(start + Duration::from_secs(2)));
+        assert!(limit.ready(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(
+            limit.ready(start + Duration::from_secs(2)),
+            vec![b"2".to_vec()]
+        );
+        assert_eq!(
+            limit.ready(start + Duration::from_secs(10)),
+            vec![b"3".to_vec()]
+        );
+        assert!(limit.is_empty());
+        assert_eq!(limit.deadline(), None);
+    }
+
+    #[test]
+    fn burst_then_paced() {
+        let start = Instant::now();
+        let mut limit = RateLimiter::with_burst(Duration::from_secs(2), 2);
+        for line in [b"1", b"2", b"3", b"4", b"5"] {
+            limit.push(line.to_vec());
+        }
+
+        assert_eq!(limit.ready(start).len(), 2);
+        assert_eq!(limit.deadline(), Some(start + Duration::from_secs(2)));
+        assert!(limit.ready(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(
+            limit.ready(start + Duration::from_secs(2)),
+            vec![b"3".to_vec()]
+        );
+        // idle time earns the burst back.
+        assert_eq!(limit.ready(start + Duration::from_secs(60)).len(), 2);
+        for line in [b"6", b"7"] {
+            limit.push(line.to_vec());
+        }
+        assert!(limit.ready(start + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn no_interval() {
+        let mut limit = RateLimiter::new(Duration::from_secs(0));
+        limit.push(b"1".to_vec());
+        limit.push(b"2".to_vec());
+        assert_eq!(limit.ready(Instant::now()).len(), 2);
+    }
+}
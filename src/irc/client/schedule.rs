@@ -0,0 +1,278 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A parsed five field cron expression: minute hour day-of-month month day-of-week.
+/// Fields take *, numbers, ranges (a-b), steps (*/n, a-b/n) and lists (a,b).
+/// Times are in UTC.
+#[derive(Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // cron matches either day field when both are restricted.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u64>()
+                    .map_err(|_| format!("bad step in {:?}", part))?,
+            ),
+            None => (part, 1),
+        };
+        let num = |num: &str| {
+            num.parse::<u64>()
+                .ok()
+                .filter(|num| (min..=max).contains(num))
+                .ok_or_else(|| format!("{:?} is not in {}-{}", num, min, max))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (num(start)?, num(end)?),
+            // a step from a single number runs to the end, e.g. 5/15.
+            None if step != 1 => (num(range)?, max),
+            None => (num(range)?, num(range)?),
+        };
+        if step == 0 || start > end {
+            return Err(format!("bad range {:?}", part));
+        }
+        for val in (start..=end).step_by(step as usize) {
+            bits |= 1 << val;
+        }
+    }
+    Ok(bits)
+}
+
+// days since the epoch to (year, month, day), from Howard Hinnant's date algorithms.
+pub fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields = expr.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 5 {
+            return Err(format!("{:?} does not have 5 fields", expr));
+        }
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            // 7 is also sunday.
+            weekdays: parse_field(fields[4], 0, 7).map(|bits| (bits | bits >> 7) & 0x7f)?,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn day_matches(&self, day: u64, weekday: u64) -> bool {
+        let day = self.days & 1 << day != 0;
+        let weekday = self.weekdays & 1 << weekday != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute after unix time secs, as unix time.
+    pub fn next_after(&self, secs: u64) -> Option<u64> {
+        let mut t = (secs / 60 + 1) * 60;
+        // enough to get through several years, a date like Feb 30 never matches.
+        for _ in 0..10_000 {
+            let days = (t / 86400) as i64;
+            let (year, month, day) = civil_from_days(days);
+            // the epoch was a thursday.
+            let weekday = (days + 4).rem_euclid(7) as u64;
+            let (hour, minute) = (t % 86400 / 3600, t % 3600 / 60);
+
+            if self.months & 1 << month == 0 {
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                t = days_from_civil(year, month, 1) as u64 * 86400;
+            } else if !self.day_matches(day, weekday) {
+                t = (days as u64 + 1) * 86400;
+            } else if self.hours & 1 << hour == 0 {
+                t = (t / 3600 + 1) * 3600;
+            } else if self.minutes & 1 << minute == 0 {
+                t += 60;
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+/// When a scheduled job runs.
+pub enum When {
+    Cron(Cron),
+    Every(Duration),
+}
+
+impl When {
+    fn next(&self, now: Instant) -> Option<Instant> {
+        match self {
+            When::Every(interval) => Some(now + *interval),
+            When::Cron(cron) => {
+                let wall = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+                let next = cron.next_after(wall)?;
+                Some(now + Duration::from_secs(next - wall))
+            }
+        }
+    }
+}
+
+struct Job<T> {
+    when: When,
+    next: Option<Instant>,
+    task: T,
+}
+
+/// Jobs run on a cron schedule or at an interval.
+pub struct Scheduler<T> {
+    jobs: Vec<Job<T>>,
+}
+
+impl<T> Default for Scheduler<T> {
+    fn default() -> Self {
+        Scheduler { jobs: vec![] }
+    }
+}
+
+impl<T: Clone> Scheduler<T> {
+    pub fn add(&mut self, now: Instant, when: When, task: T) {
+        let next = when.next(now);
+        self.jobs.push(Job { when, next, task });
+    }
+
+    /// The tasks due by now; they are rescheduled.
+    pub fn due(&mut self, now: Instant) -> Vec<T> {
+        let mut ret = vec![];
+        for job in self.jobs.iter_mut() {
+            match job.next {
+                Some(next) if next <= now => {
+                    ret.push(job.task.clone());
+                    job.next = job.when.next(now);
+                }
+                _ => (),
+            }
+        }
+        ret
+    }
+
+    /// When the next job is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.jobs.iter().filter_map(|job| job.next).min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{days_from_civil, Cron, Scheduler, When};
+
+    // 2021-03-01 00:00:00 UTC, a monday.
+    const MAR_1: u64 = 1614556800;
+
+    #[test]
+    fn cron_parsing() {
+        assert!(Cron::parse("* * * * *").is_ok());
+        assert!(Cron::parse("*/15 9-17 * * 1-5").is_ok());
+        assert!(Cron::parse("0 0 1,15 * 7").is_ok());
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+        assert!(Cron::parse("5-1 * * * *").is_err());
+    }
+
+    #[test]
+    fn cron_next() {
+        assert_eq!(days_from_civil(2021, 3, 1) as u64 * 86400, MAR_1);
+
+        let every_15 = Cron::parse("*/15 * * * *").unwrap();
+        assert_eq!(every_15.next_after(MAR_1), Some(MAR_1 + 15 * 60));
+        assert_eq!(every_15.next_after(MAR_1 + 1), Some(MAR_1 + 15 * 60));
+
+        // next sunday at noon
+        let sunday = Cron::parse("0 12 * * 0").unwrap();
+        assert_eq!(
+            sunday.next_after(MAR_1),
+            Some(MAR_1 + 6 * 86400 + 12 * 3600)
+        );
+
+        // new year, crossing the year
+        let new_year = Cron::parse("0 0 1 1 *").unwrap();
+        assert_eq!(
+            new_year.next_after(MAR_1),
+            Some(days_from_civil(2022, 1, 1) as u64 * 86400)
+        );
+
+        // either day field matches when both are restricted: the 15th or a monday.
+        let either = Cron::parse("0 0 15 * 1").unwrap();
+        assert_eq!(either.next_after(MAR_1), Some(MAR_1 + 7 * 86400));
+
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_after(MAR_1), None);
+    }
+
+    #[test]
+    fn intervals() {
+        let start = Instant::now();
+        let mut sched = Scheduler::default();
+        sched.add(start, When::Every(Duration::from_secs(10)), "a");
+        sched.add(start, When::Every(Duration::from_secs(25)), "b");
+
+        assert_eq!(sched.deadline(), Some(start + Duration::from_secs(10)));
+        assert!(sched.due(start).is_empty());
+        assert_eq!(sched.due(start + Duration::from_secs(10)), vec!["a"]);
+        assert_eq!(sched.due(start + Duration::from_secs(25)), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use super::State;
+
+/// A point in time copy of the client's public state for readers outside the event loop.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StateSnapshot {
+    pub nick: String,
+    pub channels: Vec<String>,
+    pub umode: HashSet<u8>,
+    pub channel_modes: HashMap<String, u64>,
+    // everyone in our channels, by channel and casemapped nick, with their
+    // privileges the same way as channel_modes.
+    pub members: HashMap<String, HashMap<String, u64>>,
+}
+
+impl StateSnapshot {
+    fn matches(&self, state: &State) -> bool {
+        self.nick == state.nick
+            && self.channels.len() == state.channels.len()
+            && self
+                .channels
+                .iter()
+                .zip(&state.channels)
+                .all(|(ours, theirs)| ours == theirs.as_str())
+            && self.umode == state.umode
+            && self.channel_modes.len() == state.channel_modes.len()
+            && state
+                .channel_modes
+                .iter()
+                .all(|(chan, bits)| self.channel_modes.get(chan.as_str()) == Some(bits))
+            && self.members.len() == state.members.len()
+            && state.members.iter().all(|(chan, members)| {
+                self.members.get(chan.as_str()).is_some_and(|ours| {
+                    ours.len() == members.len()
+                        && members.iter().all(|(nick, bits)| {
+                            ours.get(String::from_utf8_lossy(nick).as_ref()) == Some(bits)
+                        })
+                })
+            })
+    }
+}
+
+impl From<&State> for StateSnapshot {
+    fn from(state: &State) -> Self {
+        StateSnapshot {
+            nick: state.nick.clone(),
+            channels: state
+                .channels
+                .iter()
+                .map(|chan| chan.as_str().to_owned())
+                .collect(),
+            umode: state.umode.clone(),
+            channel_modes: state
+                .channel_modes
+                .iter()
+                .map(|(chan, &bits)| (chan.as_str().to_owned(), bits))
+                .collect(),
+            members: state
+                .members
+                .iter()
+                .map(|(chan, members)| {
+                    let members = members
+                        .iter()
+                        .map(|(nick, &bits)| (String::from_utf8_lossy(nick).to_string(), bits))
+                        .collect();
+                    (chan.as_str().to_owned(), members)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A cheaply cloneable handle to the latest snapshot.
+/// Readers only hold the lock long enough to clone the Arc, and the client swaps in
+/// a whole new snapshot, so a reader never sees a half updated state.
+#[derive(Clone, Default)]
+pub struct SnapshotHandle(Arc<Mutex<Arc<StateSnapshot>>>);
+
+impl SnapshotHandle {
+    pub fn load(&self) -> Arc<StateSnapshot> {
+        self.0
+            .lock()
+            .expect("Could not lock state snapshot.")
+            .clone()
+    }
+
+    /// Swap in a new snapshot if the state changed since the last one.
+    pub(super) fn publish(&self, state: &State) {
+        let mut current = self.0.lock().expect("Could not lock state snapshot.");
+        if !current.matches(state) {
+            *current = Arc::new(StateSnapshot::from(state));
+        }
+    }
+}
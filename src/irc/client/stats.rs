@@ -0,0 +1,425 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::irc::children::Status;
+
+/// How far back send statistics go.
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+struct Sent {
+    at: Instant,
+    // the channel, or "(private)", for PRIVMSG and NOTICE.
+    target: Option<String>,
+    source: String,
+    bytes: usize,
+}
+
+/// Lines and bytes sent for one channel or feature.
+#[derive(Debug, PartialEq)]
+pub struct Usage {
+    pub name: String,
+    pub lines: usize,
+    pub bytes: usize,
+}
+
+/// What we sent over the last WINDOW, so operators can see which channel or
+/// feature is eating into the send budget.
+#[derive(Default)]
+pub struct SendStats {
+    sent: VecDeque<Sent>,
+    // lines (and their bytes) we gave up on because too much was waiting to be sent.
+    pub dropped_lines: u64,
+    pub dropped_bytes: u64,
+}
+
+impl SendStats {
+    pub fn record(&mut self, now: Instant, target: Option<&str>, source: &str, bytes: usize) {
+        while let Some(old) = self.sent.front() {
+            if now.duration_since(old.at) < WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        self.sent.push_back(Sent {
+            at: now,
+            target: target.map(str::to_owned),
+            source: source.to_owned(),
+            bytes,
+        });
+    }
+
+    /// A line we gave up on sending.
+    pub fn dropped(&mut self, bytes: usize) {
+        self.dropped_lines += 1;
+        self.dropped_bytes += bytes as u64;
+    }
+
+    fn usage<'a, F>(&'a self, now: Instant, name: F) -> Vec<Usage>
+    where
+        F: Fn(&'a Sent) -> Option<&'a str>,
+    {
+        let mut totals = HashMap::<&str, (usize, usize)>::new();
+        for sent in self
+            .sent
+            .iter()
+            .filter(|sent| now.duration_since(sent.at) < WINDOW)
+        {
+            if let Some(name) = name(sent) {
+                let total = totals.entry(name).or_default();
+                total.0 += 1;
+                total.1 += sent.bytes;
+            }
+        }
+        let mut ret = totals
+            .into_iter()
+            .map(|(name, (lines, bytes))| Usage {
+                name: name.to_owned(),
+                lines,
+                bytes,
+            })
+            .collect::<Vec<Usage>>();
+        // busiest first
+        ret.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+        ret
+    }
+
+    /// Usage per channel, busiest first.
+    pub fn by_target(&self, now: Instant) -> Vec<Usage> {
+        self.usage(now, |sent| sent.target.as_deref())
+    }
+
+    /// Usage per feature or plugin, busiest first.
+    pub fn by_source(&self, now: Instant) -> Vec<Usage> {
+        self.usage(now, |sent| Some(&sent.source))
+    }
+}
+
+/// Totals for the current connection, and for the network since we started on it.
+pub struct ConnStats {
+    /// When we started on this network, kept across reconnects.
+    pub started: Instant,
+    /// When the server registered us, and its name.
+    pub connected: Option<(Instant, String)>,
+    pub reconnects: u64,
+    pub lines_in: u64,
+    pub bytes_in: u64,
+    pub lines_out: u64,
+    pub bytes_out: u64,
+    /// Commands, triggers and hooks run, by plugin.
+    pub invocations: HashMap<String, u64>,
+}
+
+impl Default for ConnStats {
+    fn default() -> Self {
+        ConnStats {
+            started: Instant::now(),
+            connected: None,
+            reconnects: 0,
+            lines_in: 0,
+            bytes_in: 0,
+            lines_out: 0,
+            bytes_out: 0,
+            invocations: HashMap::new(),
+        }
+    }
+}
+
+impl ConnStats {
+    /// Bytes we wrote to the server.
+    pub fn wrote(&mut self, bytes: &[u8]) {
+        self.bytes_out += bytes.len() as u64;
+        self.lines_out += bytes.iter().filter(|&&chr| chr == b'\n').count() as u64;
+    }
+
+    pub fn invoked(&mut self, plugin: &str) {
+        *self.invocations.entry(plugin.to_owned()).or_default() += 1;
+    }
+
+    /// Plugins by how often they ran, most first.
+    pub fn busiest(&self) -> Vec<(&str, u64)> {
+        let mut ret = self
+            .invocations
+            .iter()
+            .map(|(name, &count)| (name.as_str(), count))
+            .collect::<Vec<(&str, u64)>>();
+        ret.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        ret
+    }
+
+    /// The totals as "name values..." lines, for scripts reading the control socket.
+    /// Times are in seconds.
+    pub fn report(&self, now: Instant) -> Vec<String> {
+        let mut ret = vec![format!(
+            "uptime {}",
+            now.duration_since(self.started).as_secs()
+        )];
+        if let Some((at, server)) = &self.connected {
+            ret.push(format!(
+                "connected {} {}",
+                now.duration_since(*at).as_secs(),
+                server
+            ));
+        }
+        ret.push(format!("reconnects {}", self.reconnects));
+        ret.push(format!("in {} {}", self.lines_in, self.bytes_in));
+        ret.push(format!("out {} {}", self.lines_out, self.bytes_out));
+        ret.extend(
+            self.busiest()
+                .into_iter()
+                .map(|(name, count)| format!("plugin {} {}", name, count)),
+        );
+        ret
+    }
+}
+
+/// The runs of one external plugin, added up.
+#[derive(Debug, Default, PartialEq)]
+pub struct PluginUsage {
+    pub runs: u64,
+    /// From starting it until it closed its output.
+    pub wall: Duration,
+    pub slowest: Duration,
+    /// What it printed, before any of it was cut or dropped.
+    pub bytes: u64,
+    /// Runs which exited with an error, were killed, or did not start.
+    pub failed: u64,
+}
+
+impl PluginUsage {
+    pub fn average(&self) -> Duration {
+        self.wall / self.runs.max(1) as u32
+    }
+}
+
+/// What external plugins cost us, so operators can find slow or spammy ones.
+#[derive(Default)]
+pub struct PluginStats {
+    by_name: HashMap<String, PluginUsage>,
+    // plugins which closed their output but were not reaped yet.
+    exiting: Vec<(String, Status)>,
+}
+
+impl PluginStats {
+    /// A plugin closed its output; its exit status is counted once it is reaped.
+    pub fn finished(&mut self, name: &str, wall: Duration, bytes: u64, status: Status) {
+        let usage = self.by_name.entry(name.to_owned()).or_default();
+        usage.runs += 1;
+        usage.wall += wall;
+        usage.slowest = usage.slowest.max(wall);
+        usage.bytes += bytes;
+        self.exiting.push((name.to_owned(), status));
+        self.settle();
+    }
+
+    /// Count the failures of plugins reaped since.
+    pub fn settle(&mut self) {
+        let by_name = &mut self.by_name;
+        self.exiting.retain(|(name, status)| {
+            let failed = match &*status.lock().unwrap_or_else(|e| e.into_inner()) {
+                None => return true,
+                Some(Ok(exit)) => !exit.success(),
+                Some(Err(_)) => true,
+            };
+            if failed {
+                by_name.entry(name.clone()).or_default().failed += 1;
+            }
+            false
+        });
+    }
+
+    fn sorted<K: Ord>(&self, key: impl Fn(&PluginUsage) -> K) -> Vec<(&str, &PluginUsage)> {
+        let mut ret = self
+            .by_name
+            .iter()
+            .map(|(name, usage)| (name.as_str(), usage))
+            .collect::<Vec<(&str, &PluginUsage)>>();
+        ret.sort_by(|a, b| key(b.1).cmp(&key(a.1)).then_with(|| a.0.cmp(b.0)));
+        ret
+    }
+
+    /// By the time they took all together, most first.
+    pub fn slowest(&self) -> Vec<(&str, &PluginUsage)> {
+        self.sorted(|usage| usage.wall)
+    }
+
+    /// By what they printed, most first.
+    pub fn loudest(&self) -> Vec<(&str, &PluginUsage)> {
+        self.sorted(|usage| usage.bytes)
+    }
+
+    /// By how often they failed, most first, leaving out the ones which never did.
+    pub fn failing(&self) -> Vec<(&str, &PluginUsage)> {
+        let mut ret = self.sorted(|usage| usage.failed);
+        ret.retain(|(_, usage)| usage.failed > 0);
+        ret
+    }
+
+    /// "run name runs wall_ms slowest_ms bytes failed" lines, slowest first, for
+    /// the control socket.
+    pub fn report(&self) -> Vec<String> {
+        self.slowest()
+            .into_iter()
+            .map(|(name, usage)| {
+                format!(
+                    "run {} {} {} {} {} {}",
+                    name,
+                    usage.runs,
+                    usage.wall.as_millis(),
+                    usage.slowest.as_millis(),
+                    usage.bytes,
+                    usage.failed
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use std::{
+        io,
+        os::unix::process::ExitStatusExt,
+        process::ExitStatus,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{ConnStats, PluginStats, SendStats, Usage};
+
+    #[test]
+    fn connection_totals() {
+        let mut stats = ConnStats::default();
+        stats.wrote(b"PRIVMSG #a :hi\r\nPING :x\r\nNOT");
+        stats.wrote(b"ICE bob :hey\r\n");
+        assert_eq!((stats.lines_out, stats.bytes_out), (3, 42));
+        stats.invoked("seen");
+        stats.invoked("8ball");
+        stats.invoked("8ball");
+        stats.invoked("karma");
+        assert_eq!(
+            stats.busiest(),
+            vec![("8ball", 2), ("karma", 1), ("seen", 1)]
+        );
+
+        let now = stats.started + Duration::from_secs(90);
+        stats.connected = Some((
+            stats.started + Duration::from_secs(30),
+            "irc.test".to_owned(),
+        ));
+        assert_eq!(
+            stats.report(now),
+            vec![
+                "uptime 90",
+                "connected 60 irc.test",
+                "reconnects 0",
+                "in 0 0",
+                "out 3 42",
+                "plugin 8ball 2",
+                "plugin karma 1",
+                "plugin seen 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn sliding_window() {
+        let start = Instant::now();
+        let mut stats = SendStats::default();
+        stats.record(start, Some("#a"), "8ball", 10);
+        stats.record(start, None, "irc", 5);
+        stats.record(start + Duration::from_secs(30), Some("#b"), "8ball", 20);
+        stats.record(start + Duration::from_secs(30), Some("#a"), "seen", 30);
+
+        let now = start + Duration::from_secs(45);
+        assert_eq!(
+            stats.by_target(now),
+            vec![
+                Usage {
+                    name: "#a".to_owned(),
+                    lines: 2,
+                    bytes: 40
+                },
+                Usage {
+                    name: "#b".to_owned(),
+                    lines: 1,
+                    bytes: 20
+                },
+            ]
+        );
+        assert_eq!(stats.by_source(now)[0].name, "8ball");
+
+        // the first lines have aged out.
+        let now = start + Duration::from_secs(75);
+        assert_eq!(stats.by_source(now).len(), 2);
+        assert_eq!(stats.by_target(now)[0].bytes, 30);
+    }
+
+    #[test]
+    fn plugin_runs() {
+        let exited = |code: Option<i32>| {
+            Arc::new(Mutex::new(
+                code.map(|code| Ok(ExitStatus::from_raw(code << 8))),
+            ))
+        };
+        let mut stats = PluginStats::default();
+        stats.finished("fast", Duration::from_millis(10), 100, exited(Some(0)));
+        stats.finished("fast", Duration::from_millis(30), 100, exited(Some(1)));
+        stats.finished("slow", Duration::from_secs(2), 10, exited(Some(0)));
+        let running = exited(None);
+        stats.finished("slow", Duration::from_secs(1), 10, running.clone());
+        stats.finished(
+            "missing",
+            Duration::ZERO,
+            0,
+            Arc::new(Mutex::new(Some(Err(io::ErrorKind::NotFound.into())))),
+        );
+
+        let slowest = stats.slowest();
+        assert_eq!(slowest[0].0, "slow");
+        assert_eq!(slowest[0].1.average(), Duration::from_millis(1500));
+        assert_eq!(slowest[0].1.slowest, Duration::from_secs(2));
+        assert_eq!(stats.loudest()[0].0, "fast");
+        assert_eq!(
+            stats
+                .failing()
+                .iter()
+                .map(|(name, usage)| (*name, usage.failed))
+                .collect::<Vec<_>>(),
+            vec![("fast", 1), ("missing", 1)]
+        );
+
+        // killed for its timeout, once reaped.
+        *running.lock().unwrap() = Some(Ok(ExitStatus::from_raw(9)));
+        stats.settle();
+        assert_eq!(
+            stats.report(),
+            vec![
+                "run slow 2 3000 2000 20 1",
+                "run fast 2 40 30 200 1",
+                "run missing 1 0 0 0 1"
+            ]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    time::Instant,
+};
+
+// pending timers, so a runaway plugin can't grow the queue forever.
+const MAX_TIMERS: usize = 1024;
+
+/// Things due at some point, popped in order of their deadline, then of when they
+/// were added. Cancelled ones are left on the heap and skipped once they come up.
+pub struct TimerHeap<T> {
+    heap: BinaryHeap<Reverse<(Instant, u64)>>,
+    items: HashMap<u64, T>,
+    next_seq: u64,
+}
+
+impl<T> Default for TimerHeap<T> {
+    fn default() -> Self {
+        TimerHeap {
+            heap: BinaryHeap::new(),
+            items: HashMap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl<T> TimerHeap<T> {
+    /// Returns what cancel() takes.
+    pub fn add(&mut self, at: Instant, item: T) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.items.insert(seq, item);
+        self.heap.push(Reverse((at, seq)));
+        seq
+    }
+
+    pub fn cancel(&mut self, seq: u64) -> Option<T> {
+        let item = self.items.remove(&seq);
+        // cancelled entries stay in the heap until due; don't let them pile up.
+        if self.heap.len() > 2 * self.items.len() + 16 {
+            let items = &self.items;
+            self.heap
+                .retain(|Reverse((_, seq))| items.contains_key(seq));
+        }
+        item
+    }
+
+    /// Pop what is due by now.
+    pub fn due(&mut self, now: Instant) -> Vec<T> {
+        let mut ret = vec![];
+        while let Some(&Reverse((at, seq))) = self.heap.peek() {
+            if at > now {
+                break;
+            }
+            self.heap.pop();
+            ret.extend(self.items.remove(&seq));
+        }
+        ret
+    }
+
+    /// When the next item is due.
+    pub fn deadline(&mut self) -> Option<Instant> {
+        // drop cancelled items so they don't wake us up.
+        while let Some(&Reverse((at, seq))) = self.heap.peek() {
+            if self.items.contains_key(&seq) {
+                return Some(at);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+struct Timer {
+    // (plugin, id) for timers which can be cancelled.
+    key: Option<(String, String)>,
+    source: String,
+    line: Vec<u8>,
+}
+
+/// Lines plugins asked to send later.
+/// Ids are per plugin, so plugins can't cancel each other's timers.
+#[derive(Default)]
+pub struct TimerQueue {
+    heap: TimerHeap<Timer>,
+    ids: HashMap<(String, String), u64>,
+}
+
+impl TimerQueue {
+    /// Add a timer; a timer with the same plugin and id replaces the old one.
+    /// Returns false if too many timers are pending.
+    pub fn add(&mut self, at: Instant, source: &str, id: Option<&str>, line: Vec<u8>) -> bool {
+        let key = id.map(|id| (source.to_owned(), id.to_owned()));
+        if let Some(key) = &key {
+            self.cancel(&key.0, &key.1);
+        }
+        if self.heap.len() >= MAX_TIMERS {
+            return false;
+        }
+
+        let timer = Timer {
+            key: key.clone(),
+            source: source.to_owned(),
+            line,
+        };
+        let seq = self.heap.add(at, timer);
+        if let Some(key) = key {
+            self.ids.insert(key, seq);
+        }
+        true
+    }
+
+    pub fn cancel(&mut self, source: &str, id: &str) {
+        if let Some(seq) = self.ids.remove(&(source.to_owned(), id.to_owned())) {
+            self.heap.cancel(seq);
+        }
+    }
+
+    /// Pop the lines due by now, with the plugin that sent them.
+    pub fn due(&mut self, now: Instant) -> Vec<(String, Vec<u8>)> {
+        let mut ret = vec![];
+        for timer in self.heap.due(now) {
+            if let Some(key) = timer.key {
+                self.ids.remove(&key);
+            }
+            ret.push((timer.source, timer.line));
+        }
+        ret
+    }
+
+    /// When the next timer is due.
+    pub fn deadline(&mut self) -> Option<Instant> {
+        self.heap.deadline()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::TimerQueue;
+
+    #[test]
+    fn timers() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut timers = TimerQueue::default();
+        assert!(timers.add(secs(20), "remind", None, b"b".to_vec()));
+        assert!(timers.add(secs(10), "remind", Some("x"), b"a".to_vec()));
+        assert!(timers.add(secs(5), "other", Some("x"), b"c".to_vec()));
+        // replaces the first x from remind
+        assert!(timers.add(secs(30), "remind", Some("x"), b"d".to_vec()));
+        timers.cancel("other", "x");
+
+        assert_eq!(timers.deadline(), Some(secs(20)));
+        assert!(timers.due(secs(15)).is_empty());
+        assert_eq!(
+            timers.due(secs(30)),
+            vec![
+                ("remind".to_owned(), b"b".to_vec()),
+                ("remind".to_owned(), b"d".to_vec())
+            ]
+        );
+        assert_eq!(timers.deadline(), None);
+    }
+
+    #[test]
+    fn replaced_timers_dont_pile_up() {
+        let later = Instant::now() + Duration::from_secs(60);
+        let mut timers = TimerQueue::default();
+        for _ in 0..10_000 {
+            assert!(timers.add(later, "remind", Some("x"), b"a".to_vec()));
+        }
+        assert!(timers.heap.heap.len() < 100);
+        assert_eq!(timers.due(later).len(), 1);
+    }
+}
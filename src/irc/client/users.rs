@@ -0,0 +1,188 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::collections::HashMap;
+
+/// The key per-user data is stored under.
+/// Services accounts are preferred since they survive nick changes and dynamic IPs.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum UserKey {
+    Account(Vec<u8>),
+    // user@host, the nick is left off so settings survive nick changes.
+    Hostmask(Vec<u8>),
+}
+
+fn hostmask(user: &[u8], host: &[u8]) -> Vec<u8> {
+    let mut mask = Vec::with_capacity(user.len() + host.len() + 1);
+    mask.extend(user);
+    mask.push(b'@');
+    mask.extend(host);
+    mask.make_ascii_lowercase();
+    mask
+}
+
+/// Settings a user can change about how the bot treats them.
+#[derive(Debug, Default, PartialEq)]
+pub struct UserSettings {
+    /// The user asked the bot to leave their messages alone, see .optout.
+    pub optout: bool,
+}
+
+/// Per-user data keyed by services account when known, falling back to hostmask.
+/// When an account is learned for a hostmask, the data stored under the hostmask
+/// is migrated to the account.
+pub struct UserStore<T> {
+    entries: HashMap<UserKey, T>,
+    // hostmasks we have seen identified to an account this session,
+    // with the (casemapped) nick that was using them.
+    accounts: HashMap<Vec<u8>, (Vec<u8>, Vec<u8>)>,
+}
+
+impl<T> Default for UserStore<T> {
+    fn default() -> Self {
+        UserStore {
+            entries: HashMap::new(),
+            accounts: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Default> UserStore<T> {
+    /// Resolve the key for a user.
+    /// An explicitly given account (e.g. from an account tag) takes precedence over one
+    /// we learned for the hostmask.
+    pub fn key(&self, account: Option<&[u8]>, user: &[u8], host: &[u8]) -> UserKey {
+        let mask = hostmask(user, host);
+        match account.or_else(|| self.accounts.get(&mask).map(|(_, acct)| acct.as_slice())) {
+            Some(acct) => UserKey::Account(acct.to_vec()),
+            None => UserKey::Hostmask(mask),
+        }
+    }
+
+    pub fn get(&self, key: &UserKey) -> Option<&T> {
+        self.entries.get(key)
+    }
+
+    pub fn entry(&mut self, key: UserKey) -> &mut T {
+        self.entries.entry(key).or_default()
+    }
+
+    /// Record that nick, from a hostmask, is identified to an account, e.g. from
+    /// extended-join or account-notify. Data stored under the hostmask is moved to
+    /// the account, unless the account already has its own, in which case the
+    /// account's wins.
+    pub fn learn_account(&mut self, nick: &[u8], user: &[u8], host: &[u8], account: &[u8]) {
+        let mask = hostmask(user, host);
+        if let Some(data) = self.entries.remove(&UserKey::Hostmask(mask.clone())) {
+            self.entries
+                .entry(UserKey::Account(account.to_vec()))
+                .or_insert(data);
+        }
+        self.accounts
+            .insert(mask, (nick.to_vec(), account.to_vec()));
+    }
+
+    /// The account we learned for a hostmask, if any.
+    pub fn account(&self, user: &[u8], host: &[u8]) -> Option<&[u8]> {
+        self.accounts
+            .get(&hostmask(user, host))
+            .map(|(_, acct)| acct.as_slice())
+    }
+
+    /// The hostmask logged out of its account; new data falls back to the hostmask.
+    pub fn forget_account(&mut self, user: &[u8], host: &[u8]) {
+        self.accounts.remove(&hostmask(user, host));
+    }
+
+    /// Forget the accounts of nicks we can no longer watch, e.g. once they quit,
+    /// so whoever shows up with their user@host next doesn't inherit the account.
+    pub fn retain_nicks(&mut self, mut keep: impl FnMut(&[u8]) -> bool) {
+        self.accounts.retain(|_, (nick, _)| keep(nick));
+    }
+
+    /// A nick we learned an account for changed to new.
+    pub fn rename(&mut self, old: &[u8], new: &[u8]) {
+        for (nick, _) in self.accounts.values_mut() {
+            if nick == old {
+                *nick = new.to_vec();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UserKey, UserSettings, UserStore};
+
+    #[test]
+    fn hostmask_fallback() {
+        let store = UserStore::<UserSettings>::default();
+        assert_eq!(
+            store.key(None, b"Ident", b"Some.Host"),
+            UserKey::Hostmask(b"ident@some.host".to_vec())
+        );
+        assert_eq!(
+            store.key(Some(b"acct"), b"ident", b"some.host"),
+            UserKey::Account(b"acct".to_vec())
+        );
+    }
+
+    #[test]
+    fn migrate_to_account() {
+        let mut store = UserStore::<UserSettings>::default();
+        let key = store.key(None, b"ident", b"some.host");
+        store.entry(key).optout = true;
+
+        store.learn_account(b"NICK", b"ident", b"some.host", b"acct");
+        let key = store.key(None, b"ident", b"some.host");
+        assert_eq!(key, UserKey::Account(b"acct".to_vec()));
+        assert!(store.get(&key).unwrap().optout);
+        assert!(store
+            .get(&UserKey::Hostmask(b"ident@some.host".to_vec()))
+            .is_none());
+
+        // account survives the user moving to a new host
+        store.learn_account(b"NICK", b"ident", b"other.host", b"acct");
+        let key = store.key(None, b"ident", b"other.host");
+        assert!(store.get(&key).unwrap().optout);
+
+        store.forget_account(b"ident", b"other.host");
+        let key = store.key(None, b"ident", b"other.host");
+        assert!(store.get(&key).is_none());
+
+        // gone with the nick that used it.
+        store.rename(b"NICK", b"NEW");
+        store.retain_nicks(|nick| nick == b"NEW");
+        assert_eq!(store.account(b"ident", b"some.host"), Some(&b"acct"[..]));
+        store.retain_nicks(|_| false);
+        assert_eq!(store.account(b"ident", b"some.host"), None);
+    }
+
+    #[test]
+    fn account_data_wins() {
+        let mut store = UserStore::<UserSettings>::default();
+        store.entry(UserKey::Account(b"acct".to_vec()));
+        let key = store.key(None, b"ident", b"some.host");
+        store.entry(key).optout = true;
+
+        store.learn_account(b"NICK", b"ident", b"some.host", b"acct");
+        let key = store.key(None, b"ident", b"some.host");
+        assert!(!store.get(&key).unwrap().optout);
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use super::{helpers::irc_uppercase, CaseMapping};
+
+// longest list of nicks in one MONITOR or ISON line.
+const LIST_LEN: usize = 400;
+
+/// Nicks we watch come and go, with MONITOR where the server has it and by
+/// polling with ISON where it doesn't.
+pub struct Watchlist {
+    nicks: Vec<String>,
+    // uppercased nicks we believe are online.
+    online: HashSet<Vec<u8>>,
+    ison_interval: Duration,
+    // when to poll next, None if we use MONITOR or haven't started.
+    ison_deadline: Option<Instant>,
+}
+
+impl Watchlist {
+    pub fn new(nicks: Vec<String>, ison_interval: Duration) -> Self {
+        Watchlist {
+            nicks,
+            online: HashSet::new(),
+            ison_interval,
+            ison_deadline: None,
+        }
+    }
+
+    /// Start watching once registered; monitor is the ISUPPORT MONITOR limit, if
+    /// the server has it. Returns the MONITOR lines to send.
+    pub fn start(&mut self, monitor: Option<usize>, now: Instant) -> Vec<String> {
+        self.online.clear();
+        self.ison_deadline = None;
+        if self.nicks.is_empty() {
+            return vec![];
+        }
+        match monitor {
+            Some(limit) => {
+                if self.nicks.len() > limit {
+                    warn!(
+                        "The server lets us MONITOR {} nicks, only watching the first {}.",
+                        limit, limit
+                    );
+                }
+                let nicks = &self.nicks[..self.nicks.len().min(limit)];
+                chunk(nicks, ",")
+                    .into_iter()
+                    .map(|list| format!("MONITOR + {}", list))
+                    .collect()
+            }
+            None => {
+                self.ison_deadline = Some(now);
+                vec![]
+            }
+        }
+    }
+
+    /// When the next ISON poll is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.ison_deadline
+    }
+
+    /// The ISON lines to send, if a poll is due.
+    pub fn due(&mut self, now: Instant) -> Vec<String> {
+        match self.ison_deadline {
+            Some(deadline) if deadline <= now => {
+                self.ison_deadline = Some(now + self.ison_interval);
+                chunk(&self.nicks, " ")
+                    .into_iter()
+                    .map(|list| format!("ISON {}", list))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// RPL_MONONLINE, nicks came online; returns the ones we didn't know were.
+    pub fn came_online<'a>(
+        &mut self,
+        casemap: &CaseMapping,
+        nicks: impl Iterator<Item = &'a [u8]>,
+    ) -> Vec<String> {
+        nicks
+            .filter(|nick| self.online.insert(irc_uppercase(casemap, nick)))
+            .map(|nick| String::from_utf8_lossy(nick).to_string())
+            .collect()
+    }
+
+    /// RPL_MONOFFLINE, nicks went offline; returns the ones we thought were online.
+    pub fn went_offline<'a>(
+        &mut self,
+        casemap: &CaseMapping,
+        nicks: impl Iterator<Item = &'a [u8]>,
+    ) -> Vec<String> {
+        nicks
+            .filter(|nick| self.online.remove(&irc_uppercase(casemap, nick)))
+            .map(|nick| String::from_utf8_lossy(nick).to_string())
+            .collect()
+    }
+
+    /// RPL_ISON lists every watched nick online; the rest are offline.
+    /// Returns who came online and who went offline.
+    pub fn ison<'a>(
+        &mut self,
+        casemap: &CaseMapping,
+        nicks: impl Iterator<Item = &'a [u8]>,
+    ) -> (Vec<String>, Vec<String>) {
+        let present = nicks
+            .map(|nick| irc_uppercase(casemap, nick))
+            .collect::<HashSet<Vec<u8>>>();
+        let (nicks, online) = (&self.nicks, &mut self.online);
+        let went = nicks
+            .iter()
+            .filter(|nick| !present.contains(&irc_uppercase(casemap, nick.as_bytes())))
+            .filter(|nick| online.remove(&irc_uppercase(casemap, nick.as_bytes())))
+            .cloned()
+            .collect();
+        let came = nicks
+            .iter()
+            .filter(|nick| present.contains(&irc_uppercase(casemap, nick.as_bytes())))
+            .filter(|nick| online.insert(irc_uppercase(casemap, nick.as_bytes())))
+            .cloned()
+            .collect();
+        (came, went)
+    }
+
+    /// If a watched nick is online, as far as we know.
+    pub fn is_online(&self, casemap: &CaseMapping, nick: &str) -> bool {
+        self.online
+            .contains(&irc_uppercase(casemap, nick.as_bytes()))
+    }
+}
+
+/// Join nicks with sep into lists no longer than LIST_LEN.
+fn chunk(nicks: &[String], sep: &str) -> Vec<String> {
+    let mut lists = vec![];
+    let mut list = String::new();
+    for nick in nicks {
+        if !list.is_empty() && list.len() + sep.len() + nick.len() > LIST_LEN {
+            lists.push(std::mem::take(&mut list));
+        }
+        if !list.is_empty() {
+            list.push_str(sep);
+        }
+        list.push_str(nick);
+    }
+    if !list.is_empty() {
+        lists.push(list);
+    }
+    lists
+}
+
+#[cfg(test)]
+mod test {
+    use super::Watchlist;
+    use crate::irc::client::CaseMapping;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn watchlist() {
+        let now = Instant::now();
+        let nicks = vec!["Alice".to_owned(), "bob".to_owned()];
+        let mut watch = Watchlist::new(nicks, Duration::from_secs(60));
+        let casemap = CaseMapping::Rfc1459;
+
+        assert_eq!(watch.start(Some(1), now), vec!["MONITOR + Alice"]);
+        assert_eq!(watch.deadline(), None);
+        assert_eq!(watch.start(Some(100), now), vec!["MONITOR + Alice,bob"]);
+        let came = watch.came_online(&casemap, vec![&b"alice"[..], b"alice"].into_iter());
+        assert_eq!(came, vec!["alice"]);
+        assert!(watch.is_online(&casemap, "ALICE"));
+        let went = watch.went_offline(&casemap, vec![&b"ALICE"[..], b"bob"].into_iter());
+        assert_eq!(went, vec!["ALICE"]);
+
+        // no MONITOR, poll.
+        assert!(watch.start(None, now).is_empty());
+        assert_eq!(watch.due(now), vec!["ISON Alice bob"]);
+        assert!(watch.due(now).is_empty());
+        assert_eq!(watch.deadline(), Some(now + Duration::from_secs(60)));
+        let (came, went) = watch.ison(&casemap, vec![&b"BOB"[..]].into_iter());
+        assert_eq!((came, went), (vec!["bob".to_owned()], vec![]));
+        let (came, went) = watch.ison(&casemap, vec![&b"alice"[..]].into_iter());
+        assert_eq!(
+            (came, went),
+            (vec!["Alice".to_owned()], vec!["bob".to_owned()])
+        );
+    }
+}
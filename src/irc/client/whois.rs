@@ -0,0 +1,265 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{numeric::Numeric, output::Route};
+
+// lookups in flight, so plugins can't queue WHOIS without end.
+const MAX_PENDING: usize = 32;
+// how long the server has to finish a WHOIS reply.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a WHOIS told us about a nick.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WhoisInfo {
+    pub nick: String,
+    // false if the server said there is no such nick.
+    pub found: bool,
+    pub user: String,
+    pub host: String,
+    pub realname: String,
+    pub server: String,
+    pub oper: bool,
+    // seconds idle and the unix time they connected.
+    pub idle: Option<u64>,
+    pub signon: Option<u64>,
+    pub account: Option<String>,
+}
+
+impl WhoisInfo {
+    /// The result as R8_WHOIS_* variables for a plugin.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![
+            ("R8_WHOIS_NICK", self.nick.clone()),
+            ("R8_WHOIS_FOUND", (self.found as u8).to_string()),
+        ];
+        if self.found {
+            env.push(("R8_WHOIS_USER", self.user.clone()));
+            env.push(("R8_WHOIS_HOST", self.host.clone()));
+            env.push(("R8_WHOIS_REALNAME", self.realname.clone()));
+            env.push(("R8_WHOIS_SERVER", self.server.clone()));
+        }
+        if self.oper {
+            env.push(("R8_WHOIS_OPER", "1".to_owned()));
+        }
+        if let Some(idle) = self.idle {
+            env.push(("R8_WHOIS_IDLE", idle.to_string()));
+        }
+        if let Some(signon) = self.signon {
+            env.push(("R8_WHOIS_SIGNON", signon.to_string()));
+        }
+        if let Some(account) = &self.account {
+            env.push(("R8_WHOIS_ACCOUNT", account.clone()));
+        }
+        env.into_iter()
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect()
+    }
+}
+
+/// Who is waiting on a WHOIS.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Waiter {
+    /// A plugin, run again with the R8_WHOIS_* variables and context as its message.
+    Plugin {
+        source: String,
+        context: String,
+        route: Option<Route>,
+    },
+}
+
+struct Lookup {
+    at: Instant,
+    info: WhoisInfo,
+    waiters: Vec<Waiter>,
+}
+
+/// WHOIS lookups in flight, by uppercased nick, collecting the numerics of each
+/// reply until RPL_ENDOFWHOIS.
+#[derive(Default)]
+pub struct Whoises {
+    pending: HashMap<Vec<u8>, Lookup>,
+}
+
+impl Whoises {
+    /// Wait on a lookup of nick. Returns true if a WHOIS needs sending, false if
+    /// one is already in flight, None if too many are.
+    pub fn request(
+        &mut self,
+        now: Instant,
+        key: Vec<u8>,
+        nick: &str,
+        waiter: Waiter,
+    ) -> Option<bool> {
+        if let Some(lookup) = self.pending.get_mut(&key) {
+            lookup.waiters.push(waiter);
+            return Some(false);
+        }
+        if self.pending.len() >= MAX_PENDING {
+            return None;
+        }
+        let info = WhoisInfo {
+            nick: nick.to_owned(),
+            ..WhoisInfo::default()
+        };
+        self.pending.insert(
+            key,
+            Lookup {
+                at: now,
+                info,
+                waiters: vec![waiter],
+            },
+        );
+        Some(true)
+    }
+
+    /// A WHOIS numeric; params follow our nick, starting with the nick looked up.
+    pub fn note(&mut self, key: &[u8], numeric: Numeric, params: &[&[u8]]) {
+        let info = match self.pending.get_mut(key) {
+            Some(lookup) => &mut lookup.info,
+            None => return,
+        };
+        let text = |pos: usize| {
+            params
+                .get(pos)
+                .map(|param| String::from_utf8_lossy(param).to_string())
+                .unwrap_or_default()
+        };
+        match numeric {
+            // RPL_WHOISUSER nick user host * :realname
+            Numeric::RplWhoisuser => {
+                info.found = true;
+                info.nick = text(0);
+                info.user = text(1);
+                info.host = text(2);
+                info.realname = text(4);
+            }
+            // RPL_WHOISSERVER nick server :info
+            Numeric::RplWhoisserver => info.server = text(1),
+            // RPL_WHOISOPERATOR nick :is an IRC operator
+            Numeric::RplWhoisoperator => info.oper = true,
+            // RPL_WHOISIDLE nick idle signon :seconds idle, signon time
+            Numeric::RplWhoisidle => {
+                info.idle = text(1).parse().ok();
+                info.signon = text(2).parse().ok();
+            }
+            // RPL_WHOISACCOUNT nick account :is logged in as
+            Numeric::RplWhoisaccount => info.account = Some(text(1)),
+            _ => (),
+        }
+    }
+
+    /// RPL_ENDOFWHOIS, the lookup is done.
+    pub fn finish(&mut self, key: &[u8]) -> Option<(WhoisInfo, Vec<Waiter>)> {
+        self.pending
+            .remove(key)
+            .map(|lookup| (lookup.info, lookup.waiters))
+    }
+
+    /// When the oldest one expires.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|lookup| lookup.at + TIMEOUT)
+            .min()
+    }
+
+    /// Lookups the server never finished.
+    pub fn expire(&mut self, now: Instant) -> Vec<WhoisInfo> {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, lookup)| now.duration_since(lookup.at) >= TIMEOUT)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<Vec<u8>>>();
+        expired
+            .iter()
+            .filter_map(|key| self.pending.remove(key))
+            .map(|lookup| lookup.info)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Numeric, Waiter, Whoises};
+
+    #[test]
+    fn whoises() {
+        let now = Instant::now();
+        let waiter = |context: &str| Waiter::Plugin {
+            source: "auth".to_owned(),
+            context: context.to_owned(),
+            route: None,
+        };
+        let mut whois = Whoises::default();
+        assert_eq!(
+            whois.request(now, b"NICK".to_vec(), "nick", waiter("a")),
+            Some(true)
+        );
+        assert_eq!(
+            whois.request(now, b"NICK".to_vec(), "Nick", waiter("b")),
+            Some(false)
+        );
+
+        whois.note(
+            b"NICK",
+            Numeric::RplWhoisuser,
+            &[b"Nick", b"user", b"host", b"*", b"Real Name"],
+        );
+        whois.note(
+            b"NICK",
+            Numeric::RplWhoisidle,
+            &[b"Nick", b"42", b"1600000000", b"seconds idle"],
+        );
+        whois.note(
+            b"NICK",
+            Numeric::RplWhoisaccount,
+            &[b"Nick", b"acct", b"is logged in as"],
+        );
+        whois.note(
+            b"OTHER",
+            Numeric::RplWhoisoperator,
+            &[b"other", b"is an IRC operator"],
+        );
+        let (info, waiters) = whois.finish(b"NICK").unwrap();
+        assert_eq!(waiters, vec![waiter("a"), waiter("b")]);
+        assert!(info.found && !info.oper);
+        assert_eq!(info.realname, "Real Name");
+        assert_eq!((info.idle, info.signon), (Some(42), Some(1600000000)));
+        let env = info.env();
+        assert!(env.contains(&("R8_WHOIS_ACCOUNT".to_owned(), "acct".to_owned())));
+        assert!(whois.finish(b"NICK").is_none());
+
+        assert_eq!(
+            whois.request(now, b"GONE".to_vec(), "gone", waiter("")),
+            Some(true)
+        );
+        assert!(whois.expire(now).is_empty());
+        let expired = whois.expire(now + Duration::from_secs(30));
+        assert_eq!(expired[0].nick, "gone");
+        assert!(!expired[0].found);
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! The control socket, so operators and scripts can drive the live bot. It takes one
+//! command per line: JOIN #chan `[key]`, PART #chan, SAY target text, RELOAD, STATUS,
+//! STATS for a network's traffic and plugin totals, and RAWLOG ON [file] or
+//! RAWLOG OFF to toggle the raw protocol log.
+//! A leading @N picks the Nth network, from 0 in config order, else it's the first.
+//! Every command is answered with any lines it has, then OK or ERR and why.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Read, Write},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use mio::{
+    event::Event,
+    net::{UnixListener, UnixStream},
+    Interest, Poll, Token,
+};
+
+// a line longer than this hangs up on whoever sent it.
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Join(String),
+    Part(String),
+    Say { target: String, text: String },
+    Reload,
+    Status,
+    Stats,
+    // with the file to log to, else the last one.
+    RawLogOn(Option<String>),
+    RawLogOff,
+}
+
+/// Send line to the control socket at path, for r8ball send. The answer's lines,
+/// and whether it ended with OK rather than ERR.
+pub fn send(path: &Path, line: &str) -> io::Result<(Vec<String>, bool)> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", line.trim()).as_bytes())?;
+    let mut answer = Vec::new();
+    for line in io::BufReader::new(stream).lines() {
+        let line = line?;
+        let done = line == "OK" || line.starts_with("ERR");
+        let ok = line == "OK";
+        answer.push(line);
+        if done {
+            return Ok((answer, ok));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "The control socket hung up without answering",
+    ))
+}
+
+/// A control line into the network it's for and the command.
+pub fn parse_command(line: &str) -> Result<(usize, Command), String> {
+    let mut line = line.trim();
+    let mut network = 0;
+    if let Some(rest) = line.strip_prefix('@') {
+        let (idx, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        network = idx.parse().map_err(|_| format!("bad network {:?}", idx))?;
+        line = rest.trim_start();
+    }
+    let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+    let command = match cmd.to_ascii_uppercase().as_str() {
+        "JOIN" if !args.is_empty() => Command::Join(args.to_owned()),
+        "PART" if !args.is_empty() => Command::Part(args.to_owned()),
+        "SAY" => match args.split_once(' ') {
+            Some((target, text)) if !text.trim().is_empty() => Command::Say {
+                target: target.to_owned(),
+                text: text.trim().to_owned(),
+            },
+            _ => return Err("usage: SAY target text".to_owned()),
+        },
+        "RELOAD" => Command::Reload,
+        "RAWLOG" => match args.split_once(' ').unwrap_or((args, "")) {
+            (on, "") if on.eq_ignore_ascii_case("ON") => Command::RawLogOn(None),
+            (on, path) if on.eq_ignore_ascii_case("ON") => {
+                Command::RawLogOn(Some(path.trim().to_owned()))
+            }
+            (off, "") if off.eq_ignore_ascii_case("OFF") => Command::RawLogOff,
+            _ => return Err("usage: RAWLOG ON [file] or RAWLOG OFF".to_owned()),
+        },
+        "STATUS" => Command::Status,
+        "STATS" => Command::Stats,
+        "JOIN" | "PART" => return Err(format!("usage: {} #chan", cmd.to_ascii_uppercase())),
+        "" => return Err("empty command".to_owned()),
+        _ => return Err(format!("unknown command {:?}", cmd)),
+    };
+    Ok((network, command))
+}
+
+struct ControlConn {
+    stream: UnixStream,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    // they shut down their end, we hang up once they have their answers.
+    eof: bool,
+}
+
+impl ControlConn {
+    /// Write out what we can, returns true while some is left.
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.write_buf.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// The listening socket and whoever is connected to it, on the tokens in [first, last).
+pub struct Control {
+    path: PathBuf,
+    listener: UnixListener,
+    conns: HashMap<Token, ControlConn>,
+    first: Token,
+    last: Token,
+    next_token: usize,
+}
+
+impl Control {
+    /// Listen on path, replacing a socket left behind by an earlier run.
+    /// The listener gets the first token, connections the rest.
+    pub fn open(path: &Path, poll: &Poll, first: Token, last: Token) -> io::Result<Self> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+            _ => (),
+        }
+        // it can make the bot say anything, so it's only ours from the start;
+        // a chmod after bind leaves a window where anyone could connect.
+        let old_mask = unsafe { libc::umask(0o177) };
+        let bound = UnixListener::bind(path);
+        unsafe { libc::umask(old_mask) };
+        let mut listener = bound?;
+        poll.registry()
+            .register(&mut listener, first, Interest::READABLE)?;
+        Ok(Control {
+            path: path.to_owned(),
+            listener,
+            conns: HashMap::new(),
+            first,
+            last,
+            next_token: first.0 + 1,
+        })
+    }
+
+    pub fn owns(&self, token: Token) -> bool {
+        token.0 >= self.first.0 && token.0 < self.last.0
+    }
+
+    fn accept(&mut self, poll: &Poll) -> io::Result<()> {
+        loop {
+            let (mut stream, _) = match self.listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let mut token = Token(self.next_token);
+            while self.conns.contains_key(&token) {
+                token = Token(token.0 + 1);
+                if token == self.last {
+                    token = Token(self.first.0 + 1);
+                }
+            }
+            self.next_token = if token.0 + 1 == self.last.0 {
+                self.first.0 + 1
+            } else {
+                token.0 + 1
+            };
+            poll.registry()
+                .register(&mut stream, token, Interest::READABLE)?;
+            self.conns.insert(
+                token,
+                ControlConn {
+                    stream,
+                    read_buf: Vec::new(),
+                    write_buf: Vec::new(),
+                    eof: false,
+                },
+            );
+        }
+    }
+
+    fn close(&mut self, poll: &Poll, token: Token) {
+        if let Some(mut conn) = self.conns.remove(&token) {
+            let _ = poll.registry().deregister(&mut conn.stream);
+        }
+    }
+
+    /// Accept, read and write as the event says, answering each line that came in.
+    pub fn handle<F, E>(&mut self, poll: &Poll, event: &Event, mut answer: F) -> Result<(), E>
+    where
+        F: FnMut(&str) -> Result<Vec<String>, E>,
+        E: From<io::Error>,
+    {
+        let token = event.token();
+        if token == self.first {
+            return Ok(self.accept(poll)?);
+        }
+        let conn = match self.conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let mut buf = [0u8; 1024];
+        while !conn.eof {
+            match conn.stream.read(&mut buf) {
+                Ok(0) => conn.eof = true,
+                Ok(len) => conn.read_buf.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => conn.eof = true,
+            }
+        }
+        while let Some(end) = conn.read_buf.iter().position(|&b| b == b'\n') {
+            let line = conn.read_buf.drain(..=end).collect::<Vec<u8>>();
+            for out in answer(String::from_utf8_lossy(&line).trim_end())? {
+                conn.write_buf.extend_from_slice(out.as_bytes());
+                conn.write_buf.push(b'\n');
+            }
+        }
+        if conn.read_buf.len() > MAX_LINE {
+            warn!("Control socket line too long, hanging up.");
+            self.close(poll, token);
+            return Ok(());
+        }
+
+        match conn.flush() {
+            Ok(true) => poll.registry().reregister(
+                &mut conn.stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?,
+            // a client that shut down its end still gets its answers first.
+            Ok(false) if conn.eof => self.close(poll, token),
+            Ok(false) => poll
+                .registry()
+                .reregister(&mut conn.stream, token, Interest::READABLE)?,
+            Err(_) => self.close(poll, token),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_command, Command};
+
+    #[test]
+    fn control_commands() {
+        assert_eq!(
+            parse_command("JOIN #chan key"),
+            Ok((0, Command::Join("#chan key".to_owned())))
+        );
+        assert_eq!(
+            parse_command("@1 part #chan\n"),
+            Ok((1, Command::Part("#chan".to_owned())))
+        );
+        assert_eq!(
+            parse_command("SAY #chan hello there"),
+            Ok((
+                0,
+                Command::Say {
+                    target: "#chan".to_owned(),
+                    text: "hello there".to_owned()
+                }
+            ))
+        );
+        assert_eq!(parse_command("reload"), Ok((0, Command::Reload)));
+        assert_eq!(parse_command("@2 STATUS"), Ok((2, Command::Status)));
+        assert_eq!(parse_command("@1 stats"), Ok((1, Command::Stats)));
+        assert_eq!(parse_command("RAWLOG on"), Ok((0, Command::RawLogOn(None))));
+        assert_eq!(
+            parse_command("RAWLOG ON /tmp/raw.log"),
+            Ok((0, Command::RawLogOn(Some("/tmp/raw.log".to_owned()))))
+        );
+        assert_eq!(parse_command("rawlog off"), Ok((0, Command::RawLogOff)));
+        assert!(parse_command("RAWLOG").is_err());
+        assert!(parse_command("SAY #chan").is_err());
+        assert!(parse_command("JOIN").is_err());
+        assert!(parse_command("@x STATUS").is_err());
+        assert!(parse_command("").is_err());
+        assert!(parse_command("FROB").is_err());
+    }
+}
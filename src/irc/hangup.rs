@@ -0,0 +1,113 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! SIGHUP, which mio_signals doesn't know about, as a pipe the handler writes to.
+
+use std::{
+    io::{self, Read},
+    os::unix::io::AsRawFd,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use mio::{event::Source, unix::pipe};
+
+// the write end of the latest Hangup's pipe, -1 for none.
+static HANGUP_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    let fd = HANGUP_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // Safety: write is async-signal-safe. If the pipe is full a reload is pending anyway.
+        unsafe { libc::write(fd, b"!".as_ptr().cast(), 1) };
+    }
+}
+
+/// Readable once we got a SIGHUP, see received().
+pub struct Hangup {
+    receiver: pipe::Receiver,
+    sender: pipe::Sender,
+}
+
+impl Hangup {
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = pipe::new()?;
+        HANGUP_FD.store(sender.as_raw_fd(), Ordering::Relaxed);
+        // Safety: the handler only touches an atomic and write(2).
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Hangup { receiver, sender })
+    }
+
+    /// If we got a SIGHUP since the last call, however many came in between.
+    pub fn received(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 64];
+        let mut ret = false;
+        loop {
+            match self.receiver.read(&mut buf) {
+                Ok(0) => return Ok(ret),
+                Ok(_) => ret = true,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(ret),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for Hangup {
+    fn drop(&mut self) {
+        // SIGHUPs are ignored from here on, unless another Hangup took over.
+        let _ = HANGUP_FD.compare_exchange(
+            self.sender.as_raw_fd(),
+            -1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl Source for Hangup {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        registry.register(&mut self.receiver, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        registry.reregister(&mut self.receiver, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        registry.deregister(&mut self.receiver)
+    }
+}
@@ -17,10 +17,13 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use memchr::memchr2;
+
 fn find_eom(buf: &[u8]) -> Option<usize> {
-    buf.iter().position(|&chr| chr == b'\n' || chr == b'\r')
+    memchr2(b'\n', b'\r', buf)
 }
 
+// only ever skips the CRLF between lines, not worth a memchr.
 fn find_start(buf: &[u8]) -> Option<usize> {
     buf.iter().position(|&chr| chr != b'\n' && chr != b'\r')
 }
@@ -29,7 +32,9 @@ fn find_start(buf: &[u8]) -> Option<usize> {
 /// This can be used when a read that returns is not a fully terminated IRC
 /// message.
 pub enum TruncStatus<T> {
+    /// A whole line, without its line ending.
     Full(T),
+    /// The end of the buffer, which the next read continues.
     Part(T),
 }
 
@@ -55,10 +60,7 @@ impl<'a> Iterator for BufIterator<'a> {
     type Item = TruncStatus<&'a [u8]>;
     fn next(&mut self) -> Option<Self::Item> {
         let buf: &'a [u8] = &self.buffer[self.read_head..];
-        let start = match find_start(buf) {
-            Some(start) => start,
-            None => return None,
-        };
+        let start = find_start(buf)?;
 
         // remove leading delimiter.
         self.read_head += start;
@@ -69,7 +71,7 @@ impl<'a> Iterator for BufIterator<'a> {
             Some(TruncStatus::Full(&buf[..eom]))
         } else {
             self.read_head = self.buffer.len();
-            Some(TruncStatus::Part(&buf))
+            Some(TruncStatus::Part(buf))
         }
     }
 }
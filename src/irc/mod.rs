@@ -1,5 +1,16 @@
+pub mod builtins;
+pub mod children;
 pub mod client;
+pub mod control;
+pub mod hangup;
 pub mod iter;
 pub mod net;
 pub mod parse;
 pub mod plugin;
+pub mod rawlog;
+pub mod shutdown;
+pub mod socks;
+pub mod tls;
+#[cfg(feature = "tokio")]
+pub mod tokio_net;
+pub mod workers;
@@ -18,136 +18,1512 @@
 // THE SOFTWARE.
 
 use std::collections::HashMap;
-use std::{io, net::ToSocketAddrs, path::Path};
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::Path,
+};
 
-use std::time::Duration;
+use std::sync::{mpsc, Arc};
 
-use mio::net::TcpStream;
+use rustls::ClientConfig;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mio::event::Event;
+use mio::net::{TcpSocket, TcpStream};
 use mio::Events;
 use mio::Interest;
 use mio::Poll;
 use mio::Token;
+use mio::Waker;
 use mio_signals::Signal;
 use mio_signals::SignalSet;
 use mio_signals::Signals;
 
-use crate::irc::client::{ClientReadStat, ClientWriteStat};
-use crate::{config::config_file::Config, MainError};
+use crate::irc::client::{ClientReadStat, ClientWriteStat, Sts};
+use crate::{
+    config::config_file::{Config, ConfigError, General, Proxy},
+    daemon, logging,
+    storage::Storage,
+    MainError,
+};
 
-use super::client::Client;
+use super::children::{self, Reaper};
+use super::client::{schedule::Cron, timers::TimerHeap, Client};
+use super::control::{parse_command, Command, Control};
+use super::hangup::Hangup;
 use super::plugin::Plugin;
+use super::rawlog::{RawLog, Tap};
+use super::shutdown::Shutdown;
+use super::socks::{Socks5, SocksError};
+use super::tls::{self, Conn};
+use super::workers::WorkerPool;
+
+/// Look up host:port on a thread of its own, which calls wake once it is done.
+fn spawn_lookup(
+    conn_str: String,
+    wake: impl FnOnce() + Send + 'static,
+) -> io::Result<mpsc::Receiver<io::Result<Vec<SocketAddr>>>> {
+    let (send, recv) = mpsc::channel();
+    thread::Builder::new()
+        .name("dns".to_owned())
+        .spawn(move || {
+            let addrs = conn_str.to_socket_addrs().map(Iterator::collect);
+            // we may have stopped waiting.
+            if send.send(addrs).is_ok() {
+                wake();
+            }
+        })?;
+    Ok(recv)
+}
+
+fn lookup_timed_out(conn_str: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("Timed out looking up {}", conn_str),
+    )
+}
+
+/// Look up host:port, so a slow resolver can't hold us up for more than timeout.
+/// A lookup that times out is left to finish on its own.
+#[cfg(feature = "tokio")]
+fn resolve(conn_str: String, timeout: Duration) -> Result<Vec<SocketAddr>, io::Error> {
+    let recv = spawn_lookup(conn_str.clone(), || ())?;
+    match recv.recv_timeout(timeout) {
+        Ok(addrs) => addrs,
+        Err(_) => Err(lookup_timed_out(&conn_str)),
+    }
+}
+
+/// A lookup the event loop does not wait on: it wakes the loop once done, and
+/// gives up at the deadline.
+struct Lookup {
+    conn_str: String,
+    recv: mpsc::Receiver<io::Result<Vec<SocketAddr>>>,
+    deadline: Instant,
+}
+
+impl Lookup {
+    fn start(conn_str: String, timeout: Duration, waker: Arc<Waker>) -> io::Result<Self> {
+        let recv = spawn_lookup(conn_str.clone(), move || {
+            // the loop is gone if this fails.
+            let _ = waker.wake();
+        })?;
+        Ok(Lookup {
+            conn_str,
+            recv,
+            deadline: Instant::now() + timeout,
+        })
+    }
+
+    /// The addresses, or why there are none, None while still looking.
+    fn poll(&self, now: Instant) -> Option<io::Result<Vec<SocketAddr>>> {
+        match self.recv.try_recv() {
+            Ok(addrs) => Some(addrs),
+            Err(mpsc::TryRecvError::Empty) if now < self.deadline => None,
+            Err(_) => Some(Err(lookup_timed_out(&self.conn_str))),
+        }
+    }
+}
+
+/// What Servers::connect() started.
+enum Attempt {
+    // the socket is usable once it is writable, see Network::connected().
+    Connecting(SocketAddr, TcpStream),
+    // the next server, connect again once it is looked up.
+    Resolving(Lookup),
+}
+
+/// Alternate between address families, starting with the family of the first
+/// address, so a broken route for one doesn't hold up the other (RFC 8305).
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut other = other.into_iter();
+    let mut ret = Vec::new();
+    for addr in preferred {
+        ret.push(addr);
+        ret.extend(other.next());
+    }
+    ret.extend(other);
+    ret
+}
+
+/// Restrict the socket to a network interface, like eth0.
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &TcpSocket, device: &str) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the name is passed with its length, it need not end in a NUL.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &TcpSocket, _device: &str) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "bind_device only works on Linux",
+    ))
+}
+
+/// The servers of a network, tried in turn, and what is left of the addresses
+/// the current one resolved to.
+pub(super) struct Servers {
+    list: Vec<String>,
+    next: usize,
+    addrs: std::vec::IntoIter<SocketAddr>,
+    dns_timeout: Duration,
+    // servers looked up since we were last connected.
+    tried: usize,
+    // we connect to the proxy instead, and it to target.
+    proxy: Option<Proxy>,
+    pub(super) target: String,
+    bind_addr: Option<IpAddr>,
+    bind_device: String,
+    // the index in list of target.
+    current: usize,
+    // STS policies, host to TLS port, see sts_target().
+    sts: HashMap<String, u16>,
+    // target is upgraded to TLS by an STS policy.
+    pub(super) secure: bool,
+    // the server asked us to reconnect with TLS.
+    upgrading: bool,
+    // made again when its settings change, but kept once we dropped privileges, as
+    // the client certificate may only be readable before.
+    tls: Option<Arc<ClientConfig>>,
+    tls_settings: tls::Settings,
+    reconnect_delay: Duration,
+    // the delay before the next reconnect, doubled while we fail to register.
+    backoff: Duration,
+    // for the connection statistics, which outlive each connection.
+    pub(super) started: Instant,
+    pub(super) reconnects: u64,
+}
+
+// how far reconnect_delay backs off.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// the storage namespace of STS policies, host to "port expiry".
+const STS_NS: &str = "sts";
+
+/// The host of host:port.
+fn host_of(server: &str) -> &str {
+    server.rsplit_once(':').map_or(server, |(host, _)| host)
+}
+
+impl Servers {
+    pub(super) fn new(config: &Config) -> Self {
+        Servers {
+            list: config.connect_strings(),
+            next: 0,
+            addrs: Vec::new().into_iter(),
+            dns_timeout: Duration::from_secs(config.general.dns_timeout),
+            tried: 0,
+            proxy: config.proxy.clone(),
+            target: String::new(),
+            bind_addr: config.general.bind_addr,
+            bind_device: config.general.bind_device.clone(),
+            current: 0,
+            sts: HashMap::new(),
+            secure: false,
+            upgrading: false,
+            tls: None,
+            tls_settings: tls::Settings::default(),
+            reconnect_delay: Duration::from_millis(config.general.reconnect_delay_ms),
+            backoff: Duration::from_millis(config.general.reconnect_delay_ms),
+            started: Instant::now(),
+            reconnects: 0,
+        }
+    }
+
+    /// The TLS settings to connect with, made from general unless we have them.
+    pub(super) fn tls_config(&mut self, general: &General) -> io::Result<Arc<ClientConfig>> {
+        let settings = tls::Settings::from(general);
+        match &self.tls {
+            Some(tls) if settings == self.tls_settings || daemon::privileges_dropped() => {
+                return Ok(tls.clone())
+            }
+            _ => (),
+        }
+        let tls = tls::client_config(general)?;
+        self.tls = Some(tls.clone());
+        self.tls_settings = settings;
+        Ok(tls)
+    }
+
+    /// If there is another server to fall back on after a disconnect.
+    pub(super) fn rotates(&self) -> bool {
+        self.list.len() > 1
+    }
+
+    /// Start connecting to the next address of the current server, if it has any left.
+    pub(super) fn race(&mut self) -> Option<(SocketAddr, TcpStream)> {
+        while let Some(addr) = self.addrs.next() {
+            match self.connect_from(addr) {
+                Ok(conn) => return Some((addr, conn)),
+                Err(e) => warn!("Could not connect to {}: {}", addr, e),
+            }
+        }
+        None
+    }
+
+    /// Start connecting to addr, from bind_addr and bind_device when set.
+    fn connect_from(&self, addr: SocketAddr) -> Result<TcpStream, io::Error> {
+        if self.bind_addr.is_none() && self.bind_device.is_empty() {
+            return TcpStream::connect(addr);
+        }
+        let socket = if addr.is_ipv6() {
+            TcpSocket::new_v6()?
+        } else {
+            TcpSocket::new_v4()?
+        };
+        if !self.bind_device.is_empty() {
+            bind_device(&socket, &self.bind_device)?;
+        }
+        if let Some(ip) = self.bind_addr {
+            if ip.is_ipv6() != addr.is_ipv6() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Can't reach it from bind_addr {}", ip),
+                ));
+            }
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        socket.connect(addr)
+    }
+
+    /// Start connecting to the next address, or look up the next server once we
+    /// are out of addresses. Fails with last_e once every server was tried.
+    fn connect(
+        &mut self,
+        storage: &Storage,
+        waker: &Arc<Waker>,
+        last_e: io::Error,
+    ) -> Result<Attempt, io::Error> {
+        if let Some((addr, conn)) = self.race() {
+            return Ok(Attempt::Connecting(addr, conn));
+        }
+        match self.next_server(storage) {
+            Some(lookup) => {
+                Lookup::start(lookup, self.dns_timeout, waker.clone()).map(Attempt::Resolving)
+            }
+            None => Err(last_e),
+        }
+    }
+
+    /// Hand over what the lookup of next_server() found, for race(). Returns the
+    /// error to fail connect() with, unless another address takes us.
+    fn resolved(&mut self, addrs: io::Result<Vec<SocketAddr>>) -> io::Error {
+        match self.looked_up(addrs) {
+            Ok(()) => io::Error::new(io::ErrorKind::NotFound, "No address to connect to"),
+            Err(e) => e,
+        }
+    }
+
+    /// Move on to the next server, once the current one is out of addresses.
+    /// Returns the host:port to look up for it, None once every server was tried.
+    pub(super) fn next_server(&mut self, storage: &Storage) -> Option<String> {
+        if self.tried >= self.list.len() {
+            return None;
+        }
+        let server = self.sts_target(storage, self.list[self.next].clone());
+        self.current = self.next;
+        self.next = (self.next + 1) % self.list.len();
+        self.tried += 1;
+        let lookup = match &self.proxy {
+            Some(proxy) => proxy.connect_string(),
+            None => server.clone(),
+        };
+        self.target = server;
+        Some(lookup)
+    }
+
+    /// The addresses the lookup of next_server() found, for race().
+    pub(super) fn looked_up(&mut self, addrs: io::Result<Vec<SocketAddr>>) -> io::Result<()> {
+        match addrs {
+            Ok(addrs) => {
+                self.addrs = interleave(addrs).into_iter();
+                Ok(())
+            }
+            Err(e) => {
+                warn!("{}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// The lookup of next_server(), with a timeout of dns_timeout, to run elsewhere.
+    #[cfg(feature = "tokio")]
+    pub(super) fn resolver(&self) -> impl FnOnce(String) -> io::Result<Vec<SocketAddr>> {
+        let timeout = self.dns_timeout;
+        move |lookup| resolve(lookup, timeout)
+    }
+
+    pub(super) fn connected(&mut self) {
+        self.tried = 0;
+    }
+
+    /// The handshake to get the proxy to connect us to the server, if we use one.
+    pub(super) fn socks(&self) -> Option<Result<Socks5, SocksError>> {
+        let proxy = self.proxy.as_ref()?;
+        Some(Socks5::new(&self.target, proxy.auth()))
+    }
 
-fn open_conn(conn_str: String) -> Result<TcpStream, io::Error> {
-    let mut conn_details = conn_str.to_socket_addrs()?;
-    let mut try_e = io::Error::new(io::ErrorKind::Other, "Should} Never Happen.");
-    Ok(loop {
-        if let Some(addr) = conn_details.next() {
-            match TcpStream::connect(addr) {
-                Ok(conn) => break conn,
-                Err(e) => try_e = e,
+    /// The server, on its TLS port if an STS policy says so, which sets secure.
+    fn sts_target(&mut self, storage: &Storage, server: String) -> String {
+        let host = host_of(&server);
+        let port = self.sts.get(host).copied().or_else(|| {
+            let policy = match storage.get(STS_NS, host) {
+                Ok(policy) => policy?,
+                Err(e) => {
+                    warn!("Could not look up the STS policy of {}: {}", host, e);
+                    return None;
+                }
+            };
+            let (port, expires) = policy.split_once(' ')?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+            if expires.parse::<u64>().ok()? <= now {
+                return None;
             }
+            port.parse().ok()
+        });
+        self.secure = port.is_some();
+        match port {
+            Some(port) => format!("{}:{}", host, port),
+            None => server,
+        }
+    }
+
+    /// Reconnect to the same server with TLS on port, see Sts::Upgrade.
+    pub(super) fn upgrade(&mut self, port: u16) {
+        self.sts.insert(host_of(&self.target).to_owned(), port);
+        self.upgrading = true;
+    }
+
+    /// Keep to TLS on this server for duration seconds, see Sts::Persist.
+    pub(super) fn persist_sts(&mut self, storage: &Storage, duration: u64) {
+        let (host, port) = match self.target.rsplit_once(':') {
+            Some((host, port)) => (host, port),
+            None => return,
+        };
+        let res = if duration == 0 {
+            self.sts.remove(host);
+            storage.delete(STS_NS, host)
         } else {
-            return Err(try_e);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs());
+            if let Ok(port) = port.parse() {
+                self.sts.insert(host.to_owned(), port);
+            }
+            storage.set(
+                STS_NS,
+                host,
+                &format!("{} {}", port, now.saturating_add(duration)),
+            )
+        };
+        if let Err(e) = res {
+            warn!("Could not save the STS policy of {}: {}", host, e);
+        }
+    }
+
+    /// After a disconnect, if we should reconnect, to which server, and how long to
+    /// wait before we do. registered is if the connection got as far as registering.
+    pub(super) fn reconnect(&mut self, registered: bool) -> Option<Duration> {
+        self.addrs = Vec::new().into_iter();
+        // every server gets another go.
+        self.tried = 0;
+        if self.upgrading {
+            // the same one, with TLS.
+            self.upgrading = false;
+            self.next = self.current;
+            self.reconnects += 1;
+            return Some(Duration::ZERO);
+        }
+        if !self.rotates() {
+            return None;
+        }
+        self.reconnects += 1;
+        if registered {
+            self.backoff = self.reconnect_delay;
+        }
+        let delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        Some(delay)
+    }
+}
+
+/// Ok(true) once a connection attempt came up, Ok(false) while it is pending.
+fn attempt_state(conn: &TcpStream) -> Result<bool, io::Error> {
+    if let Some(e) = conn.take_error()? {
+        return Err(e);
+    }
+    match conn.peer_addr() {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// What is left to close once the event loop exits.
+pub(super) struct Subsystems<S = TcpStream> {
+    pub(super) conn: Conn<S>,
+    pub(super) client: Client,
+    pub(super) plugins: Vec<Plugin>,
+}
+
+// how long to sleep while waiting on plugins or a blocked connection.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(10);
+
+/// Spawn the plugins the client started, see Client::take_spawns().
+pub(super) fn start_plugins(client: &mut Client) -> Vec<Plugin> {
+    let mut plugins = vec![];
+    for spawn in client.take_spawns() {
+        match Plugin::spawn(&spawn) {
+            Ok(plug) => plugins.push(plug),
+            Err(e) => {
+                warn!("Could not start plugin {:?}: {}", spawn.path, e);
+                client.plugin_failed(&spawn);
+            }
+        }
+    }
+    plugins
+}
+
+pub(super) fn shutdown_plan<S: Read + Write + 'static>(
+    grace: Duration,
+    reason: &'static str,
+) -> Shutdown<Subsystems<S>> {
+    let mut shutdown = Shutdown::new(grace);
+    // plugins first, their last lines still go out over the connection.
+    shutdown.add("plugins", |subs: &mut Subsystems<S>, deadline| {
+        let mut plugins = std::mem::take(&mut subs.plugins);
+        // nothing new starts while we quit.
+        subs.client.take_spawns();
+        while !plugins.is_empty() {
+            // no more SIGCHLDs to wait on, see if they exited.
+            children::reap(Instant::now());
+            let running = plugins
+                .iter()
+                .map(Plugin::is_running)
+                .collect::<Vec<bool>>();
+            for plug in plugins.iter_mut() {
+                subs.client
+                    .process_plugin(plug)
+                    .map_err(|e| e.to_string())?;
+            }
+            let mut running = running.into_iter();
+            plugins.retain(|_| running.next().unwrap_or(false));
+            if plugins.is_empty() {
+                break;
+            }
+            if Instant::now() > deadline {
+                // rather than leave them behind.
+                plugins.iter().for_each(Plugin::kill);
+                return Err(format!("killed {} plugins still running", plugins.len()));
+            }
+            thread::sleep(SHUTDOWN_POLL);
+        }
+        subs.client.process_workers(true);
+        Ok(())
+    });
+    shutdown.add("connection", move |subs: &mut Subsystems<S>, deadline| {
+        subs.client.quit(reason);
+        loop {
+            match subs.client.write_data(&mut subs.conn) {
+                Ok(ClientWriteStat::Eof) => match subs.conn.flush() {
+                    Ok(()) => return Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(SHUTDOWN_POLL),
+                    Err(e) => return Err(e.to_string()),
+                },
+                Ok(ClientWriteStat::Okay) => (),
+                Ok(ClientWriteStat::Blocked) => thread::sleep(SHUTDOWN_POLL),
+                Err(e) => return Err(e.to_string()),
+            }
+            if Instant::now() > deadline {
+                return Err("could not send all pending lines".to_owned());
+            }
+        }
+    });
+    shutdown.add("storage", |subs: &mut Subsystems<S>, _| {
+        subs.client
+            .storage()
+            .checkpoint()
+            .map_err(|e| e.to_string())
+    });
+    shutdown
+}
+
+const SIGNAL_TOKEN: mio::Token = Token(0);
+// woken when work done off the event loop is ready.
+const WAKER_TOKEN: mio::Token = Token(1);
+// the control socket listens here, whoever connects to it gets a token after this
+// one, up to NET_TOKENS.
+const CONTROL_TOKEN: mio::Token = Token(2);
+// every network gets a range of tokens this big, starting at (index + 1) * NET_TOKENS.
+// The first token in the range is the connection, the rest are for its plugins.
+const NET_TOKENS: usize = 1 << 20;
+// how long to wait on a connection attempt before racing the next address too.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// A connection to one network.
+struct Network {
+    conn: Conn,
+    addr: SocketAddr,
+    // more attempts to connect, on the same token, the first one up wins.
+    racing: Vec<(SocketAddr, TcpStream)>,
+    // when to start another attempt, while connecting.
+    next_attempt: Option<Instant>,
+    // talking to the proxy, after connecting to it.
+    socks: Option<Socks5>,
+    general: General,
+    client: Client,
+    plugins: HashMap<Token, Plugin>,
+    next_plugin_token: usize,
+    conn_token: Token,
+    servers: Servers,
+    // the next server, once retry() ran out of addresses.
+    lookup: Option<Lookup>,
+    waker: Arc<Waker>,
+    connect_timeout: Duration,
+    // until we are connected, when we give up on this server.
+    connect_deadline: Option<Instant>,
+}
+
+/// A network waiting on the lookup of its server before it can connect.
+struct Opening {
+    idx: usize,
+    servers: Servers,
+    storage: Storage,
+    lookup: Lookup,
+}
+
+impl Opening {
+    /// Connect once the lookup is done, until then we keep waiting on it.
+    fn resolve(
+        mut self,
+        config: &Config,
+        poll: &Poll,
+        waker: &Arc<Waker>,
+        now: Instant,
+    ) -> Result<Opened, Box<(MainError, Servers)>> {
+        let addrs = match self.lookup.poll(now) {
+            Some(addrs) => addrs,
+            None => return Ok(Opened::Resolving(Box::new(self))),
+        };
+        let failed = self.servers.resolved(addrs);
+        Network::connect(
+            self.idx,
+            config,
+            self.servers,
+            self.storage,
+            failed,
+            poll,
+            waker,
+        )
+    }
+}
+
+/// What Network::open() got to.
+enum Opened {
+    Up(Box<Network>),
+    Resolving(Box<Opening>),
+}
+
+impl Network {
+    /// Start connecting, or fail with why and the servers, to try again later.
+    fn open(
+        idx: usize,
+        config: &Config,
+        servers: Servers,
+        poll: &Poll,
+        waker: &Arc<Waker>,
+    ) -> Result<Opened, Box<(MainError, Servers)>> {
+        let storage = match Storage::from_config(&config.storage) {
+            Ok(storage) => storage,
+            Err(e) => return Err(Box::new((e.into(), servers))),
+        };
+        let failed = io::Error::new(io::ErrorKind::NotFound, "No server to connect to");
+        Network::connect(idx, config, servers, storage, failed, poll, waker)
+    }
+
+    fn connect(
+        idx: usize,
+        config: &Config,
+        mut servers: Servers,
+        storage: Storage,
+        failed: io::Error,
+        poll: &Poll,
+        waker: &Arc<Waker>,
+    ) -> Result<Opened, Box<(MainError, Servers)>> {
+        let (addr, mut conn) = match servers.connect(&storage, waker, failed) {
+            Ok(Attempt::Connecting(addr, conn)) => (addr, conn),
+            Ok(Attempt::Resolving(lookup)) => {
+                return Ok(Opened::Resolving(Box::new(Opening {
+                    idx,
+                    servers,
+                    storage,
+                    lookup,
+                })))
+            }
+            Err(e) => return Err(Box::new((e.into(), servers))),
+        };
+        let general = &config.general;
+        // up front, so the client certificate is read before we drop privileges.
+        if general.tls || !general.tls_client_cert.is_empty() {
+            if let Err(e) = servers.tls_config(general) {
+                return Err(Box::new((e.into(), servers)));
+            }
+        }
+        let connect_timeout = Duration::from_secs(general.connect_timeout);
+        let mut client = Client::new(config, storage);
+        client.resume_stats(servers.started, servers.reconnects);
+        client.set_waker(waker.clone());
+        if general.plugin_workers > 0 {
+            client.use_workers(WorkerPool::new(general.plugin_workers, waker.clone()));
+        }
+
+        let conn_token = Token((idx + 1) * NET_TOKENS);
+        if let Err(e) = poll.registry().register(
+            &mut conn,
+            conn_token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            return Err(Box::new((e.into(), servers)));
+        }
+        Ok(Opened::Up(Box::new(Network {
+            conn: Conn::new(conn),
+            addr,
+            racing: Vec::new(),
+            next_attempt: Some(Instant::now() + ATTEMPT_DELAY),
+            socks: None,
+            general: general.clone(),
+            client,
+            plugins: HashMap::new(),
+            next_plugin_token: conn_token.0 + 1,
+            conn_token,
+            servers,
+            lookup: None,
+            waker: waker.clone(),
+            connect_timeout,
+            connect_deadline: Some(Instant::now() + connect_timeout),
+        })))
+    }
+
+    /// Check if the connection came up, once a socket is writable (or failed).
+    /// Returns false while still connecting, which includes trying the next address.
+    fn connected(&mut self, poll: &Poll) -> Result<bool, MainError> {
+        let failed = match attempt_state(&self.conn.tcp) {
+            Ok(true) => return self.won(poll),
+            Ok(false) => None,
+            Err(e) => {
+                warn!("Could not connect to {}: {}", self.addr, e);
+                Some(e)
+            }
+        };
+        let mut idx = 0;
+        while idx < self.racing.len() {
+            match attempt_state(&self.racing[idx].1) {
+                Ok(true) => {
+                    self.promote(poll, idx)?;
+                    return self.won(poll);
+                }
+                Ok(false) => idx += 1,
+                Err(e) => {
+                    let (addr, mut conn) = self.racing.remove(idx);
+                    warn!("Could not connect to {}: {}", addr, e);
+                    poll.registry().deregister(&mut conn)?;
+                }
+            }
+        }
+        if let Some(failed) = failed {
+            if self.racing.is_empty() {
+                self.retry(poll, failed)?;
+            } else {
+                self.promote(poll, 0)?;
+                // no need to wait on a failure.
+                self.next_attempt = Some(Instant::now());
+            }
+        }
+        Ok(false)
+    }
+
+    /// Replace the first attempt with another one.
+    fn promote(&mut self, poll: &Poll, idx: usize) -> io::Result<()> {
+        let (addr, conn) = self.racing.remove(idx);
+        let mut old = std::mem::replace(&mut self.conn.tcp, conn);
+        self.addr = addr;
+        poll.registry().deregister(&mut old)
+    }
+
+    fn won(&mut self, poll: &Poll) -> Result<bool, MainError> {
+        for (_, mut conn) in self.racing.drain(..) {
+            poll.registry().deregister(&mut conn)?;
+        }
+        self.next_attempt = None;
+        match self.servers.socks() {
+            // connect_deadline covers the handshake too.
+            Some(Ok(socks)) => self.socks = Some(socks),
+            Some(Err(e)) => {
+                warn!("{}", e);
+                self.retry(poll, io::Error::other(e))?;
+                return Ok(false);
+            }
+            None => self.established(poll)?,
+        }
+        Ok(true)
+    }
+
+    /// We are through to the server, maybe by way of the proxy.
+    fn established(&mut self, poll: &Poll) -> Result<(), MainError> {
+        self.connect_deadline = None;
+        self.servers.connected();
+        if self.general.tls || self.servers.secure {
+            let tls = self.servers.tls_config(&self.general)?;
+            self.conn.start_tls(tls, &self.servers.target)?;
+            self.client.set_secure();
+        }
+        // the client has been waiting to send its greeting.
+        self.want_write(poll)?;
+        Ok(())
+    }
+
+    /// Talk to the proxy until it connected us to the server. Returns false until then.
+    fn handshake(&mut self, poll: &Poll) -> Result<bool, MainError> {
+        let socks = match &mut self.socks {
+            Some(socks) => socks,
+            None => return Ok(true),
+        };
+        // the longest reply is the rest of a bound domain name and port.
+        let mut buf = [0u8; 257];
+        let res = loop {
+            if !socks.to_send().is_empty() {
+                match self.conn.write(socks.to_send()) {
+                    Ok(len) => {
+                        socks.sent(len);
+                        continue;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => break Err(e),
+                }
+            }
+            if socks.is_done() {
+                break Ok(true);
+            }
+            // only read the reply, what follows is for the client.
+            let want = socks.wants();
+            match self.conn.read(&mut buf[..want]) {
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Proxy closed the connection",
+                    ))
+                }
+                Ok(len) => {
+                    if let Err(e) = socks.feed(&buf[..len]) {
+                        break Err(io::Error::other(e));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(e),
+            }
+        };
+        match res {
+            Ok(true) => {
+                self.socks = None;
+                self.established(poll)?;
+                Ok(true)
+            }
+            Ok(false) => Ok(false),
+            Err(e) => {
+                warn!(
+                    "Proxy could not connect us to {}: {}",
+                    self.servers.target, e
+                );
+                self.retry(poll, e)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Drop every connection attempt, they failed, and start on the next address.
+    /// The client has not sent anything yet, so it carries on as is.
+    fn retry(&mut self, poll: &Poll, failed: io::Error) -> Result<(), MainError> {
+        self.socks = None;
+        for (_, mut conn) in self.racing.drain(..) {
+            poll.registry().deregister(&mut conn)?;
+        }
+        poll.registry().deregister(&mut self.conn.tcp)?;
+        self.attempt(poll, failed)
+    }
+
+    /// Start connecting to the next address, or look up the next server.
+    fn attempt(&mut self, poll: &Poll, failed: io::Error) -> Result<(), MainError> {
+        let (addr, conn) = match self
+            .servers
+            .connect(self.client.storage(), &self.waker, failed)?
+        {
+            Attempt::Connecting(addr, conn) => (addr, conn),
+            Attempt::Resolving(lookup) => {
+                // tick() picks it up once it is done.
+                self.lookup = Some(lookup);
+                self.next_attempt = None;
+                return Ok(());
+            }
+        };
+        self.addr = addr;
+        self.conn = Conn::new(conn);
+        poll.registry().register(
+            &mut self.conn.tcp,
+            self.conn_token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        self.connect_deadline = Some(Instant::now() + self.connect_timeout);
+        self.next_attempt = Some(Instant::now() + ATTEMPT_DELAY);
+        Ok(())
+    }
+
+    /// Race another address, if the ones we are trying are taking a while.
+    fn race(&mut self, poll: &Poll, now: Instant) -> io::Result<()> {
+        if self.next_attempt.is_none_or(|at| at > now) {
+            return Ok(());
+        }
+        match self.servers.race() {
+            Some((addr, mut conn)) => {
+                poll.registry().register(
+                    &mut conn,
+                    self.conn_token,
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+                self.racing.push((addr, conn));
+                self.next_attempt = Some(now + ATTEMPT_DELAY);
+            }
+            None => self.next_attempt = None,
+        }
+        Ok(())
+    }
+
+    /// The next time tick() has something to do.
+    fn next_deadline(&mut self) -> Option<Instant> {
+        let connecting = match &self.lookup {
+            Some(lookup) => Some(lookup.deadline),
+            None => self.connect_deadline,
+        };
+        [self.client.next_deadline(), connecting, self.next_attempt]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    fn want_write(&mut self, poll: &Poll) -> io::Result<()> {
+        poll.registry().reregister(
+            &mut self.conn.tcp,
+            self.conn_token,
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
+
+    /// Returns false when the server closed the connection.
+    fn handle_conn(
+        &mut self,
+        poll: &Poll,
+        event: &Event,
+        mut raw: Option<&mut RawLog>,
+    ) -> Result<bool, MainError> {
+        let network = self.conn_token.0 / NET_TOKENS - 1;
+        if self.connect_deadline.is_some() && self.socks.is_none() && !self.connected(poll)? {
+            return Ok(true);
+        }
+        if self.socks.is_some() && !self.handshake(poll)? {
+            return Ok(true);
+        }
+        if event.is_readable() {
+            loop {
+                let mut conn = Tap {
+                    inner: &mut self.conn,
+                    log: raw.as_deref_mut(),
+                    network,
+                };
+                match self.client.receive_data(&mut conn)? {
+                    ClientReadStat::HasWritableData => {
+                        // we have stuff to write
+                        self.want_write(poll)?;
+                        break;
+                    }
+                    ClientReadStat::Blocked => break,
+                    ClientReadStat::Okay => (),
+                    ClientReadStat::Eof => return Ok(false),
+                    ClientReadStat::Error(err) => return Err(MainError::IrcProto(err)),
+                }
+            }
+            // TLS may have answered the server, like in the handshake.
+            if self.conn.wants_write() {
+                self.want_write(poll)?;
+            }
+            match self.client.take_sts() {
+                Some(Sts::Upgrade(port)) => {
+                    info!("The server wants TLS on port {}, reconnecting.", port);
+                    self.servers.upgrade(port);
+                    return Ok(false);
+                }
+                Some(Sts::Persist(duration)) => {
+                    self.servers.persist_sts(self.client.storage(), duration)
+                }
+                None => (),
+            }
+        } else if event.is_writable() {
+            loop {
+                let mut conn = Tap {
+                    inner: &mut self.conn,
+                    log: raw.as_deref_mut(),
+                    network,
+                };
+                match self.client.write_data(&mut conn)? {
+                    ClientWriteStat::Blocked => break,
+                    ClientWriteStat::Okay => (),
+                    ClientWriteStat::Eof => {
+                        // TLS may still hold records for the socket.
+                        match self.conn.flush() {
+                            Ok(()) => poll.registry().reregister(
+                                &mut self.conn.tcp,
+                                self.conn_token,
+                                Interest::READABLE,
+                            )?,
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                            Err(e) => return Err(e.into()),
+                        }
+                        break;
+                    }
+                }
+            }
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn handle_plugin(&mut self, poll: &Poll, event: &Event) -> Result<(), MainError> {
+        let ev_tok = event.token();
+        if let Some(plug) = self.plugins.get_mut(&ev_tok) {
+            // If true, we have writable data
+            let has_data = self.client.process_plugin(plug)?;
+            let closed = plug.is_closed();
+            if !closed && plug.backlogged() {
+                // the rest of its stderr, on a later wakeup.
+                poll.registry()
+                    .reregister(plug, ev_tok, Interest::READABLE)?;
+            }
+            if has_data {
+                self.want_write(poll)?;
+            }
+
+            if closed {
+                let plug = self.plugins.remove(&ev_tok).expect("Cannot remove plugin!");
+                self.client.plugin_done(&plug);
+            }
+        }
+        // otherwise stdout and stderr both fired in one poll, and the first event
+        // already saw the plugin close.
+        Ok(())
+    }
+
+    /// Release timed output and register any plugins the client started.
+    /// Returns false once the server stopped answering.
+    fn tick(&mut self, poll: &Poll) -> Result<bool, MainError> {
+        if let Some(lookup) = &self.lookup {
+            if let Some(addrs) = lookup.poll(Instant::now()) {
+                self.lookup = None;
+                let failed = self.servers.resolved(addrs);
+                self.attempt(poll, failed)?;
+            }
+        } else if self.connect_deadline.is_some_and(|at| at <= Instant::now()) {
+            let failed = io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting");
+            warn!("Could not connect to {}: {}", self.addr, failed);
+            self.retry(poll, failed)?;
+        }
+        self.race(poll, Instant::now())?;
+        if self.client.tick(Instant::now()) {
+            self.want_write(poll)?;
+        }
+
+        // commands and scheduled jobs may have started plugins.
+        for mut plug in start_plugins(&mut self.client) {
+            let mut tok = Token(self.next_plugin_token);
+            self.next_plugin_token += 1;
+            if self.next_plugin_token == self.conn_token.0 + NET_TOKENS {
+                self.next_plugin_token = self.conn_token.0 + 1;
+            }
+            // a long running plugin may still hold a token after we wrap around.
+            while self.plugins.contains_key(&tok) {
+                tok = Token(tok.0 + 1);
+            }
+            poll.registry()
+                .register(&mut plug, tok, Interest::READABLE)?;
+            self.plugins.insert(tok, plug);
+        }
+        if self.client.timed_out(Instant::now()) {
+            warn!("Lost the connection: {} stopped answering PINGs", self.addr);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// One line for the control socket's STATUS.
+    fn status(&self, idx: usize) -> String {
+        let state = if self.connect_deadline.is_some() {
+            "connecting"
+        } else {
+            "connected"
+        };
+        let mut ret = format!("{} {} {} {}", idx, self.client.state.nick, state, self.addr);
+        for chan in &self.client.state.channels {
+            ret.push(' ');
+            ret.push_str(chan.as_str());
+        }
+        ret
+    }
+
+    /// Apply a reloaded config without dropping the connection.
+    fn reload(&mut self, poll: &Poll, config: &Config) -> io::Result<()> {
+        self.general = config.general.clone();
+        if self.client.reload(config) {
+            self.want_write(poll)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the servers, to reconnect with.
+    fn shutdown(self, grace: Duration, reason: &'static str) -> Servers {
+        let mut subsystems = Subsystems {
+            conn: self.conn,
+            client: self.client,
+            plugins: self.plugins.into_values().collect(),
+        };
+        shutdown_plan(grace, reason).run(&mut subsystems);
+        self.servers
+    }
+}
+
+/// Close a network we lost, and queue it up to reconnect when it should.
+fn hang_up(
+    idx: usize,
+    net: Network,
+    grace: Duration,
+    reconnects: &mut TimerHeap<(usize, Servers)>,
+) {
+    let registered = net.client.is_registered();
+    let mut servers = net.shutdown(grace, "Shutting down");
+    if let Some(delay) = servers.reconnect(registered) {
+        if !delay.is_zero() {
+            info!("Reconnecting in {:?}.", delay);
+        }
+        reconnects.add(Instant::now() + delay, (idx, servers));
+    }
+}
+
+/// A network failed, e.g. none of its servers took us: try it again later, backing
+/// off like after a hang up, so the other networks carry on. Fails with e if the
+/// network has no server to fall back on and no other network is left.
+fn fail(
+    idx: usize,
+    mut servers: Servers,
+    e: MainError,
+    (networks, opening): (&[Option<Network>], &[Opening]),
+    reconnects: &mut TimerHeap<(usize, Servers)>,
+) -> Result<(), MainError> {
+    match servers.reconnect(false) {
+        Some(delay) => {
+            warn!("Could not connect: {:?}, trying again in {:?}.", e, delay);
+            reconnects.add(Instant::now() + delay, (idx, servers));
+        }
+        None if networks.iter().any(Option::is_some)
+            || !opening.is_empty()
+            || !reconnects.is_empty() =>
+        {
+            warn!("Giving up on a network: {:?}", e);
+        }
+        None => return Err(e),
+    }
+    Ok(())
+}
+
+/// When to quit so a supervisor restarts us, see max_uptime and restart_window.
+fn restart_deadline(general: &General, now: Instant) -> Option<Instant> {
+    if general.max_uptime == 0 {
+        return None;
+    }
+    let uptime = Duration::from_secs(general.max_uptime);
+    if general.restart_window.is_empty() {
+        return Some(now + uptime);
+    }
+
+    let cron = match Cron::parse(&general.restart_window) {
+        Ok(cron) => cron,
+        Err(e) => {
+            warn!("Ignoring restart_window: {}", e);
+            return Some(now + uptime);
+        }
+    };
+    let wall = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    match cron.next_after(wall + general.max_uptime) {
+        Some(next) => Some(now + Duration::from_secs(next - wall)),
+        None => Some(now + uptime),
+    }
+}
+
+/// Re-read the config and apply it to every network. A bad config is returned as
+/// the error inside, and the old one kept.
+fn reload_config(
+    config_path: &Path,
+    config: &mut Config,
+    configs: &mut [Config],
+    networks: &mut [Option<Network>],
+    poll: &Poll,
+) -> io::Result<Result<(), ConfigError>> {
+    let fresh = match Config::from_path(config_path, &config.overrides) {
+        Ok(fresh) => fresh,
+        Err(e) => {
+            warn!("Keeping the old config, could not reload: {}", e);
+            return Ok(Err(e));
         }
-    })
+    };
+    let fresh_configs = fresh.networks();
+    if fresh_configs.len() != configs.len() {
+        warn!("Adding or removing [[network]]s takes a restart.");
+    }
+    let tls_changed = configs
+        .iter()
+        .zip(&fresh_configs)
+        .any(|(old, new)| tls::Settings::from(&old.general) != tls::Settings::from(&new.general));
+    if tls_changed && daemon::privileges_dropped() {
+        warn!("Changing the tls_* settings takes a restart, as we dropped privileges.");
+    }
+    // the rest of the config is picked up when a network reconnects.
+    for ((old, new), net) in configs.iter_mut().zip(fresh_configs).zip(networks) {
+        if let Some(net) = net {
+            net.reload(poll, &new)?;
+        }
+        *old = new;
+    }
+    logging::set_format(fresh.general.log_format);
+    *config = fresh;
+    info!("Reloaded the config.");
+    Ok(Ok(()))
 }
 
-const IRC_CONN: mio::Token = Token(0);
-const SIGNAL_TOKEN: mio::Token = Token(1);
+/// Run a line from the control socket, returns the answer.
+fn control_command(
+    line: &str,
+    config_path: &Path,
+    config: &mut Config,
+    configs: &mut [Config],
+    networks: &mut [Option<Network>],
+    (raw, raw_path): (&mut Option<RawLog>, &mut String),
+    poll: &Poll,
+) -> io::Result<Vec<String>> {
+    let (idx, command) = match parse_command(line) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(vec![format!("ERR {}", e)]),
+    };
+    match command {
+        Command::RawLogOn(path) => {
+            let path = path.unwrap_or_else(|| raw_path.clone());
+            if path.is_empty() {
+                return Ok(vec!["ERR no raw_log file to go back to".to_owned()]);
+            }
+            return Ok(match RawLog::open(&path) {
+                Ok(log) => {
+                    info!("Logging raw lines to {}.", log.path());
+                    *raw = Some(log);
+                    *raw_path = path;
+                    vec!["OK".to_owned()]
+                }
+                Err(e) => vec![format!("ERR {}: {}", path, e)],
+            });
+        }
+        Command::RawLogOff => {
+            if raw.take().is_some() {
+                info!("Stopped logging raw lines.");
+            }
+            return Ok(vec!["OK".to_owned()]);
+        }
+        Command::Reload => {
+            return Ok(
+                match reload_config(config_path, config, configs, networks, poll)? {
+                    Ok(()) => vec!["OK".to_owned()],
+                    Err(e) => vec![format!("ERR {}", e)],
+                },
+            );
+        }
+        Command::Status => {
+            let mut ret = networks
+                .iter()
+                .enumerate()
+                .map(|(idx, net)| match net {
+                    Some(net) => net.status(idx),
+                    None => format!("{} - reconnecting", idx),
+                })
+                .collect::<Vec<String>>();
+            ret.push("OK".to_owned());
+            return Ok(ret);
+        }
+        _ => (),
+    }
+
+    let net = match networks.get_mut(idx) {
+        Some(Some(net)) if net.connect_deadline.is_none() => net,
+        Some(_) => return Ok(vec![format!("ERR network {} is not connected", idx)]),
+        None => return Ok(vec![format!("ERR no network {}", idx)]),
+    };
+    match command {
+        Command::Join(entry) => net.client.join_channel(&entry),
+        Command::Part(channel) => net.client.part_channel(&channel),
+        Command::Say { target, text } => net.client.say(&target, &text),
+        Command::Stats => {
+            let mut ret = net.client.state.conn.report(Instant::now());
+            ret.extend(net.client.state.plugin_runs.report());
+            ret.push("OK".to_owned());
+            return Ok(ret);
+        }
+        _ => unreachable!("answered above"),
+    }
+    net.want_write(poll)?;
+    Ok(vec!["OK".to_owned()])
+}
 
 pub fn event_loop(config_path: &Path, config: &mut Config) -> Result<(), MainError> {
-    let mut conn = open_conn(config.connect_string())?;
+    logging::set_format(config.general.log_format);
     let mut poll = Poll::new()?;
     let mut events = Events::with_capacity(128);
     let mut signals = Signals::new(SignalSet::all())?;
+    let mut hangup = Hangup::new()?;
+    let mut reaper = Reaper::new()?;
+    // the file RAWLOG ON goes back to.
+    let mut raw_path = config.general.raw_log.clone();
+    let mut raw = match raw_path.as_str() {
+        "" => None,
+        path => Some(RawLog::open(path)?),
+    };
+    let mut control = match config.general.control_socket.as_str() {
+        "" => None,
+        path => Some(Control::open(
+            Path::new(path),
+            &poll,
+            CONTROL_TOKEN,
+            Token(NET_TOKENS),
+        )?),
+    };
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+    let grace = Duration::from_millis(config.general.shutdown_grace_ms);
+    let restart_at = restart_deadline(&config.general, Instant::now());
 
-    let mut irc_client = Client::new(config);
-    let mut plugin_recv = HashMap::<Token, Plugin>::new();
+    // closed networks leave a None behind so the token ranges stay put.
+    let mut configs = config.networks();
+    let mut networks = Vec::with_capacity(configs.len());
+    // networks waiting on a lookup before they connect.
+    let mut opening = Vec::new();
+    for (idx, net) in configs.iter().enumerate() {
+        match Network::open(idx, net, Servers::new(net), &poll, &waker) {
+            Ok(Opened::Up(net)) => networks.push(Some(*net)),
+            Ok(Opened::Resolving(pending)) => {
+                networks.push(None);
+                opening.push(*pending);
+            }
+            Err(failed) => return Err(failed.0),
+        }
+    }
+    // networks to reconnect, and when.
+    let mut reconnects = TimerHeap::default();
 
-    poll.registry()
-        .register(&mut conn, IRC_CONN, Interest::READABLE | Interest::WRITABLE)?;
     poll.registry()
         .register(&mut signals, SIGNAL_TOKEN, Interest::READABLE)?;
+    // everything that may need root is open, nothing came from the servers yet.
+    daemon::drop_privileges(&config.general.user, &config.general.group)?;
+    poll.registry()
+        .register(&mut hangup, SIGNAL_TOKEN, Interest::READABLE)?;
+    poll.registry()
+        .register(&mut reaper, SIGNAL_TOKEN, Interest::READABLE)?;
 
-    'outer: loop {
-        poll.poll(&mut events, Some(Duration::from_secs(1)))?;
+    'outer: while networks.iter().any(Option::is_some)
+        || !opening.is_empty()
+        || !reconnects.is_empty()
+    {
+        if restart_at.is_some_and(|at| at <= Instant::now()) {
+            info!("Reached max_uptime, quitting to be restarted.");
+            break;
+        }
+        // with nothing due, we sleep until an event comes in.
+        let timeout = networks
+            .iter_mut()
+            .flatten()
+            .filter_map(Network::next_deadline)
+            .chain(opening.iter().map(|opening| opening.lookup.deadline))
+            .chain(restart_at)
+            .chain(children::next_deadline())
+            .chain(reconnects.deadline())
+            .min()
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        match poll.poll(&mut events, timeout) {
+            // by the SIGHUP handler, its pipe wakes us right back up.
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            polled => polled?,
+        }
         for event in &events {
             match event.token() {
-                IRC_CONN => {
-                    if event.is_readable() {
-                        loop {
-                            match irc_client.receive_data(&mut conn)? {
-                                ClientReadStat::ReadBufferFull => panic!(
-                                    "Our read buffer is full and we aren't processing events!"
-                                ),
-                                ClientReadStat::HasWritableData => {
-                                    // we have stuff to write
-                                    poll.registry().reregister(
-                                        &mut conn,
-                                        IRC_CONN,
-                                        Interest::READABLE | Interest::WRITABLE,
-                                    )?;
-                                    break;
-                                }
-                                ClientReadStat::Blocked => break,
-                                ClientReadStat::Okay => (),
-                                ClientReadStat::Eof => break 'outer,
-                                ClientReadStat::Error(err) => return Err(MainError::IrcProto(err)),
-                            }
+                SIGNAL_TOKEN => {
+                    if reaper.received()? {
+                        children::reap(Instant::now());
+                    }
+                    let mut reload = hangup.received()?;
+                    loop {
+                        match signals.receive()? {
+                            Some(Signal::Interrupt)
+                            | Some(Signal::Terminate)
+                            | Some(Signal::Quit) => break 'outer,
+                            Some(Signal::User1) | Some(Signal::User2) => reload = true,
+                            None => break,
+                        }
+                    }
+                    if reload {
+                        // a bad config was already logged.
+                        let _ =
+                            reload_config(config_path, config, &mut configs, &mut networks, &poll)?;
+                    }
+                }
+                tok if tok >= CONTROL_TOKEN && tok.0 < NET_TOKENS => {
+                    if let Some(control) = control.as_mut() {
+                        control.handle(&poll, event, |line| {
+                            control_command(
+                                line,
+                                config_path,
+                                config,
+                                &mut configs,
+                                &mut networks,
+                                (&mut raw, &mut raw_path),
+                                &poll,
+                            )
+                        })?;
+                    }
+                }
+                // deferred plugin replies and finished lookups are picked up below.
+                WAKER_TOKEN => {
+                    for net in networks.iter_mut().flatten() {
+                        if net.client.process_workers(false) {
+                            net.want_write(&poll)?;
                         }
-                    } else if event.is_writable() {
-                        loop {
-                            match irc_client.write_data(&mut conn)? {
-                                ClientWriteStat::Blocked => break,
-                                ClientWriteStat::Okay => (),
-                                ClientWriteStat::Eof => {
-                                    poll.registry().reregister(
-                                        &mut conn,
-                                        IRC_CONN,
-                                        Interest::READABLE,
-                                    )?;
-                                    break;
+                    }
+                }
+                tok => {
+                    let idx = tok.0 / NET_TOKENS - 1;
+                    let net = match networks.get_mut(idx) {
+                        Some(Some(net)) => net,
+                        // left over from a network that already closed.
+                        Some(None) => continue,
+                        None => panic!("We got a token that we should not have!"),
+                    };
+                    if tok == net.conn_token {
+                        let alive = match net.handle_conn(&poll, event, raw.as_mut()) {
+                            Ok(alive) => alive,
+                            // a failure to connect already went through every server.
+                            Err(MainError::EvIo(e))
+                                if net.connect_deadline.is_none() && net.servers.rotates() =>
+                            {
+                                warn!("Lost the connection: {}", e);
+                                false
+                            }
+                            Err(e) => {
+                                if let Some(net) = networks[idx].take() {
+                                    let servers = net.shutdown(grace, "Shutting down");
+                                    fail(idx, servers, e, (&networks, &opening), &mut reconnects)?;
                                 }
+                                continue;
+                            }
+                        };
+                        if !alive {
+                            // the server hung up, the other networks carry on.
+                            if let Some(net) = networks[idx].take() {
+                                hang_up(idx, net, grace, &mut reconnects);
                             }
                         }
                     } else {
-                        break 'outer;
+                        net.handle_plugin(&poll, event)?;
                     }
                 }
-                SIGNAL_TOKEN => loop {
-                    match signals.receive()? {
-                        Some(Signal::Interrupt) | Some(Signal::Terminate) | Some(Signal::Quit) => {
-                            break 'outer
-                        }
-                        Some(Signal::User1) | Some(Signal::User2) => {
-                            *config = Config::from_path(config_path)?;
-                            println!("{:?}", config);
-                        }
-                        None => break,
-                    }
-                },
-                _ => {
-                    let ev_tok = event.token();
-                    if let Some(plug) = plugin_recv.get_mut(&ev_tok) {
-                        // If true, we have writable data
-                        if irc_client.process_plugin(plug)? {
-                            poll.registry().reregister(
-                                &mut conn,
-                                IRC_CONN,
-                                Interest::READABLE | Interest::WRITABLE,
-                            )?;
-                        }
+            }
+        }
 
-                        if event.is_read_closed() {
-                            plugin_recv.remove(&ev_tok).expect("Cannot remove plugin!");
-                        }
-                    } else {
-                        panic!("We got a token that we should not have!");
+        let mut opened = Vec::new();
+        for (idx, servers) in reconnects.due(Instant::now()) {
+            info!("Reconnecting.");
+            opened.push((
+                idx,
+                Network::open(idx, &configs[idx], servers, &poll, &waker),
+            ));
+        }
+        for pending in std::mem::take(&mut opening) {
+            let idx = pending.idx;
+            opened.push((
+                idx,
+                pending.resolve(&configs[idx], &poll, &waker, Instant::now()),
+            ));
+        }
+        for (idx, res) in opened {
+            match res {
+                Ok(Opened::Up(net)) => networks[idx] = Some(*net),
+                Ok(Opened::Resolving(pending)) => opening.push(*pending),
+                Err(failed) => {
+                    let (e, servers) = *failed;
+                    fail(idx, servers, e, (&networks, &opening), &mut reconnects)?;
+                }
+            }
+        }
+        // plugins past their timeout.
+        if children::next_deadline().is_some_and(|at| at <= Instant::now()) {
+            children::reap(Instant::now());
+        }
+        for idx in 0..networks.len() {
+            let alive = match networks[idx].as_mut().map(|net| net.tick(&poll)) {
+                Some(Ok(alive)) => alive,
+                Some(Err(e)) => {
+                    if let Some(net) = networks[idx].take() {
+                        let servers = net.shutdown(grace, "Shutting down");
+                        fail(idx, servers, e, (&networks, &opening), &mut reconnects)?;
                     }
+                    continue;
+                }
+                None => continue,
+            };
+            if !alive {
+                if let Some(net) = networks[idx].take() {
+                    hang_up(idx, net, grace, &mut reconnects);
                 }
             }
         }
     }
+
+    let reason = if restart_at.is_some_and(|at| at <= Instant::now()) {
+        "Restarting"
+    } else {
+        "Shutting down"
+    };
+    for net in networks.into_iter().flatten() {
+        net.shutdown(grace, reason);
+    }
     Ok(())
 }
 
@@ -155,14 +1531,23 @@ pub fn event_loop(config_path: &Path, config: &mut Config) -> Result<(), MainErr
 mod test {
     use std::{
         io::{Read, Write},
-        net::TcpListener,
+        net::{Shutdown, TcpListener},
+        os::unix::{fs::PermissionsExt, net::UnixStream},
         path::Path,
-        thread::spawn,
+        str::FromStr,
+        sync::Arc,
+        thread::{self, spawn},
+        time::{Duration, Instant},
     };
 
-    use crate::config::config_file::Config;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
 
-    use super::event_loop;
+    use crate::{config::config_file::Config, storage::Storage};
+
+    use mio::{Events, Poll, Token, Waker};
+
+    use super::{event_loop, interleave, restart_deadline, Lookup, Servers};
 
     const DEFAULT_CONF: &str = r##"
 [general]
@@ -174,19 +1559,19 @@ tls = false
 [commands]
 test = "./test"
 "##;
-    const DEFAULT_GREETER: &str = "CAP REQ :multi-prefix\r
+    const DEFAULT_GREETER: &str = "CAP LS 302\r
 NICK bot\r
-USER bot +i * :bot\r
+USER bot 0 * :bot\r
 ";
 
     #[test]
     fn event_loop_test() {
         let inval = Path::new("testadsfads");
         let mut conf = Config::from_str(DEFAULT_CONF).unwrap();
-        let serv = TcpListener::bind(conf.connect_string()).unwrap();
+        let serv = TcpListener::bind(&conf.connect_strings()[0]).unwrap();
         let j = spawn(move || {
             let (mut stream, _) = serv.accept().unwrap();
-            let mut b = [0u8; 64];
+            let mut b = [0u8; 512];
             let len = stream.read(&mut b).unwrap();
             assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
             stream.write_all(b"PING :xyz\r\n").unwrap();
@@ -197,4 +1582,472 @@ USER bot +i * :bot\r
         event_loop(inval, &mut conf).unwrap();
         j.join().unwrap();
     }
+
+    #[test]
+    fn plugin_workers_test() {
+        let inval = Path::new("testadsfads");
+        let mut conf = Config::from_str(&format!(
+            "{}test = \"{}/examples/plugins/test.sh\"\n",
+            DEFAULT_CONF
+                .replace("9643", "9656")
+                .replace("tls = false", "tls = false\nplugin_workers = 1")
+                .replace("test = \"./test\"\n", ""),
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        let serv = TcpListener::bind(&conf.connect_strings()[0]).unwrap();
+        let j = spawn(move || {
+            let (mut stream, _) = serv.accept().unwrap();
+            let mut b = [0u8; 512];
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+            stream
+                .write_all(b":nick!user@host PRIVMSG #chan :.test\r\n")
+                .unwrap();
+            // the reply is parsed on a worker, then sent once it wakes the loop.
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"PRIVMSG #chan :Hello, World!\r\n");
+        });
+
+        event_loop(inval, &mut conf).unwrap();
+        j.join().unwrap();
+    }
+
+    #[test]
+    fn multi_network_test() {
+        let inval = Path::new("testadsfads");
+        let mut conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF.replace("9643", "9644"),
+            r##"
+[[network]]
+nick = "bot2"
+server = "localhost"
+port = 9645
+"##
+        ))
+        .unwrap();
+        let servers = conf
+            .networks()
+            .iter()
+            .map(|net| {
+                let serv = TcpListener::bind(&net.connect_strings()[0]).unwrap();
+                let nick = net.general.nick.clone();
+                spawn(move || {
+                    let (mut stream, _) = serv.accept().unwrap();
+                    let mut b = [0u8; 512];
+                    let len = stream.read(&mut b).unwrap();
+                    assert_eq!(&b[0..len], DEFAULT_GREETER.replace("bot", &nick).as_bytes());
+                    stream.write_all(b"PING :xyz\r\n").unwrap();
+                    let len = stream.read(&mut b).unwrap();
+                    assert_eq!(&b[0..len], b"PONG :xyz\r\n");
+                })
+            })
+            .collect::<Vec<_>>();
+
+        event_loop(inval, &mut conf).unwrap();
+        for serv in servers {
+            serv.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn max_uptime_test() {
+        let inval = Path::new("testadsfads");
+        let mut conf = Config::from_str(&DEFAULT_CONF.replace(
+            "port = 9643",
+            "port = 9646\nmax_uptime = 1\nshutdown_grace_ms = 500",
+        ))
+        .unwrap();
+        let serv = TcpListener::bind(&conf.connect_strings()[0]).unwrap();
+        let j = spawn(move || {
+            let (mut stream, _) = serv.accept().unwrap();
+            let mut b = [0u8; 512];
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"QUIT :Restarting\r\n");
+        });
+
+        event_loop(inval, &mut conf).unwrap();
+        j.join().unwrap();
+    }
+
+    #[test]
+    fn fallback_servers_test() {
+        let inval = Path::new("testadsfads");
+        // nothing listens on the first server.
+        let mut conf = Config::from_str(&DEFAULT_CONF.replace(
+            "server = \"localhost\"\nport = 9643",
+            "server = \"127.0.0.1\"\nport = 9648\nservers = [\"127.0.0.1:9649\"]\nreconnect_delay_ms = 100\nmax_uptime = 4",
+        ))
+        .unwrap();
+        assert_eq!(
+            conf.connect_strings(),
+            vec!["127.0.0.1:9648", "127.0.0.1:9649"]
+        );
+        let serv = TcpListener::bind("127.0.0.1:9649").unwrap();
+        let j = spawn(move || {
+            let mut b = [0u8; 512];
+            let (mut stream, _) = serv.accept().unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+            drop(stream);
+
+            // back again after trying the first server.
+            let (mut stream, _) = serv.accept().unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+            stream.write_all(b"PING :xyz\r\n").unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"PONG :xyz\r\n");
+            drop(serv);
+            drop(stream);
+
+            // neither server takes us for a while, we keep trying.
+            thread::sleep(Duration::from_millis(300));
+            let serv = TcpListener::bind("127.0.0.1:9649").unwrap();
+            let (mut stream, _) = serv.accept().unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+        });
+
+        event_loop(inval, &mut conf).unwrap();
+        j.join().unwrap();
+    }
+
+    #[test]
+    fn socks_proxy_test() {
+        let inval = Path::new("testadsfads");
+        // the proxy looks up the server, not us.
+        let mut conf = Config::from_str(&format!(
+            "{}{}",
+            DEFAULT_CONF.replace("localhost", "irc.invalid"),
+            r##"
+[proxy]
+host = "127.0.0.1"
+port = 9650
+username = "me"
+password = "pw"
+"##
+        ))
+        .unwrap();
+        let serv = TcpListener::bind("127.0.0.1:9650").unwrap();
+        let j = spawn(move || {
+            let (mut stream, _) = serv.accept().unwrap();
+            let mut b = [0u8; 512];
+            stream.read_exact(&mut b[..4]).unwrap();
+            assert_eq!(&b[..4], &[5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).unwrap();
+            stream.read_exact(&mut b[..7]).unwrap();
+            assert_eq!(&b[..7], b"\x01\x02me\x02pw");
+            stream.write_all(&[1, 0]).unwrap();
+            stream.read_exact(&mut b[..18]).unwrap();
+            assert_eq!(&b[..18], b"\x05\x01\x00\x03\x0birc.invalid\x25\xab");
+            // the server may talk first, in the same packet as the reply.
+            stream
+                .write_all(b"\x05\x00\x00\x01\x7f\x00\x00\x01\x25\xa3PING :xyz\r\n")
+                .unwrap();
+            let mut got = Vec::new();
+            let want = format!("{}PONG :xyz\r\n", DEFAULT_GREETER);
+            while got.len() < want.len() {
+                let len = stream.read(&mut b).unwrap();
+                assert_ne!(len, 0);
+                got.extend_from_slice(&b[..len]);
+            }
+            assert_eq!(String::from_utf8(got).unwrap(), want);
+        });
+
+        event_loop(inval, &mut conf).unwrap();
+        j.join().unwrap();
+    }
+
+    #[test]
+    fn bind_addr_test() {
+        let inval = Path::new("testadsfads");
+        let mut conf = Config::from_str(&DEFAULT_CONF.replace(
+            "server = \"localhost\"\nport = 9643",
+            "server = \"127.0.0.1\"\nport = 9651\nbind_addr = \"127.0.0.2\"",
+        ))
+        .unwrap();
+        let serv = TcpListener::bind("127.0.0.1:9651").unwrap();
+        let j = spawn(move || {
+            let (mut stream, from) = serv.accept().unwrap();
+            assert_eq!(from.ip(), "127.0.0.2".parse::<std::net::IpAddr>().unwrap());
+            let mut b = [0u8; 512];
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+        });
+
+        event_loop(inval, &mut conf).unwrap();
+        j.join().unwrap();
+    }
+
+    #[test]
+    fn control_socket_test() {
+        let inval = Path::new("testadsfads");
+        let sock = std::env::temp_dir().join(format!("r8ball-ctl-{}.sock", std::process::id()));
+        let mut conf = Config::from_str(&DEFAULT_CONF.replace(
+            "server = \"localhost\"\nport = 9643",
+            &format!(
+                "server = \"127.0.0.1\"\nport = 9655\ncontrol_socket = {:?}",
+                sock
+            ),
+        ))
+        .unwrap();
+        let serv = TcpListener::bind("127.0.0.1:9655").unwrap();
+        let ctl_path = sock.clone();
+        let raw = std::env::temp_dir().join(format!("r8ball-ctl-raw-{}.log", std::process::id()));
+        let commands = format!(
+            "STATUS\nRAWLOG ON {}\nSAY #chan hi there\n@3 JOIN #x\nFROB\n",
+            raw.display()
+        );
+        let j = spawn(move || {
+            let (mut stream, _) = serv.accept().unwrap();
+            let mut b = [0u8; 512];
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+
+            let mode = std::fs::metadata(&ctl_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            let mut ctl = UnixStream::connect(&ctl_path).unwrap();
+            ctl.write_all(commands.as_bytes()).unwrap();
+            ctl.shutdown(Shutdown::Write).unwrap();
+            let mut answer = String::new();
+            ctl.read_to_string(&mut answer).unwrap();
+            assert_eq!(
+                answer,
+                "0 bot connected 127.0.0.1:9655\nOK\nOK\nOK\nERR no network 3\nERR unknown command \"FROB\"\n"
+            );
+            // as r8ball send does it.
+            assert_eq!(
+                crate::irc::control::send(&ctl_path, "@3 JOIN #x").unwrap(),
+                (vec!["ERR no network 3".to_owned()], false)
+            );
+            let (stats, ok) = crate::irc::control::send(&ctl_path, "STATS").unwrap();
+            assert!(ok);
+            assert!(stats[0].starts_with("uptime "));
+            assert_eq!(stats[1..3], ["reconnects 0", "in 0 0"]);
+
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"PRIVMSG #chan :hi there\r\n");
+        });
+
+        event_loop(inval, &mut conf).unwrap();
+        j.join().unwrap();
+        // cleaned up after itself.
+        assert!(!sock.exists());
+        let logged = std::fs::read_to_string(&raw).unwrap();
+        assert!(logged.ends_with(" 0 >> PRIVMSG #chan :hi there\n"));
+        std::fs::remove_file(raw).unwrap();
+    }
+
+    /// A server with the self-signed certificate in testdata.
+    fn tls_server() -> Arc<ServerConfig> {
+        let cert = CertificateDer::from(&include_bytes!("testdata/localhost.der")[..]);
+        let key = PrivateKeyDer::Pkcs8(include_bytes!("testdata/localhost.key.der")[..].into());
+        Arc::new(
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert], key)
+                .unwrap(),
+        )
+    }
+
+    const PIN: &str = "SHA256:mEyWml/Kfk8LteYOvDpPXKLhE0Usx8lX436rkMjuurs";
+
+    #[test]
+    fn sts_test() {
+        let inval = Path::new("testadsfads");
+        let db = std::env::temp_dir().join(format!("r8ball-sts-{}.db", std::process::id()));
+        let mut conf = Config::from_str(&format!(
+            "{}\n[storage]\npath = {:?}\n",
+            DEFAULT_CONF.replace(
+                "server = \"localhost\"\nport = 9643",
+                &format!(
+                    "server = \"127.0.0.1\"\nport = 9653\ntls_fingerprint = \"{}\"",
+                    PIN
+                ),
+            ),
+            db
+        ))
+        .unwrap();
+        let plain = TcpListener::bind("127.0.0.1:9653").unwrap();
+        let secure = TcpListener::bind("127.0.0.1:9654").unwrap();
+        let j = spawn(move || {
+            let (mut stream, _) = plain.accept().unwrap();
+            let mut b = [0u8; 512];
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], DEFAULT_GREETER.as_bytes());
+            // only the port counts over plain text.
+            stream
+                .write_all(b":server CAP * LS :multi-prefix sts=port=9654,duration=5\r\n")
+                .unwrap();
+
+            let (stream, _) = secure.accept().unwrap();
+            let tls = ServerConnection::new(tls_server()).unwrap();
+            let mut stream = StreamOwned::new(tls, stream);
+            let mut got = Vec::new();
+            while got.len() < DEFAULT_GREETER.len() {
+                let len = stream.read(&mut b).unwrap();
+                got.extend_from_slice(&b[..len]);
+            }
+            assert_eq!(got, DEFAULT_GREETER.as_bytes());
+            stream
+                .write_all(b":server CAP * LS :multi-prefix sts=duration=300\r\n")
+                .unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"CAP REQ :multi-prefix\r\n");
+        });
+
+        event_loop(inval, &mut conf).unwrap();
+        j.join().unwrap();
+        let storage = Storage::open(&db).unwrap();
+        let policy = storage.get("sts", "127.0.0.1").unwrap().unwrap();
+        assert!(policy.starts_with("9654 "), "{}", policy);
+        drop(storage);
+        let _ = std::fs::remove_file(&db);
+    }
+
+    #[test]
+    fn tls_test() {
+        let inval = Path::new("testadsfads");
+        let server_conf = tls_server();
+        let serv = Arc::new(TcpListener::bind("127.0.0.1:9652").unwrap());
+        let pin = "98:4C:96:9A:5F:CA:7E:4F:0B:B5:E6:0E:BC:3A:4F:5C:A2:E1:13:45:2C:C7:C9:57:E3:7E:AB:90:C8:EE:BA:BB";
+        for (settings, trusted) in [
+            (format!("tls_fingerprint = \"{}\"", PIN), true),
+            (format!("tls_fingerprint = \"SHA256:{}\"", pin), true),
+            ("tls_verify = false".to_owned(), true),
+            // self-signed, so not trusted unless pinned.
+            (String::new(), false),
+            (
+                format!("tls_fingerprint = \"{}\"", pin.replace("BB", "BC")),
+                false,
+            ),
+        ] {
+            let mut conf = Config::from_str(&DEFAULT_CONF.replace(
+                "server = \"localhost\"\nport = 9643\ntls = false",
+                &format!(
+                    "server = \"127.0.0.1\"\nport = 9652\ntls = true\n{}",
+                    settings
+                ),
+            ))
+            .unwrap();
+            let (serv, server_conf) = (serv.clone(), server_conf.clone());
+            let j = spawn(move || {
+                let (stream, _) = serv.accept().unwrap();
+                let tls = ServerConnection::new(server_conf).unwrap();
+                let mut stream = StreamOwned::new(tls, stream);
+                let mut b = [0u8; 512];
+                let mut got = Vec::new();
+                while got.len() < DEFAULT_GREETER.len() {
+                    match stream.read(&mut b) {
+                        Ok(len) if len > 0 => got.extend_from_slice(&b[..len]),
+                        _ => return false,
+                    }
+                }
+                assert_eq!(got, DEFAULT_GREETER.as_bytes());
+                stream.write_all(b"PING :xyz\r\n").unwrap();
+                let len = stream.read(&mut b).unwrap();
+                assert_eq!(&b[0..len], b"PONG :xyz\r\n");
+                true
+            });
+
+            assert_eq!(
+                event_loop(inval, &mut conf).is_ok(),
+                trusted,
+                "{}",
+                settings
+            );
+            assert_eq!(j.join().unwrap(), trusted, "{}", settings);
+        }
+    }
+
+    #[test]
+    fn tls_reload() {
+        let mut conf = Config::from_str(DEFAULT_CONF).unwrap();
+        let mut servers = Servers::new(&conf);
+        let tls = servers.tls_config(&conf.general).unwrap();
+        assert!(Arc::ptr_eq(
+            &tls,
+            &servers.tls_config(&conf.general).unwrap()
+        ));
+        // a reload changed them.
+        conf.general.tls_verify = false;
+        let reloaded = servers.tls_config(&conf.general).unwrap();
+        assert!(!Arc::ptr_eq(&tls, &reloaded));
+        assert!(Arc::ptr_eq(
+            &reloaded,
+            &servers.tls_config(&conf.general).unwrap()
+        ));
+    }
+
+    #[test]
+    fn interleave_families() {
+        let addrs = ["[::1]:1", "[::2]:1", "[::3]:1", "1.0.0.1:1", "1.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let expect = ["[::1]:1", "1.0.0.1:1", "[::2]:1", "1.0.0.2:1", "[::3]:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect::<Vec<std::net::SocketAddr>>();
+        assert_eq!(interleave(addrs), expect);
+
+        // starts with whichever the resolver put first.
+        let addrs = ["1.0.0.1:1", "1.0.0.2:1", "[::1]:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let expect = ["1.0.0.1:1", "[::1]:1", "1.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect::<Vec<std::net::SocketAddr>>();
+        assert_eq!(interleave(addrs), expect);
+    }
+
+    #[test]
+    fn restart_window() {
+        let now = Instant::now();
+        let mut conf = Config::from_str(DEFAULT_CONF).unwrap();
+        assert_eq!(restart_deadline(&conf.general, now), None);
+
+        conf.general.max_uptime = 3600;
+        assert_eq!(
+            restart_deadline(&conf.general, now),
+            Some(now + Duration::from_secs(3600))
+        );
+
+        // waits for the window after max_uptime, at most a day later.
+        conf.general.restart_window = "0 4 * * *".to_owned();
+        let at = restart_deadline(&conf.general, now).unwrap();
+        assert!(at > now + Duration::from_secs(3600));
+        assert!(at <= now + Duration::from_secs(3600 + 86400));
+    }
+
+    #[test]
+    fn lookup_wakes() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        let waker = Arc::new(Waker::new(poll.registry(), Token(0)).unwrap());
+        let lookup = Lookup::start(
+            "127.0.0.1:9647".to_owned(),
+            Duration::from_secs(5),
+            waker.clone(),
+        )
+        .unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(!events.is_empty());
+        let addrs = lookup.poll(Instant::now()).unwrap().unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:9647".parse().unwrap()]);
+
+        let lookup = Lookup::start("no port".to_owned(), Duration::from_secs(5), waker).unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(lookup.poll(Instant::now()).unwrap().is_err());
+    }
 }
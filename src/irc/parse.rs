@@ -17,6 +17,8 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use memchr::{memchr, memchr_iter};
+
 #[derive(PartialEq)]
 enum ParseState {
     Prefix,
@@ -25,20 +27,25 @@ enum ParseState {
 }
 
 /// A non-general purpose IRCv2 parsed message.
-/// This struct does not support tags as I do not use them or need them.
+/// IRCv3 tags are kept as one unparsed slice, see Message::tag() to look one up.
 /// It also assumes the content is free of line delimiters.
 /// This type was constructed to zero-copy view into a raw read buffer returned in parts
 /// from crate::irc::iter::BufIterator.
+#[derive(Default)]
 pub struct Message<'a> {
+    /// Unparsed tags, without the leading @.
+    pub tags: Option<&'a [u8]>,
+    /// The prefix: a nick, or a server name, then the user and host if given.
     pub nick: Option<&'a [u8]>,
     pub user: Option<&'a [u8]>,
     pub host: Option<&'a [u8]>,
+    /// e.g. PRIVMSG or 001, as sent.
     pub command: Option<&'a [u8]>,
-    // unparsed parameters slice
-    // consider using the iterator instead.
+    /// The unparsed parameters; Message::parameters() splits them.
     pub params: Option<&'a [u8]>,
 }
 
+/// The parameters of a Message, the trailing one without its colon.
 pub struct MessageParamIter<'a> {
     pos: usize,
     params: Option<&'a [u8]>,
@@ -75,16 +82,7 @@ impl<'a> Iterator for MessageParamIter<'a> {
             }
 
             // else try and find the next separator or end of string
-            let end = loop {
-                if i < params.len() {
-                    if params[i] == b' ' {
-                        break i;
-                    }
-                    i += 1;
-                } else {
-                    break params.len();
-                }
-            };
+            let end = memchr(b' ', &params[i..]).map_or(params.len(), |end| i + end);
 
             if start < end {
                 self.pos = end + 1;
@@ -98,21 +96,12 @@ impl<'a> Iterator for MessageParamIter<'a> {
     }
 }
 
-impl<'a> Default for Message<'a> {
-    fn default() -> Self {
-        Message {
-            nick: None,
-            user: None,
-            host: None,
-            command: None,
-            params: None,
-        }
-    }
-}
+/// nick, user and host parts of a message prefix.
+type Prefix<'a> = (Option<&'a [u8]>, Option<&'a [u8]>, Option<&'a [u8]>);
 
-fn parse_prefix(b: &[u8]) -> (Option<&[u8]>, Option<&[u8]>, Option<&[u8]>) {
-    let user_start = b.iter().position(|&chr| chr == b'!');
-    let host_start = b.iter().position(|&chr| chr == b'@');
+fn parse_prefix(b: &[u8]) -> Prefix<'_> {
+    let user_start = memchr(b'!', b);
+    let host_start = memchr(b'@', b);
     match (user_start, host_start) {
         (None, None) => (Some(b), None, None),
         (None, Some(host)) => (Some(&b[0..host]), None, Some(&b[host + 1..])),
@@ -135,14 +124,26 @@ fn parse_prefix(b: &[u8]) -> (Option<&[u8]>, Option<&[u8]>, Option<&[u8]>) {
 
 impl<'a> Message<'a> {
     pub fn is_empty(&self) -> bool {
-        self.nick == None
-            && self.user == None
-            && self.host == None
-            && self.command == None
-            && self.params == None
+        self.nick.is_none()
+            && self.user.is_none()
+            && self.host.is_none()
+            && self.command.is_none()
+            && self.params.is_none()
+    }
+
+    /// The raw value of tag key, e.g. msg.tag(b"time").
+    /// Tags without a value are Some(b"").
+    pub fn tag(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.tags?
+            .split(|&chr| chr == b';')
+            .find_map(|tag| match memchr(b'=', tag) {
+                Some(eq) if &tag[..eq] == key => Some(&tag[eq + 1..]),
+                None if tag == key => Some(&tag[tag.len()..]),
+                _ => None,
+            })
     }
 
-    pub fn parameters(&self) -> MessageParamIter {
+    pub fn parameters(&self) -> MessageParamIter<'a> {
         MessageParamIter {
             pos: 0,
             params: self.params,
@@ -153,14 +154,22 @@ impl<'a> Message<'a> {
         let mut ret = Message::default();
         let mut arg_state = ParseState::Prefix;
 
-        for part in raw.split(|&chr| chr == b' ') {
+        let mut from = 0;
+        let ends = memchr_iter(b' ', raw).chain(std::iter::once(raw.len()));
+        for end in ends {
+            let part = &raw[from..end];
+            from = end + 1;
             if part.is_empty() {
                 continue;
             }
 
             arg_state = match arg_state {
+                ParseState::Prefix if part[0] == b'@' && ret.tags.is_none() => {
+                    ret.tags = Some(&part[1..]);
+                    ParseState::Prefix
+                }
                 ParseState::Prefix => {
-                    let has_prefix = if let Some(chr) = part.get(0) {
+                    let has_prefix = if let Some(chr) = part.first() {
                         *chr == b':'
                     } else {
                         false
@@ -204,10 +213,10 @@ mod test {
         command: Option<&[u8]>,
         params: Option<Vec<&[u8]>>,
     ) {
-        assert_eq!(m.nick.as_deref(), nick.as_deref());
-        assert_eq!(m.user.as_deref(), user.as_deref());
-        assert_eq!(m.host.as_deref(), host.as_deref());
-        assert_eq!(m.command.as_deref(), command.as_deref());
+        assert_eq!(m.nick, nick);
+        assert_eq!(m.user, user);
+        assert_eq!(m.host, host);
+        assert_eq!(m.command, command);
         if let Some(params) = params {
             // zip truncates, make sure the expected counts are comparable.
             assert_eq!(m.parameters().count(), params.len());
@@ -233,6 +242,24 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_irc_message_parse_tags() {
+        let m = Message::new(
+            b"@time=2021-01-01T00:00:00.000Z;draft/bot :happy!test@case command 1 :trailing",
+        );
+        assert_eq!(m.tag(b"time"), Some(&b"2021-01-01T00:00:00.000Z"[..]));
+        assert_eq!(m.tag(b"draft/bot"), Some(&b""[..]));
+        assert_eq!(m.tag(b"msgid"), None);
+        assert_all_of_the_parameters(
+            m,
+            Some(b"happy"),
+            Some(b"test"),
+            Some(b"case"),
+            Some(b"command"),
+            Some(vec![b"1", b"trailing"]),
+        );
+    }
+
     #[test]
     fn test_irc_message_parse_no_prefix() {
         let m = Message::new(b"command 1 2 3 :trailing param.");
@@ -16,69 +16,216 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
+//! External plugins and the protocol they speak. A plugin is any executable, run
+//! once per command or event with arguments like --nick=, --reply= (the channel or
+//! nick to answer), --command= and --message=, and R8_* environment variables, e.g.
+//! R8_DB_PATH for the shared storage. Every line it prints is sent to the server
+//! as is, except for directives starting with a colon: `:reply <text>` to answer
+//! where it was asked, `:timer`, `:whois`, and for moderators `:kick`, `:ban` and
+//! `:mode`. See examples/r8ball_conf.toml for the details of each.
 
 use std::{
+    env,
     io::{self, Read},
-    os::unix::prelude::{FromRawFd, IntoRawFd},
-    process::{self, Child, ExitStatus, Stdio},
-    sync::{Arc, Mutex},
-    thread,
+    os::unix::{
+        prelude::{FromRawFd, IntoRawFd},
+        process::CommandExt,
+    },
+    process::{self, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use mio::{event::Source, unix::pipe};
 
-use super::iter::BufIterator;
+use super::{
+    children::{self, Status},
+    client::output::Route,
+    iter::BufIterator,
+};
+use crate::config::config_file::Sandbox;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+// longest stderr line we hold on to before logging it anyway.
+const ERR_LINE_MAX: usize = 512;
+// most of stderr we read per wakeup, so a noisy plugin can't hold up the rest.
+const ERR_READ_MAX: usize = 16 * 1024;
 
+/// What reading a plugin's output came to.
 pub enum PluginReadStat {
     Okay,
+    /// The plugin closed its output.
     Eof,
+    /// Nothing more until its pipe is readable again.
     Blocked,
+    /// The buffer has to be consumed before reading on; an overlong line is cut.
     ReadBufferFull,
 }
 
+/// A plugin the client wants started; the transport spawns it, see [`Plugin::spawn`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginSpawn {
+    /// Unique for the life of the process, the id of the Plugin it becomes.
+    pub id: usize,
+    pub name: String,
+    pub channel: String,
+    pub route: Option<Route>,
+    pub path: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub sandbox: Sandbox,
+}
+
+/// An id no other plugin has.
+pub fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 /// An r8b plugin, its receiver and exit status.
 pub struct Plugin {
-    /// The exit status of the plugin.
-    /// You can use the is_read_closed() event in mio to know when this field should be set.
-    pub exit_code: Arc<Mutex<Option<io::Result<ExitStatus>>>>,
+    /// Unique for the life of the process.
+    pub id: usize,
+    /// What the plugin is reported as, e.g. in send statistics.
+    pub name: String,
+    /// Where the plugin was invoked from, for logging.
+    pub channel: String,
+    /// Where its :reply lines go.
+    pub route: Option<Route>,
+    /// The exit status of the plugin, set once it is reaped, see [`children::reap`].
+    pub exit_code: Status,
+    /// For the runtime accounting, see [`crate::irc::client::stats::PluginStats`].
+    pub started: Instant,
+    /// What it printed so far.
+    pub bytes_read: u64,
+    pid: u32,
     read_buf: [u8; 512],
     read_start: usize,
     read_len: usize,
     pipe: pipe::Receiver,
     discard_out: bool,
+    out_closed: bool,
+    err_pipe: pipe::Receiver,
+    err_buf: Vec<u8>,
+    err_closed: bool,
+    // stderr had more than ERR_READ_MAX when we last read it.
+    err_backlog: bool,
 }
 
 impl Plugin {
-    pub fn new(command: String, args: Vec<String>) -> io::Result<Self> {
+    pub fn new(
+        command: String,
+        args: Vec<String>,
+        env: Vec<(String, String)>,
+        sandbox: &Sandbox,
+    ) -> io::Result<Self> {
         let (send, recv) = pipe::new()?;
-        let exit_code = Arc::new(Mutex::new(None));
-        let thread_ecode = exit_code.clone();
-
-        thread::spawn(move || {
-            let mut ecode = thread_ecode
-                .lock()
-                .expect("Could not lock plugin status field.");
-            *ecode = Some(
-                process::Command::new(command)
-                    .stdin(Stdio::null())
-                    .stderr(Stdio::inherit())
-                    .stdout(unsafe { Stdio::from_raw_fd(send.into_raw_fd()) })
-                    .args(args)
-                    .spawn()
-                    .and_then(|mut child: Child| -> io::Result<ExitStatus> { child.wait() }),
-            );
-        });
+        let (err_send, err_recv) = pipe::new()?;
+        let name = command.clone();
+
+        // a relative path is ours, not the working directory's.
+        let program = match env::current_dir() {
+            Ok(cwd) if !sandbox.workdir.is_empty() && command.contains('/') => cwd.join(&command),
+            _ => command.into(),
+        };
+        let mut cmd = process::Command::new(program);
+        cmd.stdin(Stdio::null())
+            .stderr(unsafe { Stdio::from_raw_fd(err_send.into_raw_fd()) })
+            .stdout(unsafe { Stdio::from_raw_fd(send.into_raw_fd()) })
+            .args(args)
+            // so a timeout kills whatever the plugin started too, see children::reap().
+            .process_group(0);
+        if !sandbox.inherit_env {
+            cmd.env_clear()
+                .envs(env::vars().filter(|(name, _)| sandbox.env.contains(name)));
+        }
+        cmd.envs(env);
+        if !sandbox.workdir.is_empty() {
+            cmd.current_dir(&sandbox.workdir);
+        }
+
+        let limits = vec![
+            (libc::RLIMIT_CPU, sandbox.cpu_secs),
+            (
+                libc::RLIMIT_AS,
+                sandbox.memory_mb.saturating_mul(1024 * 1024),
+            ),
+            (libc::RLIMIT_NOFILE, sandbox.nofile),
+        ]
+        .into_iter()
+        .filter(|(_, limit)| *limit != 0)
+        .collect::<Vec<_>>();
+        if !limits.is_empty() {
+            // Safety: setrlimit is async-signal-safe and we don't allocate after the fork.
+            unsafe {
+                cmd.pre_exec(move || {
+                    for &(resource, limit) in &limits {
+                        let rlim = libc::rlimit {
+                            rlim_cur: limit as libc::rlim_t,
+                            rlim_max: limit as libc::rlim_t,
+                        };
+                        if libc::setrlimit(resource, &rlim) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        let timeout = match sandbox.timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        // our ends of the pipes close with cmd, so they end with the plugin, or
+        // right away when it could not start.
+        let (pid, exit_code) = match cmd.spawn() {
+            Ok(child) => (child.id(), children::track(child, timeout)),
+            Err(e) => {
+                warn!("Could not start plugin {}: {}", name, e);
+                (0, Arc::new(Mutex::new(Some(Err(e)))))
+            }
+        };
 
         Ok(Plugin {
+            id: next_id(),
+            name,
+            channel: String::new(),
+            route: None,
             exit_code,
+            started: Instant::now(),
+            bytes_read: 0,
+            pid,
             read_buf: [0u8; 512],
             read_start: 0,
             read_len: 0,
             pipe: recv,
             discard_out: false,
+            out_closed: false,
+            err_pipe: err_recv,
+            err_buf: vec![],
+            err_closed: false,
+            err_backlog: false,
         })
     }
 
+    /// Start what the client asked for.
+    pub fn spawn(spawn: &PluginSpawn) -> io::Result<Self> {
+        let mut plug = Plugin::new(
+            spawn.path.clone(),
+            spawn.args.clone(),
+            spawn.env.clone(),
+            &spawn.sandbox,
+        )?;
+        plug.id = spawn.id;
+        plug.name = spawn.name.clone();
+        plug.channel = spawn.channel.clone();
+        plug.route = spawn.route.clone();
+        Ok(plug)
+    }
+
     pub fn get_buf(&self) -> &[u8] {
         &self.read_buf[..self.read_len]
     }
@@ -89,14 +236,10 @@ impl Plugin {
             // We check if it can be, else we attach a newline to the body.
             // this may cause gibberish to be sent to the server, but it is better
             // than deadlocking.
-            if !self.read_buf.iter().any(|&chr| chr == b'\n') {
-                self.read_buf
-                    .last_mut()
-                    .and_then(|refer: &mut u8| {
-                        *refer = b'\n';
-                        Some(())
-                    })
-                    .unwrap();
+            if !self.read_buf.contains(&b'\n') {
+                if let Some(last) = self.read_buf.last_mut() {
+                    *last = b'\n';
+                }
                 // Because the rest of the output may have been broken by the above,
                 // we set this flag that tells us to discard the remaining undelimited content.
                 self.discard_out = true;
@@ -112,13 +255,17 @@ impl Plugin {
         }
 
         let size = match self.pipe.read(&mut self.read_buf[self.read_len..]) {
-            Ok(s) if s == 0 => return Ok(PluginReadStat::Eof),
+            Ok(0) => {
+                self.out_closed = true;
+                return Ok(PluginReadStat::Eof);
+            }
             Ok(s) => s,
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                 return Ok(PluginReadStat::Blocked);
             }
             Err(e) => return Err(e),
         };
+        self.bytes_read += size as u64;
 
         if !self.discard_out {
             self.read_len += size;
@@ -138,6 +285,59 @@ impl Plugin {
         Ok(PluginReadStat::Okay)
     }
 
+    /// Read up to ERR_READ_MAX of what the plugin wrote to stderr, returning the
+    /// complete lines. See backlogged() for whether there is more.
+    pub fn receive_err(&mut self) -> io::Result<Vec<String>> {
+        let mut lines = vec![];
+        let mut buf = [0u8; 512];
+        let mut read = 0;
+        self.err_backlog = false;
+        loop {
+            if read >= ERR_READ_MAX {
+                self.err_backlog = true;
+                break;
+            }
+            match self.err_pipe.read(&mut buf) {
+                Ok(0) => {
+                    self.err_closed = true;
+                    if !self.err_buf.is_empty() {
+                        lines.push(String::from_utf8_lossy(&self.err_buf).to_string());
+                        self.err_buf.clear();
+                    }
+                    break;
+                }
+                Ok(size) => {
+                    read += size;
+                    self.err_buf.extend_from_slice(&buf[..size]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+
+            while let Some(pos) = self.err_buf.iter().position(|&chr| chr == b'\n') {
+                let line = self.err_buf.drain(..=pos).collect::<Vec<u8>>();
+                let line = String::from_utf8_lossy(&line[..pos]);
+                lines.push(line.trim_end_matches('\r').to_owned());
+            }
+            if self.err_buf.len() >= ERR_LINE_MAX {
+                lines.push(String::from_utf8_lossy(&self.err_buf).to_string());
+                self.err_buf.clear();
+            }
+        }
+        Ok(lines)
+    }
+
+    /// If receive_err() left some of stderr for later. The poll won't report it again
+    /// on its own, reregister the plugin then.
+    pub fn backlogged(&self) -> bool {
+        self.err_backlog
+    }
+
+    /// Both stdout and stderr were closed, the plugin is done.
+    pub fn is_closed(&self) -> bool {
+        self.out_closed && self.err_closed
+    }
+
     pub fn split_at(&mut self, pos: usize) {
         if pos == 0 {
             self.reset_buf();
@@ -147,16 +347,26 @@ impl Plugin {
     }
 
     pub fn get_slice_pos(&self, slice: &[u8]) -> usize {
-        self.read_buf.as_ptr() as usize - slice.as_ptr() as usize
+        slice.as_ptr() as usize - self.read_buf.as_ptr() as usize
     }
 
-    pub fn iter(&self) -> BufIterator {
+    pub fn iter(&self) -> BufIterator<'_> {
         BufIterator::new(&self.read_buf[..self.read_len])
     }
 
     pub fn reset_buf(&mut self) {
         self.read_len = 0;
     }
+
+    /// Until the process was reaped; it may have closed its output long before.
+    pub fn is_running(&self) -> bool {
+        self.exit_code.lock().is_ok_and(|status| status.is_none())
+    }
+
+    /// Kill the process, and reap it, if it still runs.
+    pub fn kill(&self) {
+        children::kill(self.pid);
+    }
 }
 
 impl Source for Plugin {
@@ -166,7 +376,9 @@ impl Source for Plugin {
         token: mio::Token,
         interests: mio::Interest,
     ) -> io::Result<()> {
-        registry.register(&mut self.pipe, token, interests)
+        registry.register(&mut self.pipe, token, interests)?;
+        // stderr shares the token, readers drain both on any event.
+        registry.register(&mut self.err_pipe, token, interests)
     }
 
     fn reregister(
@@ -175,19 +387,27 @@ impl Source for Plugin {
         token: mio::Token,
         interests: mio::Interest,
     ) -> io::Result<()> {
-        registry.reregister(&mut self.pipe, token, interests)
+        registry.reregister(&mut self.pipe, token, interests)?;
+        registry.reregister(&mut self.err_pipe, token, interests)
     }
 
     fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
-        registry.deregister(&mut self.pipe)
+        registry.deregister(&mut self.pipe)?;
+        registry.deregister(&mut self.err_pipe)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
 
-    use crate::irc::{iter::TruncStatus, parse::Message, plugin::PluginReadStat};
+    use crate::{
+        config::config_file::Sandbox,
+        irc::{children, iter::TruncStatus, parse::Message, plugin::PluginReadStat},
+    };
 
     use super::Plugin;
     use mio::{Events, Interest, Poll, Token};
@@ -197,7 +417,13 @@ mod test {
         let mut poll = Poll::new().unwrap();
         let mut events = Events::with_capacity(1);
         let plugin_file = format!("{}/examples/plugins/test.sh", env!("CARGO_MANIFEST_DIR"));
-        let mut plug = Plugin::new(plugin_file, vec!["--reply=#chan".to_owned()]).unwrap();
+        let mut plug = Plugin::new(
+            plugin_file,
+            vec!["--reply=#chan".to_owned()],
+            vec![],
+            &Sandbox::default(),
+        )
+        .unwrap();
 
         let tok = Token(127);
 
@@ -222,6 +448,10 @@ mod test {
                                 }
                             }
                         } else if event.is_read_closed() {
+                            while plug.is_running() {
+                                children::reap(Instant::now());
+                                thread::sleep(Duration::from_millis(10));
+                            }
                             match plug.exit_code.lock().unwrap().as_ref().unwrap() {
                                 Ok(status) => assert_eq!(status.code(), Some(0)),
                                 Err(e) => panic!("Our Plugin had an io::Error: {:?}", e),
@@ -246,13 +476,130 @@ mod test {
         assert!(has_output);
     }
 
+    #[test]
+    fn capture_stderr() {
+        let mut plug = Plugin::new(
+            "sh".to_owned(),
+            vec![
+                "-c".to_owned(),
+                "echo out; printf 'oops\\nno newline' >&2".to_owned(),
+            ],
+            vec![],
+            &Sandbox::default(),
+        )
+        .unwrap();
+
+        let mut lines = vec![];
+        while !plug.is_closed() {
+            while let PluginReadStat::Okay = plug.receive().unwrap() {}
+            lines.extend(plug.receive_err().unwrap());
+        }
+        assert_eq!(lines, vec!["oops", "no newline"]);
+    }
+
+    #[test]
+    fn stderr_capped() {
+        let mut plug = Plugin::new(
+            "sh".to_owned(),
+            vec!["-c".to_owned(), "yes oops | head -n 8000 >&2".to_owned()],
+            vec![],
+            &Sandbox::default(),
+        )
+        .unwrap();
+        while plug.is_running() {
+            children::reap(Instant::now());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut lines = plug.receive_err().unwrap();
+        assert!(lines.len() < 8000);
+        assert!(plug.backlogged());
+        while !plug.is_closed() {
+            while let PluginReadStat::Okay = plug.receive().unwrap() {}
+            lines.extend(plug.receive_err().unwrap());
+        }
+        assert!(!plug.backlogged());
+        assert_eq!(lines.len(), 8000);
+    }
+
+    #[test]
+    fn sandboxed() {
+        let sandbox = Sandbox {
+            cpu_secs: 5,
+            memory_mb: 0,
+            nofile: 32,
+            workdir: "/".to_owned(),
+            env: vec!["PATH".to_owned()],
+            inherit_env: false,
+            timeout: 0,
+        };
+        let mut plug = Plugin::new(
+            "sh".to_owned(),
+            vec![
+                "-c".to_owned(),
+                "pwd; echo \"$HOME:$R8_TEST\"; ulimit -n; ulimit -t".to_owned(),
+            ],
+            vec![("R8_TEST".to_owned(), "yes".to_owned())],
+            &sandbox,
+        )
+        .unwrap();
+
+        while !plug.is_closed() {
+            while let PluginReadStat::Okay = plug.receive().unwrap() {}
+            plug.receive_err().unwrap();
+        }
+        assert_eq!(plug.get_buf(), b"/\n:yes\n32\n5\n");
+
+        // only PATH and LANG, unless asked for everything.
+        for (inherit_env, expect) in [(false, "\n".to_owned()), (true, "1\n".to_owned())] {
+            let sandbox = Sandbox {
+                inherit_env,
+                ..Sandbox::default()
+            };
+            std::env::set_var("R8_SANDBOX_SECRET", "1");
+            let mut plug = Plugin::new(
+                "sh".to_owned(),
+                vec!["-c".to_owned(), "echo \"$R8_SANDBOX_SECRET\"".to_owned()],
+                vec![],
+                &sandbox,
+            )
+            .unwrap();
+            while !plug.is_closed() {
+                while let PluginReadStat::Okay = plug.receive().unwrap() {}
+                plug.receive_err().unwrap();
+            }
+            assert_eq!(plug.get_buf(), expect.as_bytes());
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let sandbox = Sandbox {
+            timeout: 1,
+            ..Sandbox::default()
+        };
+        let mut plug =
+            Plugin::new("sleep".to_owned(), vec!["30".to_owned()], vec![], &sandbox).unwrap();
+
+        let start = Instant::now();
+        while !plug.is_closed() {
+            plug.receive().unwrap();
+            plug.receive_err().unwrap();
+            children::reap(Instant::now());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+        let status = plug.exit_code.lock().unwrap();
+        assert!(!status.as_ref().unwrap().as_ref().unwrap().success());
+    }
+
     #[test]
     fn large_output_truncation() {
         let plugin_file = format!(
             "{}/examples/plugins/big_output.sh",
             env!("CARGO_MANIFEST_DIR")
         );
-        let mut plug = Plugin::new(plugin_file, vec![]).unwrap();
+        let mut plug = Plugin::new(plugin_file, vec![], vec![], &Sandbox::default()).unwrap();
 
         loop {
             match plug.receive().unwrap() {
@@ -266,7 +613,7 @@ mod test {
                                 let m = Message::new(out);
                                 let p = m.parameters().collect::<Vec<&[u8]>>();
 
-                                assert_eq!(m.command.as_deref(), Some(&b"PRIVMSG"[..]));
+                                assert_eq!(m.command, Some(&b"PRIVMSG"[..]));
                                 assert_eq!(p[0], b"#test");
                                 // trailing a should not be in this message.
                                 assert!(!p[1].iter().any(|&chr| chr != b' '));
@@ -287,7 +634,7 @@ mod test {
                     let m = Message::new(out);
                     let p = m.parameters().collect::<Vec<&[u8]>>();
 
-                    assert_eq!(m.command.as_deref(), Some(&b"PRIVMSG"[..]));
+                    assert_eq!(m.command, Some(&b"PRIVMSG"[..]));
                     assert_eq!(p[0], b"#test");
                     assert_eq!(p[1], b"Hello, World!");
                 }
@@ -304,7 +651,7 @@ mod test {
             "{}/examples/plugins/truncated_read.sh",
             env!("CARGO_MANIFEST_DIR")
         );
-        let mut plug = Plugin::new(plugin_file, vec![]).unwrap();
+        let mut plug = Plugin::new(plugin_file, vec![], vec![], &Sandbox::default()).unwrap();
 
         loop {
             match plug.receive().unwrap() {
@@ -319,14 +666,10 @@ mod test {
                                 let m = Message::new(out);
                                 let p = m.parameters().collect::<Vec<&[u8]>>();
 
-                                assert_eq!(m.command.as_deref(), Some(&b"PRIVMSG"[..]));
+                                assert_eq!(m.command, Some(&b"PRIVMSG"[..]));
                                 assert_eq!(p[0], b"#test");
                                 // trailing a should not be in this message.
-                                assert!(!p[1]
-                                    .iter()
-                                    .last()
-                                    .and_then(|&chr| if chr == b'a' { Some(()) } else { None })
-                                    .is_some());
+                                assert_ne!(p[1].last(), Some(&b'a'));
                             }
                             TruncStatus::Part(out) => {
                                 split_at = plug.get_slice_pos(out);
@@ -346,7 +689,7 @@ mod test {
                     let m = Message::new(out);
                     let p = m.parameters().collect::<Vec<&[u8]>>();
 
-                    assert_eq!(m.command.as_deref(), Some(&b"PRIVMSG"[..]));
+                    assert_eq!(m.command, Some(&b"PRIVMSG"[..]));
                     assert_eq!(p[0], b"#test");
                     assert_eq!(p[1], b"Hello, World!");
                 }
@@ -0,0 +1,209 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! A tap on the IRC connections for debugging, appending every line that goes by to a
+//! file, e.g. "2021-06-01T12:00:00.123Z 0 >> NICK bot" for network 0, with << for
+//! lines from the server. Passwords we send are left out.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, IoSlice, Read, Write},
+    time::SystemTime,
+};
+
+use crate::logging::timestamp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn marker(self) -> &'static str {
+        match self {
+            Direction::In => "<<",
+            Direction::Out => ">>",
+        }
+    }
+}
+
+/// The line with any password blanked out.
+fn redact(line: &[u8]) -> Vec<u8> {
+    let upper = line.to_ascii_uppercase();
+    let keep = if upper.starts_with(b"PASS ") || upper.starts_with(b"AUTHENTICATE ") {
+        Some(line.iter().position(|&b| b == b' ').expect("checked above"))
+    } else if upper.starts_with(b"OPER ") {
+        // OPER name password
+        line.iter()
+            .enumerate()
+            .filter(|(_, &b)| b == b' ')
+            .nth(1)
+            .map(|(idx, _)| idx)
+    } else if upper.starts_with(b"PRIVMSG NICKSERV :IDENTIFY ") {
+        Some("PRIVMSG NickServ :IDENTIFY".len())
+    } else {
+        None
+    };
+    match keep {
+        Some(idx) => {
+            let mut ret = line[..idx].to_vec();
+            ret.extend_from_slice(b" <redacted>");
+            ret
+        }
+        None => line.to_vec(),
+    }
+}
+
+pub struct RawLog {
+    path: String,
+    file: File,
+    // the start of a line each network and direction has yet to finish.
+    partial: HashMap<(usize, Direction), Vec<u8>>,
+}
+
+impl RawLog {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RawLog {
+            path: path.to_owned(),
+            file,
+            partial: HashMap::new(),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Note bytes that went by, logging every line they finish.
+    pub fn record(&mut self, network: usize, dir: Direction, bytes: &[u8]) {
+        let partial = self.partial.entry((network, dir)).or_default();
+        partial.extend_from_slice(bytes);
+        let mut out = Vec::new();
+        let stamp = timestamp(SystemTime::now());
+        while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+            let line = partial.drain(..=end).collect::<Vec<u8>>();
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let line = if dir == Direction::Out {
+                redact(line)
+            } else {
+                line.to_vec()
+            };
+            out.extend_from_slice(format!("{} {} {} ", stamp, network, dir.marker()).as_bytes());
+            out.extend_from_slice(&line);
+            out.push(b'\n');
+        }
+        if !out.is_empty() {
+            if let Err(e) = self.file.write_all(&out) {
+                warn!("Could not write to raw log {}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// Reads and writes through to inner, recording what went by to log, if any.
+pub struct Tap<'a, T> {
+    pub inner: &'a mut T,
+    pub log: Option<&'a mut RawLog>,
+    pub network: usize,
+}
+
+impl<T: Read> Read for Tap<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(log) = self.log.as_mut() {
+            log.record(self.network, Direction::In, &buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for Tap<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        if let Some(log) = self.log.as_mut() {
+            log.record(self.network, Direction::Out, &buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len = self.inner.write_vectored(bufs)?;
+        if let Some(log) = self.log.as_mut() {
+            let mut left = len;
+            for buf in bufs {
+                let part = left.min(buf.len());
+                log.record(self.network, Direction::Out, &buf[..part]);
+                left -= part;
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::{Cursor, Read, Write},
+    };
+
+    use super::{redact, RawLog, Tap};
+
+    #[test]
+    fn raw_lines() {
+        assert_eq!(redact(b"PASS hunter2"), b"PASS <redacted>");
+        assert_eq!(redact(b"OPER me hunter2"), b"OPER me <redacted>");
+        assert_eq!(
+            redact(b"PRIVMSG NickServ :IDENTIFY hunter2"),
+            b"PRIVMSG NickServ :IDENTIFY <redacted>"
+        );
+        assert_eq!(redact(b"PRIVMSG #chan :hi"), b"PRIVMSG #chan :hi");
+
+        let path = std::env::temp_dir().join(format!("r8ball-raw-{}.log", std::process::id()));
+        let mut log = RawLog::open(path.to_str().unwrap()).unwrap();
+        let mut server = Cursor::new(b"PING :a\r\nPI".to_vec());
+        let mut buf = [0u8; 64];
+        let mut tap = Tap {
+            inner: &mut server,
+            log: Some(&mut log),
+            network: 1,
+        };
+        assert_eq!(tap.read(&mut buf).unwrap(), 11);
+        tap.write_all(b"PONG :a\r\nPASS x\r\n").unwrap();
+
+        let lines = fs::read_to_string(&path).unwrap();
+        let lines = lines
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect::<Vec<&str>>();
+        // the partial PI waits for the rest of its line.
+        assert_eq!(
+            lines,
+            vec!["1 << PING :a", "1 >> PONG :a", "1 >> PASS <redacted>"]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
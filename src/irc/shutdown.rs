@@ -0,0 +1,96 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::time::{Duration, Instant};
+
+type Hook<T> = Box<dyn FnOnce(&mut T, Instant) -> Result<(), String>>;
+
+/// Closes subsystems in the order they were added, e.g. plugins before the
+/// connection their output goes to, and the connection before storage.
+/// Every hook is handed a deadline and is expected to give up once it passes.
+pub struct Shutdown<T> {
+    hooks: Vec<(&'static str, Hook<T>)>,
+    grace: Duration,
+}
+
+impl<T> Shutdown<T> {
+    /// Each hook gets up to grace to finish.
+    pub fn new(grace: Duration) -> Self {
+        Shutdown {
+            hooks: vec![],
+            grace,
+        }
+    }
+
+    pub fn add<F>(&mut self, name: &'static str, hook: F)
+    where
+        F: FnOnce(&mut T, Instant) -> Result<(), String> + 'static,
+    {
+        self.hooks.push((name, Box::new(hook)));
+    }
+
+    /// Run every hook; a failing hook does not stop the ones after it.
+    /// Returns the names of the subsystems which failed to close cleanly.
+    pub fn run(self, subsystems: &mut T) -> Vec<&'static str> {
+        let mut failed = vec![];
+        for (name, hook) in self.hooks {
+            let deadline = Instant::now() + self.grace;
+            match hook(subsystems, deadline) {
+                Ok(()) if Instant::now() > deadline => {
+                    warn!("Shutting down {} took longer than allowed.", name);
+                }
+                Ok(()) => info!("Shut down {}.", name),
+                Err(e) => {
+                    warn!("Could not shut down {} cleanly: {}", name, e);
+                    failed.push(name);
+                }
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Shutdown;
+
+    #[test]
+    fn ordered_hooks() {
+        let mut shutdown = Shutdown::<Vec<&str>>::new(Duration::from_secs(1));
+        shutdown.add("plugins", |order, deadline| {
+            assert!(deadline > Instant::now());
+            order.push("plugins");
+            Ok(())
+        });
+        shutdown.add("irc", |order, _| {
+            order.push("irc");
+            Err("connection reset".to_owned())
+        });
+        shutdown.add("storage", |order, _| {
+            order.push("storage");
+            Ok(())
+        });
+
+        let mut order = vec![];
+        assert_eq!(shutdown.run(&mut order), vec!["irc"]);
+        assert_eq!(order, vec!["plugins", "irc", "storage"]);
+    }
+}
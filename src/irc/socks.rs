@@ -0,0 +1,248 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! The client side of a SOCKS5 handshake (RFC 1928, RFC 1929 for passwords),
+//! without any IO of its own: write out to_send(), read wants() bytes and feed() them.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SocksError {
+    #[error("Bad proxy target {0}, expected host:port")]
+    Target(String),
+    #[error("Proxy username and password can be at most 255 bytes each")]
+    Credentials,
+    #[error("Proxy does not speak SOCKS5")]
+    Version,
+    #[error("Proxy accepts none of our authentication methods")]
+    NoMethod,
+    #[error("Proxy rejected our username or password")]
+    AuthFailed,
+    #[error("Proxy could not connect: {0}")]
+    Reply(&'static str),
+}
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const PASSWORD: u8 = 2;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Method,
+    Auth,
+    // the reply header, up to the first byte of the bound address.
+    Reply,
+    // the rest of the bound address, which we ignore.
+    Bound(usize),
+    Done,
+}
+
+pub struct Socks5 {
+    state: State,
+    out: Vec<u8>,
+    input: Vec<u8>,
+    auth: Option<(String, String)>,
+    request: Vec<u8>,
+}
+
+/// The CONNECT request for host:port; hosts are sent as names so the proxy
+/// resolves them, as Tor wants.
+fn connect_request(target: &str) -> Result<Vec<u8>, SocksError> {
+    let bad = || SocksError::Target(target.to_owned());
+    let (host, port) = target.rsplit_once(':').ok_or_else(bad)?;
+    let port = port.parse::<u16>().map_err(|_| bad())?;
+    let mut req = vec![VERSION, CONNECT, 0];
+    if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        let ip = v6.parse::<Ipv6Addr>().map_err(|_| bad())?;
+        req.push(ATYP_IPV6);
+        req.extend_from_slice(&ip.octets());
+    } else if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        req.push(ATYP_IPV4);
+        req.extend_from_slice(&ip.octets());
+    } else if !host.is_empty() && host.len() <= 255 {
+        req.push(ATYP_DOMAIN);
+        req.push(host.len() as u8);
+        req.extend_from_slice(host.as_bytes());
+    } else {
+        return Err(bad());
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    Ok(req)
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+impl Socks5 {
+    /// Start a handshake to connect to target, as host:port.
+    pub fn new(target: &str, auth: Option<(String, String)>) -> Result<Self, SocksError> {
+        if let Some((user, pass)) = &auth {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(SocksError::Credentials);
+            }
+        }
+        let out = if auth.is_some() {
+            vec![VERSION, 2, NO_AUTH, PASSWORD]
+        } else {
+            vec![VERSION, 1, NO_AUTH]
+        };
+        Ok(Socks5 {
+            state: State::Method,
+            out,
+            input: Vec::new(),
+            auth,
+            request: connect_request(target)?,
+        })
+    }
+
+    pub fn to_send(&self) -> &[u8] {
+        &self.out
+    }
+
+    pub fn sent(&mut self, len: usize) {
+        self.out.drain(..len);
+    }
+
+    /// How many more bytes to read; never more than the proxy sends before
+    /// the connection is handed over to us.
+    pub fn wants(&self) -> usize {
+        let need = match self.state {
+            State::Method | State::Auth => 2,
+            State::Reply => 5,
+            State::Bound(len) => len,
+            State::Done => 0,
+        };
+        need - self.input.len()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Take in at most wants() bytes of the proxy's reply.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), SocksError> {
+        self.input.extend_from_slice(data);
+        if self.wants() > 0 {
+            return Ok(());
+        }
+        let input = std::mem::take(&mut self.input);
+        self.state = match self.state {
+            State::Method if input[0] != VERSION => return Err(SocksError::Version),
+            State::Method => match (input[1], &self.auth) {
+                (NO_AUTH, _) => self.send_request(),
+                (PASSWORD, Some((user, pass))) => {
+                    self.out.push(1);
+                    self.out.push(user.len() as u8);
+                    self.out.extend_from_slice(user.as_bytes());
+                    self.out.push(pass.len() as u8);
+                    self.out.extend_from_slice(pass.as_bytes());
+                    State::Auth
+                }
+                _ => return Err(SocksError::NoMethod),
+            },
+            State::Auth if input[1] != 0 => return Err(SocksError::AuthFailed),
+            State::Auth => self.send_request(),
+            State::Reply if input[0] != VERSION => return Err(SocksError::Version),
+            State::Reply if input[1] != 0 => return Err(SocksError::Reply(reply_error(input[1]))),
+            // we already have the first byte of the address, the port is left.
+            State::Reply => match input[3] {
+                ATYP_IPV4 => State::Bound(4 - 1 + 2),
+                ATYP_IPV6 => State::Bound(16 - 1 + 2),
+                ATYP_DOMAIN => State::Bound(input[4] as usize + 2),
+                _ => return Err(SocksError::Reply(reply_error(8))),
+            },
+            State::Bound(_) | State::Done => State::Done,
+        };
+        Ok(())
+    }
+
+    fn send_request(&mut self) -> State {
+        self.out.append(&mut self.request);
+        State::Reply
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Socks5, SocksError};
+
+    #[test]
+    fn socks5_handshake() {
+        let mut socks = Socks5::new("irc.example.net:6697", None).unwrap();
+        assert_eq!(socks.to_send(), &[5, 1, 0]);
+        socks.sent(3);
+        assert_eq!(socks.wants(), 2);
+        socks.feed(&[5, 0]).unwrap();
+        let mut req = vec![5, 1, 0, 3, 15];
+        req.extend_from_slice(b"irc.example.net");
+        req.extend_from_slice(&[0x1a, 0x29]);
+        assert_eq!(socks.to_send(), &req[..]);
+        socks.sent(req.len());
+
+        // bound to an IPv4 address, fed in pieces.
+        socks.feed(&[5, 0, 0]).unwrap();
+        assert_eq!(socks.wants(), 2);
+        socks.feed(&[1, 127]).unwrap();
+        assert_eq!(socks.wants(), 5);
+        assert!(!socks.is_done());
+        socks.feed(&[0, 0, 1, 0, 80]).unwrap();
+        assert!(socks.is_done());
+        assert_eq!(socks.wants(), 0);
+    }
+
+    #[test]
+    fn socks5_password() {
+        let auth = Some(("me".to_owned(), "pw".to_owned()));
+        let mut socks = Socks5::new("[::1]:6667", auth.clone()).unwrap();
+        assert_eq!(socks.to_send(), &[5, 2, 0, 2]);
+        socks.sent(4);
+        socks.feed(&[5, 2]).unwrap();
+        assert_eq!(socks.to_send(), &[1, 2, b'm', b'e', 2, b'p', b'w']);
+        socks.sent(7);
+        socks.feed(&[1, 0]).unwrap();
+        let mut req = vec![5, 1, 0, 4];
+        req.extend_from_slice(&[0; 15]);
+        req.extend_from_slice(&[1, 0x1a, 0x0b]);
+        assert_eq!(socks.to_send(), &req[..]);
+        socks.sent(req.len());
+        socks.feed(&[5, 4, 0, 1, 0]).unwrap_err();
+
+        let mut socks = Socks5::new("10.0.0.1:6667", auth).unwrap();
+        socks.feed(&[5, 2]).unwrap();
+        assert_eq!(socks.feed(&[1, 1]), Err(SocksError::AuthFailed));
+
+        let mut socks = Socks5::new("10.0.0.1:6667", None).unwrap();
+        assert_eq!(socks.feed(&[5, 2]), Err(SocksError::NoMethod));
+        assert!(Socks5::new("no port", None).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! TLS, with rustls, over the non-blocking connection to the server.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use mio::net::TcpStream;
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::config::config_file::General;
+
+/// The connection to the server, encrypted once start_tls() is called.
+/// Any non-blocking socket will do for tcp, mio's is the one the event loop uses.
+pub struct Conn<S = TcpStream> {
+    pub tcp: S,
+    tls: Option<ClientConnection>,
+}
+
+impl<S> Conn<S> {
+    pub fn new(tcp: S) -> Self {
+        Conn { tcp, tls: None }
+    }
+
+    /// Swap the socket for another handle to it, keeping the TLS session.
+    pub fn try_map<T>(self, f: impl FnOnce(S) -> io::Result<T>) -> io::Result<Conn<T>> {
+        Ok(Conn {
+            tcp: f(self.tcp)?,
+            tls: self.tls,
+        })
+    }
+
+    /// Start the handshake with target, as host:port; it goes out with the first write.
+    pub fn start_tls(&mut self, config: Arc<ClientConfig>, target: &str) -> io::Result<()> {
+        let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .to_owned();
+        self.tls = Some(ClientConnection::new(config, name).map_err(io::Error::other)?);
+        Ok(())
+    }
+
+    /// If TLS has records waiting for the socket to be writable.
+    pub fn wants_write(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+}
+
+/// Send what TLS records we can, WouldBlock if some are left.
+fn send_tls<S: Write>(tls: &mut ClientConnection, tcp: &mut S) -> io::Result<()> {
+    while tls.wants_write() {
+        tls.write_tls(tcp)?;
+    }
+    Ok(())
+}
+
+/// Like send_tls(), for when we try again once the socket is writable.
+fn try_send_tls<S: Write>(tls: &mut ClientConnection, tcp: &mut S) -> io::Result<()> {
+    match send_tls(tls, tcp) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        res => res,
+    }
+}
+
+impl<S: Read + Write> Read for Conn<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (tls, tcp) = match &mut self.tls {
+            Some(tls) => (tls, &mut self.tcp),
+            None => return self.tcp.read(buf),
+        };
+        loop {
+            match tls.reader().read(buf) {
+                Ok(len) => return Ok(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                // servers rarely bother with a close_notify.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+            if tls.read_tls(tcp)? == 0 {
+                return Ok(0);
+            }
+            let processed = tls.process_new_packets();
+            // the handshake, or the alert about why it failed.
+            try_send_tls(tls, tcp)?;
+            processed.map_err(io::Error::other)?;
+        }
+    }
+}
+
+impl<S: Read + Write> Write for Conn<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tls = match &mut self.tls {
+            Some(tls) => tls,
+            None => return self.tcp.write(buf),
+        };
+        let len = tls.writer().write(buf)?;
+        try_send_tls(tls, &mut self.tcp)?;
+        // rustls buffers a limited amount until the socket takes it.
+        if len == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(len)
+    }
+
+    /// Send what TLS still holds on to, WouldBlock if the socket won't take it all.
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(tls) = &mut self.tls {
+            send_tls(tls, &mut self.tcp)?;
+        }
+        self.tcp.flush()
+    }
+}
+
+/// The SHA-256 of a certificate, as SHA256:base64, the form tls_fingerprint takes.
+pub fn fingerprint(cert: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(digest(&SHA256, cert)))
+}
+
+/// SHA256:base64, or the hash in hex, with or without colons between bytes.
+fn parse_fingerprint(fp: &str) -> Option<Vec<u8>> {
+    let fp = match fp.get(..7) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("SHA256:") => &fp[7..],
+        _ => fp,
+    };
+    let hex = fp.replace(':', "");
+    let hash = if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?
+    } else {
+        STANDARD_NO_PAD.decode(fp.trim_end_matches('=')).ok()?
+    };
+    if hash.len() == 32 {
+        Some(hash)
+    } else {
+        None
+    }
+}
+
+/// Checks the server's certificate against the pinned fingerprint, else against
+/// the web PKI unless tls_verify is off.
+#[derive(Debug)]
+struct Verifier {
+    pin: Option<Vec<u8>>,
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    algs: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(pin) = &self.pin {
+            return if digest(&SHA256, end_entity).as_ref() == &pin[..] {
+                Ok(ServerCertVerified::assertion())
+            } else {
+                Err(rustls::Error::General(format!(
+                    "Certificate {} does not match tls_fingerprint",
+                    fingerprint(end_entity)
+                )))
+            };
+        }
+        match &self.webpki {
+            Some(webpki) => webpki
+                .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+                .inspect_err(|_| {
+                    warn!(
+                        "Certificate {} is not trusted, set tls_fingerprint to it if you do.",
+                        fingerprint(end_entity)
+                    )
+                }),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algs.supported_schemes()
+    }
+}
+
+fn pem_error(path: &str, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Could not load {}: {}", path, e),
+    )
+}
+
+/// What client_config reads from General, to tell when it needs making again.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    verify: bool,
+    fingerprint: String,
+    client_cert: String,
+    client_key: String,
+}
+
+impl From<&General> for Settings {
+    fn from(general: &General) -> Self {
+        Settings {
+            verify: general.tls_verify,
+            fingerprint: general.tls_fingerprint.clone(),
+            client_cert: general.tls_client_cert.clone(),
+            client_key: general.tls_client_key.clone(),
+        }
+    }
+}
+
+/// The TLS settings for a network, see the tls_* settings.
+pub fn client_config(general: &General) -> Result<Arc<ClientConfig>, io::Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let pin = if general.tls_fingerprint.is_empty() {
+        None
+    } else {
+        Some(parse_fingerprint(&general.tls_fingerprint).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Bad tls_fingerprint {}, expected SHA256: and the hash in base64 or hex",
+                    general.tls_fingerprint
+                ),
+            )
+        })?)
+    };
+    let webpki = if pin.is_none() && general.tls_verify {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        Some(
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .map_err(io::Error::other)?,
+        )
+    } else {
+        None
+    };
+    if pin.is_none() && webpki.is_none() {
+        warn!("tls_verify is off, anyone in the way can pretend to be the server.");
+    }
+    let verifier = Verifier {
+        pin,
+        webpki,
+        algs: provider.signature_verification_algorithms,
+    };
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    if general.tls_client_cert.is_empty() {
+        return Ok(Arc::new(builder.with_no_client_auth()));
+    }
+    let cert_path = &general.tls_client_cert;
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| pem_error(cert_path, e))?;
+    let key_path = if general.tls_client_key.is_empty() {
+        cert_path
+    } else {
+        &general.tls_client_key
+    };
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+    let config = builder
+        .with_client_auth_cert(certs, key)
+        .map_err(io::Error::other)?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::config_file::Config;
+
+    use super::{client_config, fingerprint, parse_fingerprint};
+
+    #[test]
+    fn client_certs() {
+        let conf = |settings: &str| {
+            format!("[general]\nnick = \"bot\"\n{}", settings)
+                .parse::<Config>()
+                .unwrap()
+                .general
+        };
+        let config = client_config(&conf("")).unwrap();
+        assert!(!config.client_auth_cert_resolver.has_certs());
+
+        // the key is in the same file.
+        let pem = "tls_client_cert = \"src/irc/testdata/localhost.pem\"";
+        let config = client_config(&conf(pem)).unwrap();
+        assert!(config.client_auth_cert_resolver.has_certs());
+        let key = "tls_client_key = \"src/irc/testdata/missing.key\"";
+        assert!(client_config(&conf(&format!("{}\n{}", pem, key))).is_err());
+        assert!(client_config(&conf("tls_fingerprint = \"SHA256:bad\"")).is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        let cert = include_bytes!("testdata/localhost.der");
+        let hex = "98:4C:96:9A:5F:CA:7E:4F:0B:B5:E6:0E:BC:3A:4F:5C:A2:E1:13:45:2C:C7:C9:57:E3:7E:AB:90:C8:EE:BA:BB";
+        let hash = parse_fingerprint(hex).unwrap();
+        assert_eq!(
+            parse_fingerprint(&format!("sha256:{}", hex)),
+            Some(hash.clone())
+        );
+        assert_eq!(
+            parse_fingerprint(&hex.replace(':', "").to_lowercase()),
+            Some(hash.clone())
+        );
+        // what we print is what we take.
+        assert_eq!(parse_fingerprint(&fingerprint(cert)), Some(hash.clone()));
+        assert_eq!(
+            parse_fingerprint(&format!("{}=", fingerprint(cert))),
+            Some(hash)
+        );
+        assert_eq!(parse_fingerprint("SHA256:abcd"), None);
+        assert_eq!(parse_fingerprint("nope"), None);
+    }
+}
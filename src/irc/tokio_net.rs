@@ -0,0 +1,573 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! The bot on a tokio runtime, for apps that already run one, see [`run()`].
+//!
+//! Every network is a local task of its own, waiting at once on the connection, on
+//! the timers of its [`Client`] and on its plugins. What comes in goes to the same
+//! Client the mio event loop in [`super::net`] drives, so the two behave alike.
+//! Plugins and plugin workers still signal through mio; its poller is just one
+//! more file descriptor the task waits on. Plugin processes are reaped by [`run()`].
+//!
+//! Address lookups and closing a network block, so they run on tokio's blocking
+//! threads. A network that can't connect backs off and tries again on its own,
+//! the others carry on.
+//!
+//! Only the networks are run. Signals, the control socket, raw_log, max_uptime
+//! and dropping privileges belong to the process, and are left to the app.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, Token, Waker};
+use tokio::io::{unix::AsyncFd, Interest};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::{self, JoinSet, LocalSet};
+use tokio::time;
+
+use crate::{config::config_file::Config, logging, storage::Storage, MainError};
+
+use super::children;
+use super::client::{Client, ClientReadStat, ClientWriteStat, Sts};
+use super::net::{shutdown_plan, start_plugins, Servers, Subsystems};
+use super::plugin::Plugin;
+use super::socks::Socks5;
+use super::tls::Conn;
+use super::workers::WorkerPool;
+
+// woken when work done off the task is ready, plugins get the tokens after it.
+const WAKER_TOKEN: Token = Token(0);
+const EVENTS: usize = 128;
+
+/// tokio's socket as a [`Conn`] wants it, failing with WouldBlock instead of waiting.
+pub struct TryIo(pub TcpStream);
+
+impl Read for TryIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.try_read(buf)
+    }
+}
+
+impl Write for TryIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.try_write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run every network in config until stop completes, or every network gave up.
+/// The networks quit, with up to shutdown_grace_ms for their plugins and last lines.
+/// Fails with why the last network gave up, if none is left.
+///
+/// The networks are local tasks, so this is not Send: block_on() it, or spawn_local() it.
+pub async fn run(config: Config, stop: impl Future<Output = ()>) -> Result<(), MainError> {
+    logging::set_format(config.general.log_format);
+    LocalSet::new().run_until(run_local(config, stop)).await
+}
+
+async fn run_local(config: Config, stop: impl Future<Output = ()>) -> Result<(), MainError> {
+    let (quit, quitting) = watch::channel(false);
+    let mut tasks = JoinSet::new();
+    for net in config.networks() {
+        tasks.spawn_local(network(net, quitting.clone()));
+    }
+    // one for every network, as plugins are children of the process. Through tokio's
+    // own SIGCHLD handling, so tokio::process and the host's handlers keep working.
+    let mut exited = signal(SignalKind::child())?;
+    tokio::pin!(stop);
+    let mut stopped = false;
+    loop {
+        let deadline = children::next_deadline();
+        let kill_at = time::Instant::from_std(deadline.unwrap_or_else(Instant::now));
+        tokio::select! {
+            _ = &mut stop, if !stopped => {
+                stopped = true;
+                let _ = quit.send(true);
+            }
+            _ = exited.recv() => {
+                children::reap(Instant::now());
+            }
+            // plugins past their timeout.
+            _ = time::sleep_until(kill_at), if deadline.is_some() => {
+                children::reap(Instant::now());
+            }
+            done = tasks.join_next() => match done {
+                Some(Ok(Ok(()))) => (),
+                Some(Ok(Err(e))) if tasks.is_empty() => return Err(e),
+                Some(Ok(Err(e))) => warn!("Giving up on a network: {:?}", e),
+                Some(Err(e)) => return Err(io::Error::other(e).into()),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// One network, reconnecting after the server hangs up or none of its servers took
+/// us, until we are told to quit. Fails once there is no other server to try.
+async fn network(config: Config, mut quit: watch::Receiver<bool>) -> Result<(), MainError> {
+    let grace = Duration::from_millis(config.general.shutdown_grace_ms);
+    let mut servers = Servers::new(&config);
+    loop {
+        let opened = tokio::select! {
+            opened = Session::open(&config, servers) => opened,
+            _ = quit.changed() => return Ok(()),
+        };
+        let mut session = match opened {
+            Ok(session) => session,
+            Err((e, failed)) => {
+                servers = failed;
+                let delay = match servers.reconnect(false) {
+                    Some(delay) => delay,
+                    None => return Err(e),
+                };
+                warn!("Could not connect: {:?}, trying again in {:?}.", e, delay);
+                tokio::select! {
+                    _ = time::sleep(delay) => continue,
+                    _ = quit.changed() => return Ok(()),
+                }
+            }
+        };
+        let quitting = match session.run(&mut quit).await {
+            Ok(quitting) => quitting,
+            Err(MainError::EvIo(e)) if session.servers.rotates() => {
+                warn!("Lost the connection: {}", e);
+                false
+            }
+            Err(e) => return Err(e),
+        };
+        let registered = session.client.is_registered();
+        servers = session.close(grace, "Shutting down").await?;
+        let delay = match servers.reconnect(registered) {
+            Some(delay) if !quitting => delay,
+            _ => return Ok(()),
+        };
+        if !delay.is_zero() {
+            info!("Reconnecting in {:?}.", delay);
+            tokio::select! {
+                _ = time::sleep(delay) => (),
+                _ = quit.changed() => return Ok(()),
+            }
+        }
+        info!("Reconnecting.");
+    }
+}
+
+/// Connect to the next server that takes us, through the proxy if there is one.
+/// Unlike the event loop, this tries one address at a time.
+async fn connect(
+    servers: &mut Servers,
+    storage: &Storage,
+    timeout: Duration,
+) -> Result<TcpStream, MainError> {
+    let mut failed = io::Error::new(io::ErrorKind::NotFound, "No server to connect to");
+    loop {
+        let (addr, tcp) = match servers.race() {
+            Some(attempt) => attempt,
+            None => {
+                let lookup = match servers.next_server(storage) {
+                    Some(lookup) => lookup,
+                    None => return Err(failed.into()),
+                };
+                // it blocks for up to dns_timeout.
+                let resolver = servers.resolver();
+                let addrs = task::spawn_blocking(move || resolver(lookup))
+                    .await
+                    .map_err(io::Error::other)?;
+                if let Err(e) = servers.looked_up(addrs) {
+                    failed = e;
+                }
+                continue;
+            }
+        };
+        // SAFETY: mio gives up the descriptor, so it has the one owner.
+        let tcp = unsafe { std::net::TcpStream::from_raw_fd(tcp.into_raw_fd()) };
+        let tcp = TcpStream::from_std(tcp)?;
+        let attempt = async {
+            tcp.writable().await?;
+            if let Some(e) = tcp.take_error()? {
+                return Err(e);
+            }
+            tcp.peer_addr()?;
+            match servers.socks() {
+                Some(socks) => handshake(&tcp, socks.map_err(io::Error::other)?).await,
+                None => Ok(()),
+            }
+        };
+        // connect_timeout covers the handshake too.
+        failed = match time::timeout(timeout, attempt).await {
+            Ok(Ok(())) => {
+                servers.connected();
+                return Ok(tcp);
+            }
+            Ok(Err(e)) => e,
+            Err(_) => io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting"),
+        };
+        warn!("Could not connect to {}: {}", addr, failed);
+    }
+}
+
+/// Talk to the proxy until it connected us to the server.
+async fn handshake(tcp: &TcpStream, mut socks: Socks5) -> io::Result<()> {
+    // the longest reply is the rest of a bound domain name and port.
+    let mut buf = [0u8; 257];
+    loop {
+        while !socks.to_send().is_empty() {
+            tcp.writable().await?;
+            match tcp.try_write(socks.to_send()) {
+                Ok(len) => socks.sent(len),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if socks.is_done() {
+            return Ok(());
+        }
+        tcp.readable().await?;
+        // only read the reply, what follows is for the client.
+        let want = socks.wants();
+        match tcp.try_read(&mut buf[..want]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Proxy closed the connection",
+                ))
+            }
+            Ok(len) => socks.feed(&buf[..len]).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A connection to one network, from connecting until it is closed.
+struct Session {
+    conn: Conn<TryIo>,
+    client: Client,
+    servers: Servers,
+    // plugins and workers wake it.
+    poll: AsyncFd<Poll>,
+    plugins: HashMap<Token, Plugin>,
+    next_plugin_token: usize,
+    // the client has lines to send.
+    want_write: bool,
+}
+
+impl Session {
+    /// Connect to the network, or fail with why and the servers, to try again later.
+    async fn open(config: &Config, mut servers: Servers) -> Result<Self, (MainError, Servers)> {
+        match Session::start(config, &mut servers).await {
+            Ok((conn, client, poll)) => Ok(Session {
+                conn,
+                client,
+                servers,
+                poll,
+                plugins: HashMap::new(),
+                next_plugin_token: WAKER_TOKEN.0 + 1,
+                // the greeting.
+                want_write: true,
+            }),
+            Err(e) => Err((e, servers)),
+        }
+    }
+
+    async fn start(
+        config: &Config,
+        servers: &mut Servers,
+    ) -> Result<(Conn<TryIo>, Client, AsyncFd<Poll>), MainError> {
+        let general = &config.general;
+        let storage = Storage::from_config(&config.storage)?;
+        if general.tls || !general.tls_client_cert.is_empty() {
+            servers.tls_config(general)?;
+        }
+        let timeout = Duration::from_secs(general.connect_timeout);
+        let mut conn = Conn::new(TryIo(connect(servers, &storage, timeout).await?));
+        let mut client = Client::new(config, storage);
+        client.resume_stats(servers.started, servers.reconnects);
+        if general.tls || servers.secure {
+            let tls = servers.tls_config(general)?;
+            conn.start_tls(tls, &servers.target)?;
+            client.set_secure();
+        }
+
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        client.set_waker(waker.clone());
+        if general.plugin_workers > 0 {
+            client.use_workers(WorkerPool::new(general.plugin_workers, waker));
+        }
+        let poll = AsyncFd::with_interest(poll, Interest::READABLE)?;
+        Ok((conn, client, poll))
+    }
+
+    /// Returns Ok(true) once told to quit, Ok(false) when the server hung up.
+    async fn run(&mut self, quit: &mut watch::Receiver<bool>) -> Result<bool, MainError> {
+        let mut events = Events::with_capacity(EVENTS);
+        loop {
+            self.tick()?;
+            if self.client.timed_out(Instant::now()) {
+                warn!("Lost the connection: the server stopped answering PINGs");
+                return Ok(false);
+            }
+            let interest = if self.want_write || self.conn.wants_write() {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            let deadline = self.client.next_deadline();
+            let wake_at = time::Instant::from_std(deadline.unwrap_or_else(Instant::now));
+            tokio::select! {
+                ready = self.conn.tcp.0.ready(interest) => {
+                    let ready = ready?;
+                    if (ready.is_readable() || ready.is_read_closed()) && !self.read()? {
+                        return Ok(false);
+                    }
+                    if ready.is_writable() {
+                        self.write()?;
+                    }
+                }
+                guard = self.poll.readable_mut() => {
+                    let mut guard = guard?;
+                    guard.get_inner_mut().poll(&mut events, Some(Duration::ZERO))?;
+                    // more may be waiting if we could not take them all.
+                    if events.iter().count() < EVENTS {
+                        guard.clear_ready();
+                    }
+                    drop(guard);
+                    self.handle_events(&events)?;
+                }
+                _ = time::sleep_until(wake_at), if deadline.is_some() => (),
+                _ = quit.changed() => return Ok(true),
+            }
+        }
+    }
+
+    /// Everything the server sent so far. Returns false once it hung up.
+    fn read(&mut self) -> Result<bool, MainError> {
+        loop {
+            match self.client.receive_data(&mut self.conn)? {
+                ClientReadStat::HasWritableData => {
+                    self.want_write = true;
+                    break;
+                }
+                ClientReadStat::Blocked => break,
+                ClientReadStat::Okay => (),
+                ClientReadStat::Eof => return Ok(false),
+                ClientReadStat::Error(err) => return Err(MainError::IrcProto(err)),
+            }
+        }
+        match self.client.take_sts() {
+            Some(Sts::Upgrade(port)) => {
+                info!("The server wants TLS on port {}, reconnecting.", port);
+                self.servers.upgrade(port);
+                return Ok(false);
+            }
+            Some(Sts::Persist(duration)) => {
+                self.servers.persist_sts(self.client.storage(), duration)
+            }
+            None => (),
+        }
+        Ok(true)
+    }
+
+    fn write(&mut self) -> Result<(), MainError> {
+        loop {
+            match self.client.write_data(&mut self.conn)? {
+                ClientWriteStat::Blocked => return Ok(()),
+                ClientWriteStat::Okay => (),
+                ClientWriteStat::Eof => break,
+            }
+        }
+        // TLS may still hold records for the socket.
+        match self.conn.flush() {
+            Ok(()) => self.want_write = false,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    fn handle_events(&mut self, events: &Events) -> Result<(), MainError> {
+        for event in events {
+            let tok = event.token();
+            if tok == WAKER_TOKEN {
+                // deferred plugin replies are picked up by tick().
+                if self.client.process_workers(false) {
+                    self.want_write = true;
+                }
+                continue;
+            }
+            // left over from a plugin that already closed.
+            let plug = match self.plugins.get_mut(&tok) {
+                Some(plug) => plug,
+                None => continue,
+            };
+            if self.client.process_plugin(plug)? {
+                self.want_write = true;
+            }
+            if plug.is_closed() {
+                let plug = self.plugins.remove(&tok).expect("Cannot remove plugin!");
+                self.client.plugin_done(&plug);
+            } else if plug.backlogged() {
+                // the rest of its stderr, on a later wakeup.
+                self.poll
+                    .get_ref()
+                    .registry()
+                    .reregister(plug, tok, mio::Interest::READABLE)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Release timed output and register any plugins the client started.
+    fn tick(&mut self) -> io::Result<()> {
+        if self.client.tick(Instant::now()) {
+            self.want_write = true;
+        }
+        for mut plug in start_plugins(&mut self.client) {
+            let tok = Token(self.next_plugin_token);
+            self.next_plugin_token += 1;
+            self.poll
+                .get_ref()
+                .registry()
+                .register(&mut plug, tok, mio::Interest::READABLE)?;
+            self.plugins.insert(tok, plug);
+        }
+        Ok(())
+    }
+
+    /// Quit, giving plugins and the last lines time to finish. Like in the event loop,
+    /// this blocks, for up to grace a step, on sockets taken back from tokio, so it
+    /// runs on a blocking thread. Returns the servers, to reconnect with.
+    async fn close(self, grace: Duration, reason: &'static str) -> Result<Servers, MainError> {
+        let mut subsystems = Subsystems {
+            conn: self.conn.try_map(|tcp| tcp.0.into_std())?,
+            client: self.client,
+            plugins: self.plugins.into_values().collect(),
+        };
+        task::spawn_blocking(move || shutdown_plan(grace, reason).run(&mut subsystems))
+            .await
+            .map_err(io::Error::other)?;
+        Ok(self.servers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        str::FromStr,
+        thread::spawn,
+    };
+
+    use tokio::sync::oneshot;
+
+    use super::run;
+    use crate::config::config_file::Config;
+
+    #[tokio::test]
+    async fn tokio_runtime() {
+        let conf = Config::from_str(
+            r##"
+[general]
+nick = "bot"
+server = "localhost"
+port = 9670
+tls = false
+"##,
+        )
+        .unwrap();
+        let serv = TcpListener::bind(&conf.connect_strings()[0]).unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let j = spawn(move || {
+            let (mut stream, _) = serv.accept().unwrap();
+            let mut b = [0u8; 512];
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(
+                &b[0..len],
+                b"CAP LS 302\r\nNICK bot\r\nUSER bot 0 * :bot\r\n"
+            );
+            stream.write_all(b"PING :xyz\r\n").unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"PONG :xyz\r\n");
+            stop.send(()).unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"QUIT :Shutting down\r\n");
+        });
+
+        run(conf, async {
+            let _ = stopped.await;
+        })
+        .await
+        .unwrap();
+        j.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tokio_network_fails_alone() {
+        // nothing listens on the second network.
+        let conf = Config::from_str(
+            r##"
+[general]
+nick = "bot"
+server = "127.0.0.1"
+port = 9671
+tls = false
+
+[[network]]
+nick = "bot2"
+server = "127.0.0.1"
+port = 9672
+"##,
+        )
+        .unwrap();
+        let serv = TcpListener::bind("127.0.0.1:9671").unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let j = spawn(move || {
+            let (mut stream, _) = serv.accept().unwrap();
+            let mut b = [0u8; 512];
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(
+                &b[0..len],
+                b"CAP LS 302\r\nNICK bot\r\nUSER bot 0 * :bot\r\n"
+            );
+            // still here once the other one gave up.
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            stream.write_all(b"PING :xyz\r\n").unwrap();
+            let len = stream.read(&mut b).unwrap();
+            assert_eq!(&b[0..len], b"PONG :xyz\r\n");
+            stop.send(()).unwrap();
+        });
+
+        run(conf, async {
+            let _ = stopped.await;
+        })
+        .await
+        .unwrap();
+        j.join().unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use mio::Waker;
+
+use super::client::output::{parse_output, Action, Route};
+
+type Job = (String, Option<Route>, Vec<u8>);
+
+struct Worker {
+    jobs: Sender<Job>,
+    handle: JoinHandle<()>,
+}
+
+/// Parses plugin output on worker threads so a burst of verbose plugins doesn't
+/// stall the event loop. Output from the same plugin always goes to the same worker,
+/// so its lines stay in order. The event loop is woken when results are ready.
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+    results: Receiver<(String, Vec<Action>)>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize, waker: Arc<Waker>) -> Self {
+        let (send_result, results) = mpsc::channel();
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let (jobs, recv_job) = mpsc::channel::<Job>();
+                let send_result = send_result.clone();
+                let waker = waker.clone();
+                let handle = thread::spawn(move || {
+                    for (source, route, chunk) in recv_job {
+                        let actions = parse_output(&chunk, route.as_ref());
+                        if send_result.send((source, actions)).is_err() {
+                            break;
+                        }
+                        if let Err(e) = waker.wake() {
+                            warn!("Could not wake the event loop: {}", e);
+                        }
+                    }
+                });
+                Worker { jobs, handle }
+            })
+            .collect();
+        WorkerPool { workers, results }
+    }
+
+    /// Queue complete lines of output from the plugin identified by shard.
+    /// Results are labeled with source; route is where :reply lines go.
+    pub fn submit(&self, shard: usize, source: &str, route: Option<Route>, chunk: Vec<u8>) {
+        let worker = &self.workers[shard % self.workers.len()];
+        if worker.jobs.send((source.to_owned(), route, chunk)).is_err() {
+            warn!("A plugin output worker exited, output was lost.");
+        }
+    }
+
+    /// Results which are ready, without blocking.
+    pub fn ready(&self) -> Vec<(String, Vec<Action>)> {
+        self.results.try_iter().collect()
+    }
+
+    /// Wait for the queued jobs to finish and return their results.
+    pub fn finish(self) -> Vec<(String, Vec<Action>)> {
+        for worker in self.workers {
+            drop(worker.jobs);
+            if worker.handle.join().is_err() {
+                warn!("A plugin output worker panicked.");
+            }
+        }
+        self.results.try_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use mio::{Events, Poll, Token, Waker};
+
+    use crate::irc::client::output::Action;
+
+    use super::WorkerPool;
+
+    #[test]
+    fn wake_with_results() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(1);
+        let waker = Arc::new(Waker::new(poll.registry(), Token(3)).unwrap());
+        let pool = WorkerPool::new(2, waker);
+
+        pool.submit(
+            1,
+            "test",
+            None,
+            b"PRIVMSG #chan :one\nPRIVMSG #chan :two\n".to_vec(),
+        );
+        poll.poll(&mut events, Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), Token(3));
+
+        pool.submit(1, "test", None, b"PRIVMSG #chan :three\n".to_vec());
+        let mut results = pool.ready();
+        results.extend(pool.finish());
+        assert_eq!(
+            results
+                .into_iter()
+                .flat_map(|(_, actions)| actions)
+                .collect::<Vec<Action>>(),
+            vec![
+                Action::Send(b"PRIVMSG #chan :one".to_vec()),
+                Action::Send(b"PRIVMSG #chan :two".to_vec()),
+                Action::Send(b"PRIVMSG #chan :three".to_vec()),
+            ]
+        );
+    }
+}
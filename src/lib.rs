@@ -0,0 +1,83 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! r8ball, an IRC bot which is mostly the plugins it runs. The binary is a thin
+//! consumer of this library; the parts meant for other projects are:
+//!
+//! - [`irc::iter::BufIterator`] splits a read buffer into lines without copying,
+//!   telling a [`irc::iter::TruncStatus::Full`] line from a partial one at the end.
+//! - [`irc::parse::Message`] parses a line into zero-copy slices of it.
+//! - [`irc::client::Client`] is the protocol state of one network: it turns
+//!   messages into the lines to send, with no I/O of its own.
+//! - [`irc::plugin`] runs the external plugins, see its docs for the protocol.
+//! - `irc::tokio_net::run()`, with the `tokio` feature, runs the bot on a tokio
+//!   runtime instead of its own mio event loop.
+//!
+//! ```
+//! use r8ball::irc::{iter::{BufIterator, TruncStatus}, parse::Message};
+//!
+//! let read = b":nick!user@host PRIVMSG #chan :hello\r\nPING :par";
+//! let mut lines = BufIterator::new(read);
+//! match lines.next() {
+//!     Some(TruncStatus::Full(line)) => {
+//!         let msg = Message::new(line);
+//!         assert_eq!(msg.command, Some(&b"PRIVMSG"[..]));
+//!         assert_eq!(msg.nick, Some(&b"nick"[..]));
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! // the rest waits for the next read.
+//! assert!(matches!(lines.next(), Some(TruncStatus::Part(b"PING :par"))));
+//! ```
+
+#[macro_use]
+pub mod logging;
+
+pub mod config;
+pub mod daemon;
+pub mod irc;
+pub mod storage;
+
+use std::io;
+
+use config::{cmdline::ParsedArgsError, config_file::ConfigError};
+use storage::StorageError;
+
+/// Our version, git commit, target and features, e.g. for r8ball version and CTCP VERSION.
+pub const VERSION: &str = concat!(
+    "r8ball v",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("R8BALL_BUILD"),
+    ")"
+);
+
+#[derive(thiserror::Error, Debug)]
+pub enum MainError {
+    #[error("")]
+    Cmdline(#[from] ParsedArgsError),
+    #[error("")]
+    Config(#[from] ConfigError),
+    #[error("")]
+    EvIo(#[from] io::Error),
+    #[error("{0}")]
+    Storage(#[from] StorageError),
+    #[error("ERROR: {0}")]
+    IrcProto(String),
+}
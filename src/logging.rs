@@ -0,0 +1,232 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+//! Log records, as "LEVEL: message" lines or, with log_format = "json", one JSON
+//! object per line with level, time, module and message, and channel and nick where
+//! they apply. Use the debug!, info!, warn!, plugin! and event! macros.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::config_file::LogFormat, irc::client::schedule::civil_from_days};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    // what a plugin wrote to stderr.
+    Plugin,
+    // something happened in a channel, see General::log_events.
+    Event,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Plugin => "plugin",
+            Level::Event => "event",
+        }
+    }
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Quoted and escaped as a JSON string.
+fn json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for chr in value.chars() {
+        match chr {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            chr if (chr as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", chr as u32);
+            }
+            chr => out.push(chr),
+        }
+    }
+    out.push('"');
+}
+
+/// RFC 3339 in UTC, to the millisecond.
+pub fn timestamp(now: SystemTime) -> String {
+    let since = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+fn format_json(
+    level: Level,
+    time: SystemTime,
+    module: &str,
+    channel: Option<&str>,
+    nick: Option<&str>,
+    message: &str,
+) -> String {
+    let mut out = String::from("{\"level\":");
+    json_str(&mut out, level.name());
+    out.push_str(",\"time\":");
+    json_str(&mut out, &timestamp(time));
+    out.push_str(",\"module\":");
+    json_str(&mut out, module);
+    if let Some(channel) = channel {
+        out.push_str(",\"channel\":");
+        json_str(&mut out, channel);
+    }
+    if let Some(nick) = nick {
+        out.push_str(",\"nick\":");
+        json_str(&mut out, nick);
+    }
+    out.push_str(",\"message\":");
+    json_str(&mut out, message);
+    out.push('}');
+    out
+}
+
+/// Write a log record, see the macros.
+pub fn record(
+    level: Level,
+    module: &str,
+    channel: Option<&str>,
+    nick: Option<&str>,
+    message: fmt::Arguments,
+) {
+    let line = if JSON.load(Ordering::Relaxed) {
+        format_json(
+            level,
+            SystemTime::now(),
+            module,
+            channel,
+            nick,
+            &message.to_string(),
+        )
+    } else {
+        format!("{}: {}", level.name().to_uppercase(), message)
+    };
+    // like println!, but a closed stdout is no reason to panic.
+    let _ = writeln!(io::stdout().lock(), "{}", line);
+}
+
+macro_rules! log_record {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::logging::record(
+            $crate::logging::Level::$level,
+            module_path!(),
+            None,
+            None,
+            format_args!($($arg)+),
+        )
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log_record!(Debug, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log_record!(Info, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log_record!(Warn, $($arg)+) };
+}
+
+/// A line a plugin wrote to stderr, while answering in channel.
+macro_rules! plugin {
+    ($channel:expr, $($arg:tt)+) => {
+        $crate::logging::record(
+            $crate::logging::Level::Plugin,
+            module_path!(),
+            Some($channel),
+            None,
+            format_args!($($arg)+),
+        )
+    };
+}
+
+/// Something nick did in channel.
+macro_rules! event {
+    ($channel:expr, $nick:expr, $($arg:tt)+) => {
+        $crate::logging::record(
+            $crate::logging::Level::Event,
+            module_path!(),
+            Some($channel),
+            Some($nick),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{format_json, timestamp, Level};
+
+    #[test]
+    fn json_records() {
+        let time = UNIX_EPOCH + Duration::from_millis(1622548800123);
+        assert_eq!(timestamp(time), "2021-06-01T12:00:00.123Z");
+        assert_eq!(
+            format_json(
+                Level::Warn,
+                time,
+                "r8ball::irc::net",
+                None,
+                None,
+                "bad \"thing\"\n"
+            ),
+            r##"{"level":"warn","time":"2021-06-01T12:00:00.123Z","module":"r8ball::irc::net","message":"bad \"thing\"\n"}"##
+        );
+        assert_eq!(
+            format_json(
+                Level::Event,
+                time,
+                "m",
+                Some("#chan"),
+                Some("bob"),
+                "<bob> \x01hi"
+            ),
+            r##"{"level":"event","time":"2021-06-01T12:00:00.123Z","module":"m","channel":"#chan","nick":"bob","message":"<bob> \u0001hi"}"##
+        );
+    }
+}
@@ -17,32 +17,99 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-mod config;
-mod irc;
-
-use std::io;
 use std::path::Path;
+use std::process;
+
+use r8ball::config::cmdline::{ParsedArgs, ParsedArgsError, Subcommand};
+use r8ball::config::config_file::{self, Config};
+use r8ball::daemon::{self, PidFile};
+use r8ball::irc::{self, net::event_loop};
+use r8ball::{config, MainError, VERSION};
 
-use config::cmdline::{ParsedArgs, ParsedArgsError};
-use config::config_file::{Config, ConfigError};
-use irc::net::event_loop;
-
-#[derive(thiserror::Error, Debug)]
-pub enum MainError {
-    #[error("")]
-    Cmdline(#[from] ParsedArgsError),
-    #[error("")]
-    Config(#[from] ConfigError),
-    #[error("")]
-    EvIo(#[from] io::Error),
-    #[error("ERROR: {0}")]
-    IrcProto(String),
+/// r8ball check: the config's problems, exiting 1 if it has any.
+fn check(args: &ParsedArgs, config: &Config) {
+    let problems = config::check::check(config);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        println!("{}: {} problem(s)", args.config, problems.len());
+        process::exit(1);
+    }
+    println!("{}: OK", args.config);
+}
+
+/// r8ball send: print the control socket's answer, exiting 1 if it was an error.
+fn send(args: &ParsedArgs, line: &str, socket: &str) -> Result<(), MainError> {
+    let socket = match socket {
+        "" => Config::from_path(Path::new(&args.config), &[])?
+            .general
+            .control_socket
+            .clone(),
+        socket => socket.to_owned(),
+    };
+    if socket.is_empty() {
+        eprintln!(
+            "{} has no control_socket, give one with --socket",
+            args.config
+        );
+        process::exit(2);
+    }
+    let (answer, ok) = irc::control::send(Path::new(&socket), line)?;
+    for line in answer {
+        println!("{}", line);
+    }
+    if !ok {
+        process::exit(1);
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), MainError> {
-    let args = ParsedArgs::new()?;
+    let args = match ParsedArgs::new() {
+        Ok(args) => args,
+        Err(ParsedArgsError::Help(help)) => {
+            print!("{}", help);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    match &args.command {
+        Subcommand::Run | Subcommand::Check => (),
+        Subcommand::DumpConfig => {
+            print!("{}", config_file::EXAMPLE);
+            return Ok(());
+        }
+        Subcommand::Version => {
+            println!("{}", VERSION);
+            return Ok(());
+        }
+        Subcommand::Send { line, socket } => return send(&args, line, socket),
+    }
     let config_path = Path::new(&args.config);
-    let mut config = Config::from_path(config_path)?;
+    let mut config = Config::from_path(config_path, &args.overrides)?;
+    if args.command == Subcommand::Check {
+        check(&args, &config);
+        return Ok(());
+    }
+    if !args.raw_log.is_empty() {
+        config.general.raw_log = args.raw_log.clone();
+    }
+    // locked before detaching, so a second copy fails where someone can see it.
+    let mut pidfile = match args.pidfile.as_str() {
+        "" => None,
+        path => Some(PidFile::lock(Path::new(path))?),
+    };
+    if args.daemon {
+        daemon::daemonize()?;
+    }
+    daemon::redirect_output(&args.log_file, args.daemon)?;
+    if let Some(pidfile) = pidfile.as_mut() {
+        pidfile.write_pid()?;
+    }
     event_loop(config_path, &mut config)?;
 
     Ok(())
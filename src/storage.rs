@@ -0,0 +1,171 @@
+// Copyright (C) 2021  Anthony DeDominic <adedomin@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::{path::Path, time::Duration};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::config::config_file::StorageConfig;
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("Storage error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Could not create the storage directory: {0}")]
+    Dir(#[from] std::io::Error),
+}
+
+// The database is shared with plugins, which may use this table directly.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS kv (
+    ns    TEXT NOT NULL,
+    key   TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (ns, key)
+);
+";
+
+/// A namespaced key/value store backed by SQLite.
+/// Without a configured path (or with ":memory:") the store lives in memory and is
+/// lost on exit.
+pub struct Storage {
+    conn: Connection,
+}
+
+impl Storage {
+    pub fn from_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        if config.path.is_empty() || config.path == ":memory:" {
+            return Storage::in_memory();
+        }
+        let path = Path::new(&config.path);
+        // e.g. $XDG_STATE_HOME/r8ball, which nothing else makes.
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        Storage::open(path)
+    }
+
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let conn = Connection::open(path)?;
+        // plugins write to the same file, wait on them instead of failing.
+        conn.busy_timeout(Duration::from_secs(2))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Storage::init(conn)
+    }
+
+    pub fn in_memory() -> Result<Self, StorageError> {
+        Storage::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, StorageError> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Storage { conn })
+    }
+
+    pub fn get(&self, ns: &str, key: &str) -> Result<Option<String>, StorageError> {
+        self.conn
+            .query_row(
+                "SELECT value FROM kv WHERE ns = ?1 AND key = ?2",
+                params![ns, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.into())
+    }
+
+    pub fn set(&self, ns: &str, key: &str, value: &str) -> Result<(), StorageError> {
+        self.conn.execute(
+            "INSERT INTO kv (ns, key, value) VALUES (?1, ?2, ?3)
+             ON CONFLICT (ns, key) DO UPDATE SET value = excluded.value",
+            params![ns, key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Move everything written to the WAL into the database file, e.g. before exiting.
+    pub fn checkpoint(&self) -> Result<(), StorageError> {
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    /// Add to a counter, treating a missing or non-numeric value as 0.
+    /// Returns the new value.
+    pub fn incr(&self, ns: &str, key: &str, by: i64) -> Result<i64, StorageError> {
+        self.conn
+            .query_row(
+                "INSERT INTO kv (ns, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (ns, key) DO UPDATE SET value = CAST(value AS INTEGER) + ?3
+                 RETURNING CAST(value AS INTEGER)",
+                params![ns, key, by],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.into())
+    }
+
+    pub fn delete(&self, ns: &str, key: &str) -> Result<(), StorageError> {
+        self.conn.execute(
+            "DELETE FROM kv WHERE ns = ?1 AND key = ?2",
+            params![ns, key],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Storage;
+
+    #[test]
+    fn get_set_incr() {
+        let store = Storage::in_memory().unwrap();
+        assert_eq!(store.get("ns", "key").unwrap(), None);
+
+        store.set("ns", "key", "value").unwrap();
+        store.set("ns", "key", "value2").unwrap();
+        assert_eq!(store.get("ns", "key").unwrap().as_deref(), Some("value2"));
+        assert_eq!(store.get("other", "key").unwrap(), None);
+
+        assert_eq!(store.incr("karma", "rust", 1).unwrap(), 1);
+        assert_eq!(store.incr("karma", "rust", 2).unwrap(), 3);
+        assert_eq!(store.incr("karma", "rust", -4).unwrap(), -1);
+        assert_eq!(store.incr("ns", "key", 1).unwrap(), 1);
+
+        store.delete("ns", "key").unwrap();
+        assert_eq!(store.get("ns", "key").unwrap(), None);
+    }
+
+    #[test]
+    fn shared_file() {
+        let path = std::env::temp_dir().join(format!("r8ball-test-{}.db", std::process::id()));
+        {
+            let store = Storage::open(&path).unwrap();
+            store.set("ns", "key", "persisted").unwrap();
+        }
+        let store = Storage::open(&path).unwrap();
+        assert_eq!(
+            store.get("ns", "key").unwrap().as_deref(),
+            Some("persisted")
+        );
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}